@@ -1,50 +1,155 @@
-use clap::Parser;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use goauld::payloads::CallArg;
+use goauld::report::ReturnType;
+use goauld::Staging;
 use log::{error, info, warn, LevelFilter};
 use simple_logger::SimpleLogger;
 
 /// Inject code into a running process using /proc/pid/mem
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
     /// pid of the target process
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pid: Option<i32>,
 
     /// target application's package name, (re)start the application and do injection
     #[arg(short, long)]
     app_package_name: Option<String>,
 
-    /// path of the library to inject
-    #[arg(short, long)]
-    file: String,
+    /// path of the library to inject,
+    /// repeat it to load several libraries in order
+    #[arg(short, long, required_unless_present = "shellcode", conflicts_with = "shellcode")]
+    file: Vec<String>,
+
+    /// path of a position-independent shellcode to inject instead of a library,
+    /// called as a function and expected to return
+    #[arg(long)]
+    shellcode: Option<String>,
+
+    /// load the library from an anonymous memfd created in the target,
+    /// instead of copying it to a temporary directory
+    #[arg(long)]
+    memfd: bool,
+
+    /// export of the library to call once it is loaded,
+    /// as `int entry(const char *args, size_t len)`
+    #[arg(long)]
+    entry: Option<String>,
+
+    /// argument string passed to the entry point
+    #[arg(long, requires = "entry", allow_hyphen_values = true)]
+    args: Option<String>,
 
     /// function to hijack for injection,
     /// in the form "lib.so!symbol_name"
-    #[arg(long)]
+    #[arg(long, global = true)]
     func_sym: Option<String>,
 
     /// variable to hijack for injection,
     /// in the form "lib.so!symbol_name"
-    #[arg(long)]
+    #[arg(long, global = true)]
     var_sym: Option<String>,
 
+    /// hijack the function through its GOT slot in this module (e.g. the file name of the executable)
+    /// instead of overwriting its prologue, with the first stage in a code cave of the module
+    #[arg(long, global = true, value_name = "MODULE")]
+    got: Option<String>,
+
+    /// hijack the libc wrapper of a system call the threads of the target are blocked in,
+    /// ranked by how soon it should be called again, instead of --func-sym;
+    /// with --got, through its GOT slot in the module, as most wrappers are shorter than the first stage
+    #[arg(long, global = true, conflicts_with = "func_sym")]
+    auto_trigger: bool,
+
+    /// never map the stages writable and executable at once: the second stage is written
+    /// in a writable map, then its code is made executable and its data left writable
+    #[arg(long, global = true)]
+    w_xor_x: bool,
+
+    /// also save the FP/SIMD registers (x87/SSE/AVX, NEON) and their control registers around
+    /// the second stage, to hijack functions taking float or vector arguments
+    #[arg(long, global = true)]
+    full_context: bool,
+
+    /// resolve the symbols and build the stages, print what would be done and exit
+    /// without writing anything in the target
+    #[arg(long)]
+    dry_run: bool,
+
+    /// print the dry run plan as JSON
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+
+    /// give up (and roll back) if the target did not respond after this many seconds
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+
     /// enable debug logs
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     debug: bool,
 
     /// print logs to logcat
-    #[arg(long)]
+    #[arg(long, global = true)]
     logcat: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Unload a library previously injected in the target process
+    Eject {
+        /// file name of the library to unload, as shown in /proc/pid/maps
+        #[arg(short, long)]
+        lib: String,
+
+        /// handle dlopen returned when the library was injected, as printed then (e.g. 0x5581f2a4b6c0),
+        /// instead of finding it in the target
+        #[arg(long, value_parser = parse_handle)]
+        handle: Option<usize>,
+    },
+    /// Call a function in the target process and print its return value and errno
+    Call {
+        /// function to call, in the form "lib.so!symbol_name"
+        target: String,
+
+        /// arguments: integers (123, -1, 0x7b), strings (str:text) or buffers (hex:deadbeef),
+        /// after a `--` if one starts with a hyphen and is not a decimal number
+        #[arg(allow_negative_numbers = true)]
+        args: Vec<String>,
+
+        /// type of the return value: "int" (32 bit) or "long" (as wide as a pointer, for ssize_t and pointers),
+        /// the upper bits of the register being undefined for an int
+        #[arg(long = "ret", value_parser = ["int", "long"], default_value = "long")]
+        return_type: String,
+    },
+}
+
+/// Parses a handle printed in hexadecimal, with or without its 0x prefix.
+fn parse_handle(handle: &str) -> Result<usize, String> {
+    let hex = handle.strip_prefix("0x").unwrap_or(handle);
+    usize::from_str_radix(hex, 16).map_err(|err| format!("invalid handle {}: {}", handle, err))
 }
 
 fn main() {
     let args = Args::parse();
 
+    // keep the JSON plan alone on stdout, unless something goes wrong
+    let level = match args.json {
+        true => LevelFilter::Error,
+        false => LevelFilter::Info,
+    };
+
     SimpleLogger::new()
-        .with_level(LevelFilter::Info)
+        .with_level(level)
         .init()
         .unwrap();
 
+    #[cfg_attr(not(target_os = "android"), allow(unused_mut))]
     let mut target_pid = args.pid.unwrap_or(0);
 
     #[cfg(target_os = "android")]
@@ -70,21 +175,46 @@ fn main() {
         }
     };
 
-    match injector.set_file_path(args.file) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Error setting file path: {:?}", e);
-            std::process::exit(1);
+    if !args.file.is_empty() {
+        for file in &args.file {
+            match injector.add_file_path(file.clone()) {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Error setting file path: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
         }
-    }
 
-    match injector.use_raw_dlopen() {
-        Ok(_) => {
-            info!("use_raw_dlopen successful");
+        if args.memfd {
+            injector.set_staging(Staging::Memfd);
         }
-        Err(e) => {
-            error!("Error use_raw_dlopen: {:?}", e);
-            std::process::exit(1);
+
+        if let Some(entry) = &args.entry {
+            let entry_args = args.args.clone().unwrap_or_default();
+            injector.set_entry(entry, entry_args.into_bytes());
+        }
+
+        match injector.use_raw_dlopen() {
+            Ok(_) => {
+                info!("use_raw_dlopen successful");
+            }
+            Err(e) => {
+                error!("Error use_raw_dlopen: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(shellcode) = &args.shellcode {
+        match std::fs::read(shellcode) {
+            Ok(bytes) => {
+                injector.set_shellcode(bytes);
+            }
+            Err(e) => {
+                error!("Error reading shellcode: {:?}", e);
+                std::process::exit(1);
+            }
         }
     }
 
@@ -130,8 +260,118 @@ fn main() {
         };
     }
 
+    if let Some(module) = &args.got {
+        injector.set_got_module(module);
+    }
+
+    injector.set_write_xor_execute(args.w_xor_x);
+    injector.set_full_context(args.full_context);
+
+    if args.auto_trigger {
+        match injector.auto_trigger() {
+            Ok(report) if args.json => info!("{}", report),
+            Ok(report) => println!("{}", report),
+            Err(e) => {
+                error!("Error choosing the function to hijack: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(timeout) = args.timeout {
+        injector.set_timeout(Duration::from_secs(timeout));
+    }
+
+    let cancel = injector.cancel_handle();
+    if let Err(e) = ctrlc::set_handler(move || {
+        warn!("Interrupted, cancelling the injection");
+        cancel.cancel();
+    }) {
+        error!("Error setting Ctrl-C handler: {:?}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(Command::Eject { lib, handle }) = &args.command {
+        let report = match handle {
+            Some(handle) => injector.eject_handle(lib, *handle),
+            None => injector.eject(lib),
+        };
+        match report {
+            Ok(report) => {
+                println!("{}", report);
+                if report.error.is_some() || !report.unloaded {
+                    error!("Library was not unloaded");
+                    std::process::exit(1);
+                }
+                info!("Ejection successful");
+            }
+            Err(e) => {
+                error!("Error ejecting: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Call { target, args, return_type }) = &args.command {
+        let sym_pair: Vec<&str> = target.split('!').collect();
+        if sym_pair.len() != 2 {
+            error!("Invalid function symbol format, use lib.so!symbol_name");
+            std::process::exit(1);
+        }
+        let call_args = match args.iter().map(|arg| arg.parse()).collect::<Result<Vec<CallArg>, _>>() {
+            Ok(call_args) => call_args,
+            Err(e) => {
+                error!("Error parsing call arguments: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        match injector.call(sym_pair[0], sym_pair[1], &call_args) {
+            Ok(mut report) => {
+                report.return_type = match return_type.as_str() {
+                    "int" => ReturnType::Int,
+                    _ => ReturnType::Long,
+                };
+                println!("{}", report);
+                info!("Call successful");
+            }
+            Err(e) => {
+                error!("Error calling {}: {:?}", target, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.dry_run {
+        match injector.plan() {
+            Ok(plan) if args.json => match serde_json::to_string_pretty(&plan) {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    error!("Error serializing the plan: {:?}", e);
+                    std::process::exit(1);
+                }
+            },
+            Ok(plan) => println!("{}", plan),
+            Err(e) => {
+                error!("Error planning the injection: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     match injector.inject() {
-        Ok(_) => {
+        Ok(report) => {
+            println!("{}", report);
+            if !report.is_loaded() {
+                error!("Library was not loaded");
+                std::process::exit(1);
+            }
+            if args.entry.is_some() && report.entry_value.is_none() {
+                error!("Entry point was not called");
+                std::process::exit(1);
+            }
             info!("Injection successful");
         }
         Err(e) => {
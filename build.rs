@@ -16,7 +16,7 @@ fn android() {
         std::fs::copy(
             lib_path,
             Path::new(&output_path)
-                .join(&env::var("CARGO_NDK_ANDROID_TARGET").unwrap())
+                .join(env::var("CARGO_NDK_ANDROID_TARGET").unwrap())
                 .join("libc++_shared.so"),
         )
         .unwrap();
@@ -25,7 +25,7 @@ fn android() {
         std::fs::copy(
             lib_path2,
             Path::new(&output_path)
-                .join(&env::var("CARGO_NDK_ANDROID_TARGET").unwrap())
+                .join(env::var("CARGO_NDK_ANDROID_TARGET").unwrap())
                 .join("liblogger.so"),
         )
         .unwrap();
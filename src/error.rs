@@ -1,5 +1,20 @@
 use std::io::Error as IoError;
 
+use nix::errno::Errno;
+
+/// The phases of an injection during which the injector waits for the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the hijacked function to be called and the first stage to allocate its map.
+    Trigger,
+    /// Waiting for the threads to leave the hijacked code, before it is overwritten or restored.
+    Handshake,
+    /// Waiting for the second stage to report its result.
+    SecondStage,
+    /// Waiting for the second stage to unmap its map.
+    Release,
+}
+
 /// The errors may occurr.
 #[derive(Debug)]
 pub enum InjectionError {
@@ -20,6 +35,22 @@ pub enum InjectionError {
     /// It occurs when `dlopen` symbol name (`__libc_dlopen_mode`/`dlopen` on Linux, `dlopen` on Android)
     /// was not found in the expected library.
     SymbolNotFound(String),
+    /// It occurs when no unused bytes large enough were found at the end of the executable segments of the
+    /// given module, for the tail unmapping the second stage (and the first stage, to hijack a function through
+    /// its GOT slot).
+    CodeCaveNotFound(String),
+    /// It occurs when no unused bytes large enough were found at the end of the writable segments of the given
+    /// module, for the block of the first stage next to the mailbox, or when they are given to `malloc` (musl).
+    DataCaveNotFound(String),
+    /// It occurs when the map holding the second stage can't be allocated by the first stage: it is larger
+    /// than the first stage can ask for, or the page size of the target leaves no room for the mailbox flags.
+    InvalidAllocation(String),
+    /// It occurs when a system call of the first stage failed in the target with the given errno, e.g. `mmap`
+    /// with `ENOMEM`. The threads went back to the hijacked function, which has been restored.
+    FirstStageFailed(&'static str, Errno),
+    /// It occurs when the first stage which ran in the target wrote another version of the mailbox protocol
+    /// than the one of the injector in its block.
+    MailboxVersion(u32),
     /// It occurs when the instruction pointer of the target process couldn't be retrieved. This either means there's a lack of priviliges,
     /// `/proc/<id>/syscall` is missing or was improperly parsed, or none of the process thread was blocked when the intruduction
     /// was attempted.
@@ -31,6 +62,12 @@ pub enum InjectionError {
     /// It occurs when the intruducer process lacks of sufficient priviliges. This typically depends on `/proc/sys/kernel/yama/ptrace_scope`
     /// value on Linux.
     InsufficientPriviliges,
+    /// It occurs when the arguments of a function to call in the target can't be parsed or passed.
+    InvalidCallArgs(String),
+    /// It occurs when the injector waited too long for the target during the given phase.
+    Timeout(Phase),
+    /// It occurs when the injection was cancelled during the given phase.
+    Cancelled(Phase),
     /// It occurs when a I/O error occurred.
     Io(IoError),
 }
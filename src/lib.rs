@@ -6,24 +6,67 @@ extern crate log;
 pub mod error;
 pub mod payloads;
 pub mod proc;
+pub mod report;
+pub mod trigger;
 pub mod utils;
+pub mod wait;
 
+use std::io::Write;
 use std::ops::Not;
+use std::time::Duration;
 
-use crate::error::InjectionError;
+use serde::Serialize;
+
+use crate::error::{InjectionError, Phase};
+use crate::payloads::{ArchBackend, CallArg, DlFns, Entry, LoadedLibrary, Mailbox, MailboxBlock, MailboxState, SecondStage, StageConfig, StageReturn};
+use crate::proc::mem::Mem;
+use crate::proc::task::TaskSyscall;
 use crate::proc::Proc;
+use crate::report::{
+    CallReport, EjectionReport, InjectionPlan, InjectionReport, LibraryReport, PlannedLibrary, PlannedPatch,
+    PlannedSymbol, ReturnType, ThreadReport, TriggerCandidate, TriggerReport,
+};
 use crate::utils::ptrace::PtraceScope;
 use crate::utils::resolv::RemoteModule;
+use crate::wait::{CancelHandle, Waiter};
+
+/// Name of the function called, if exported, before a library is ejected.
+pub const FINI_SYM_NAME: &str = "goauld_fini";
+
+/// How the library is made available to the target before `dlopen`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Staging {
+    /// Copied to a temporary directory (`/tmp` on Linux, `/data/local/tmp` on Android).
+    #[default]
+    TmpFile,
+    /// Written to an anonymous memfd created in the target, and loaded from `/proc/self/fd/<fd>`.
+    Memfd,
+}
 
 pub struct Injector {
     remote_proc: proc::Proc,
-    file_path: String,
+    file_paths: Vec<String>,
+    library_bytes: Option<Vec<u8>>,
+    staging: Staging,
+    entry: Option<Entry>,
+    shellcode: Option<Vec<u8>>,
+    got_module: Option<String>,
+    write_xor_execute: bool,
+    full_context: bool,
+    target_func_module_name: String,
     target_func_sym_name: String,
     target_func_sym_addr: usize,
+    target_var_module_name: String,
     target_var_sym_name: String,
     target_var_sym_addr: usize,
+    /// Address of the mailbox block, in a data cave of the module of the mailbox.
+    target_var_block_addr: usize,
     module_cache: HashMap<String, RemoteModule>,
-    sym_cache: HashMap<String, usize>,
+    /// Addresses of the symbols, by module and name: a same name may be exported by several modules.
+    sym_cache: HashMap<(String, String), usize>,
+    timeout: Option<Duration>,
+    cancel: CancelHandle,
 }
 
 impl Injector {
@@ -45,68 +88,158 @@ impl Injector {
 
         Ok(Injector {
             remote_proc: proc,
-            file_path: String::new(),
+            file_paths: Vec::new(),
+            library_bytes: None,
+            staging: Staging::default(),
+            entry: None,
+            shellcode: None,
+            got_module: None,
+            write_xor_execute: false,
+            full_context: false,
+            target_func_module_name: String::new(),
             target_func_sym_name: String::new(),
             target_func_sym_addr: 0,
+            target_var_module_name: String::new(),
             target_var_sym_name: String::new(),
             target_var_sym_addr: 0,
+            target_var_block_addr: 0,
             module_cache: HashMap::new(),
             sym_cache: HashMap::new(),
+            timeout: None,
+            cancel: CancelHandle::default(),
         })
     }
 
     pub fn set_file_path(&mut self, file_path: String) -> Result<&mut Self, InjectionError> {
+        self.file_paths.clear();
+        self.add_file_path(file_path)
+    }
+
+    /// Adds a library to inject after the ones already set. They are loaded in order, by the same second stage.
+    pub fn add_file_path(&mut self, file_path: String) -> Result<&mut Self, InjectionError> {
         let file = std::fs::File::open(&file_path);
         if file.is_err() {
             error!("File not found: {}", file_path);
             return Err(InjectionError::FileError);
         }
 
-        self.file_path = file_path;
+        self.file_paths.push(file_path);
+        Ok(self)
+    }
+
+    /// Sets the library to inject from memory, instead of a file. It implies the [`Staging::Memfd`] staging.
+    pub fn set_library_bytes(&mut self, bytes: Vec<u8>) -> Result<&mut Self, InjectionError> {
+        utils::verify_elf_bytes(&bytes)?;
+
+        self.library_bytes = Some(bytes);
+        self.staging = Staging::Memfd;
         Ok(self)
     }
 
+    /// Sets how the library is made available to the target.
+    pub fn set_staging(&mut self, staging: Staging) -> &mut Self {
+        self.staging = staging;
+        self
+    }
+
+    /// Sets an export of the (last) library to call once it is loaded, as `entry(args, args_len)`.
+    /// Its return value is reported back in [`InjectionReport::entry_value`].
+    pub fn set_entry(&mut self, name: &str, args: Vec<u8>) -> &mut Self {
+        self.entry = Some(Entry {
+            name: name.to_string(),
+            args,
+        });
+        self
+    }
+
+    /// Injects a position-independent shellcode instead of a library, without `dlopen`.
+    /// It is called as a function taking no argument, and must return. Its return value is
+    /// reported back in [`InjectionReport::entry_value`].
+    pub fn set_shellcode(&mut self, shellcode: Vec<u8>) -> &mut Self {
+        self.shellcode = Some(shellcode);
+        self
+    }
+
+    /// Hijacks the function through its GOT slot in `module_name` instead of overwriting its prologue:
+    /// only the calls made by this module are hijacked, and the first stage is written in a code cave
+    /// of the module. `module_name` is matched like the hijacked symbols' modules.
+    pub fn set_got_module(&mut self, module_name: &str) -> &mut Self {
+        self.got_module = Some(module_name.to_string());
+        self
+    }
+
+    /// Never maps the stages writable and executable at once: the second stage is written in a writable map,
+    /// then its code is made executable (and no longer writable) while its data stays writable.
+    /// Needed on targets denying such maps (e.g. SELinux `execmem`, PaX `MPROTECT`).
+    pub fn set_write_xor_execute(&mut self, enabled: bool) -> &mut Self {
+        self.write_xor_execute = enabled;
+        self
+    }
+
+    /// Saves the FP/SIMD registers along with their control and status registers (x87/SSE/AVX with xsave,
+    /// NEON with FPSR and FPCR) before the second stage calls anything, and restores them before it returns.
+    /// Needed to hijack a function taking float or vector arguments. The flags are always saved.
+    pub fn set_full_context(&mut self, enabled: bool) -> &mut Self {
+        self.full_context = enabled;
+        self
+    }
+
     #[cfg(not(target_os = "android"))]
-    fn prepare_file(&self) -> Result<String, InjectionError> {
-        utils::verify_elf_file(self.file_path.as_str())?;
+    fn prepare_file(&self, file_path: &str) -> Result<String, InjectionError> {
+        utils::verify_elf_file(file_path)?;
 
-        let tmp_file_path = utils::copy_file_to_tmp(self.file_path.as_str())?;
+        let tmp_file_path = utils::copy_file_to_tmp(file_path)?;
         Ok(tmp_file_path)
     }
 
     #[cfg(target_os = "android")]
-    fn prepare_file(&self) -> Result<String, InjectionError> {
-        utils::verify_elf_file(self.file_path.as_str())?;
+    fn prepare_file(&self, file_path: &str) -> Result<String, InjectionError> {
+        utils::verify_elf_file(file_path)?;
 
-        let tmp_file_path = utils::copy_file_to_tmp(self.file_path.as_str())?;
+        let tmp_file_path = utils::copy_file_to_tmp(file_path)?;
         utils::fix_file_context(tmp_file_path.as_str())?;
         utils::fix_file_permissions(tmp_file_path.as_str())?;
         utils::print_file_hexdump(tmp_file_path.as_str())?;
         Ok(tmp_file_path)
     }
 
-    fn add_sym(&mut self, module_name: &str, sym_name: &str) -> Result<usize, InjectionError> {
-        debug!("add_sym: {}!{}", module_name, sym_name);
-
+    fn module(&mut self, module_name: &str) -> Result<&RemoteModule, InjectionError> {
         if !self.module_cache.contains_key(module_name) {
             let module = self.remote_proc.maps()?.module(module_name)?;
             self.module_cache.insert(module_name.to_string(), module);
         }
 
-        let module = self.module_cache.get(module_name).unwrap();
-        debug!("add_sym: {} 0x{:x}", module_name, module.vm_addr);
+        Ok(self.module_cache.get(module_name).unwrap())
+    }
 
-        if !self.sym_cache.contains_key(sym_name) {
-            let sym = module.dlsym_from_fs(sym_name)?;
-            self.sym_cache.insert(sym_name.to_string(), sym);
+    fn add_sym(&mut self, module_name: &str, sym_name: &str) -> Result<usize, InjectionError> {
+        debug!("add_sym: {}!{}", module_name, sym_name);
+
+        let key = (module_name.to_string(), sym_name.to_string());
+        if let Some(&sym) = self.sym_cache.get(&key) {
+            return Ok(sym);
         }
 
-        debug!(
-            "add_sym: {} 0x{:x}",
-            sym_name,
-            self.sym_cache.get(sym_name).unwrap()
-        );
-        Ok(*self.sym_cache.get(sym_name).unwrap())
+        let module = self.module(module_name)?;
+        debug!("add_sym: {} 0x{:x}", module_name, module.vm_addr);
+
+        let sym = module.dlsym(sym_name).inspect_err(|err| {
+            error!("{}!{} not resolved: {:?}", module_name, sym_name, err);
+        })?;
+        debug!("add_sym: {} 0x{:x}", sym_name, sym);
+
+        Ok(*self.sym_cache.entry(key).or_insert(sym))
+    }
+
+    /// Sets how long the injector waits for the target in each phase (forever by default).
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Gets a handle that cancels the injection while it waits for the target.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
     }
 
     pub fn set_func_sym(
@@ -115,21 +248,27 @@ impl Injector {
         sym_name: &str,
     ) -> Result<&mut Self, InjectionError> {
         let sym_addr = self.add_sym(module_name, sym_name)?;
+        self.target_func_module_name = module_name.to_string();
         self.target_func_sym_name = sym_name.to_string();
         self.target_func_sym_addr = sym_addr;
         debug!("set_func_sym: {} 0x{:x}", sym_name, sym_addr);
         Ok(self)
     }
 
+    /// Sets the variable used as a mailbox by the stages. Its module must have a data cave for the mailbox block,
+    /// which the first stage writes its protocol version and its failures in.
     pub fn set_var_sym(
         &mut self,
         module_name: &str,
         sym_name: &str,
     ) -> Result<&mut Self, InjectionError> {
         let sym_addr = self.add_sym(module_name, sym_name)?;
+        let block_addr = self.module(module_name)?.data_cave(payloads::MAILBOX_BLOCK_LEN)?;
+        self.target_var_module_name = module_name.to_string();
         self.target_var_sym_name = sym_name.to_string();
         self.target_var_sym_addr = sym_addr;
-        debug!("set_var_sym: {} 0x{:x}", sym_name, sym_addr);
+        self.target_var_block_addr = block_addr;
+        debug!("set_var_sym: {} 0x{:x}, block at 0x{:x}", sym_name, sym_addr, block_addr);
         Ok(self)
     }
 
@@ -144,6 +283,160 @@ impl Injector {
         Ok(self)
     }
 
+    /// Chooses the function to hijack among the libc wrappers of the system calls the threads of the target
+    /// are blocked in, ranked by how soon they should be called again.
+    ///
+    /// A wrapper is only chosen if a blocked thread called it, and if no thread would resume within the bytes
+    /// overwritten by the first stage. Otherwise the function set before (`malloc` by default) is kept.
+    ///
+    /// Most wrappers are shorter than the first stage. Hijacked through the GOT of a module (see
+    /// [`Injector::set_got_module`]), they are left untouched: a wrapper is then chosen if the module has a GOT
+    /// slot for it, only the calls made by the module being hijacked.
+    pub fn auto_trigger(&mut self) -> Result<TriggerReport, InjectionError> {
+        self.ensure_hijack_syms()?;
+
+        let arch = self
+            .remote_proc
+            .arch()
+            .ok_or(InjectionError::UnsupportedArch)?;
+        let backend = arch.backend();
+
+        let patch_len = self.first_stage_len(backend)?;
+        let got_module = match &self.got_module {
+            Some(module_name) => Some(self.remote_proc.maps()?.module(module_name)?),
+            None => None,
+        };
+        let word_len = backend.pointer_len();
+        let page_size = self.remote_proc.page_size()?;
+
+        info!("Reading the threads of the target");
+        let tasks = self.remote_proc.tasks()?;
+        let mut mem = self.remote_proc.mem_read_only()?;
+
+        // where each blocked thread resumes, and the return addresses on top of its stack
+        let mut resumes: Vec<(i32, usize, Vec<usize>)> = Vec::new();
+        let mut threads: Vec<ThreadReport> = Vec::new();
+        let mut candidates: Vec<TriggerCandidate> = Vec::new();
+        for task in tasks {
+            let syscall = match task.syscall {
+                TaskSyscall::InSyscall { nr, .. } => trigger::syscall_wrappers(&arch, nr),
+                _ => None,
+            };
+
+            if let TaskSyscall::Blocked { sp, pc } | TaskSyscall::InSyscall { sp, pc, .. } = task.syscall {
+                resumes.push((task.tid, pc, stack_words(&mut mem, sp, word_len, page_size)));
+            }
+
+            if let Some(syscall) = syscall {
+                for &symbol in syscall.wrappers {
+                    let index = match candidates.iter().position(|candidate| candidate.symbol == symbol) {
+                        Some(index) => index,
+                        None => {
+                            candidates.push(TriggerCandidate {
+                                symbol,
+                                addr: 0,
+                                syscall: syscall.name,
+                                kind: syscall.kind,
+                                threads: 0,
+                                score: 0,
+                                rejected: None,
+                            });
+                            candidates.len() - 1
+                        }
+                    };
+                    candidates[index].threads += 1;
+                    candidates[index].score += syscall.kind.weight();
+                }
+            }
+
+            threads.push(ThreadReport {
+                tid: task.tid,
+                name: task.name,
+                state: task.state,
+                syscall: task.syscall,
+                syscall_name: syscall.map(|syscall| syscall.name),
+            });
+        }
+
+        let libc = self.module("libc.so")?;
+        for candidate in &mut candidates {
+            let (addr, size) = match libc.sym(candidate.symbol) {
+                Ok(sym) => sym,
+                Err(_) => {
+                    candidate.rejected = Some(String::from("not exported by libc"));
+                    continue;
+                }
+            };
+            candidate.addr = addr;
+            let start = backend.code_addr(addr);
+            let function = start..start + size;
+
+            // through the GOT, the wrapper is left untouched and the first stage is written in a code cave
+            let patched = match &got_module {
+                Some(module) => match module.got_slot(candidate.symbol) {
+                    Ok(_) => 0..0,
+                    Err(InjectionError::SymbolNotFound(_)) => {
+                        candidate.rejected = Some(format!("not called through the GOT of {}", module.name));
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                },
+                None if size < patch_len => {
+                    candidate.rejected = Some(format!(
+                        "{} bytes long, the first stage ({} bytes) would overwrite the next function",
+                        size, patch_len
+                    ));
+                    continue;
+                }
+                None => start..start + patch_len,
+            };
+
+            for (tid, pc, words) in &resumes {
+                if patched.contains(pc) {
+                    candidate.rejected = Some(format!(
+                        "thread {} would resume at {}+0x{:x}, within the first stage",
+                        tid, candidate.symbol, pc - start
+                    ));
+                } else if let Some(word) = words.iter().find(|word| patched.contains(word)) {
+                    candidate.rejected = Some(format!(
+                        "thread {} may return to {}+0x{:x}, within the first stage",
+                        tid, candidate.symbol, word - start
+                    ));
+                }
+                if candidate.rejected.is_some() {
+                    break;
+                }
+            }
+
+            let called = resumes
+                .iter()
+                .any(|(_, pc, words)| function.contains(pc) || words.iter().any(|word| function.contains(word)));
+            if candidate.rejected.is_none() && !called {
+                candidate.rejected = Some(String::from("not called by the blocked threads"));
+            }
+        }
+
+        // the wrappers of a same system call keep their order, the innermost first
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.score));
+
+        let chosen = candidates.iter().find(|candidate| candidate.rejected.is_none());
+        match chosen {
+            Some(candidate) => {
+                info!("auto trigger: {} ({} thread(s) in {})", candidate.symbol, candidate.threads, candidate.syscall);
+                let symbol = candidate.symbol;
+                self.set_func_sym("libc.so", symbol)?;
+            }
+            None => warn!("auto trigger: no candidate left, keeping {}", self.target_func_sym_name),
+        }
+
+        Ok(TriggerReport {
+            chosen: chosen.is_some(),
+            threads,
+            candidates,
+            function: self.target_func_sym_name.clone(),
+        })
+    }
+
     pub fn restart_app_and_get_pid(package_name: &str) -> Result<u32, InjectionError> {
         let pid = utils::restart_app_and_get_pid(package_name);
         if pid > 0 {
@@ -153,76 +446,1091 @@ impl Injector {
         }
     }
 
-    pub fn inject(&mut self) -> Result<(), InjectionError> {
-        let file_path = self.prepare_file()?;
+    /// Reads the remote memory, telling apart a read failure from a target that exited.
+    fn read_remote(&self, mem: &mut Mem, addr: usize, len: usize) -> Result<Vec<u8>, InjectionError> {
+        mem.read(addr, len).map_err(|err| match self.remote_proc.is_running() {
+            true => err,
+            false => InjectionError::ProcessNotRunning,
+        })
+    }
+
+    /// Reads the mailbox variable, holding the state of the first stage, its thread count and the address of its map.
+    fn mailbox(&self, backend: &dyn ArchBackend, mem: &mut Mem) -> Result<Mailbox, InjectionError> {
+        let data = self.read_remote(mem, self.target_var_sym_addr, backend.mailbox_len())?;
+        let value = word_from_le_bytes(&data) as u64;
+        Mailbox::parse(value).ok_or_else(|| {
+            error!("unexpected mailbox: 0x{:x}", value);
+            InjectionError::RemoteProcessError
+        })
+    }
+
+    /// Reads the mailbox block, once the first stage has allocated its map or failed to, checking that it was written
+    /// by a first stage of the protocol version of the injector.
+    fn mailbox_block(&self, mem: &mut Mem) -> Result<MailboxBlock, InjectionError> {
+        let data = self.read_remote(mem, self.target_var_block_addr, payloads::MAILBOX_BLOCK_LEN)?;
+        let mailbox_block = MailboxBlock::parse(&data);
+        if mailbox_block.version != payloads::MAILBOX_VERSION {
+            error!("mailbox protocol v{} in the target, v{} expected", mailbox_block.version, payloads::MAILBOX_VERSION);
+            return Err(InjectionError::MailboxVersion(mailbox_block.version));
+        }
+        Ok(mailbox_block)
+    }
+
+    /// Writes the mailbox variable and its block: zeroed along with the first stage, restored along with the function.
+    fn write_mailbox(&self, mem: &mut Mem, var_bytes: &[u8], block_bytes: &[u8]) -> Result<(), InjectionError> {
+        mem.write(self.target_var_block_addr, block_bytes)?;
+        mem.write(self.target_var_sym_addr, var_bytes)
+    }
 
+    /// Reads the mailbox variable, returning it once the first stage has allocated its map, or failed to.
+    fn read_mailbox(&self, backend: &dyn ArchBackend, mem: &mut Mem) -> Result<Option<Mailbox>, InjectionError> {
+        let mailbox = self.mailbox(backend, mem)?;
+        Ok((mailbox.state >= MailboxState::Allocated).then_some(mailbox))
+    }
+
+    /// Waits for the mailbox to reach `state` with no thread left in the first stage at `addr`.
+    ///
+    /// The threads which had not counted themselves yet when the function was parked are drained,
+    /// and must then leave the first stage too.
+    fn wait_first_stage_left(&self, backend: &dyn ArchBackend, waiter: &Waiter, mem: &mut Mem, state: MailboxState, addr: usize, len: usize) -> Result<(), InjectionError> {
+        loop {
+            waiter.poll(Phase::Handshake, || {
+                let mailbox = self.mailbox(backend, mem)?;
+                Ok((mailbox.state == state && mailbox.threads == 0).then_some(()))
+            })?;
+            self.drain(waiter, addr, len)?;
+
+            if self.mailbox(backend, mem)?.threads == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the threads to leave the code at `addr`, whose start has just been parked on a self jmp.
+    ///
+    /// The pc of the running (or runnable) threads can't be read: they have left once they have spent
+    /// more time on a CPU, and can't have gone back past the parking spot.
+    fn drain(&self, waiter: &Waiter, addr: usize, len: usize) -> Result<(), InjectionError> {
+        let parked: HashMap<i32, u64> = self
+            .remote_proc
+            .tasks()?
+            .into_iter()
+            .map(|task| (task.tid, task.cpu_time))
+            .collect();
+
+        waiter.poll(Phase::Handshake, || {
+            for task in self.remote_proc.tasks()? {
+                let left = match task.syscall {
+                    TaskSyscall::Blocked { pc, .. } | TaskSyscall::InSyscall { pc, .. } => pc <= addr || pc >= addr + len,
+                    TaskSyscall::Running => parked.get(&task.tid).is_none_or(|cpu_time| task.cpu_time > *cpu_time),
+                };
+                if !left {
+                    debug!("thread {} may still be in 0x{:x}..0x{:x}", task.tid, addr, addr + len);
+                    return Ok(None);
+                }
+            }
+            Ok(Some(()))
+        })
+    }
+
+    /// Resolves the `libdl` functions used by the second stages.
+    fn dl_fns(&mut self) -> Result<DlFns, InjectionError> {
+        let dlopen_lib_name = utils::get_dlopen_lib_name();
+        Ok(DlFns {
+            dlopen: self.add_sym(&dlopen_lib_name, "dlopen")?,
+            dlsym: self.add_sym(&dlopen_lib_name, "dlsym")?,
+            dlclose: self.add_sym(&dlopen_lib_name, "dlclose")?,
+            dlerror: self.add_sym(&dlopen_lib_name, "dlerror")?,
+        })
+    }
+
+    /// Makes sure a function and a variable to hijack have been chosen.
+    fn ensure_hijack_syms(&mut self) -> Result<(), InjectionError> {
         if self.target_func_sym_name.is_empty() || self.target_var_sym_name.is_empty() {
             warn!("target_func_sym or target_var_sym is empty, using defaults");
             self.set_default_syms()?;
         }
+        Ok(())
+    }
+
+    /// Gets the length of the first stage, which does not depend on the size of the map it allocates.
+    fn first_stage_len(&self, backend: &dyn ArchBackend) -> Result<usize, InjectionError> {
+        let page_size = self.remote_proc.page_size()?;
+        let exec_len = self.write_xor_execute.then_some(page_size);
+        Ok(backend.first_shellcode(self.target_var_sym_addr, self.target_var_block_addr, StageReturn::default(), page_size, exec_len)?.len())
+    }
+
+    /// Gets the alignment of the data of the second stage: a page when its code is made executable apart.
+    fn data_align(&self) -> Result<usize, InjectionError> {
+        match self.write_xor_execute {
+            true => self.remote_proc.page_size(),
+            false => Ok(0x8),
+        }
+    }
 
-        let class = self
-            .remote_proc
-            .class()
-            .ok_or(InjectionError::UnsupportedArch)?;
+    /// Gets how the second stages are built.
+    fn stage_config(&self) -> Result<StageConfig, InjectionError> {
+        Ok(StageConfig {
+            var_addr: self.target_var_sym_addr,
+            data_align: self.data_align()?,
+            full_context: self.full_context,
+            page_size: self.remote_proc.page_size()?,
+        })
+    }
 
-        info!("Building second stage shellcode");
-        let second_stage = payloads::raw_dlopen_shellcode(
-            &class,
-            *self.sym_cache.get("dlopen").unwrap(),
-            file_path,
-            self.target_func_sym_addr,
-        )?;
+    /// Gets the length of the code of `second_stage` the first stage makes executable, if the map is not.
+    fn exec_len(&self, second_stage: &SecondStage) -> Option<usize> {
+        self.write_xor_execute.then_some(second_stage.data_offset)
+    }
+
+    /// Gets the length of the map allocated by the first stage for `second_stage`: its size rounded up
+    /// to the page size of the target.
+    fn alloc_len(&self, backend: &dyn ArchBackend, second_stage: &SecondStage) -> Result<usize, InjectionError> {
+        let page_size = self.remote_proc.page_size()?;
+
+        // the map address is published in the mailbox along with its state and thread count in its low bits
+        if !page_size.is_power_of_two() || page_size <= payloads::MAILBOX_FIELDS as usize {
+            error!("unsupported page size: 0x{:x}", page_size);
+            return Err(InjectionError::InvalidAllocation(format!("page size 0x{:x}", page_size)));
+        }
+
+        let alloc_len = second_stage.code.len().next_multiple_of(page_size);
+        if alloc_len > backend.max_alloc_len() {
+            error!("second stage too large: {} bytes", second_stage.code.len());
+            return Err(InjectionError::InvalidAllocation(format!("map of {} bytes", alloc_len)));
+        }
+
+        debug!("alloc_len: {} bytes, page size: {} bytes", alloc_len, page_size);
+        Ok(alloc_len)
+    }
+
+    /// Chooses where the first stage and the tail unmapping the second stage are written, and where the second
+    /// stage jumps back to.
+    fn hijack(&mut self, backend: &dyn ArchBackend) -> Result<Hijack, InjectionError> {
+        let stage_len = self.first_stage_len(backend)?;
+        let tail_len = backend.unmap_tail(StageReturn::default())?.len();
+
+        let Some(module_name) = self.got_module.clone() else {
+            let func_module_name = self.target_func_module_name.clone();
+            let tail_addr = match self.module(&func_module_name)?.code_cave(tail_len) {
+                Ok(tail_addr) => {
+                    info!("{} code cave at 0x{:x}", func_module_name, tail_addr);
+                    Some(tail_addr)
+                }
+                // the tail then takes a page at the end of the code of the second stage, which stays mapped
+                Err(InjectionError::CodeCaveNotFound(_)) => {
+                    warn!("no code cave in {}, the tail unmapping the second stage is left in its map", func_module_name);
+                    None
+                }
+                Err(err) => return Err(err),
+            };
+
+            // the symbol of a Thumb function has bit 0 set, and is where the stages return to
+            return Ok(Hijack {
+                stage_addr: backend.code_addr(self.target_func_sym_addr),
+                got_slot: None,
+                return_addr: self.target_func_sym_addr,
+                tail_addr,
+                stage_len,
+                tail_len,
+            });
+        };
+
+        // the tail follows the first stage in the code cave
+        let tail_offset = stage_len.next_multiple_of(0x10);
+        let sym_name = self.target_func_sym_name.clone();
+        let module = self.module(&module_name)?;
+        let got_slot = module.got_slot(&sym_name)?;
+        let stage_addr = module.code_cave(tail_offset + tail_len)?;
+        info!("{}@got at 0x{:x}, code cave at 0x{:x}", sym_name, got_slot, stage_addr);
+
+        // the slot may still point to the PLT stub resolving the function, which is as good to jump to
+        let return_addr = read_word(&mut self.remote_proc.mem_read_only()?, got_slot, backend.pointer_len())?;
+
+        Ok(Hijack {
+            stage_addr,
+            got_slot: Some(got_slot),
+            return_addr,
+            tail_addr: Some(stage_addr + tail_offset),
+            stage_len,
+            tail_len,
+        })
+    }
+
+    /// Builds the second stage loading the libraries (or running the shellcode).
+    ///
+    /// With `dry_run`, the libraries are not copied where the target can access them.
+    fn prepare_second_stage(&mut self, backend: &dyn ArchBackend, hijack: &Hijack, dry_run: bool) -> Result<PreparedStage, InjectionError> {
+        if let Some(shellcode) = &self.shellcode {
+            if shellcode.is_empty() {
+                error!("Empty shellcode");
+                return Err(InjectionError::ShellcodeError);
+            }
+
+            info!("Building second stage shellcode");
+            return Ok(PreparedStage {
+                second_stage: backend.shellcode_stage(
+                    shellcode,
+                    hijack.ret(),
+                    self.stage_config()?,
+                )?,
+                names: Vec::new(),
+                staged_paths: Vec::new(),
+                memfd_libraries: Vec::new(),
+            });
+        }
+
+        let dl = self.dl_fns()?;
+        let config = self.stage_config()?;
+
+        match self.staging {
+            Staging::TmpFile => {
+                let staged_paths = self
+                    .file_paths
+                    .iter()
+                    .map(|file_path| match dry_run {
+                        true => utils::verify_elf_file(file_path).and_then(|_| utils::tmp_file_path(file_path)),
+                        false => self.prepare_file(file_path),
+                    })
+                    .collect::<Result<Vec<String>, _>>()?;
+
+                info!("Building second stage shellcode");
+                let second_stage = backend.raw_dlopen_shellcode(
+                    &dl,
+                    self.entry.as_ref(),
+                    &staged_paths,
+                    hijack.ret(),
+                    config,
+                )?;
+
+                Ok(PreparedStage {
+                    second_stage,
+                    names: self.file_paths.clone(),
+                    staged_paths,
+                    memfd_libraries: Vec::new(),
+                })
+            }
+            Staging::Memfd => {
+                let (names, libraries) = match &self.library_bytes {
+                    Some(bytes) => (vec![String::from("<memory>")], vec![bytes.clone()]),
+                    None => {
+                        let libraries = self
+                            .file_paths
+                            .iter()
+                            .map(|file_path| std::fs::read(file_path).map_err(|_| InjectionError::FileError))
+                            .collect::<Result<Vec<Vec<u8>>, _>>()?;
+                        (self.file_paths.clone(), libraries)
+                    }
+                };
+                for bytes in &libraries {
+                    utils::verify_elf_bytes(bytes)?;
+                }
+
+                // the memfds are named after the files, "goauld" for a library given as bytes
+                let memfd_names: Vec<_> = match &self.library_bytes {
+                    Some(_) => vec![String::from(payloads::MEMFD_NAME)],
+                    None => names.iter().map(|name| memfd_name(name)).collect(),
+                };
+
+                info!("Building second stage shellcode");
+                let second_stage = backend.memfd_dlopen_shellcode(
+                    &dl,
+                    self.entry.as_ref(),
+                    &memfd_names,
+                    hijack.ret(),
+                    config,
+                )?;
+
+                Ok(PreparedStage {
+                    second_stage,
+                    staged_paths: vec![String::from("/proc/self/fd/<memfd>"); names.len()],
+                    names,
+                    memfd_libraries: libraries,
+                })
+            }
+        }
+    }
+
+    /// Hijacks the target function with the first stage, and runs `second_stage` in the map it allocates.
+    ///
+    /// `on_request` is called with the result block address and value whenever the second stage waits for the injector.
+    fn run_second_stage<F>(&self, backend: &dyn ArchBackend, hijack: &Hijack, second_stage: &SecondStage, mut on_request: F) -> Result<StageResult, InjectionError>
+    where
+        F: FnMut(&mut Mem, usize, usize) -> Result<(), InjectionError>,
+    {
+        let alloc_len = self.alloc_len(backend, second_stage)?;
 
         info!("Building first stage shellcode");
-        //let first_stage = payloads::first_shellcode(&class, self.target_var_sym_addr, second_stage.len()).unwrap();
-        let first_stage = payloads::first_shellcode(&class, self.target_var_sym_addr, 4028)?;
+        debug!(
+            "{} backend: {} bytes mailbox, instruction cache synced by the stages: {}",
+            backend.name(),
+            backend.mailbox_len(),
+            backend.syncs_icache()
+        );
+        let exec_len = self.exec_len(second_stage);
+        let first_stage = backend.first_shellcode(self.target_var_sym_addr, self.target_var_block_addr, hijack.ret(), alloc_len, exec_len)?;
+        let tail = backend.unmap_tail(hijack.ret())?;
 
         let mut mem = self.remote_proc.mem()?;
 
         info!("read original bytes");
-        let func_original_bytes = mem.read(self.target_func_sym_addr, first_stage.len())?;
-        let var_original_bytes = mem.read(self.target_var_sym_addr, 0x8)?;
+        let func_original_bytes = mem.read(hijack.stage_addr, first_stage.len())?;
+        let var_original_bytes = mem.read(self.target_var_sym_addr, backend.mailbox_len())?;
+        let block_original_bytes = mem.read(self.target_var_block_addr, payloads::MAILBOX_BLOCK_LEN)?;
+        let tail_original_bytes = match hijack.tail_addr {
+            Some(tail_addr) => mem.read(tail_addr, tail.len())?,
+            None => Vec::new(),
+        };
+        let got_original_bytes = match hijack.got_slot {
+            Some(got_slot) => mem.read(got_slot, backend.pointer_len())?,
+            None => Vec::new(),
+        };
+
+        let self_jmp = backend.self_jmp(hijack.ret())?;
+        let waiter = Waiter::new(self.timeout, self.cancel.clone());
+
+        // the threads running the function must not run a partly written first stage
+        info!("hold the threads entering the function");
+        self.write_mailbox(&mut mem, &vec![0x0; backend.mailbox_len()], &[0x0; payloads::MAILBOX_BLOCK_LEN])?;
+        mem.write(hijack.stage_addr, &self_jmp)?;
+        if let Err(err) = self.drain(&waiter, hijack.stage_addr, first_stage.len()) {
+            mem.write(hijack.stage_addr, &func_original_bytes[..self_jmp.len()])?;
+            self.write_mailbox(&mut mem, &var_original_bytes, &block_original_bytes)?;
+            return Err(err);
+        }
 
         info!("write first stage shellcode");
-        mem.write(self.target_var_sym_addr, &vec![0x0; 0x8])?;
-        mem.write(self.target_func_sym_addr, &first_stage)?;
+        write_parked(&mut mem, hijack.stage_addr, &first_stage, self_jmp.len())?;
+        if let Some(got_slot) = hijack.got_slot {
+            // the first stage is in place before the slot points to it
+            info!("point the GOT slot at 0x{:x} to the first stage", got_slot);
+            mem.write(got_slot, &pointer_bytes(backend, hijack.stage_addr))?;
+        }
 
         info!("wait for shellcode to trigger");
-        let mut new_map: u64;
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(1));
-            let data = mem.read(self.target_var_sym_addr, 0x8)?;
-            //debug!("Waiting ... {:?}", data);
+        let triggered = waiter.poll(Phase::Trigger, || self.read_mailbox(backend, &mut mem));
+
+        let mailbox = match triggered {
+            Ok(mailbox) => mailbox,
+            Err(err @ (InjectionError::Timeout(_) | InjectionError::Cancelled(_))) => {
+                warn!("{:?}, rolling back", err);
+                park(&mut mem, hijack, &got_original_bytes, &self_jmp)?;
+                self.drain(&Waiter::uninterruptible(), hijack.stage_addr, first_stage.len())?;
+
+                // A thread may have entered the first stage right before it was parked:
+                // it is now running it, so the injection has to be completed.
+                let mailbox = self.mailbox(backend, &mut mem)?;
+                if mailbox.state == MailboxState::Idle && mailbox.threads == 0 {
+                    write_parked(&mut mem, hijack.stage_addr, &func_original_bytes, self_jmp.len())?;
+                    self.write_mailbox(&mut mem, &var_original_bytes, &block_original_bytes)?;
+                    info!("original bytes restored");
+                    return Err(err);
+                }
 
-            // u64 from val
-            new_map = u64::from_le_bytes(data[0..8].try_into().unwrap());
-            if (new_map & 0x1 != 0) && (new_map & 0xffff_ffff_ffff_fff0 != 0) {
-                info!("Boom ... 0x{:x}", new_map);
-                break;
+                warn!("first stage triggered during rollback, completing the injection");
+                Waiter::uninterruptible().poll(Phase::Trigger, || self.read_mailbox(backend, &mut mem))?
             }
+            Err(err) => return Err(err),
+        };
+
+        let mailbox_block = self.mailbox_block(&mut mem)?;
+
+        info!("hold the threads entering the first stage");
+        park(&mut mem, hijack, &got_original_bytes, &self_jmp)?;
+
+        // the threads go back to the function once the first stage has failed, nothing is left in the target
+        if let Some((syscall, errno)) = mailbox_block.failure() {
+            error!("{} failed with {:?} in the target", syscall, errno);
+            self.wait_first_stage_left(
+                backend,
+                &Waiter::uninterruptible(),
+                &mut mem,
+                MailboxState::Failed,
+                hijack.stage_addr,
+                first_stage.len(),
+            )?;
+            write_parked(&mut mem, hijack.stage_addr, &func_original_bytes, self_jmp.len())?;
+            self.write_mailbox(&mut mem, &var_original_bytes, &block_original_bytes)?;
+            info!("original bytes restored");
+            return Err(InjectionError::FirstStageFailed(syscall, errno));
         }
 
-        new_map &= 0xffff_ffff_ffff_fff0;
+        let new_map = mailbox.map().ok_or(InjectionError::RemoteProcessError)?;
         info!("new map: 0x{:x}", new_map);
 
-        #[cfg(any(target_arch = "aarch64"))]
-        {
-            info!("overwrite malloc with loop");
-            let self_jmp_stage = payloads::self_jmp()?;
-            mem.write(self.target_func_sym_addr, &self_jmp_stage)?;
-        }
+        // no thread runs the tail before the second stage is released, which unmaps the pages before it when it
+        // is in the map
+        let release_len = match (hijack.tail_addr, second_stage.tail_offset) {
+            (Some(tail_addr), _) => {
+                info!("write the tail unmapping the second stage at 0x{:x}", tail_addr);
+                mem.write(tail_addr, &tail)?;
+                alloc_len
+            }
+            (None, Some(tail_offset)) => {
+                info!("the tail unmapping the second stage is in its map at 0x{:x}", new_map + tail_offset);
+                tail_offset
+            }
+            (None, None) => return Err(InjectionError::ShellcodeError),
+        };
+
+        // the threads wait on the self jmp at the start of the map, it is replaced last
+        info!("overwrite new map");
+        mem.write(
+            new_map + payloads::MAP_SELF_JMP_LEN,
+            &second_stage.code[payloads::MAP_SELF_JMP_LEN..],
+        )?;
+        mem.write(new_map, &second_stage.code[..payloads::MAP_SELF_JMP_LEN])?;
 
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+        // the second stage reports once the threads counted in the first stage have left it. They are on their way
+        // to the map by then, so this can't be given up: the function stays parked until they have left.
+        info!("wait for the threads to leave the first stage");
+        self.wait_first_stage_left(
+            backend,
+            &Waiter::uninterruptible(),
+            &mut mem,
+            MailboxState::Done,
+            hijack.stage_addr,
+            first_stage.len(),
+        )?;
 
         info!("restore original bytes");
-        mem.write(self.target_func_sym_addr, &func_original_bytes)?;
-        mem.write(self.target_var_sym_addr, &var_original_bytes)?;
+        write_parked(&mut mem, hijack.stage_addr, &func_original_bytes, self_jmp.len())?;
+        self.write_mailbox(&mut mem, &var_original_bytes, &block_original_bytes)?;
 
-        info!("overwrite new map");
-        mem.write(new_map as usize, &second_stage)?;
+        info!("wait for second stage result");
+        let result_addr = new_map + second_stage.result_offset;
+        let done = waiter.poll(Phase::SecondStage, || {
+            let data = self.read_remote(&mut mem, result_addr + payloads::RESULT_STATUS_OFFSET, 0x8)?;
+            match u64::from_le_bytes(data[0..8].try_into().unwrap()) {
+                payloads::RESULT_STATUS_DONE => Ok(Some(())),
+                payloads::RESULT_STATUS_REQUEST => {
+                    let value = read_word(&mut mem, result_addr + payloads::RESULT_VALUE_OFFSET, backend.pointer_len())?;
+                    on_request(&mut mem, result_addr, value)?;
+                    mem.write(
+                        result_addr + payloads::RESULT_STATUS_OFFSET,
+                        &payloads::RESULT_STATUS_RESUME.to_le_bytes(),
+                    )?;
+                    Ok(None)
+                }
+                _ => Ok(None),
+            }
+        });
+
+        if let Err(err @ (InjectionError::Timeout(_) | InjectionError::Cancelled(_))) = &done {
+            // the tail is left in place, for the second stage to unmap itself once it is done
+            warn!("{:?}, releasing the second stage", err);
+            mem.write(result_addr + payloads::RESULT_RELEASE_OFFSET, &(release_len as u64).to_le_bytes())?;
+        }
+        done?;
+
+        // the slots of the result block are 8 bytes long, the stages fill the pointer width of the target
+        let value = read_word(&mut mem, result_addr + payloads::RESULT_VALUE_OFFSET, backend.pointer_len())?;
+        let error_addr = read_word(&mut mem, result_addr + payloads::RESULT_ERROR_OFFSET, backend.pointer_len())?;
+        let error = match error_addr {
+            0 => None,
+            _ => Some(mem.read_c_string(error_addr, 0x400)?),
+        };
+
+        let data = mem.read(result_addr + payloads::RESULT_ERRNO_OFFSET, 0x4)?;
+        let errno = i32::from_le_bytes(data[0..4].try_into().unwrap());
+
+        let entry_value = read_word(&mut mem, result_addr + payloads::RESULT_ENTRY_OFFSET, backend.pointer_len())?;
+
+        // the handles table ends where the result block starts
+        let handles = match second_stage.handles_offset {
+            Some(handles_offset) => mem
+                .read(new_map + handles_offset, second_stage.result_offset - handles_offset)?
+                .chunks_exact(0x8)
+                .map(|handle| u64::from_le_bytes(handle.try_into().unwrap()))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let tail = hijack.tail_addr.map(|tail_addr| (tail_addr, tail_original_bytes.as_slice()));
+        let unmapped = self.release_second_stage(&mut mem, new_map, alloc_len, release_len, result_addr, tail)?;
+
+        Ok(StageResult {
+            map_addr: new_map,
+            unmapped,
+            value,
+            error,
+            errno,
+            entry_value,
+            handles,
+        })
+    }
+
+    /// Lets the second stage unmap the first `release_len` bytes of its map once its result block has been read,
+    /// and restores the code cave holding the `tail` it jumps back through, at its address. Returns whether the map is gone from
+    /// `/proc/<id>/maps`: a tail in the map keeps its page and the ones after it.
+    ///
+    /// The thread which ran the second stage spins until it is released, the other ones leave the map right away.
+    fn release_second_stage(
+        &self,
+        mem: &mut Mem,
+        new_map: usize,
+        alloc_len: usize,
+        release_len: usize,
+        result_addr: usize,
+        tail: Option<(usize, &[u8])>,
+    ) -> Result<bool, InjectionError> {
+        info!("wait for the threads to leave the second stage");
+        self.drain(&Waiter::uninterruptible(), new_map, alloc_len)?;
+
+        info!("release the second stage");
+        mem.write(result_addr + payloads::RESULT_RELEASE_OFFSET, &(release_len as u64).to_le_bytes())?;
+
+        let maps = self.remote_proc.maps()?;
+        let waiter = Waiter::new(self.timeout, self.cancel.clone());
+        match waiter.poll(Phase::Release, || Ok(maps.is_mapped(new_map)?.not().then_some(()))) {
+            Ok(()) => info!("new map 0x{:x} unmapped up to 0x{:x}", new_map, new_map + release_len),
+            Err(err @ (InjectionError::Timeout(_) | InjectionError::Cancelled(_))) => {
+                warn!("{:?}, new map 0x{:x} still mapped, leaving the tail in place", err, new_map);
+                return Ok(false);
+            }
+            Err(err) => return Err(err),
+        }
+
+        let Some((tail_addr, tail_original_bytes)) = tail else {
+            warn!("tail left mapped at 0x{:x}", new_map + release_len);
+            return Ok(false);
+        };
+
+        // the thread which unmapped it is returning from the tail
+        self.drain(&Waiter::uninterruptible(), tail_addr, tail_original_bytes.len())?;
+        mem.write(tail_addr, tail_original_bytes)?;
+        info!("tail original bytes restored");
+        Ok(true)
+    }
+
+    /// Writes the library into the memfd created by the second stage, and its path into the result block.
+    fn fill_memfd(&self, mem: &mut Mem, result_addr: usize, fd: usize, bytes: &[u8]) -> Result<(), InjectionError> {
+        info!("write {} bytes to the memfd {}", bytes.len(), fd);
+        let mut memfd = self.remote_proc.fd(fd)?;
+        memfd.write_all(bytes)?;
+
+        let path = format!("/proc/self/fd/{}\0", fd);
+        if path.len() > payloads::RESULT_BUFFER_SIZE {
+            return Err(InjectionError::ShellcodeError);
+        }
+        mem.write(result_addr + payloads::RESULT_BUFFER_OFFSET, path.as_bytes())
+    }
+
+    /// Resolves the symbols and builds the stages of an injection, without writing anything in the target.
+    pub fn plan(&mut self) -> Result<InjectionPlan, InjectionError> {
+        self.ensure_hijack_syms()?;
+
+        let backend = self
+            .remote_proc
+            .arch()
+            .ok_or(InjectionError::UnsupportedArch)?
+            .backend();
+
+        let hijack = self.hijack(backend)?;
+        let prepared = self.prepare_second_stage(backend, &hijack, true)?;
+        let alloc_len = self.alloc_len(backend, &prepared.second_stage)?;
+
+        info!("Building first stage shellcode");
+        let exec_len = self.exec_len(&prepared.second_stage);
+        let first_stage = backend.first_shellcode(self.target_var_sym_addr, self.target_var_block_addr, hijack.ret(), alloc_len, exec_len)?;
+        let tail = backend.unmap_tail(hijack.ret())?;
+
+        info!("read original bytes");
+        let mut mem = self.remote_proc.mem_read_only()?;
+        let func_original_bytes = mem.read(hijack.stage_addr, first_stage.len())?;
+        let var_original_bytes = mem.read(self.target_var_sym_addr, backend.mailbox_len())?;
+        let block_original_bytes = mem.read(self.target_var_block_addr, payloads::MAILBOX_BLOCK_LEN)?;
+        let tail = match hijack.tail_addr {
+            Some(tail_addr) => Some(PlannedPatch {
+                symbol: format!("{} code cave", self.got_module.as_ref().unwrap_or(&self.target_func_module_name)),
+                addr: tail_addr,
+                original_bytes: mem.read(tail_addr, tail.len())?,
+            }),
+            None => None,
+        };
+        let got = match hijack.got_slot {
+            Some(got_slot) => Some(PlannedPatch {
+                symbol: format!("{}@got", self.target_func_sym_name),
+                addr: got_slot,
+                original_bytes: mem.read(got_slot, backend.pointer_len())?,
+            }),
+            None => None,
+        };
+
+        let symbols = match self.shellcode {
+            Some(_) => Vec::new(),
+            None => {
+                let dl = self.dl_fns()?;
+                vec![
+                    PlannedSymbol { name: "dlopen", addr: dl.dlopen },
+                    PlannedSymbol { name: "dlsym", addr: dl.dlsym },
+                    PlannedSymbol { name: "dlclose", addr: dl.dlclose },
+                    PlannedSymbol { name: "dlerror", addr: dl.dlerror },
+                ]
+            }
+        };
+
+        Ok(InjectionPlan {
+            pid: self.remote_proc.pid,
+            backend: backend.name(),
+            staging: self.shellcode.is_none().then_some(self.staging),
+            libraries: prepared
+                .names
+                .into_iter()
+                .zip(prepared.staged_paths)
+                .map(|(path, staged_path)| PlannedLibrary { path, staged_path })
+                .collect(),
+            entry: self.entry.as_ref().map(|entry| entry.name.clone()),
+            symbols,
+            function: PlannedPatch {
+                symbol: match &self.got_module {
+                    Some(module_name) => format!("{} code cave", module_name),
+                    None => self.target_func_sym_name.clone(),
+                },
+                addr: hijack.stage_addr,
+                original_bytes: func_original_bytes,
+            },
+            got,
+            tail,
+            mailbox: PlannedPatch {
+                symbol: self.target_var_sym_name.clone(),
+                addr: self.target_var_sym_addr,
+                original_bytes: var_original_bytes,
+            },
+            mailbox_block: PlannedPatch {
+                symbol: format!("{} data cave", self.target_var_module_name),
+                addr: self.target_var_block_addr,
+                original_bytes: block_original_bytes,
+            },
+            mailbox_version: payloads::MAILBOX_VERSION,
+            first_stage_size: first_stage.len(),
+            second_stage_size: prepared.second_stage.code.len(),
+            alloc_len,
+            page_size: self.remote_proc.page_size()?,
+            exec_len,
+            full_context: self.full_context,
+        })
+    }
+
+    pub fn inject(&mut self) -> Result<InjectionReport, InjectionError> {
+        self.ensure_hijack_syms()?;
+
+        let backend = self
+            .remote_proc
+            .arch()
+            .ok_or(InjectionError::UnsupportedArch)?
+            .backend();
+
+        let hijack = self.hijack(backend)?;
+        let prepared = self.prepare_second_stage(backend, &hijack, false)?;
+
+        // the second stage requests the memfds in the order of the libraries
+        let mut next = prepared.memfd_libraries.iter();
+        let result = self.run_second_stage(backend, &hijack, &prepared.second_stage, |mem, result_addr, fd| match next.next() {
+            Some(bytes) => self.fill_memfd(mem, result_addr, fd, bytes),
+            None => unexpected_request(mem, result_addr, fd),
+        })?;
+
+        if self.shellcode.is_some() {
+            info!("shellcode returned 0x{:x}", result.value);
+
+            info!("injection done.");
+            return Ok(InjectionReport {
+                first_stage_addr: hijack.stage_addr,
+                second_stage_addr: result.map_addr,
+                second_stage_unmapped: result.unmapped,
+                libraries: Vec::new(),
+                entry_value: Some(result.value),
+                error: None,
+                pointer_len: backend.pointer_len(),
+            });
+        }
+
+        let mut error = result.error;
+        let mut entry_value = None;
+        let mut libraries: Vec<LibraryReport> = prepared
+            .names
+            .into_iter()
+            .zip(&result.handles)
+            .map(|(path, &handle)| LibraryReport {
+                path,
+                handle: (handle != payloads::HANDLE_SKIPPED).then_some(handle as usize),
+                error: None,
+            })
+            .collect();
+
+        if let Some(library) = libraries.iter_mut().find(|library| library.handle == Some(0)) {
+            // loading stops at the first library which failed
+            let error = error.take().unwrap_or_else(|| match result.errno {
+                0 => String::from("dlopen failed, no dlerror() text"),
+                errno => format!("memfd_create failed: {}", std::io::Error::from_raw_os_error(errno)),
+            });
+            error!("dlopen {} failed: {}", library.path, error);
+            library.error = Some(error);
+        } else if let Some(entry) = &self.entry {
+            // the second stage only calls dlerror() when the entry point is not found
+            match &error {
+                Some(error) => error!("{} not found: {}", entry.name, error),
+                None => {
+                    info!("{} returned 0x{:x}", entry.name, result.entry_value);
+                    entry_value = Some(result.entry_value);
+                }
+            }
+        } else {
+            error = None;
+        }
+
+        for library in &libraries {
+            if let Some(handle) = library.handle.filter(|&handle| handle != 0) {
+                info!("dlopen {} handle: 0x{:x}", library.path, handle);
+            }
+        }
 
         info!("injection done.");
-        Ok(())
+        Ok(InjectionReport {
+            first_stage_addr: hijack.stage_addr,
+            second_stage_addr: result.map_addr,
+            second_stage_unmapped: result.unmapped,
+            libraries,
+            entry_value,
+            error,
+            pointer_len: backend.pointer_len(),
+        })
+    }
+
+    /// Unloads a library previously injected in the target, calling its `goauld_fini` export first if any.
+    ///
+    /// `module_name` is matched against the file names of `/proc/<id>/maps`, like the hijacked symbols' modules.
+    /// The library is opened again by path to get its handle, or when its file is gone (a memfd, a deleted file),
+    /// its handle is found in the list of the modules of the linker, see [`RemoteModule::link_map`].
+    pub fn eject(&mut self, module_name: &str) -> Result<EjectionReport, InjectionError> {
+        self.eject_library(module_name, None)
+    }
+
+    /// Unloads a library previously injected in the target through `handle`, the one `dlopen` returned in the
+    /// [`InjectionReport`], see [`Injector::eject`].
+    pub fn eject_handle(&mut self, module_name: &str, handle: usize) -> Result<EjectionReport, InjectionError> {
+        self.eject_library(module_name, Some(handle))
+    }
+
+    fn eject_library(&mut self, module_name: &str, handle: Option<usize>) -> Result<EjectionReport, InjectionError> {
+        self.ensure_hijack_syms()?;
+
+        let backend = self
+            .remote_proc
+            .arch()
+            .ok_or(InjectionError::UnsupportedArch)?
+            .backend();
+
+        info!("Looking for {} in the target", module_name);
+        let module = self.remote_proc.maps()?.module(module_name)?;
+        let fini_addr = module.dlsym(FINI_SYM_NAME).ok();
+        match fini_addr {
+            Some(addr) => info!("{} found at 0x{:x}", FINI_SYM_NAME, addr),
+            None => info!("{} not exported by {}", FINI_SYM_NAME, module.name),
+        }
+
+        let library = match handle {
+            Some(handle) => LoadedLibrary::Handle(handle),
+            None if std::path::Path::new(&module.name).exists() => LoadedLibrary::Path(module.name.clone()),
+            None => {
+                let executable = self.remote_proc.maps()?.executable()?;
+                let handle = module.link_map(&executable).inspect_err(|err| {
+                    error!("no handle found for {} ({:?}), give the one of the injection", module.name, err)
+                })?;
+                info!("{} handle: 0x{:x}", module.name, handle);
+                LoadedLibrary::Handle(handle)
+            }
+        };
+
+        let dl = self.dl_fns()?;
+        let hijack = self.hijack(backend)?;
+
+        info!("Building second stage shellcode");
+        let second_stage = backend.raw_dlclose_shellcode(
+            &dl,
+            fini_addr,
+            &library,
+            hijack.ret(),
+            self.stage_config()?,
+        )?;
+
+        let result = self.run_second_stage(backend, &hijack, &second_stage, unexpected_request)?;
+
+        let mut error = result.error;
+        if result.value != 0 {
+            let error = error.get_or_insert_with(|| String::from("dlclose failed, no dlerror() text"));
+            error!("dlclose failed: {}", error);
+        } else {
+            error = None;
+        }
+
+        // the library is gone from the maps once its last reference has been dropped
+        self.module_cache.remove(module_name);
+        self.sym_cache.retain(|(module, _), _| module != module_name);
+        let unloaded = !self.remote_proc.maps()?.contains(module_name)?;
+        match unloaded {
+            true => info!("{} unloaded", module.name),
+            false => warn!("{} is still mapped in the target", module.name),
+        }
+
+        info!("ejection done.");
+        Ok(EjectionReport {
+            second_stage_addr: result.map_addr,
+            second_stage_unmapped: result.unmapped,
+            // the second stage reports -1 when the library was not loaded, which its handle tells beforehand
+            fini_called: fini_addr.is_some()
+                && (matches!(library, LoadedLibrary::Handle(_)) || result.value != pointer_all_ones(backend)),
+            unloaded,
+            error,
+        })
+    }
+
+    /// Calls `module_name!sym_name` in the target with `args`, returning its value and `errno`.
+    pub fn call(
+        &mut self,
+        module_name: &str,
+        sym_name: &str,
+        args: &[CallArg],
+    ) -> Result<CallReport, InjectionError> {
+        self.ensure_hijack_syms()?;
+
+        let backend = self
+            .remote_proc
+            .arch()
+            .ok_or(InjectionError::UnsupportedArch)?
+            .backend();
+
+        if args.len() > backend.max_call_args() {
+            return Err(InjectionError::InvalidCallArgs(format!(
+                "{} arguments given, at most {} are supported",
+                args.len(),
+                backend.max_call_args()
+            )));
+        }
+
+        let func_addr = self.add_sym(module_name, sym_name)?;
+        let errno_fn_addr = self.add_sym("libc.so", &utils::get_errno_fn_name())?;
+        let hijack = self.hijack(backend)?;
+
+        info!("Building second stage shellcode");
+        let second_stage = backend.call_shellcode(
+            func_addr,
+            errno_fn_addr,
+            args,
+            hijack.ret(),
+            self.stage_config()?,
+        )?;
+
+        let result = self.run_second_stage(backend, &hijack, &second_stage, unexpected_request)?;
+        info!("{} returned 0x{:x}, errno {}", sym_name, result.value, result.errno);
+
+        Ok(CallReport {
+            second_stage_addr: result.map_addr,
+            second_stage_unmapped: result.unmapped,
+            value: result.value,
+            errno: result.errno,
+            pointer_len: backend.pointer_len(),
+            return_type: ReturnType::default(),
+        })
+    }
+}
+
+/// Gets the name of the memfd a library is staged in: the name of its file, cut to the longest name of a memfd.
+fn memfd_name(file_path: &str) -> String {
+    let name = std::path::Path::new(file_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(payloads::MEMFD_NAME);
+    let len = (0..=name.len().min(payloads::MEMFD_NAME_MAX))
+        .rev()
+        .find(|&len| name.is_char_boundary(len))
+        .unwrap_or(0);
+    name[..len].to_string()
+}
+
+/// Request handler of the second stages which never wait for the injector.
+fn unexpected_request(_: &mut Mem, _: usize, _: usize) -> Result<(), InjectionError> {
+    error!("unexpected request from the second stage");
+    Err(InjectionError::ShellcodeError)
+}
+
+/// Reads the words on top of a stack, among which are the return addresses of the innermost frames.
+fn stack_words(mem: &mut Mem, sp: usize, word_len: usize, page_size: usize) -> Vec<usize> {
+    // the stack may end before STACK_SCAN_LEN bytes, but not before the end of the page of sp
+    let bytes = mem
+        .read(sp, trigger::STACK_SCAN_LEN)
+        .or_else(|_| mem.read(sp, trigger::STACK_SCAN_LEN.min(page_size - (sp & (page_size - 1)))))
+        .unwrap_or_default();
+
+    bytes
+        .chunks_exact(word_len)
+        .map(word_from_le_bytes)
+        .collect()
+}
+
+/// Reads a word of `len` bytes, the width of a pointer or of the mailbox of the target.
+fn read_word(mem: &mut Mem, addr: usize, len: usize) -> Result<usize, InjectionError> {
+    Ok(word_from_le_bytes(&mem.read(addr, len)?))
+}
+
+/// Decodes a little endian word of 4 or 8 bytes.
+fn word_from_le_bytes(word: &[u8]) -> usize {
+    match word.len() {
+        4 => u32::from_le_bytes(word.try_into().unwrap()) as usize,
+        _ => u64::from_le_bytes(word.try_into().unwrap()) as usize,
+    }
+}
+
+/// Holds the threads entering the first stage on a self jmp written over its parking spot,
+/// and restores the GOT slot leading to it.
+fn park(mem: &mut Mem, hijack: &Hijack, got_original_bytes: &[u8], self_jmp: &[u8]) -> Result<(), InjectionError> {
+    if let Some(got_slot) = hijack.got_slot {
+        mem.write(got_slot, got_original_bytes)?;
+    }
+    mem.write(hijack.stage_addr, self_jmp)
+}
+
+/// Writes `bytes` over code whose start is parked on a self jmp: their end first, then their start in a single write,
+/// so the parked threads never run a partly written instruction.
+fn write_parked(mem: &mut Mem, addr: usize, bytes: &[u8], self_jmp_len: usize) -> Result<(), InjectionError> {
+    mem.write(addr + self_jmp_len, &bytes[self_jmp_len..])?;
+    mem.write(addr, &bytes[..self_jmp_len])
+}
+
+/// Gets the pointer of a process of this architecture with all its bits set, -1 as read back from its result block.
+fn pointer_all_ones(backend: &dyn ArchBackend) -> usize {
+    word_from_le_bytes(&vec![0xff; backend.pointer_len()])
+}
+
+/// Encodes a pointer for a process of this architecture.
+fn pointer_bytes(backend: &dyn ArchBackend, addr: usize) -> Vec<u8> {
+    (addr as u64).to_le_bytes()[..backend.pointer_len()].to_vec()
+}
+
+
+/// Where the first stage is written, and how the target is made to run it.
+struct Hijack {
+    /// Address the first stage is written at: the hijacked function, or a code cave.
+    stage_addr: usize,
+    /// GOT slot pointed to the first stage, when the function is hijacked through it.
+    got_slot: Option<usize>,
+    /// Address the second stage jumps back to: the hijacked function, or the original value of the GOT slot.
+    return_addr: usize,
+    /// Address of the tail unmapping the second stage: a code cave of the module of the function, or the one
+    /// the first stage is written in. Without one, the tail is in the map of the second stage.
+    tail_addr: Option<usize>,
+    /// Length of the first stage.
+    stage_len: usize,
+    /// Length of the tail.
+    tail_len: usize,
+}
+
+impl Hijack {
+    /// Gets where the second stage jumps back to.
+    fn ret(&self) -> StageReturn {
+        StageReturn {
+            addr: self.return_addr,
+            tail_addr: self.tail_addr,
+            tail_len: self.tail_len,
+            stage_addr: self.stage_addr,
+            stage_len: self.stage_len,
+        }
+    }
+}
+
+/// A second stage ready to be run, along with what the injector needs to run it.
+struct PreparedStage {
+    second_stage: SecondStage,
+    /// Names of the libraries loaded by the second stage, as given to the injector.
+    names: Vec<String>,
+    /// Paths the libraries are loaded from in the target.
+    staged_paths: Vec<String>,
+    /// Libraries written to the memfds requested by the second stage, in order.
+    memfd_libraries: Vec<Vec<u8>>,
+}
+
+/// What a second stage wrote in its result block.
+struct StageResult {
+    map_addr: usize,
+    /// Is the map gone from `/proc/<id>/maps` ?
+    unmapped: bool,
+    value: usize,
+    error: Option<String>,
+    errno: i32,
+    entry_value: usize,
+    handles: Vec<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gets an injector of the test process itself.
+    fn own_injector() -> Injector {
+        Injector::new(std::process::id() as i32).unwrap()
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn same_symbol_in_two_modules() {
+        // exported by the dynamic linker and by libc, at different addresses
+        let sym_name = "_dl_catch_exception";
+        let mut injector = own_injector();
+
+        let libc = injector.add_sym("libc.so", sym_name).unwrap();
+        let ld = injector.add_sym("ld-linux", sym_name).unwrap();
+        assert_ne!(libc, ld);
+        assert_eq!(libc, injector.module("libc.so").unwrap().dlsym(sym_name).unwrap());
+        assert_eq!(ld, injector.module("ld-linux").unwrap().dlsym(sym_name).unwrap());
+
+        // and from the cache
+        assert_eq!(injector.add_sym("libc.so", sym_name).unwrap(), libc);
+        assert_eq!(injector.add_sym("ld-linux", sym_name).unwrap(), ld);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn auto_trigger_through_the_got() {
+        // a thread of the test process blocked in clock_nanosleep, called by nanosleep through the GOT of the executable
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let sleeper = std::thread::spawn({
+            let done = done.clone();
+            move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    std::thread::sleep(Duration::from_millis(200));
+                }
+            }
+        });
+        std::thread::sleep(Duration::from_millis(50));
+
+        let exe = std::env::current_exe().unwrap();
+        let mut injector = own_injector();
+        injector.set_got_module(exe.file_name().unwrap().to_str().unwrap());
+        let report = injector.auto_trigger();
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        sleeper.join().unwrap();
+
+        let report = report.unwrap();
+        assert!(report.chosen, "{}", report);
+        assert!(["clock_nanosleep", "nanosleep"].contains(&report.function.as_str()), "{}", report);
+    }
+
+    #[test]
+    fn memfd_named_after_the_file() {
+        assert_eq!(memfd_name("target/debug/examples/libevil.so"), "libevil.so");
+        assert_eq!(memfd_name(&format!("/tmp/{}.so", "x".repeat(300))), "x".repeat(payloads::MEMFD_NAME_MAX));
+        // not cut in the middle of a character
+        assert_eq!(memfd_name(&"é".repeat(200)), "é".repeat(124));
+    }
+
+    #[test]
+    fn missing_symbol_not_cached() {
+        let mut injector = own_injector();
+        assert!(matches!(
+            injector.add_sym("libc.so", "goauld_missing"),
+            Err(InjectionError::SymbolNotFound(_))
+        ));
+        assert!(injector.sym_cache.is_empty());
     }
 }
@@ -1,27 +1,48 @@
 use crate::error::InjectionError;
+use crate::payloads::{
+    label_offset, map_prot, ArchBackend, CallArg, DlFns, Entry, LoadedLibrary, MailboxState, SecondStage, StageConfig, StageReturn, HANDLE_SKIPPED,
+    MAILBOX_BLOCK_ERRNO_OFFSET, MAILBOX_BLOCK_SYSCALL_OFFSET, MAILBOX_BLOCK_VERSION_OFFSET, MAILBOX_FIELDS, MAILBOX_STATE, MAILBOX_SYSCALL_MMAP,
+    MAILBOX_SYSCALL_MPROTECT, MAILBOX_THREAD, MAILBOX_THREADS, MAILBOX_VERSION, MFD_CLOEXEC, PROT_EXEC, PROT_READ, RESULT_BUFFER_SIZE,
+    RESULT_STATUS_DONE, RESULT_STATUS_REQUEST, RESULT_STATUS_RESUME, RTLD_NOLOAD, RTLD_NOW,
+};
 
+use dynasmrt::aarch64::Assembler;
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
 
-pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, InjectionError> {
+const SYS_CLOSE: u64 = 57;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MUNMAP: u64 = 215;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_MEMFD_CREATE: u64 = 279;
+
+/// System registers, encoded as op0:op1:CRn:CRm:op2 for mrs and msr.
+const SYSREG_NZCV: u32 = 0x5a10;
+const SYSREG_FPCR: u32 = 0x5a20;
+const SYSREG_FPSR: u32 = 0x5a21;
+const SYSREG_CTR_EL0: u32 = 0x5801;
+
+/// Largest map the first stage can allocate, its length being loaded from a 64 bit literal.
+pub const MAX_ALLOC_LEN: usize = usize::MAX;
+
+/// Builds the first stage. It saves the context of the thread in a frame of 0x100 bytes below the stack pointer of
+/// the hijacked call, which AAPCS64 keeps 16 bytes aligned, with no red zone above:
+///
+/// - x0 to x29 at `8 * n`
+/// - x30 at 0xf0, and NZCV at 0xf8
+///
+/// The second stage runs below this frame, and the tail unmapping it restores it (see `restore_registers`).
+pub fn first_shellcode(var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
     debug!("first_shellcode aarch64");
 
     let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
     dynasm!(ops
         ; .arch aarch64
 
+        // parking spot, replaced by a self loop holding the new threads while the function is restored
         ; ->start:
-        // check if the bit is set
-        ; ldr x6, ->var_addr
-        ; ldxrb w1, [x6]
-        ; cbnz w1, ->start
-
-        // set the bit
-        ; mov w2, 0x1
-        ; stxrb w1, w2, [x6]
-        ; cbnz w1, ->start
+        ; sub sp, sp, #0x100
 
         // save the registers
-        ; sub sp, sp, #0x100
         ; stp x0, x1, [sp, #0x0]
         ; stp x2, x3, [sp, #0x10]
         ; stp x4, x5, [sp, #0x20]
@@ -37,40 +58,166 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
         ; stp x24, x25, [sp, #0xc0]
         ; stp x26, x27, [sp, #0xd0]
         ; stp x28, x29, [sp, #0xe0]
-        ; stp x30, xzr, [sp, #0xf0]
+
+        // save the flags next to x30
+        ; mrs x9, SYSREG_NZCV
+        ; stp x30, x9, [sp, #0xf0]
+
+        // count the thread in the first stage
+        ; ldr x6, ->var_addr
+        ; ->count:
+        ; ldxr x1, [x6]
+        ; add x1, x1, MAILBOX_THREAD as u32
+        ; stxr w2, x1, [x6]
+        ; cbnz w2, ->count
+
+        // claim the stage if nobody has: idle -> claimed
+        ; ->claim:
+        ; ldxr x1, [x6]
+        ; tst x1, #MAILBOX_STATE
+        ; b.ne ->wait_map
+        ; add x1, x1, MailboxState::Claimed as u32
+        ; stxr w2, x1, [x6]
+        ; cbnz w2, ->claim
+        ; b ->claimed
+
+        // wait for the map, and join the thread which claimed the stage there
+        ; ->wait_map:
+        ; clrex
+        ; ldr x1, [x6]
+        ; and x0, x1, #MAILBOX_STATE
+        ; cmp x0, MailboxState::Failed as u32
+        ; b.eq ->leave
+        ; cmp x0, MailboxState::Allocated as u32
+        ; b.lo ->wait_map
+        ; and x0, x1, #!MAILBOX_FIELDS
+        ; br x0
+
+        // write the protocol version in the mailbox block
+        ; ->claimed:
+        ; ldr x10, ->block_addr
+        ; mov w1, MAILBOX_VERSION as u64
+        ; str w1, [x10, MAILBOX_BLOCK_VERSION_OFFSET as u32]
 
         // mmap call
         ; mov x0, #0x0                  // addr       (NULL)
-        ; mov x1, alloc_len as _        // len        (0x1000)
-        ; mov x2, #0x7                  // prot       (RWX)
+        ; ldr x1, ->alloc_len           // len        (alloc_len)
+        ; mov x2, map_prot(exec_len) as u64 // prot  (RWX, or RW)
         ; mov x3, #0x22                 // flags      (MAP_PRIVATE | MAP_ANONYMOUS)
         ; mvn x4, xzr                   // fd         (-1)
         ; mov x5, #0x0                  // offset     (ignored)
         ; mov x8, #0xde                 // syscall no (mmap)
         ; svc #0x0                      // syscall
+        ; cmn x0, #0xfff                // -4095..-1 is an errno
+        ; b.hs ->mmap_failed
 
         // write self loop instruction to the new map
         ; ldr w1, ->self_jmp
         ; str w1, [x0]
+    );
+
+    if exec_len.is_some() {
+        dynasm!(ops
+            ; .arch aarch64
+
+            // make the code executable, and no longer writable
+            ; mov x7, x0                    // keep the map, x6 holds var_addr
+            ; ldr x1, ->exec_len            // len        (exec_len)
+            ; mov x2, (PROT_READ | PROT_EXEC) as u64 // prot (RX)
+            ; mov x8, SYS_MPROTECT
+            ; svc #0x0
+            ; cbnz x0, ->mprotect_failed
+            ; mov x0, x7
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
 
-        // flush cache
+        // make the self loop visible to the instruction fetches, it fits in a cache line
         // https://chromium.googlesource.com/v8/v8/+/9405fcfdd1984341ea06a192b3b08bdb6069db15/src/arm64/cpu-arm64.cc
-        // ; dc civac, x0
-        // ; dsb ish
-        // ; ic ivau, x0
+        ; dc civac, x0
+        ; dsb ish
+        ; ic ivau, x0
         ; dsb ish
         ; isb
 
-        // save mmap addr (with bit set to keep the other threads spinning)
-        ; orr x0, x0, #0x1
-        ; str x0, [x6, #0x0]
-
-        // turn off the bit
-        ; eor x0, x0, #0x1
+        // publish the map address next to the count: claimed -> allocated
+        ; ->publish:
+        ; ldxr x1, [x6]
+        ; add x1, x1, x0
+        ; add x1, x1, (MailboxState::Allocated as u32 - MailboxState::Claimed as u32)
+        ; stlxr w2, x1, [x6]
+        ; cbnz w2, ->publish
 
         // jump to the new map
         ; br x0
 
+        // write the system call and its errno in the mailbox block, then publish them: claimed -> failed
+        ; ->mmap_failed:
+        ; neg x0, x0
+        ; mov w9, MAILBOX_SYSCALL_MMAP as u64
+        ; ->failed:
+        ; str w9, [x10, MAILBOX_BLOCK_SYSCALL_OFFSET as u32]
+        ; str w0, [x10, MAILBOX_BLOCK_ERRNO_OFFSET as u32]
+        ; ->publish_failure:
+        ; ldxr x1, [x6]
+        ; add x1, x1, (MailboxState::Failed as u32 - MailboxState::Claimed as u32)
+        ; stlxr w2, x1, [x6]
+        ; cbnz w2, ->publish_failure
+
+        // leave the first stage, and go back to the function once the injector has restored it
+        ; ->leave:
+        ; ldxr x1, [x6]
+        ; sub x1, x1, MAILBOX_THREAD as u32
+        ; stxr w2, x1, [x6]
+        ; cbnz w2, ->leave
+
+        ; ldr x9, [sp, #0xf8]
+        ; msr SYSREG_NZCV, x9
+        ; ldp x0, x1, [sp, #0x0]
+        ; ldp x2, x3, [sp, #0x10]
+        ; ldp x4, x5, [sp, #0x20]
+        ; ldp x6, x7, [sp, #0x30]
+        ; ldp x8, x9, [sp, #0x40]
+        ; ldp x10, x11, [sp, #0x50]
+        ; ldp x12, x13, [sp, #0x60]
+        ; ldp x14, x15, [sp, #0x70]
+        ; ldp x16, x17, [sp, #0x80]
+        ; ldp x18, x19, [sp, #0x90]
+        ; ldp x20, x21, [sp, #0xa0]
+        ; ldp x22, x23, [sp, #0xb0]
+        ; ldp x24, x25, [sp, #0xc0]
+        ; ldp x26, x27, [sp, #0xd0]
+        ; ldp x28, x29, [sp, #0xe0]
+        ; ldp x30, xzr, [sp, #0xf0]
+        ; add sp, sp, #0x100
+
+        // x17 is free to clobber on a call
+        ; ldr x17, ->return_addr
+        ; br x17
+    );
+
+    if exec_len.is_some() {
+        dynasm!(ops
+            ; .arch aarch64
+
+            // unmap the map, and publish the errno
+            ; ->mprotect_failed:
+            ; neg x11, x0
+            ; mov x0, x7                    // addr       (map)
+            ; ldr x1, ->alloc_len           // len        (alloc_len)
+            ; mov x8, SYS_MUNMAP
+            ; svc #0x0
+            ; mov x0, x11
+            ; mov w9, MAILBOX_SYSCALL_MPROTECT as u64
+            ; b ->failed
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
         ; .align 4
         ; ->minus_one:
         ; .qword -1 as _
@@ -79,6 +226,14 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
         ; ->var_addr:
         ; .qword var_addr as _
 
+        ; .align 4
+        ; ->block_addr:
+        ; .qword block_addr as _
+
+        ; .align 4
+        ; ->return_addr:
+        ; .qword ret.addr as _
+
         ; .align 4
         ; ->alloc_len:
         ; .qword alloc_len as _
@@ -88,6 +243,15 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
         ; b ->self_jmp
     );
 
+    if let Some(exec_len) = exec_len {
+        dynasm!(ops
+            ; .arch aarch64
+            ; .align 4
+            ; ->exec_len:
+            ; .qword exec_len as _
+        );
+    }
+
     match ops.finalize() {
         Ok(shellcode) => Ok(shellcode.to_vec()),
         Err(_) => Err(InjectionError::ShellcodeError),
@@ -95,36 +259,881 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
 }
 
 pub fn raw_dlopen_shellcode(
-    dlopen_addr: usize,
-    dlopen_path: String,
-    jmp_addr: usize,
-) -> Result<Vec<u8>, InjectionError> {
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    dlopen_paths: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
     debug!("raw_dlopen_shellcode aarch64");
 
     let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+    let paths: Vec<_> = dlopen_paths.iter().map(|_| ops.new_dynamic_label()).collect();
+    handshake(&mut ops, config);
+
+    // for testing
+    // dynasm!(ops ; .arch aarch64 ; brk #0x1);
+
+    for (i, path) in paths.iter().enumerate() {
+        dynasm!(ops
+            ; .arch aarch64
+
+            // load args
+            ; adr x0, =>*path
+            ; mov x1, RTLD_NOW as _
 
-    // dlopen flags RTLD_NOW
-    let dlopen_flags: usize = 0x2;
-    let dlopen_path_bytes: &[u8] = dlopen_path.as_bytes();
+            // call dlopen
+            ; ldr x8, ->dlopen
+            ; blr x8
+        );
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, entry);
 
     dynasm!(ops
         ; .arch aarch64
+        ; b ->done
+    );
+
+    dlopen_failed(&mut ops);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // tell the injector we are done
+        ; ->done:
+        ; mov x0, RESULT_STATUS_DONE as _
+        ; adr x9, ->result_status
+        ; str x0, [x9]
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (path, dlopen_path) in paths.iter().zip(dlopen_paths) {
+        dynasm!(ops
+            ; .arch aarch64
+
+            ; .align 4
+            ; =>*path
+            ; .bytes dlopen_path.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        ; .align 4
+        ; ->dlopen:
+        ; .qword dl.dlopen as _
 
-        // for testing
-        // ; brk #0x1
+        ; .align 4
+        ; ->dlsym:
+        ; .qword dl.dlsym as _
+
+        ; .align 4
+        ; ->dlerror:
+        ; .qword dl.dlerror as _
+    );
+
+    data_section(&mut ops, config.data_align);
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, dlopen_paths.len());
+    finalize_second_stage(ops)
+}
+
+pub fn memfd_dlopen_shellcode(
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    memfd_names: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("memfd_dlopen_shellcode aarch64");
+
+    let count = memfd_names.len();
+    let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+    let names: Vec<_> = memfd_names.iter().map(|_| ops.new_dynamic_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, name) in names.iter().enumerate() {
+        let wait = ops.new_dynamic_label();
+
+        dynasm!(ops
+            ; .arch aarch64
+
+            // mark the library as attempted
+            ; adr x9, ->handles
+            ; str xzr, [x9, (i * 8) as u32]
+
+            // create the memfd
+            ; adr x0, =>*name
+            ; mov x1, MFD_CLOEXEC as _
+            ; mov x8, SYS_MEMFD_CREATE
+            ; svc #0x0
+            ; tbnz x0, #63, ->memfd_failed
+            ; adr x9, ->memfds
+            ; str x0, [x9, (i * 8) as u32]
+
+            // ask the injector to fill the memfd and its path, and wait for it
+            ; adr x9, ->result_value
+            ; str x0, [x9]
+            ; mov x0, RESULT_STATUS_REQUEST as _
+            ; adr x9, ->result_status
+            ; str x0, [x9]
+            ; =>wait
+            ; mov x8, SYS_SCHED_YIELD
+            ; svc #0x0
+            ; adr x9, ->result_status
+            ; ldr x0, [x9]
+            ; cmp x0, RESULT_STATUS_RESUME as u32
+            ; b.ne =>wait
+
+            // call dlopen on /proc/self/fd/<memfd>
+            ; adr x0, ->result_buffer
+            ; mov x1, RTLD_NOW as _
+            ; ldr x8, ->dlopen
+            ; blr x8
+        );
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, entry);
+
+    dynasm!(ops
+        ; .arch aarch64
+        ; b ->done
+    );
+
+    dlopen_failed(&mut ops);
+
+    dynasm!(ops
+        ; .arch aarch64
+        ; b ->done
 
-        // load args
-        ; adr x0, ->dlopen_path
-        ; ldr x1, ->dlopen_flags
+        // save the errno of memfd_create
+        ; ->memfd_failed:
+        ; neg w0, w0
+        ; adr x9, ->result_errno
+        ; str w0, [x9]
 
-        // call dlopen
-        ; ldr x8, ->dlopen
+        ; ->done:
+    );
+
+    // close the memfds, the library mappings keep them alive. They are kept open until now,
+    // so each library gets its own /proc/self/fd/<memfd> path.
+    for i in 0..count {
+        let closed = ops.new_dynamic_label();
+
+        dynasm!(ops
+            ; .arch aarch64
+            ; adr x9, ->memfds
+            ; ldr x0, [x9, (i * 8) as u32]
+            ; tbnz x0, #63, =>closed
+            ; mov x8, SYS_CLOSE
+            ; svc #0x0
+            ; =>closed
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // tell the injector we are done
+        ; mov x0, RESULT_STATUS_DONE as _
+        ; adr x9, ->result_status
+        ; str x0, [x9]
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (name, memfd_name) in names.iter().zip(memfd_names) {
+        dynasm!(ops
+            ; .arch aarch64
+            ; .align 4
+            ; =>*name
+            ; .bytes memfd_name.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        ; .align 4
+        ; ->dlopen:
+        ; .qword dl.dlopen as _
+
+        ; .align 4
+        ; ->dlsym:
+        ; .qword dl.dlsym as _
+
+        ; .align 4
+        ; ->dlerror:
+        ; .qword dl.dlerror as _
+    );
+
+    data_section(&mut ops, config.data_align);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // file descriptors of the memfds, one per library
+        ; ->memfds:
+    );
+
+    for _ in 0..count {
+        dynasm!(ops
+            ; .arch aarch64
+            ; .qword -1
+        );
+    }
+
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, count);
+    finalize_second_stage(ops)
+}
+
+pub fn raw_dlclose_shellcode(
+    dl: &DlFns,
+    fini_addr: Option<usize>,
+    library: &LoadedLibrary,
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("raw_dlclose_shellcode aarch64");
+
+    let fini_addr = fini_addr.unwrap_or(0);
+
+    let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+    handshake(&mut ops, config);
+
+    match library {
+        LoadedLibrary::Path(_) => dynasm!(ops
+            ; .arch aarch64
+
+            // get a handle on the loaded library, without loading it again
+            ; adr x0, ->dlopen_path
+            ; mov x1, (RTLD_NOW | RTLD_NOLOAD) as _
+            ; ldr x8, ->dlopen
+            ; blr x8
+            ; cbz x0, ->not_loaded
+            ; mov x19, x0
+        ),
+        LoadedLibrary::Handle(_) => dynasm!(ops
+            ; .arch aarch64
+
+            // the handle returned when the library was injected
+            ; ldr x19, ->handle
+        ),
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // call the fini hook if the library exports one
+        ; ldr x8, ->fini
+        ; cbz x8, ->close
         ; blr x8
+        ; ->close:
+    );
+
+    if let LoadedLibrary::Path(_) = library {
+        dynasm!(ops
+            ; .arch aarch64
 
-        // if dlopen fails, crash
-        ; cbz x0, ->crash
+            // drop the reference we just took
+            ; mov x0, x19
+            ; ldr x8, ->dlclose
+            ; blr x8
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // drop the reference of the injection
+        ; mov x0, x19
+        ; ldr x8, ->dlclose
+        ; blr x8
+        ; adr x9, ->result_value
+        ; str x0, [x9]
+        ; cbz x0, ->closed
+        ; b ->failed
+
+        ; ->not_loaded:
+        ; mvn x0, xzr
+        ; adr x9, ->result_value
+        ; str x0, [x9]
+
+        // save the dlerror() text
+        ; ->failed:
+        ; ldr x8, ->dlerror
+        ; blr x8
+        ; adr x9, ->result_error
+        ; str x0, [x9]
+
+        // tell the injector we are done
+        ; ->closed:
+        ; mov x0, RESULT_STATUS_DONE as _
+        ; adr x9, ->result_status
+        ; str x0, [x9]
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    match library {
+        LoadedLibrary::Path(dlopen_path) => dynasm!(ops
+            ; .arch aarch64
+            ; .align 4
+            ; ->dlopen_path:
+            ; .bytes dlopen_path.as_bytes()
+            ; .bytes [0x0]
+        ),
+        LoadedLibrary::Handle(handle) => dynasm!(ops
+            ; .arch aarch64
+            ; .align 4
+            ; ->handle:
+            ; .qword *handle as _
+        ),
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        ; .align 4
+        ; ->dlopen:
+        ; .qword dl.dlopen as _
+
+        ; .align 4
+        ; ->dlclose:
+        ; .qword dl.dlclose as _
+
+        ; .align 4
+        ; ->dlerror:
+        ; .qword dl.dlerror as _
+
+        ; .align 4
+        ; ->fini:
+        ; .qword fini_addr as _
+    );
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+pub fn call_shellcode(
+    func_addr: usize,
+    errno_fn_addr: usize,
+    args: &[CallArg],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("call_shellcode aarch64");
+
+    let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+    let values: Vec<_> = args.iter().map(|_| ops.new_dynamic_label()).collect();
+    handshake(&mut ops, config);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // clear errno
+        ; ldr x8, ->errno_fn
+        ; blr x8
+        ; str wzr, [x0]
+    );
+
+    // load the arguments in x0-x5, following the AAPCS64
+    for (i, arg) in args.iter().enumerate() {
+        let reg = i as u32;
+        match arg {
+            CallArg::Int(_) => dynasm!(ops
+                ; .arch aarch64
+                ; ldr X(reg), =>values[i]
+            ),
+            CallArg::Bytes(_) => dynasm!(ops
+                ; .arch aarch64
+                ; adr X(reg), =>values[i]
+            ),
+        }
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // call the function
+        ; ldr x8, ->func
+        ; blr x8
+        ; adr x9, ->result_value
+        ; str x0, [x9]
+
+        // save errno
+        ; ldr x8, ->errno_fn
+        ; blr x8
+        ; ldr w0, [x0]
+        ; adr x9, ->result_errno
+        ; str w0, [x9]
+
+        // tell the injector we are done
+        ; mov x0, RESULT_STATUS_DONE as _
+        ; adr x9, ->result_status
+        ; str x0, [x9]
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        ; .align 4
+        ; ->func:
+        ; .qword func_addr as _
+
+        ; .align 4
+        ; ->errno_fn:
+        ; .qword errno_fn_addr as _
+    );
 
-        // load the original args
+    // the called function may write in the buffers
+    data_section(&mut ops, config.data_align);
+
+    for (i, arg) in args.iter().enumerate() {
+        match arg {
+            CallArg::Int(value) => dynasm!(ops
+                ; .arch aarch64
+                ; .align 8
+                ; =>values[i]
+                ; .qword *value as _
+            ),
+            CallArg::Bytes(bytes) => dynasm!(ops
+                ; .arch aarch64
+                ; .align 8
+                ; =>values[i]
+                ; .bytes bytes
+            ),
+        }
+    }
+
+    finalize_second_stage(ops)
+}
+
+pub fn shellcode_stage(shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("shellcode_stage aarch64 {} bytes", shellcode.len());
+
+    let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+    handshake(&mut ops, config);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // call the shellcode, and save what it returned
+        ; bl ->shellcode
+        ; adr x9, ->result_value
+        ; str x0, [x9]
+
+        // tell the injector we are done
+        ; mov x0, RESULT_STATUS_DONE as _
+        ; adr x9, ->result_status
+        ; str x0, [x9]
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        ; .align 16
+        ; ->shellcode:
+        ; .bytes shellcode
+    );
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+/// Lets a single thread run the second stage, once it has counted the threads left in the first stage.
+/// The others call the function, which the injector holds until it is restored.
+///
+/// Every thread synchronises the code of the stage with its instruction cache first.
+fn handshake(ops: &mut Assembler, config: StageConfig) {
+    let leave_count = ops.new_dynamic_label();
+    let claim = ops.new_dynamic_label();
+    let ready = ops.new_dynamic_label();
+    let wait = ops.new_dynamic_label();
+    let done = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch aarch64
+        ; ->map:
+
+        // the first instruction replaces the self loop the threads spin on, the next ones may have been fetched
+        // before the stage was written
+        ; isb
+        ; adr x9, ->map
+        ; adr x10, ->data
+    );
+
+    sync_code(ops);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // the thread has left the first stage
+        ; ldr x9, ->mailbox
+        ; =>leave_count
+        ; ldxr x10, [x9]
+        ; sub x10, x10, MAILBOX_THREAD as u32
+        ; stxr w11, x10, [x9]
+        ; cbnz w11, =>leave_count
+
+        // only the first thread runs the stage: allocated -> stage2-ready
+        ; adr x12, ->gate
+        ; =>claim
+        ; ldxr x10, [x12]
+        ; cbnz x10, ->leave
+        ; mov x10, #0x1
+        ; stxr w11, x10, [x12]
+        ; cbnz w11, =>claim
+        ; =>ready
+        ; ldxr x10, [x9]
+        ; add x10, x10, (MailboxState::Stage2Ready as u32 - MailboxState::Allocated as u32)
+        ; stxr w11, x10, [x9]
+        ; cbnz w11, =>ready
+
+        // wait for the other threads to leave the first stage, and tell the injector: stage2-ready -> done
+        ; =>wait
+        ; ldr x10, [x9]
+        ; tst x10, #MAILBOX_THREADS
+        ; b.ne =>wait
+        ; =>done
+        ; ldxr x10, [x9]
+        ; add x10, x10, (MailboxState::Done as u32 - MailboxState::Stage2Ready as u32)
+        ; stxr w11, x10, [x9]
+        ; cbnz w11, =>done
+        ; b ->handshaken
+
+        ; .align 8
+        ; ->mailbox:
+        ; .qword config.var_addr as _
+
+        ; ->handshaken:
+    );
+
+    if config.full_context {
+        save_context(ops);
+    }
+}
+
+/// Saves the NEON registers, FPSR and FPCR below the stack.
+fn save_context(ops: &mut Assembler) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; sub sp, sp, #0x220
+        ; mrs x9, SYSREG_FPSR
+        ; mrs x10, SYSREG_FPCR
+        ; stp x9, x10, [sp]
+        ; stp q0, q1, [sp, #0x10]
+        ; stp q2, q3, [sp, #0x30]
+        ; stp q4, q5, [sp, #0x50]
+        ; stp q6, q7, [sp, #0x70]
+        ; stp q8, q9, [sp, #0x90]
+        ; stp q10, q11, [sp, #0xb0]
+        ; stp q12, q13, [sp, #0xd0]
+        ; stp q14, q15, [sp, #0xf0]
+        ; stp q16, q17, [sp, #0x110]
+        ; stp q18, q19, [sp, #0x130]
+        ; stp q20, q21, [sp, #0x150]
+        ; stp q22, q23, [sp, #0x170]
+        ; stp q24, q25, [sp, #0x190]
+        ; stp q26, q27, [sp, #0x1b0]
+        ; stp q28, q29, [sp, #0x1d0]
+        ; stp q30, q31, [sp, #0x1f0]
+    );
+}
+
+/// Restores the registers saved by `save_context`, and the stack pointer.
+fn restore_context(ops: &mut Assembler) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; ldp x9, x10, [sp]
+        ; msr SYSREG_FPSR, x9
+        ; msr SYSREG_FPCR, x10
+        ; ldp q0, q1, [sp, #0x10]
+        ; ldp q2, q3, [sp, #0x30]
+        ; ldp q4, q5, [sp, #0x50]
+        ; ldp q6, q7, [sp, #0x70]
+        ; ldp q8, q9, [sp, #0x90]
+        ; ldp q10, q11, [sp, #0xb0]
+        ; ldp q12, q13, [sp, #0xd0]
+        ; ldp q14, q15, [sp, #0xf0]
+        ; ldp q16, q17, [sp, #0x110]
+        ; ldp q18, q19, [sp, #0x130]
+        ; ldp q20, q21, [sp, #0x150]
+        ; ldp q22, q23, [sp, #0x170]
+        ; ldp q24, q25, [sp, #0x190]
+        ; ldp q26, q27, [sp, #0x1b0]
+        ; ldp q28, q29, [sp, #0x1d0]
+        ; ldp q30, q31, [sp, #0x1f0]
+        ; add sp, sp, #0x220
+    );
+}
+
+/// Saves the handle returned by dlopen for the library `index`, and stops loading if it failed.
+fn save_handle(ops: &mut Assembler, index: usize) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; adr x9, ->handles
+        ; str x0, [x9, (index * 8) as u32]
+        ; cbz x0, ->dlopen_failed
+    );
+}
+
+/// Calls the entry point of the last loaded library with its arguments, if one is set.
+fn call_entry(ops: &mut Assembler, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+    let called = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // look for the entry point
+        ; adr x1, ->entry_name
+        ; ldr x8, ->dlsym
+        ; blr x8
+        ; cbz x0, ->dlopen_failed
+
+        // call it with a pointer to its arguments and their length
+        ; mov x8, x0
+        ; adr x0, ->entry_args
+        ; ldr x1, ->entry_args_len
+        ; blr x8
+        ; adr x9, ->result_entry
+        ; str x0, [x9]
+        ; b =>called
+
+        ; .align 4
+        ; ->entry_args_len:
+        ; .qword entry.args.len() as _
+
+        ; =>called
+    );
+}
+
+/// Appends the name of the entry point and its arguments to the data of the second stage, which it may write to.
+fn entry_data(ops: &mut Assembler, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    dynasm!(ops
+        ; .arch aarch64
+        ; ->entry_name:
+        ; .bytes entry.name.as_bytes()
+        ; .bytes [0x0]
+        ; .align 8
+        ; ->entry_args:
+        ; .bytes entry.args.as_slice()
+        ; .bytes [0x0]
+        ; .align 4
+    );
+}
+
+/// Saves the dlerror() text when a library or the entry point could not be loaded.
+fn dlopen_failed(ops: &mut Assembler) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; ->dlopen_failed:
+        ; ldr x8, ->dlerror
+        ; blr x8
+        ; adr x9, ->result_error
+        ; str x0, [x9]
+    );
+}
+
+/// Starts the data written by the second stage, aligned to `data_align` to be protected apart from its code.
+fn data_section(ops: &mut Assembler, data_align: usize) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; .align data_align
+        ; ->data:
+    );
+}
+
+/// Appends the table of the handles returned by dlopen, one per library.
+fn handles_table(ops: &mut Assembler, count: usize) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; .align 8
+        ; ->handles:
+    );
+
+    for _ in 0..count {
+        dynasm!(ops
+            ; .arch aarch64
+            ; .qword HANDLE_SKIPPED as _
+        );
+    }
+}
+
+/// Jumps back to the hijacked function. The thread which ran the stage waits for the injector to release the map,
+/// and jumps to the tail unmapping it with the stack pointer back on the frame of the first stage, once the tail
+/// and the restored bytes of the function are synchronised with its instruction cache. The others restore the
+/// registers saved by the first stage themselves.
+///
+/// Without a code cave for the tail, it follows on a page of its own, synchronised along with the stage, the pages
+/// before it being unmapped.
+fn restore_and_return(ops: &mut Assembler, ret: StageReturn, config: StageConfig) {
+    let release = ops.new_dynamic_label();
+
+    if config.full_context {
+        restore_context(ops);
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // wait for the injector to read the result block, and the other threads to leave the map
+        ; adr x9, ->result_release
+        ; =>release
+        ; yield
+        ; ldr x10, [x9]
+        ; cbz x10, =>release
+
+        // the injector restored the function before the release
+        ; ldr x9, ->stage_addr
+        ; ldr x10, ->stage_end
+    );
+
+    sync_code(ops);
+
+    if let Some(tail_addr) = ret.tail_addr {
+        let tail_addr_label = ops.new_dynamic_label();
+
+        // the injector wrote the tail before the stage
+        dynasm!(ops
+            ; .arch aarch64
+            ; ldr x9, =>tail_addr_label
+            ; ldr x10, ->tail_end
+        );
+
+        sync_code(ops);
+
+        dynasm!(ops
+            ; .arch aarch64
+
+            // jump to the tail with the map and its length as the arguments of munmap
+            ; adr x0, ->map
+            ; adr x1, ->result_release
+            ; ldr x1, [x1]
+            ; ldr x17, =>tail_addr_label
+            ; br x17
+
+            ; .align 4
+            ; =>tail_addr_label
+            ; .qword tail_addr as _
+            ; ->tail_end:
+            ; .qword (tail_addr + ret.tail_len) as _
+        );
+    } else {
+        dynasm!(ops
+            ; .arch aarch64
+
+            // jump to the tail with the map and its length as the arguments of munmap
+            ; adr x0, ->map
+            ; adr x1, ->result_release
+            ; ldr x1, [x1]
+            ; b ->tail
+        );
+    }
+
+    dynasm!(ops
+        ; .arch aarch64
+        ; ->leave:
+        ; clrex
+    );
+
+    restore_registers(ops);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // jump to the original function, x8 may hold the address of an indirect result
+        ; ldr x17, ->oldfun
+        ; br x17
+
+        ; .align 4
+        ; ->oldfun:
+        ; .qword ret.addr as _
+
+        ; ->stage_addr:
+        ; .qword ret.stage_addr as _
+        ; ->stage_end:
+        ; .qword (ret.stage_addr + ret.stage_len) as _
+    );
+
+    if ret.tail_addr.is_none() {
+        dynasm!(ops
+            ; .arch aarch64
+            ; .align config.page_size
+            ; ->tail:
+        );
+        tail(ops, ret);
+    }
+}
+
+/// Makes the code between x9 and x10 visible to the instruction fetches of every core: its data cache lines are
+/// cleaned to the point of unification, then its instruction cache lines are invalidated. Clobbers x11 to x13.
+///
+/// The data cache lines are invalidated as well, the cleaning alone being affected by errata of some cores.
+fn sync_code(ops: &mut Assembler) {
+    let dcache = ops.new_dynamic_label();
+    let icache = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // data cache line size: 4 << CTR_EL0.DminLine bytes
+        ; mrs x11, SYSREG_CTR_EL0
+        ; ubfx x12, x11, #16, #4
+        ; mov x13, #0x4
+        ; lsl x12, x13, x12
+        ; sub x13, x12, #0x1
+        ; bic x13, x9, x13
+        ; =>dcache
+        ; dc civac, x13
+        ; add x13, x13, x12
+        ; cmp x13, x10
+        ; b.lo =>dcache
+        ; dsb ish
+
+        // instruction cache line size: 4 << CTR_EL0.IminLine bytes
+        ; and x12, x11, #0xf
+        ; mov x13, #0x4
+        ; lsl x12, x13, x12
+        ; sub x13, x12, #0x1
+        ; bic x13, x9, x13
+        ; =>icache
+        ; ic ivau, x13
+        ; add x13, x13, x12
+        ; cmp x13, x10
+        ; b.lo =>icache
+        ; dsb ish
+        ; isb
+    );
+}
+
+/// Restores the registers and the flags saved by the first stage, along with the stack pointer.
+fn restore_registers(ops: &mut Assembler) {
+    dynasm!(ops
+        ; .arch aarch64
+        ; ldr x9, [sp, #0xf8]
+        ; msr SYSREG_NZCV, x9
         ; ldp x0, x1, [sp, #0x0]
         ; ldp x2, x3, [sp, #0x10]
         ; ldp x4, x5, [sp, #0x20]
@@ -142,39 +1151,56 @@ pub fn raw_dlopen_shellcode(
         ; ldp x28, x29, [sp, #0xe0]
         ; ldp x30, xzr, [sp, #0xf0]
         ; add sp, sp, #0x100
+    );
+}
 
-        // jump to the original function
-        ; ldr x8, ->oldfun
-        ; br x8
-
-        ; ->crash:
-        ; brk #0x1
+/// Appends the result block read back by the injector, and assembles the second stage.
+fn finalize_second_stage(mut ops: Assembler) -> Result<SecondStage, InjectionError> {
+    dynasm!(ops
+        ; .arch aarch64
 
-        ; .align 4
-        ; ->dlopen_path:
-        ; .bytes dlopen_path_bytes
-        ; .bytes [0x0]
+        // claimed by the thread running the stage
+        ; .align 8
+        ; ->gate:
+        ; .qword 0x0
 
-        ; .align 4
-        ; ->dlopen_flags:
-        ; .qword dlopen_flags as _
+        // result block, read back by the injector
+        ; ->result:
+        ; ->result_status:
+        ; .qword 0x0
+        ; ->result_value:
+        ; .qword 0x0
+        ; ->result_error:
+        ; .qword 0x0
+        ; ->result_errno:
+        ; .qword 0x0
+        ; ->result_entry:
+        ; .qword 0x0
+        ; ->result_release:
+        ; .qword 0x0
+        ; ->result_buffer:
+        ; .bytes [0x0; RESULT_BUFFER_SIZE]
+    );
 
-        ; .align 4
-        ; ->dlopen:
-        ; .qword dlopen_addr as _
+    let result_offset = label_offset(ops.labels(), "result")?;
+    let handles_offset = label_offset(ops.labels(), "handles").ok();
+    let data_offset = label_offset(ops.labels(), "data")?;
+    let tail_offset = label_offset(ops.labels(), "tail").ok();
 
-        ; .align 4
-        ; ->oldfun:
-        ; .qword jmp_addr as _
-    );
     match ops.finalize() {
-        Ok(shellcode) => Ok(shellcode.to_vec()),
+        Ok(shellcode) => Ok(SecondStage {
+            code: shellcode.to_vec(),
+            result_offset,
+            handles_offset,
+            data_offset,
+            tail_offset,
+        }),
         Err(_) => Err(InjectionError::ShellcodeError),
     }
 }
 
-pub fn self_jmp() -> Result<Vec<u8>, InjectionError> {
-    debug!("self_jmp aarch64");
+pub fn self_jmp(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("self_jmp aarch64 0x{:x}", ret.stage_addr);
 
     let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
 
@@ -189,3 +1215,96 @@ pub fn self_jmp() -> Result<Vec<u8>, InjectionError> {
         Err(_) => Err(InjectionError::ShellcodeError),
     }
 }
+
+pub fn unmap_tail(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("unmap_tail aarch64");
+
+    let mut ops = dynasmrt::aarch64::Assembler::new().unwrap();
+    tail(&mut ops, ret);
+
+    match ops.finalize() {
+        Ok(shellcode) => Ok(shellcode.to_vec()),
+        Err(_) => Err(InjectionError::ShellcodeError),
+    }
+}
+
+/// Unmaps the map given in x0 and x1, restores the registers saved by the first stage and jumps to `ret.addr`,
+/// from a code cave or from the page of the map left mapped.
+fn tail(ops: &mut Assembler, ret: StageReturn) {
+    let return_addr = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // unmap the map, given in x0 and x1
+        ; mov x8, SYS_MUNMAP
+        ; svc #0x0
+    );
+
+    restore_registers(ops);
+
+    dynasm!(ops
+        ; .arch aarch64
+
+        // return to the hijacked function
+        ; ldr x17, =>return_addr
+        ; br x17
+
+        ; .align 4
+        ; =>return_addr
+        ; .qword ret.addr as _
+    );
+}
+
+/// Builds the payloads for the aarch64 processes.
+pub struct Backend;
+
+impl ArchBackend for Backend {
+    fn name(&self) -> &'static str {
+        "aarch64"
+    }
+
+    fn pointer_len(&self) -> usize {
+        8
+    }
+
+    fn syncs_icache(&self) -> bool {
+        true
+    }
+
+    fn max_alloc_len(&self) -> usize {
+        MAX_ALLOC_LEN
+    }
+
+    fn first_shellcode(&self, var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+        first_shellcode(var_addr, block_addr, ret, alloc_len, exec_len)
+    }
+
+    fn raw_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlopen_shellcode(dl, entry, dlopen_paths, ret, config)
+    }
+
+    fn raw_dlclose_shellcode(&self, dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlclose_shellcode(dl, fini_addr, library, ret, config)
+    }
+
+    fn call_shellcode(&self, func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        call_shellcode(func_addr, errno_fn_addr, args, ret, config)
+    }
+
+    fn memfd_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        memfd_dlopen_shellcode(dl, entry, memfd_names, ret, config)
+    }
+
+    fn shellcode_stage(&self, shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        shellcode_stage(shellcode, ret, config)
+    }
+
+    fn self_jmp(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        self_jmp(ret)
+    }
+
+    fn unmap_tail(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        unmap_tail(ret)
+    }
+}
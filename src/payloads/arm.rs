@@ -0,0 +1,1565 @@
+use std::collections::HashMap;
+
+use crate::error::InjectionError;
+use crate::payloads::{
+    map_prot, ArchBackend, CallArg, DlFns, Entry, LoadedLibrary, MailboxState, SecondStage, StageConfig, StageReturn, HANDLE_SKIPPED,
+    MAILBOX_BLOCK_ERRNO_OFFSET, MAILBOX_BLOCK_SYSCALL_OFFSET, MAILBOX_BLOCK_VERSION_OFFSET, MAILBOX_STATE, MAILBOX_SYSCALL_MMAP, MAILBOX_SYSCALL_MPROTECT,
+    MAILBOX_THREAD, MAILBOX_THREADS, MAILBOX_VALUE_SHIFT, MAILBOX_VERSION, MAX_CALL_ARGS, MFD_CLOEXEC, PROT_EXEC, PROT_READ,
+    RESULT_BUFFER_SIZE, RESULT_STATUS_DONE, RESULT_STATUS_REQUEST, RESULT_STATUS_RESUME, RTLD_NOLOAD, RTLD_NOW,
+};
+
+const SYS_CLOSE: u32 = 6;
+const SYS_MUNMAP: u32 = 91;
+const SYS_MPROTECT: u32 = 125;
+const SYS_SCHED_YIELD: u32 = 158;
+const SYS_MMAP2: u32 = 192;
+const SYS_MEMFD_CREATE: u32 = 385;
+/// ARM private system call making the code between two addresses visible to the instruction fetches.
+const SYS_CACHEFLUSH: u32 = 0xf0002;
+
+/// Alignment of the stack pointer at a call, required by the AAPCS.
+const STACK_ALIGN: u32 = 0x8;
+/// Length of the area kept at the stack pointer by the second stage for the arguments of its calls past r0-r3.
+const CALL_ARGS_LEN: u32 = (((MAX_CALL_ARGS - 4) * 4) as u32).next_multiple_of(STACK_ALIGN);
+
+/// Self loops, `b .` in ARM and Thumb.
+const ARM_SELF_JMP: u32 = 0xeafffffe;
+const THUMB_SELF_JMP: u16 = 0xe7fe;
+/// Thumb `bx pc`, going on in ARM at the next word, and `nop` (`mov r8, r8`).
+const THUMB_BX_PC: u16 = 0x4778;
+const THUMB_NOP: u16 = 0x46c0;
+/// Length of the longest Thumb prefix of the first stage, when it starts between two words.
+const THUMB_PREFIX_LEN: usize = 0x6;
+
+/// Largest map the first stage can allocate, its length being loaded with movw and movt.
+pub const MAX_ALLOC_LEN: usize = u32::MAX as usize;
+
+const R0: u32 = 0;
+const R1: u32 = 1;
+const R2: u32 = 2;
+const R3: u32 = 3;
+const R4: u32 = 4;
+const R5: u32 = 5;
+const R6: u32 = 6;
+const R7: u32 = 7;
+const R8: u32 = 8;
+const R9: u32 = 9;
+const R10: u32 = 10;
+const IP: u32 = 12;
+const SP: u32 = 13;
+const PC: u32 = 15;
+
+/// Register of the second stage holding the address of the map, where its labels are relative to.
+const MAP: u32 = R4;
+
+/// Condition codes.
+const EQ: u32 = 0x0;
+const NE: u32 = 0x1;
+const LO: u32 = 0x3;
+const HI: u32 = 0x8;
+const LT: u32 = 0xb;
+const AL: u32 = 0xe;
+
+/// Opcodes of the data processing instructions.
+const OP_AND: u32 = 0x0;
+const OP_SUB: u32 = 0x2;
+const OP_RSB: u32 = 0x3;
+const OP_ADD: u32 = 0x4;
+const OP_TST: u32 = 0x8;
+const OP_CMP: u32 = 0xa;
+const OP_CMN: u32 = 0xb;
+const OP_MOV: u32 = 0xd;
+const OP_BIC: u32 = 0xe;
+const OP_MVN: u32 = 0xf;
+
+/// Shift of a register operand, by an immediate amount.
+#[derive(Debug, Clone, Copy)]
+enum Shift {
+    Lsl(u32),
+    Lsr(u32),
+}
+
+impl Shift {
+    /// Encodes the shift, in bits 5 to 11.
+    fn bits(self) -> u32 {
+        match self {
+            Shift::Lsl(amount) => amount << 7,
+            Shift::Lsr(amount) => (amount << 7) | (0x1 << 5),
+        }
+    }
+}
+
+/// A label of the assembled code, bound to an offset once.
+#[derive(Debug, Clone, Copy)]
+struct Label(usize);
+
+/// A reference to a label, patched once the code is assembled.
+enum Fixup {
+    /// The 24 bit word offset of a branch.
+    Branch,
+    /// The immediate of a movw, loaded with the low half of the offset of the label.
+    Low,
+    /// The immediate of a movt, loaded with the high half of the offset of the label.
+    High,
+}
+
+/// Assembles ARM (A32) code, dynasm having no 32 bit ARM target. Its global labels are created on first use, like
+/// the `->name` labels of dynasm, and their offsets are relative to the start of the code.
+struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    globals: HashMap<&'static str, Label>,
+    fixups: Vec<(usize, Label, Fixup)>,
+    /// Set when an immediate can't be encoded: the code is then rejected by `finalize`.
+    invalid: bool,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            labels: Vec::new(),
+            globals: HashMap::new(),
+            fixups: Vec::new(),
+            invalid: false,
+        }
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Gets the global label `name`.
+    fn global(&mut self, name: &'static str) -> Label {
+        if let Some(label) = self.globals.get(name) {
+            return *label;
+        }
+
+        let label = self.new_label();
+        self.globals.insert(name, label);
+        label
+    }
+
+    /// Binds `label` to the current offset.
+    fn bind(&mut self, label: Label) {
+        if self.labels[label.0].replace(self.code.len()).is_some() {
+            self.invalid = true;
+        }
+    }
+
+    /// Binds the global label `name` to the current offset.
+    fn bind_global(&mut self, name: &'static str) {
+        let label = self.global(name);
+        self.bind(label);
+    }
+
+    /// Gets the offset of the global label `name`, once bound.
+    fn offset_of(&self, name: &'static str) -> Result<usize, InjectionError> {
+        self.globals
+            .get(name)
+            .and_then(|label| self.labels[label.0])
+            .ok_or(InjectionError::ShellcodeError)
+    }
+
+    fn emit(&mut self, word: u32) {
+        self.code.extend_from_slice(&word.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn qword(&mut self, value: u64) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Pads the code with zeroes up to a multiple of `align`.
+    fn align(&mut self, align: usize) {
+        self.code.resize(self.code.len().next_multiple_of(align), 0x0);
+    }
+
+    /// Encodes `imm` as an 8 bit value rotated right by an even amount, if it can be.
+    fn modified_imm(imm: u32) -> Option<u32> {
+        (0..16).find_map(|rotation| {
+            let value = imm.rotate_left(2 * rotation);
+            (value <= 0xff).then_some((rotation << 8) | value)
+        })
+    }
+
+    /// Emits a data processing instruction with an immediate operand.
+    fn data_imm(&mut self, op: u32, set_flags: bool, rd: u32, rn: u32, imm: u32) {
+        let imm = Self::modified_imm(imm).unwrap_or_else(|| {
+            self.invalid = true;
+            0x0
+        });
+        self.emit((AL << 28) | (1 << 25) | (op << 21) | ((set_flags as u32) << 20) | (rn << 16) | (rd << 12) | imm);
+    }
+
+    /// Emits a data processing instruction with a shifted register operand.
+    fn data_reg(&mut self, op: u32, set_flags: bool, rd: u32, rn: u32, rm: u32, shift: Shift) {
+        self.emit((AL << 28) | (op << 21) | ((set_flags as u32) << 20) | (rn << 16) | (rd << 12) | shift.bits() | rm);
+    }
+
+    fn mov_imm(&mut self, rd: u32, imm: u32) {
+        self.data_imm(OP_MOV, false, rd, 0, imm);
+    }
+
+    fn mvn_imm(&mut self, rd: u32, imm: u32) {
+        self.data_imm(OP_MVN, false, rd, 0, imm);
+    }
+
+    fn add_imm(&mut self, rd: u32, rn: u32, imm: u32) {
+        self.data_imm(OP_ADD, false, rd, rn, imm);
+    }
+
+    fn sub_imm(&mut self, rd: u32, rn: u32, imm: u32) {
+        self.data_imm(OP_SUB, false, rd, rn, imm);
+    }
+
+    fn rsb_imm(&mut self, rd: u32, rn: u32, imm: u32) {
+        self.data_imm(OP_RSB, false, rd, rn, imm);
+    }
+
+    fn and_imm(&mut self, rd: u32, rn: u32, imm: u32) {
+        self.data_imm(OP_AND, false, rd, rn, imm);
+    }
+
+    fn bic_imm(&mut self, rd: u32, rn: u32, imm: u32) {
+        self.data_imm(OP_BIC, false, rd, rn, imm);
+    }
+
+    fn tst_imm(&mut self, rn: u32, imm: u32) {
+        self.data_imm(OP_TST, true, 0, rn, imm);
+    }
+
+    fn cmp_imm(&mut self, rn: u32, imm: u32) {
+        self.data_imm(OP_CMP, true, 0, rn, imm);
+    }
+
+    fn cmn_imm(&mut self, rn: u32, imm: u32) {
+        self.data_imm(OP_CMN, true, 0, rn, imm);
+    }
+
+    fn mov(&mut self, rd: u32, rm: u32) {
+        self.data_reg(OP_MOV, false, rd, 0, rm, Shift::Lsl(0));
+    }
+
+    fn add(&mut self, rd: u32, rn: u32, rm: u32) {
+        self.data_reg(OP_ADD, false, rd, rn, rm, Shift::Lsl(0));
+    }
+
+    fn tst(&mut self, rn: u32, rm: u32) {
+        self.data_reg(OP_TST, true, 0, rn, rm, Shift::Lsl(0));
+    }
+
+    fn lsl(&mut self, rd: u32, rm: u32, amount: u32) {
+        self.data_reg(OP_MOV, false, rd, 0, rm, Shift::Lsl(amount));
+    }
+
+    fn lsr(&mut self, rd: u32, rm: u32, amount: u32) {
+        self.data_reg(OP_MOV, false, rd, 0, rm, Shift::Lsr(amount));
+    }
+
+    fn movw(&mut self, rd: u32, imm: u32) {
+        self.emit((AL << 28) | 0x03000000 | ((imm & 0xf000) << 4) | (rd << 12) | (imm & 0xfff));
+    }
+
+    fn movt(&mut self, rd: u32, imm: u32) {
+        self.emit((AL << 28) | 0x03400000 | ((imm & 0xf000) << 4) | (rd << 12) | (imm & 0xfff));
+    }
+
+    /// Loads a 32 bit value with movw and movt, whatever it is.
+    fn mov32(&mut self, rd: u32, value: u32) {
+        self.movw(rd, value & 0xffff);
+        self.movt(rd, value >> 16);
+    }
+
+    /// Loads a value in the shortest way.
+    fn load_imm(&mut self, rd: u32, value: u32) {
+        if Self::modified_imm(value).is_some() {
+            self.mov_imm(rd, value);
+        } else if Self::modified_imm(!value).is_some() {
+            self.mvn_imm(rd, !value);
+        } else if value <= 0xffff {
+            self.movw(rd, value);
+        } else {
+            self.mov32(rd, value);
+        }
+    }
+
+    /// Loads the address of a label of the second stage, relative to the map.
+    fn adr(&mut self, rd: u32, label: Label) {
+        self.fixups.push((self.code.len(), label, Fixup::Low));
+        self.movw(rd, 0x0);
+        self.fixups.push((self.code.len(), label, Fixup::High));
+        self.movt(rd, 0x0);
+        self.add(rd, MAP, rd);
+    }
+
+    /// Loads the address of the global label `name` of the second stage.
+    fn adr_global(&mut self, rd: u32, name: &'static str) {
+        let label = self.global(name);
+        self.adr(rd, label);
+    }
+
+    /// Emits a load or a store of a word at a positive offset.
+    fn transfer(&mut self, load: bool, rt: u32, rn: u32, offset: u32) {
+        if offset > 0xfff {
+            self.invalid = true;
+        }
+        self.emit((AL << 28) | 0x05800000 | ((load as u32) << 20) | (rn << 16) | (rt << 12) | (offset & 0xfff));
+    }
+
+    fn ldr(&mut self, rt: u32, rn: u32, offset: u32) {
+        self.transfer(true, rt, rn, offset);
+    }
+
+    fn str(&mut self, rt: u32, rn: u32, offset: u32) {
+        self.transfer(false, rt, rn, offset);
+    }
+
+    fn ldrex(&mut self, rt: u32, rn: u32) {
+        self.emit(0xe1900f9f | (rn << 16) | (rt << 12));
+    }
+
+    /// Stores `rt` at `rn` if the monitor is still held, setting `rd` to 0, or to 1 if it is not.
+    fn strex(&mut self, rd: u32, rt: u32, rn: u32) {
+        self.emit(0xe1800f90 | (rn << 16) | (rd << 12) | rt);
+    }
+
+    fn clrex(&mut self) {
+        self.emit(0xf57ff01f);
+    }
+
+    fn isb(&mut self) {
+        self.emit(0xf57ff06f);
+    }
+
+    /// Orders the memory accesses before it with the ones after it, for the other cores (`dmb ish`).
+    fn dmb(&mut self) {
+        self.emit(0xf57ff05b);
+    }
+
+    fn yield_(&mut self) {
+        self.emit(0xe320f001);
+    }
+
+    fn svc(&mut self) {
+        self.emit(0xef000000);
+    }
+
+    /// Pushes the registers of the `list` bitmask, the lowest one at the lowest address.
+    fn push(&mut self, list: u32) {
+        self.emit(0xe92d0000 | list);
+    }
+
+    fn pop(&mut self, list: u32) {
+        self.emit(0xe8bd0000 | list);
+    }
+
+    fn b(&mut self, cond: u32, label: Label) {
+        self.fixups.push((self.code.len(), label, Fixup::Branch));
+        self.emit((cond << 28) | 0x0a000000);
+    }
+
+    fn b_global(&mut self, cond: u32, name: &'static str) {
+        let label = self.global(name);
+        self.b(cond, label);
+    }
+
+    fn bl(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label, Fixup::Branch));
+        self.emit((AL << 28) | 0x0b000000);
+    }
+
+    /// Branches to `rm`, in Thumb if its bit 0 is set.
+    fn bx(&mut self, rm: u32) {
+        self.emit(0xe12fff10 | rm);
+    }
+
+    /// Calls `rm`, in Thumb if its bit 0 is set.
+    fn blx(&mut self, rm: u32) {
+        self.emit(0xe12fff30 | rm);
+    }
+
+    /// Reads the flags, with mrs.
+    fn mrs_apsr(&mut self, rd: u32) {
+        self.emit(0xe10f0000 | (rd << 12));
+    }
+
+    /// Writes the flags (NZCVQ and GE), with msr.
+    fn msr_apsr(&mut self, rn: u32) {
+        self.emit(0xe12cf000 | rn);
+    }
+
+    fn vmrs_fpscr(&mut self, rt: u32) {
+        self.emit(0xeef10a10 | (rt << 12));
+    }
+
+    fn vmsr_fpscr(&mut self, rt: u32) {
+        self.emit(0xeee10a10 | (rt << 12));
+    }
+
+    /// Pushes `count` double registers from `first`.
+    fn vpush(&mut self, first: u32, count: u32) {
+        self.emit(0xed2d0b00 | ((first >> 4) << 22) | ((first & 0xf) << 12) | (count * 2));
+    }
+
+    fn vpop(&mut self, first: u32, count: u32) {
+        self.emit(0xecbd0b00 | ((first >> 4) << 22) | ((first & 0xf) << 12) | (count * 2));
+    }
+
+    /// Patches the references to the labels, and gets the code.
+    fn finalize(mut self) -> Result<Vec<u8>, InjectionError> {
+        for (at, label, fixup) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].ok_or(InjectionError::ShellcodeError)?;
+            let word = u32::from_le_bytes(self.code[at..at + 4].try_into().unwrap());
+            let word = match fixup {
+                // the pc is 8 bytes ahead of the branch
+                Fixup::Branch => word | (((target as i64 - at as i64 - 8) >> 2) as u32 & 0xffffff),
+                Fixup::Low => word | ((target as u32 & 0xf000) << 4) | (target as u32 & 0xfff),
+                Fixup::High => word | (((target as u32 >> 16) & 0xf000) << 4) | ((target as u32 >> 16) & 0xfff),
+            };
+            self.code[at..at + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        match self.invalid {
+            true => Err(InjectionError::ShellcodeError),
+            false => Ok(self.code),
+        }
+    }
+}
+
+/// Is the first stage entered in Thumb, written over the prologue of a Thumb function ? Its symbol, where the stages
+/// return to, then has bit 0 set. A first stage written in a code cave is always entered in ARM, its GOT slot
+/// holding its (even) address.
+fn thumb_entry(ret: &StageReturn) -> bool {
+    ret.addr & 0x1 == 0x1 && ret.addr & !0x1 == ret.stage_addr
+}
+
+/// Gets the address of the code of a function from the address of its symbol, odd for the Thumb ones.
+pub fn code_addr(addr: usize) -> usize {
+    addr & !0x1
+}
+
+/// Builds the first stage. Entered in Thumb, it starts with a `bx pc` going on in ARM at the next word, and is
+/// padded so its length does not depend on it. It saves the context of the thread in a frame of 0x40 bytes below
+/// the stack pointer of the hijacked call, which the AAPCS keeps 8 bytes aligned, with no red zone above:
+///
+/// - r0 to r12 at `4 * n`
+/// - lr at 0x34, and the flags (APSR) at 0x38
+///
+/// The second stage runs below this frame, and the tail unmapping it restores it (see `restore_registers`).
+pub fn first_shellcode(var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+    debug!("first_shellcode arm");
+
+    let mut ops = Assembler::new();
+
+    // parking spot, replaced by a self loop holding the new threads while the function is restored
+    ops.sub_imm(SP, SP, 0x8);
+
+    // save the registers, and the flags above them
+    ops.push(0x5fff);
+    ops.mrs_apsr(R0);
+    ops.str(R0, SP, 0x38);
+
+    // count the thread in the first stage
+    ops.mov32(R6, var_addr as u32);
+    ops.bind_global("count");
+    ops.ldrex(R1, R6);
+    ops.add_imm(R1, R1, MAILBOX_THREAD as u32);
+    ops.strex(R2, R1, R6);
+    ops.cmp_imm(R2, 0x0);
+    ops.b_global(NE, "count");
+
+    // claim the stage if nobody has: idle -> claimed
+    ops.bind_global("claim");
+    ops.ldrex(R1, R6);
+    ops.tst_imm(R1, MAILBOX_STATE as u32);
+    ops.b_global(NE, "wait_map");
+    ops.add_imm(R1, R1, MailboxState::Claimed as u32);
+    ops.strex(R2, R1, R6);
+    ops.cmp_imm(R2, 0x0);
+    ops.b_global(NE, "claim");
+    ops.b_global(AL, "claimed");
+
+    // wait for the map, and join the thread which claimed the stage there
+    ops.bind_global("wait_map");
+    ops.clrex();
+    ops.ldr(R1, R6, 0x0);
+    ops.and_imm(R0, R1, MAILBOX_STATE as u32);
+    ops.cmp_imm(R0, MailboxState::Failed as u32);
+    ops.b_global(EQ, "leave");
+    ops.cmp_imm(R0, MailboxState::Allocated as u32);
+    ops.b_global(LO, "wait_map");
+    ops.lsr(R0, R1, MAILBOX_VALUE_SHIFT);
+    ops.lsl(R0, R0, MAILBOX_VALUE_SHIFT);
+    ops.bx(R0);
+
+    // write the protocol version in the mailbox block
+    ops.bind_global("claimed");
+    ops.mov32(R10, block_addr as u32);
+    ops.mov_imm(R1, MAILBOX_VERSION);
+    ops.str(R1, R10, MAILBOX_BLOCK_VERSION_OFFSET as u32);
+
+    // mmap2 call
+    ops.mov_imm(R0, 0x0); // addr       (NULL)
+    ops.mov32(R1, alloc_len as u32); // len (alloc_len)
+    ops.mov_imm(R2, map_prot(exec_len) as u32); // prot (RWX, or RW)
+    ops.mov_imm(R3, 0x22); // flags      (MAP_PRIVATE | MAP_ANONYMOUS)
+    ops.mvn_imm(R4, 0x0); // fd         (-1)
+    ops.mov_imm(R5, 0x0); // pgoffset   (ignored)
+    ops.mov_imm(R7, SYS_MMAP2);
+    ops.svc();
+    ops.cmn_imm(R0, 0x1000); // -4095..-1 is an errno
+    ops.b_global(HI, "mmap_failed");
+
+    // write self loop instruction to the new map
+    ops.mov32(R1, ARM_SELF_JMP);
+    ops.str(R1, R0, 0x0);
+    ops.mov(R8, R0); // keep the map, r6 holds var_addr
+
+    if let Some(exec_len) = exec_len {
+        // make the code executable, and no longer writable
+        ops.mov32(R1, exec_len as u32); // len (exec_len)
+        ops.mov_imm(R2, (PROT_READ | PROT_EXEC) as u32); // prot (RX)
+        ops.mov_imm(R7, SYS_MPROTECT);
+        ops.svc();
+        ops.cmp_imm(R0, 0x0);
+        ops.b_global(NE, "mprotect_failed");
+    }
+
+    // make the self loop visible to the instruction fetches
+    ops.mov(R0, R8);
+    ops.add_imm(R1, R8, 0x4);
+    ops.mov_imm(R2, 0x0);
+    ops.mov32(R7, SYS_CACHEFLUSH);
+    ops.svc();
+    ops.mov(R0, R8);
+
+    // publish the map address next to the count: claimed -> allocated
+    ops.dmb();
+    ops.bind_global("publish");
+    ops.ldrex(R1, R6);
+    ops.add(R1, R1, R0);
+    ops.add_imm(R1, R1, MailboxState::Allocated as u32 - MailboxState::Claimed as u32);
+    ops.strex(R2, R1, R6);
+    ops.cmp_imm(R2, 0x0);
+    ops.b_global(NE, "publish");
+
+    // jump to the new map
+    ops.bx(R0);
+
+    // write the system call and its errno in the mailbox block, then publish them: claimed -> failed
+    ops.bind_global("mmap_failed");
+    ops.rsb_imm(R0, R0, 0x0);
+    ops.mov_imm(R9, MAILBOX_SYSCALL_MMAP);
+    ops.bind_global("failed");
+    ops.str(R9, R10, MAILBOX_BLOCK_SYSCALL_OFFSET as u32);
+    ops.str(R0, R10, MAILBOX_BLOCK_ERRNO_OFFSET as u32);
+    ops.dmb();
+    ops.bind_global("publish_failure");
+    ops.ldrex(R1, R6);
+    ops.add_imm(R1, R1, MailboxState::Failed as u32 - MailboxState::Claimed as u32);
+    ops.strex(R2, R1, R6);
+    ops.cmp_imm(R2, 0x0);
+    ops.b_global(NE, "publish_failure");
+
+    // leave the first stage, and go back to the function once the injector has restored it
+    ops.bind_global("leave");
+    ops.ldrex(R1, R6);
+    ops.sub_imm(R1, R1, MAILBOX_THREAD as u32);
+    ops.strex(R2, R1, R6);
+    ops.cmp_imm(R2, 0x0);
+    ops.b_global(NE, "leave");
+
+    restore_registers(&mut ops);
+    return_to(&mut ops, ret.addr);
+
+    if exec_len.is_some() {
+        // unmap the map, and publish the errno
+        ops.bind_global("mprotect_failed");
+        ops.rsb_imm(R9, R0, 0x0);
+        ops.mov(R0, R8); // addr       (map)
+        ops.mov32(R1, alloc_len as u32); // len (alloc_len)
+        ops.mov_imm(R7, SYS_MUNMAP);
+        ops.svc();
+        ops.mov(R0, R9);
+        ops.mov_imm(R9, MAILBOX_SYSCALL_MPROTECT);
+        ops.b_global(AL, "failed");
+    }
+
+    let body = ops.finalize()?;
+    let mut shellcode = thumb_prefix(&ret);
+    let len = THUMB_PREFIX_LEN + body.len();
+    shellcode.extend(body);
+    shellcode.resize(len, 0x0);
+    Ok(shellcode)
+}
+
+/// Gets the Thumb code going on in ARM at the next word, when the first stage is entered in Thumb. `bx pc` has to be
+/// on a word.
+fn thumb_prefix(ret: &StageReturn) -> Vec<u8> {
+    let halfwords: &[u16] = match (thumb_entry(ret), ret.stage_addr % 0x4) {
+        (false, _) => &[],
+        (true, 0x0) => &[THUMB_BX_PC, THUMB_NOP],
+        (true, _) => &[THUMB_NOP, THUMB_BX_PC, THUMB_NOP],
+    };
+
+    halfwords.iter().flat_map(|halfword| halfword.to_le_bytes()).collect()
+}
+
+pub fn raw_dlopen_shellcode(
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    dlopen_paths: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("raw_dlopen_shellcode arm");
+
+    let mut ops = Assembler::new();
+    let paths: Vec<_> = dlopen_paths.iter().map(|_| ops.new_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, path) in paths.iter().enumerate() {
+        // load args
+        ops.adr(R0, *path);
+        ops.mov_imm(R1, RTLD_NOW as u32);
+
+        // call dlopen
+        ops.mov32(IP, dl.dlopen as u32);
+        ops.blx(IP);
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, dl, entry);
+    ops.b_global(AL, "done");
+
+    dlopen_failed(&mut ops, dl);
+
+    // tell the injector we are done
+    ops.bind_global("done");
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (path, dlopen_path) in paths.iter().zip(dlopen_paths) {
+        ops.align(0x4);
+        ops.bind(*path);
+        ops.bytes(dlopen_path.as_bytes());
+        ops.bytes(&[0x0]);
+    }
+
+    data_section(&mut ops, config.data_align);
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, dlopen_paths.len());
+    finalize_second_stage(ops)
+}
+
+pub fn memfd_dlopen_shellcode(
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    memfd_names: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("memfd_dlopen_shellcode arm");
+
+    let count = memfd_names.len();
+    let mut ops = Assembler::new();
+    let names: Vec<_> = memfd_names.iter().map(|_| ops.new_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, name) in names.iter().enumerate() {
+        let wait = ops.new_label();
+
+        // mark the library as attempted
+        ops.adr_global(R1, "handles");
+        ops.mov_imm(R0, 0x0);
+        ops.str(R0, R1, (i * 8) as u32);
+        ops.str(R0, R1, (i * 8 + 4) as u32);
+
+        // create the memfd
+        ops.adr(R0, *name);
+        ops.mov_imm(R1, MFD_CLOEXEC as u32);
+        ops.load_imm(R7, SYS_MEMFD_CREATE);
+        ops.svc();
+        ops.cmp_imm(R0, 0x0);
+        ops.b_global(LT, "memfd_failed");
+        ops.adr_global(R1, "memfds");
+        ops.str(R0, R1, (i * 4) as u32);
+
+        // ask the injector to fill the memfd and its path, and wait for it
+        ops.adr_global(R1, "result_value");
+        ops.str(R0, R1, 0x0);
+        set_status(&mut ops, RESULT_STATUS_REQUEST);
+        ops.bind(wait);
+        ops.mov_imm(R7, SYS_SCHED_YIELD);
+        ops.svc();
+        ops.adr_global(R1, "result_status");
+        ops.ldr(R0, R1, 0x0);
+        ops.cmp_imm(R0, RESULT_STATUS_RESUME as u32);
+        ops.b(NE, wait);
+
+        // call dlopen on /proc/self/fd/<memfd>
+        ops.adr_global(R0, "result_buffer");
+        ops.mov_imm(R1, RTLD_NOW as u32);
+        ops.mov32(IP, dl.dlopen as u32);
+        ops.blx(IP);
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, dl, entry);
+    ops.b_global(AL, "done");
+
+    dlopen_failed(&mut ops, dl);
+    ops.b_global(AL, "done");
+
+    // save the errno of memfd_create
+    ops.bind_global("memfd_failed");
+    ops.rsb_imm(R0, R0, 0x0);
+    ops.adr_global(R1, "result_errno");
+    ops.str(R0, R1, 0x0);
+
+    ops.bind_global("done");
+
+    // close the memfds, the library mappings keep them alive. They are kept open until now,
+    // so each library gets its own /proc/self/fd/<memfd> path.
+    for i in 0..count {
+        let closed = ops.new_label();
+
+        ops.adr_global(R1, "memfds");
+        ops.ldr(R0, R1, (i * 4) as u32);
+        ops.cmp_imm(R0, 0x0);
+        ops.b(LT, closed);
+        ops.mov_imm(R7, SYS_CLOSE);
+        ops.svc();
+        ops.bind(closed);
+    }
+
+    // tell the injector we are done
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (name, memfd_name) in names.iter().zip(memfd_names) {
+        ops.align(0x4);
+        ops.bind(*name);
+        ops.bytes(memfd_name.as_bytes());
+        ops.bytes(&[0x0]);
+    }
+
+    data_section(&mut ops, config.data_align);
+
+    // file descriptors of the memfds, one per library
+    ops.bind_global("memfds");
+    for _ in 0..count {
+        ops.emit(u32::MAX);
+    }
+
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, count);
+    finalize_second_stage(ops)
+}
+
+pub fn raw_dlclose_shellcode(
+    dl: &DlFns,
+    fini_addr: Option<usize>,
+    library: &LoadedLibrary,
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("raw_dlclose_shellcode arm");
+
+    let mut ops = Assembler::new();
+    handshake(&mut ops, config);
+
+    match library {
+        LoadedLibrary::Path(_) => {
+            // get a handle on the loaded library, without loading it again
+            ops.adr_global(R0, "dlopen_path");
+            ops.mov_imm(R1, (RTLD_NOW | RTLD_NOLOAD) as u32);
+            ops.mov32(IP, dl.dlopen as u32);
+            ops.blx(IP);
+            ops.cmp_imm(R0, 0x0);
+            ops.b_global(EQ, "not_loaded");
+            ops.mov(R8, R0);
+        }
+        LoadedLibrary::Handle(handle) => {
+            // the handle returned when the library was injected
+            ops.mov32(R8, *handle as u32);
+        }
+    }
+
+    // call the fini hook if the library exports one
+    if let Some(fini_addr) = fini_addr {
+        ops.mov32(IP, fini_addr as u32);
+        ops.blx(IP);
+    }
+
+    if let LoadedLibrary::Path(_) = library {
+        // drop the reference we just took
+        ops.mov(R0, R8);
+        ops.mov32(IP, dl.dlclose as u32);
+        ops.blx(IP);
+    }
+
+    // drop the reference of the injection
+    ops.mov(R0, R8);
+    ops.mov32(IP, dl.dlclose as u32);
+    ops.blx(IP);
+    ops.adr_global(R1, "result_value");
+    ops.str(R0, R1, 0x0);
+    ops.cmp_imm(R0, 0x0);
+    ops.b_global(EQ, "closed");
+    ops.b_global(AL, "failed");
+
+    ops.bind_global("not_loaded");
+    ops.mvn_imm(R0, 0x0);
+    ops.adr_global(R1, "result_value");
+    ops.str(R0, R1, 0x0);
+    ops.str(R0, R1, 0x4);
+
+    // save the dlerror() text
+    ops.bind_global("failed");
+    ops.mov32(IP, dl.dlerror as u32);
+    ops.blx(IP);
+    ops.adr_global(R1, "result_error");
+    ops.str(R0, R1, 0x0);
+
+    // tell the injector we are done
+    ops.bind_global("closed");
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    if let LoadedLibrary::Path(dlopen_path) = library {
+        ops.align(0x4);
+        ops.bind_global("dlopen_path");
+        ops.bytes(dlopen_path.as_bytes());
+        ops.bytes(&[0x0]);
+    }
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+pub fn call_shellcode(
+    func_addr: usize,
+    errno_fn_addr: usize,
+    args: &[CallArg],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("call_shellcode arm");
+
+    let mut ops = Assembler::new();
+    let buffers: Vec<_> = args.iter().map(|_| ops.new_label()).collect();
+    handshake(&mut ops, config);
+
+    // clear errno
+    ops.mov32(IP, errno_fn_addr as u32);
+    ops.blx(IP);
+    ops.mov_imm(R1, 0x0);
+    ops.str(R1, R0, 0x0);
+
+    // load the arguments in r0-r3, and the next ones at the stack pointer, following the AAPCS. The ones on the
+    // stack are stored first, with r0 as a scratch register.
+    for (i, arg) in args.iter().enumerate().rev() {
+        let reg = if i < 4 { i as u32 } else { R0 };
+        match arg {
+            CallArg::Int(value) => ops.mov32(reg, *value as u32),
+            CallArg::Bytes(_) => ops.adr(reg, buffers[i]),
+        }
+        if i >= 4 {
+            ops.str(R0, SP, ((i - 4) * 4) as u32);
+        }
+    }
+
+    // call the function
+    ops.mov32(IP, func_addr as u32);
+    ops.blx(IP);
+    ops.adr_global(R1, "result_value");
+    ops.str(R0, R1, 0x0);
+
+    // save errno
+    ops.mov32(IP, errno_fn_addr as u32);
+    ops.blx(IP);
+    ops.ldr(R0, R0, 0x0);
+    ops.adr_global(R1, "result_errno");
+    ops.str(R0, R1, 0x0);
+
+    // tell the injector we are done
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    // the called function may write in the buffers
+    data_section(&mut ops, config.data_align);
+
+    for (i, arg) in args.iter().enumerate() {
+        if let CallArg::Bytes(bytes) = arg {
+            ops.align(0x4);
+            ops.bind(buffers[i]);
+            ops.bytes(bytes);
+        }
+    }
+
+    finalize_second_stage(ops)
+}
+
+/// Builds the second stage running a shellcode, called in ARM.
+pub fn shellcode_stage(shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("shellcode_stage arm {} bytes", shellcode.len());
+
+    let mut ops = Assembler::new();
+    let start = ops.new_label();
+    handshake(&mut ops, config);
+
+    // call the shellcode, and save what it returned
+    ops.bl(start);
+    ops.adr_global(R1, "result_value");
+    ops.str(R0, R1, 0x0);
+
+    // tell the injector we are done
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    ops.align(0x10);
+    ops.bind(start);
+    ops.bytes(shellcode);
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+/// Lets a single thread run the second stage, once it has counted the threads left in the first stage.
+/// The others call the function, which the injector holds until it is restored.
+///
+/// Every thread synchronises the code of the stage with its instruction cache first, and keeps the map in r4. The
+/// thread running the stage keeps its stack pointer in r6, aligns it, and keeps `CALL_ARGS_LEN` bytes there for
+/// the arguments of its calls.
+fn handshake(ops: &mut Assembler, config: StageConfig) {
+    let leave_count = ops.new_label();
+    let claim = ops.new_label();
+    let ready = ops.new_label();
+    let wait = ops.new_label();
+    let done = ops.new_label();
+
+    // the first instruction replaces the self loop the threads spin on, the next ones may have been fetched
+    // before the stage was written
+    ops.isb();
+
+    // the pc is 8 bytes ahead of the instruction
+    ops.sub_imm(MAP, PC, 0xc);
+
+    ops.mov(R0, MAP);
+    ops.adr_global(R1, "data");
+    sync_code(ops);
+
+    // the thread has left the first stage
+    ops.mov32(R5, config.var_addr as u32);
+    ops.bind(leave_count);
+    ops.ldrex(R1, R5);
+    ops.sub_imm(R1, R1, MAILBOX_THREAD as u32);
+    ops.strex(R2, R1, R5);
+    ops.cmp_imm(R2, 0x0);
+    ops.b(NE, leave_count);
+
+    // only the first thread runs the stage: allocated -> stage2-ready
+    ops.adr_global(R3, "gate");
+    ops.bind(claim);
+    ops.ldrex(R1, R3);
+    ops.cmp_imm(R1, 0x0);
+    ops.b_global(NE, "leave");
+    ops.mov_imm(R1, 0x1);
+    ops.strex(R2, R1, R3);
+    ops.cmp_imm(R2, 0x0);
+    ops.b(NE, claim);
+    ops.bind(ready);
+    ops.ldrex(R1, R5);
+    ops.add_imm(R1, R1, MailboxState::Stage2Ready as u32 - MailboxState::Allocated as u32);
+    ops.strex(R2, R1, R5);
+    ops.cmp_imm(R2, 0x0);
+    ops.b(NE, ready);
+
+    // wait for the other threads to leave the first stage, and tell the injector: stage2-ready -> done
+    ops.movw(R2, MAILBOX_THREADS as u32);
+    ops.bind(wait);
+    ops.yield_();
+    ops.ldr(R1, R5, 0x0);
+    ops.tst(R1, R2);
+    ops.b(NE, wait);
+    ops.bind(done);
+    ops.ldrex(R1, R5);
+    ops.add_imm(R1, R1, MailboxState::Done as u32 - MailboxState::Stage2Ready as u32);
+    ops.strex(R2, R1, R5);
+    ops.cmp_imm(R2, 0x0);
+    ops.b(NE, done);
+
+    ops.mov(R6, SP);
+    ops.bic_imm(SP, SP, STACK_ALIGN - 1);
+
+    if config.full_context {
+        save_context(ops);
+    }
+
+    ops.sub_imm(SP, SP, CALL_ARGS_LEN);
+}
+
+/// Saves FPSCR and the VFP registers not preserved across calls, d0-d7 and d16-d31, below the stack. The targets
+/// have the 32 double registers of NEON.
+fn save_context(ops: &mut Assembler) {
+    ops.vmrs_fpscr(R0);
+    ops.sub_imm(SP, SP, 0x8);
+    ops.str(R0, SP, 0x0);
+    ops.vpush(0, 8);
+    ops.vpush(16, 16);
+}
+
+/// Restores the registers saved by `save_context`, and the stack pointer.
+fn restore_context(ops: &mut Assembler) {
+    ops.vpop(16, 16);
+    ops.vpop(0, 8);
+    ops.ldr(R0, SP, 0x0);
+    ops.add_imm(SP, SP, 0x8);
+    ops.vmsr_fpscr(R0);
+}
+
+/// Saves the handle returned by dlopen for the library `index`, and stops loading if it failed.
+fn save_handle(ops: &mut Assembler, index: usize) {
+    ops.adr_global(R1, "handles");
+    ops.str(R0, R1, (index * 8) as u32);
+    ops.mov_imm(R2, 0x0);
+    ops.str(R2, R1, (index * 8 + 4) as u32);
+    ops.cmp_imm(R0, 0x0);
+    ops.b_global(EQ, "dlopen_failed");
+}
+
+/// Calls the entry point of the last loaded library with its arguments, if one is set.
+fn call_entry(ops: &mut Assembler, dl: &DlFns, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+    // look for the entry point, the handle is still in r0
+    ops.adr_global(R1, "entry_name");
+    ops.mov32(IP, dl.dlsym as u32);
+    ops.blx(IP);
+    ops.cmp_imm(R0, 0x0);
+    ops.b_global(EQ, "dlopen_failed");
+
+    // call it with a pointer to its arguments and their length
+    ops.mov(IP, R0);
+    ops.adr_global(R0, "entry_args");
+    ops.mov32(R1, entry.args.len() as u32);
+    ops.blx(IP);
+    ops.adr_global(R1, "result_entry");
+    ops.str(R0, R1, 0x0);
+}
+
+/// Appends the name of the entry point and its arguments to the data of the second stage, which it may write to.
+fn entry_data(ops: &mut Assembler, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    ops.bind_global("entry_name");
+    ops.bytes(entry.name.as_bytes());
+    ops.bytes(&[0x0]);
+    ops.align(0x4);
+    ops.bind_global("entry_args");
+    ops.bytes(entry.args.as_slice());
+    ops.bytes(&[0x0]);
+    ops.align(0x4);
+}
+
+/// Saves the dlerror() text when a library or the entry point could not be loaded.
+fn dlopen_failed(ops: &mut Assembler, dl: &DlFns) {
+    ops.bind_global("dlopen_failed");
+    ops.mov32(IP, dl.dlerror as u32);
+    ops.blx(IP);
+    ops.adr_global(R1, "result_error");
+    ops.str(R0, R1, 0x0);
+}
+
+/// Writes the status word of the result block.
+fn set_status(ops: &mut Assembler, status: u64) {
+    ops.mov_imm(R0, status as u32);
+    ops.adr_global(R1, "result_status");
+    ops.str(R0, R1, 0x0);
+}
+
+/// Starts the data written by the second stage, aligned to `data_align` to be protected apart from its code.
+fn data_section(ops: &mut Assembler, data_align: usize) {
+    ops.align(data_align);
+    ops.bind_global("data");
+}
+
+/// Appends the table of the handles returned by dlopen, one per library.
+fn handles_table(ops: &mut Assembler, count: usize) {
+    ops.align(0x8);
+    ops.bind_global("handles");
+
+    for _ in 0..count {
+        ops.qword(HANDLE_SKIPPED);
+    }
+}
+
+/// Jumps back to the hijacked function. The thread which ran the stage waits for the injector to release the map,
+/// and jumps to the tail unmapping it with the stack pointer back on the frame of the first stage, once the tail
+/// and the restored bytes of the function are synchronised with its instruction cache. The others restore the
+/// registers saved by the first stage themselves.
+///
+/// Without a code cave for the tail, it follows on a page of its own, synchronised along with the stage, the pages
+/// before it being unmapped.
+fn restore_and_return(ops: &mut Assembler, ret: StageReturn, config: StageConfig) {
+    let release = ops.new_label();
+
+    if config.full_context {
+        ops.add_imm(SP, SP, CALL_ARGS_LEN);
+        restore_context(ops);
+    }
+
+    // wait for the injector to read the result block, and the other threads to leave the map
+    ops.adr_global(R8, "result_release");
+    ops.bind(release);
+    ops.yield_();
+    ops.ldr(R0, R8, 0x0);
+    ops.cmp_imm(R0, 0x0);
+    ops.b(EQ, release);
+
+    // the injector restored the function before the release, and wrote the tail before the stage
+    ops.mov32(R0, ret.stage_addr as u32);
+    ops.mov32(R1, (ret.stage_addr + ret.stage_len) as u32);
+    sync_code(ops);
+    if let Some(tail_addr) = ret.tail_addr {
+        ops.mov32(R0, tail_addr as u32);
+        ops.mov32(R1, (tail_addr + ret.tail_len) as u32);
+        sync_code(ops);
+    }
+
+    // jump to the tail with the map and its length as the arguments of munmap
+    ops.mov(SP, R6);
+    ops.mov(R0, MAP);
+    ops.ldr(R1, R8, 0x0);
+    match ret.tail_addr {
+        Some(tail_addr) => {
+            ops.mov32(IP, tail_addr as u32);
+            ops.bx(IP);
+        }
+        None => ops.b_global(AL, "tail"),
+    }
+
+    ops.bind_global("leave");
+    ops.clrex();
+    restore_registers(ops);
+    return_to(ops, ret.addr);
+
+    if ret.tail_addr.is_none() {
+        ops.align(config.page_size);
+        ops.bind_global("tail");
+        tail(ops, ret);
+    }
+}
+
+/// Makes the code between r0 and r1 visible to the instruction fetches of every core, with the cacheflush system
+/// call. Clobbers r0, r2 and r7.
+fn sync_code(ops: &mut Assembler) {
+    ops.mov_imm(R2, 0x0);
+    ops.mov32(R7, SYS_CACHEFLUSH);
+    ops.svc();
+}
+
+/// Restores the registers and the flags saved by the first stage, along with the stack pointer.
+fn restore_registers(ops: &mut Assembler) {
+    ops.ldr(R0, SP, 0x38);
+    ops.msr_apsr(R0);
+    ops.pop(0x5fff);
+    ops.add_imm(SP, SP, 0x8);
+}
+
+/// Jumps to `addr`, in Thumb if its bit 0 is set. ip is free to clobber on a call.
+fn return_to(ops: &mut Assembler, addr: usize) {
+    ops.mov32(IP, addr as u32);
+    ops.bx(IP);
+}
+
+/// Appends the result block read back by the injector, and assembles the second stage.
+fn finalize_second_stage(mut ops: Assembler) -> Result<SecondStage, InjectionError> {
+    // claimed by the thread running the stage
+    ops.align(0x8);
+    ops.bind_global("gate");
+    ops.qword(0x0);
+
+    // result block, read back by the injector
+    ops.bind_global("result");
+    for name in ["result_status", "result_value", "result_error", "result_errno", "result_entry", "result_release"] {
+        ops.bind_global(name);
+        ops.qword(0x0);
+    }
+    ops.bind_global("result_buffer");
+    ops.bytes(&[0x0; RESULT_BUFFER_SIZE]);
+
+    let result_offset = ops.offset_of("result")?;
+    let handles_offset = ops.offset_of("handles").ok();
+    let data_offset = ops.offset_of("data")?;
+    let tail_offset = ops.offset_of("tail").ok();
+
+    Ok(SecondStage {
+        code: ops.finalize()?,
+        result_offset,
+        handles_offset,
+        data_offset,
+        tail_offset,
+    })
+}
+
+/// Builds the self loop parking the first stage, in Thumb if it is entered in Thumb.
+pub fn self_jmp(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("self_jmp arm 0x{:x}", ret.stage_addr);
+
+    match thumb_entry(&ret) {
+        true => Ok(THUMB_SELF_JMP.to_le_bytes().to_vec()),
+        false => Ok(ARM_SELF_JMP.to_le_bytes().to_vec()),
+    }
+}
+
+pub fn unmap_tail(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("unmap_tail arm");
+
+    let mut ops = Assembler::new();
+    tail(&mut ops, ret);
+    ops.finalize()
+}
+
+/// Unmaps the map given in r0 and r1, restores the registers saved by the first stage and jumps to `ret.addr`,
+/// from a code cave or from the page of the map left mapped.
+fn tail(ops: &mut Assembler, ret: StageReturn) {
+    // unmap the map, given in r0 and r1
+    ops.mov_imm(R7, SYS_MUNMAP);
+    ops.svc();
+
+    restore_registers(ops);
+
+    // return to the hijacked function
+    return_to(ops, ret.addr);
+}
+
+/// Builds the payloads for the 32 bit ARM processes.
+pub struct Backend;
+
+impl ArchBackend for Backend {
+    fn name(&self) -> &'static str {
+        "arm"
+    }
+
+    fn pointer_len(&self) -> usize {
+        4
+    }
+
+    fn syncs_icache(&self) -> bool {
+        true
+    }
+
+    fn max_alloc_len(&self) -> usize {
+        MAX_ALLOC_LEN
+    }
+
+    fn code_addr(&self, addr: usize) -> usize {
+        code_addr(addr)
+    }
+
+    fn first_shellcode(&self, var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+        first_shellcode(var_addr, block_addr, ret, alloc_len, exec_len)
+    }
+
+    fn raw_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlopen_shellcode(dl, entry, dlopen_paths, ret, config)
+    }
+
+    fn raw_dlclose_shellcode(&self, dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlclose_shellcode(dl, fini_addr, library, ret, config)
+    }
+
+    fn call_shellcode(&self, func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        call_shellcode(func_addr, errno_fn_addr, args, ret, config)
+    }
+
+    fn memfd_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        memfd_dlopen_shellcode(dl, entry, memfd_names, ret, config)
+    }
+
+    fn shellcode_stage(&self, shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        shellcode_stage(shellcode, ret, config)
+    }
+
+    fn self_jmp(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        self_jmp(ret)
+    }
+
+    fn unmap_tail(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        unmap_tail(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles the instructions emitted by `emit`, as words.
+    fn assemble(emit: impl FnOnce(&mut Assembler)) -> Vec<u32> {
+        let mut ops = Assembler::new();
+        emit(&mut ops);
+        ops.finalize()
+            .unwrap()
+            .chunks_exact(0x4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Returns to a function at `addr`, whose first stage is written at `stage_addr`.
+    fn stage_return(addr: usize, stage_addr: usize) -> StageReturn {
+        StageReturn {
+            addr,
+            tail_addr: Some(0x4000_f000),
+            tail_len: 0x40,
+            stage_addr,
+            stage_len: 0x100,
+        }
+    }
+
+    #[test]
+    fn modified_imm() {
+        assert_eq!(Assembler::modified_imm(0x0), Some(0x000));
+        assert_eq!(Assembler::modified_imm(0xff), Some(0x0ff));
+        assert_eq!(Assembler::modified_imm(0x100), Some(0xc01));
+        assert_eq!(Assembler::modified_imm(0x1000), Some(0xa01));
+        assert_eq!(Assembler::modified_imm(0xff00_0000), Some(0x4ff));
+        assert_eq!(Assembler::modified_imm(0xf000_000f), Some(0x2ff));
+        assert_eq!(Assembler::modified_imm(0x101), None);
+        assert_eq!(Assembler::modified_imm(0x1fe0_0000 | 0x1), None);
+        assert_eq!(Assembler::modified_imm(0xffff_ffff), None);
+    }
+
+    #[test]
+    fn data_imm() {
+        let code = assemble(|ops| {
+            ops.mov_imm(R1, 0x100);
+            ops.sub_imm(SP, SP, 0x8);
+            ops.cmn_imm(R0, 0x1000);
+            ops.mvn_imm(R4, 0x0);
+            ops.tst_imm(R1, 0x7);
+            ops.cmp_imm(R2, 0x0);
+            ops.add_imm(R1, R1, 0x8);
+            ops.rsb_imm(R0, R0, 0x0);
+        });
+        assert_eq!(code, [0xe3a01c01, 0xe24dd008, 0xe3700a01, 0xe3e04000, 0xe3110007, 0xe3520000, 0xe2811008, 0xe2600000]);
+    }
+
+    #[test]
+    fn data_imm_out_of_range() {
+        let mut ops = Assembler::new();
+        ops.add_imm(R0, R0, 0x101);
+        assert!(ops.finalize().is_err());
+    }
+
+    #[test]
+    fn data_reg() {
+        let code = assemble(|ops| {
+            ops.mov(R8, R0);
+            ops.lsr(R0, R1, 12);
+            ops.lsl(R0, R0, 12);
+            ops.add(R1, R1, R0);
+        });
+        assert_eq!(code, [0xe1a08000, 0xe1a00621, 0xe1a00600, 0xe0811000]);
+    }
+
+    #[test]
+    fn mov32() {
+        let code = assemble(|ops| ops.mov32(R6, 0x1234_5678));
+        assert_eq!(code, [0xe3056678, 0xe3416234]);
+    }
+
+    #[test]
+    fn load_imm() {
+        assert_eq!(assemble(|ops| ops.load_imm(R0, 0x100)), [0xe3a00c01]);
+        assert_eq!(assemble(|ops| ops.load_imm(R4, 0xffff_ffff)), [0xe3e04000]);
+        assert_eq!(assemble(|ops| ops.load_imm(R6, 0x5678)), [0xe3056678]);
+        assert_eq!(assemble(|ops| ops.load_imm(R6, 0x1234_5678)), [0xe3056678, 0xe3416234]);
+    }
+
+    #[test]
+    fn transfer() {
+        let code = assemble(|ops| {
+            ops.ldr(R0, SP, 0x38);
+            ops.str(R1, R0, 0x0);
+            ops.ldr(R1, R8, 0xfff);
+        });
+        assert_eq!(code, [0xe59d0038, 0xe5801000, 0xe5981fff]);
+    }
+
+    #[test]
+    fn transfer_out_of_range() {
+        let mut ops = Assembler::new();
+        ops.ldr(R0, SP, 0x1000);
+        assert!(ops.finalize().is_err());
+    }
+
+    #[test]
+    fn exclusive() {
+        let code = assemble(|ops| {
+            ops.ldrex(R1, R6);
+            ops.strex(R2, R1, R6);
+            ops.clrex();
+        });
+        assert_eq!(code, [0xe1961f9f, 0xe1862f91, 0xf57ff01f]);
+    }
+
+    #[test]
+    fn system() {
+        let code = assemble(|ops| {
+            ops.push(0x5fff);
+            ops.pop(0x5fff);
+            ops.bx(IP);
+            ops.blx(R3);
+            ops.mrs_apsr(R0);
+            ops.msr_apsr(R0);
+            ops.vpush(8, 8);
+            ops.vpop(16, 16);
+            ops.yield_();
+            ops.isb();
+            ops.dmb();
+            ops.svc();
+        });
+        assert_eq!(
+            code,
+            [0xe92d5fff, 0xe8bd5fff, 0xe12fff1c, 0xe12fff33, 0xe10f0000, 0xe12cf000, 0xed2d8b10, 0xecfd0b20, 0xe320f001, 0xf57ff06f, 0xf57ff05b, 0xef000000]
+        );
+    }
+
+    #[test]
+    fn branches() {
+        let code = assemble(|ops| {
+            let back = ops.new_label();
+            ops.bind(back);
+            ops.b(AL, back);
+            ops.b_global(NE, "forward");
+            ops.bl(back);
+            ops.bind_global("forward");
+            ops.yield_();
+        });
+        assert_eq!(code, [ARM_SELF_JMP, 0x1a000000, 0xebfffffc, 0xe320f001]);
+    }
+
+    #[test]
+    fn adr_relative_to_the_map() {
+        let code = assemble(|ops| {
+            ops.adr_global(R0, "data");
+            ops.align(0x1_0000);
+            ops.bytes(&[0x0; 0x234]);
+            ops.bind_global("data");
+        });
+        assert_eq!(code[..3], [0xe3000234, 0xe3400001, 0xe0840000]);
+    }
+
+    #[test]
+    fn unbound_label() {
+        let mut ops = Assembler::new();
+        ops.b_global(AL, "nowhere");
+        assert!(ops.finalize().is_err());
+    }
+
+    #[test]
+    fn thumb_entry_of_the_prologue_only() {
+        assert!(thumb_entry(&stage_return(0x4000_1001, 0x4000_1000)));
+        assert!(!thumb_entry(&stage_return(0x4000_1000, 0x4000_1000)));
+
+        // a Thumb function hijacked through its GOT slot, from a code cave entered in ARM
+        assert!(!thumb_entry(&stage_return(0x4000_1001, 0x4000_8000)));
+    }
+
+    #[test]
+    fn thumb_prefix_reaches_a_word() {
+        assert_eq!(thumb_prefix(&stage_return(0x4000_1000, 0x4000_1000)), []);
+        assert_eq!(thumb_prefix(&stage_return(0x4000_1001, 0x4000_1000)), [0x78, 0x47, 0xc0, 0x46]);
+        assert_eq!(thumb_prefix(&stage_return(0x4000_1003, 0x4000_1002)), [0xc0, 0x46, 0x78, 0x47, 0xc0, 0x46]);
+
+        // bx pc is on a word, and the ARM code follows it at the next one
+        for stage_addr in [0x4000_1000, 0x4000_1002] {
+            let ret = stage_return(stage_addr | 0x1, stage_addr);
+            let prefix = thumb_prefix(&ret);
+            let bx_pc = prefix.chunks_exact(0x2).position(|halfword| halfword == THUMB_BX_PC.to_le_bytes()).unwrap();
+            assert_eq!((stage_addr + 2 * bx_pc) % 0x4, 0x0);
+            assert_eq!((stage_addr + prefix.len()) % 0x4, 0x0);
+        }
+    }
+
+    #[test]
+    fn first_stage_length_does_not_depend_on_the_entry() {
+        let entries = [(0x4000_1000, 0x4000_1000), (0x4000_1001, 0x4000_1000), (0x4000_1003, 0x4000_1002)];
+        for exec_len in [None, Some(0x1000)] {
+            let lens: Vec<_> = entries
+                .iter()
+                .map(|&(addr, stage_addr)| first_shellcode(0x4000_8000, 0x4000_9ff0, stage_return(addr, stage_addr), 0x2000, exec_len).unwrap().len())
+                .collect();
+            assert!(lens.iter().all(|&len| len == lens[0]), "{:?}", lens);
+        }
+    }
+
+    #[test]
+    fn first_stage_starts_with_the_thumb_prefix() {
+        let ret = stage_return(0x4000_1003, 0x4000_1002);
+        let first_stage = first_shellcode(0x4000_8000, 0x4000_9ff0, ret, 0x2000, None).unwrap();
+        let prefix = thumb_prefix(&ret);
+        assert_eq!(first_stage[..prefix.len()], prefix);
+
+        // the parking spot, sub sp, sp, #8, follows in ARM
+        assert_eq!(first_stage[prefix.len()..prefix.len() + 0x4], 0xe24dd008u32.to_le_bytes());
+    }
+
+    #[test]
+    fn self_jmp_in_the_mode_of_the_entry() {
+        assert_eq!(self_jmp(stage_return(0x4000_1000, 0x4000_1000)).unwrap(), ARM_SELF_JMP.to_le_bytes());
+        assert_eq!(self_jmp(stage_return(0x4000_1001, 0x4000_1000)).unwrap(), THUMB_SELF_JMP.to_le_bytes());
+        assert_eq!(self_jmp(stage_return(0x4000_1001, 0x4000_8000)).unwrap(), ARM_SELF_JMP.to_le_bytes());
+    }
+
+    #[test]
+    fn tail_returns_in_thumb() {
+        // movw ip, #0x1001 keeps bit 0 of the symbol, for bx to switch to Thumb
+        let tail = unmap_tail(stage_return(0x4000_1001, 0x4000_1000)).unwrap();
+        let words: Vec<_> = tail.chunks_exact(0x4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect();
+        assert_eq!(words[words.len() - 3..], [0xe301c001, 0xe344c000, 0xe12fff1c]);
+    }
+
+    #[test]
+    fn second_stages_assemble() {
+        let dl = DlFns { dlopen: 0x4001_0000, dlsym: 0x4001_0100, dlclose: 0x4001_0200, dlerror: 0x4001_0300 };
+        let entry = Entry { name: String::from("entry"), args: b"args".to_vec() };
+        let ret = stage_return(0x4000_1001, 0x4000_1000);
+        let config = StageConfig { var_addr: 0x4000_8000, data_align: 0x8, full_context: true, page_size: 0x1000 };
+        let paths = [String::from("/data/local/tmp/libevil.so")];
+        let args = [CallArg::Int(0x1), CallArg::Bytes(b"buffer".to_vec()), CallArg::Int(0x3), CallArg::Int(0x4), CallArg::Int(0x5)];
+
+        let stages = [
+            raw_dlopen_shellcode(&dl, Some(&entry), &paths, ret, config).unwrap(),
+            memfd_dlopen_shellcode(&dl, Some(&entry), &[String::from("libevil.so"), String::from("goauld")], ret, config).unwrap(),
+            raw_dlclose_shellcode(&dl, Some(0x4002_0000), &LoadedLibrary::Path(paths[0].clone()), ret, config).unwrap(),
+            raw_dlclose_shellcode(&dl, Some(0x4002_0000), &LoadedLibrary::Handle(0x4004_0000), ret, config).unwrap(),
+            call_shellcode(0x4003_0000, 0x4003_0100, &args, ret, config).unwrap(),
+            shellcode_stage(&[0x0; 0x10], ret, config).unwrap(),
+        ];
+
+        for stage in stages {
+            assert_eq!(stage.data_offset % 0x4, 0x0);
+            assert_eq!(stage.result_offset % 0x8, 0x0);
+            assert!(stage.data_offset <= stage.result_offset);
+            assert_eq!(stage.code.len(), stage.result_offset + 0x30 + RESULT_BUFFER_SIZE);
+        }
+    }
+}
@@ -1,47 +1,770 @@
+use std::str::FromStr;
+
+use dynasmrt::components::{LabelRegistry, StaticLabel};
+use goblin::elf::header::{self as elf_header, Header};
+use nix::errno::Errno;
+
 use crate::error::InjectionError;
-use crate::proc::class::ProcClass;
 
-#[cfg(target_arch = "aarch64")]
 mod aarch64;
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod arm;
+mod riscv64;
 mod x86;
-#[cfg(target_arch = "x86_64")]
 mod x86_64;
 
+/// Offset of the status word in the second stage result block (see the `RESULT_STATUS_*` values).
+pub const RESULT_STATUS_OFFSET: usize = 0x0;
+/// Offset of the value returned by the second stage (e.g. the `dlopen` handle) in its result block.
+pub const RESULT_VALUE_OFFSET: usize = 0x8;
+/// Offset of the `dlerror()` text pointer in the second stage result block.
+pub const RESULT_ERROR_OFFSET: usize = 0x10;
+/// Offset of the `errno` value left by a called function in the second stage result block.
+pub const RESULT_ERRNO_OFFSET: usize = 0x18;
+/// Offset of the value returned by the entry point of an injected library in the second stage result block.
+pub const RESULT_ENTRY_OFFSET: usize = 0x20;
+/// Offset of the length of the map, written by the injector once it has read the result block:
+/// the second stage then unmaps it.
+pub const RESULT_RELEASE_OFFSET: usize = 0x28;
+/// Offset of the buffer the injector fills when the second stage requests it.
+pub const RESULT_BUFFER_OFFSET: usize = 0x30;
+/// Size of the buffer the injector fills when the second stage requests it.
+pub const RESULT_BUFFER_SIZE: usize = 0x20;
+
+/// Value of the status word while the second stage is running.
+pub const RESULT_STATUS_PENDING: u64 = 0x0;
+/// Value written in the status word once the second stage has finished its work.
+pub const RESULT_STATUS_DONE: u64 = 0x1;
+/// Value written in the status word when the second stage waits for the injector.
+pub const RESULT_STATUS_REQUEST: u64 = 0x2;
+/// Value written in the status word by the injector to resume a waiting second stage.
+pub const RESULT_STATUS_RESUME: u64 = 0x3;
+
+/// Bits of the mailbox holding its state (see [`MailboxState`]).
+pub const MAILBOX_STATE: u64 = 0x7;
+/// Added to the mailbox by each thread entering the first stage, and subtracted once it has reached the map or left.
+pub const MAILBOX_THREAD: u64 = 0x8;
+/// Bits of the mailbox counting the threads in the first stage.
+pub const MAILBOX_THREADS: u64 = 0xff8;
+/// Bits of the mailbox below its value: the state and the thread count.
+pub const MAILBOX_FIELDS: u64 = 0xfff;
+/// Shift of the value of the mailbox: the page aligned address of the map.
+pub const MAILBOX_VALUE_SHIFT: u32 = 12;
+
+/// Version of the mailbox protocol, written in the mailbox block by the thread claiming the first stage.
+pub const MAILBOX_VERSION: u32 = 4;
+/// Offset of the protocol version in the mailbox block.
+pub const MAILBOX_BLOCK_VERSION_OFFSET: usize = 0x0;
+/// Offset of the system call of the first stage which failed in the mailbox block (see the `MAILBOX_SYSCALL_*`
+/// values).
+pub const MAILBOX_BLOCK_SYSCALL_OFFSET: usize = 0x4;
+/// Offset of the errno of the system call of the first stage which failed in the mailbox block.
+pub const MAILBOX_BLOCK_ERRNO_OFFSET: usize = 0x8;
+/// Length of the mailbox block, written by the first stage next to the mailbox, in a data cave of its module.
+pub const MAILBOX_BLOCK_LEN: usize = 0x10;
+
+/// Value of the syscall field of the mailbox block while no system call of the first stage failed.
+pub const MAILBOX_SYSCALL_NONE: u32 = 0x0;
+/// Value of the syscall field of the mailbox block when the map could not be allocated.
+pub const MAILBOX_SYSCALL_MMAP: u32 = 0x1;
+/// Value of the syscall field of the mailbox block when the map could not be made executable.
+pub const MAILBOX_SYSCALL_MPROTECT: u32 = 0x2;
+
+/// Length of the self jmp written by the first stage at the start of the map, where the threads wait for the
+/// second stage: it is overwritten last, in a single write.
+pub const MAP_SELF_JMP_LEN: usize = 0x4;
+
+/// Value left in the handles table of a second stage for the libraries it did not try to load.
+pub const HANDLE_SKIPPED: u64 = u64::MAX;
+
+/// `mmap` and `mprotect` flag making the pages readable.
+pub const PROT_READ: usize = 0x1;
+/// `mmap` and `mprotect` flag making the pages writable.
+pub const PROT_WRITE: usize = 0x2;
+/// `mmap` and `mprotect` flag making the pages executable.
+pub const PROT_EXEC: usize = 0x4;
+
+/// `memfd_create` flag closing the file descriptor on `execve`.
+pub const MFD_CLOEXEC: usize = 0x1;
+/// Name of the memfd a library given as bytes is staged in, the others being named after their file.
+pub const MEMFD_NAME: &str = "goauld";
+/// Longest name of a memfd, without the `memfd:` prefix the kernel adds and the terminating nul.
+pub const MEMFD_NAME_MAX: usize = 249;
+
+/// `dlopen` flag resolving all the symbols at load time.
+pub const RTLD_NOW: usize = 0x2;
+/// `dlopen` flag returning the handle of an already loaded library, without loading it.
+pub const RTLD_NOLOAD: usize = 0x4;
+
+/// Maximum number of arguments of a function called in the target (passed in registers on 64 bit).
+pub const MAX_CALL_ARGS: usize = 6;
+
+/// An argument of a function called in the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallArg {
+    /// An integer or a pointer, passed as is.
+    Int(u64),
+    /// A buffer copied into the second stage map, passed as a pointer to it.
+    Bytes(Vec<u8>),
+}
+
+impl FromStr for CallArg {
+    type Err = InjectionError;
+
+    /// Parses `123`, `-1` or `0x7b` as integers, `str:text` as a NUL-terminated string
+    /// and `hex:deadbeef` as a buffer.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InjectionError::InvalidCallArgs(format!("invalid argument: {}", s));
+
+        if let Some(text) = s.strip_prefix("str:") {
+            let mut bytes = text.as_bytes().to_vec();
+            bytes.push(0x0);
+            return Ok(CallArg::Bytes(bytes));
+        }
+
+        if let Some(hex) = s.strip_prefix("hex:") {
+            if hex.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            return (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+                .collect::<Result<Vec<u8>, _>>()
+                .map(CallArg::Bytes);
+        }
+
+        if let Some(hex) = s.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16).map(CallArg::Int).map_err(|_| invalid());
+        }
+
+        match s.strip_prefix('-') {
+            Some(value) => value.parse::<u64>().map(|v| CallArg::Int(v.wrapping_neg())),
+            None => s.parse::<u64>().map(CallArg::Int),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+/// Addresses of the `libdl` functions in the target.
+#[derive(Debug, Clone, Copy)]
+pub struct DlFns {
+    pub dlopen: usize,
+    pub dlsym: usize,
+    pub dlclose: usize,
+    pub dlerror: usize,
+}
+
+/// Where a second stage jumps back to once it is done.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StageReturn {
+    /// Address of the hijacked function, or the original value of its GOT slot.
+    pub addr: usize,
+    /// Address of the tail unmapping the map of the second stage, before jumping to `addr`. Without a code cave
+    /// for it, the tail follows the code of the second stage in its map, from a page boundary: only the pages
+    /// before it are unmapped.
+    pub tail_addr: Option<usize>,
+    /// Length of the tail.
+    pub tail_len: usize,
+    /// Address the first stage was written at, holding the original bytes again once the second stage is released.
+    pub stage_addr: usize,
+    /// Length of the first stage.
+    pub stage_len: usize,
+}
+
+/// How the second stages are built, whatever they run.
+#[derive(Debug, Clone, Copy)]
+pub struct StageConfig {
+    /// Address of the variable used as a mailbox by the stages.
+    pub var_addr: usize,
+    /// Alignment of the data of the second stage: a page when its code is made executable apart.
+    pub data_align: usize,
+    /// Are the FP/SIMD registers, along with their control and status registers, saved before the second stage
+    /// calls anything and restored before it returns ?
+    pub full_context: bool,
+    /// Page size of the target, which the tail is aligned to when it is in the map.
+    pub page_size: usize,
+}
+
+/// An exported function called once its library is loaded, as `entry(args, args_len)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Name of the export.
+    pub name: String,
+    /// Buffer copied into the second stage map, passed as a pointer to it along with its length.
+    /// It is followed by a NUL byte, so a string can be used as is.
+    pub args: Vec<u8>,
+}
+
+/// A library loaded in the target, as the second stage unloading it finds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadedLibrary {
+    /// Opened again by path with `RTLD_NOLOAD`, the reference it takes being dropped along with the one of the injection.
+    Path(String),
+    /// Handle returned by `dlopen` when the library was injected, for the libraries which can't be opened by path.
+    Handle(usize),
+}
+
+/// A second stage payload, along with the location of the result block it fills.
+pub struct SecondStage {
+    pub code: Vec<u8>,
+    pub result_offset: usize,
+    /// Location of the table of the handles returned by dlopen, for the stages loading libraries.
+    pub handles_offset: Option<usize>,
+    /// Start of the data written by the second stage, which follows its code.
+    pub data_offset: usize,
+    /// Location of the tail unmapping the pages before it, when it is in the map.
+    pub tail_offset: Option<usize>,
+}
+
+/// Architecture of a target process. The payloads are built for it whatever the host is, they do not depend on the
+/// OS or the libc of the target, whose functions are resolved by the injector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetArch {
+    X86,
+    X86_64,
+    /// 32 bit ARM, with ARM or Thumb functions.
+    Arm,
+    Aarch64,
+    /// RV64GC.
+    Riscv64,
+}
+
+impl TargetArch {
+    /// Gets the architecture of an ELF file from its header.
+    pub fn from_elf_header(header: &Header) -> Option<Self> {
+        let sixty_four = header.e_ident[elf_header::EI_CLASS] == elf_header::ELFCLASS64;
+
+        match header.e_machine {
+            elf_header::EM_386 => Some(Self::X86),
+            elf_header::EM_X86_64 => Some(Self::X86_64),
+            elf_header::EM_ARM => Some(Self::Arm),
+            elf_header::EM_AARCH64 => Some(Self::Aarch64),
+            elf_header::EM_RISCV if sixty_four => Some(Self::Riscv64),
+            _ => None,
+        }
+    }
+
+    /// Can this build of the injector inject a process of this architecture ? The processes it can read and write
+    /// are the ones running natively on the host, or in its 32 bit mode.
+    pub fn is_native(&self) -> bool {
+        match self {
+            Self::X86 => cfg!(any(target_arch = "x86", target_arch = "x86_64")),
+            Self::X86_64 => cfg!(target_arch = "x86_64"),
+            Self::Arm => cfg!(target_arch = "aarch64"),
+            Self::Aarch64 => cfg!(target_arch = "aarch64"),
+            Self::Riscv64 => cfg!(target_arch = "riscv64"),
+        }
+    }
+
+    /// Gets the backend building the payloads for this architecture.
+    pub fn backend(&self) -> &'static dyn ArchBackend {
+        match self {
+            Self::X86 => &x86::Backend,
+            Self::X86_64 => &x86_64::Backend,
+            Self::Arm => &arm::Backend,
+            Self::Aarch64 => &aarch64::Backend,
+            Self::Riscv64 => &riscv64::Backend,
+        }
+    }
+}
+
+/// Builds the payloads for the processes of one architecture, and describes what the injector needs to know
+/// about them. The stages return to the hijacked function through a `StageReturn`, and share the mailbox protocol
+/// and the layout of the result block.
+pub trait ArchBackend {
+    /// Gets the name of the backend.
+    fn name(&self) -> &'static str;
 
-pub(crate) fn first_shellcode(class: &ProcClass, var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, InjectionError> {
-    match class {
-        #[cfg(any(target_arch = "aarch64"))]
-        ProcClass::ThirtyTwo => aarch64::first_shellcode(var_addr, alloc_len),
-        #[cfg(any(target_arch = "aarch64"))]
-        ProcClass::SixtyFour => aarch64::first_shellcode(var_addr, alloc_len),
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        ProcClass::ThirtyTwo => x86::first_shellcode(var_addr, alloc_len),
-        #[cfg(target_arch = "x86")]
-        ProcClass::SixtyFour => unimplemented!(),
-        #[cfg(target_arch = "x86_64")]
-        ProcClass::SixtyFour => x86_64::first_shellcode(var_addr, alloc_len),
+    /// Gets the length of a pointer.
+    fn pointer_len(&self) -> usize;
+
+    /// Gets the length of the mailbox, which the stages update with atomic operations of this width.
+    fn mailbox_len(&self) -> usize {
+        self.pointer_len()
     }
+
+    /// Does the code written by the injector or by the stages have to be synchronised with the instruction fetches
+    /// before it runs ? The stages do it themselves then, the kernel only doing it for the writes to
+    /// `/proc/PID/mem`.
+    fn syncs_icache(&self) -> bool;
+
+    /// Gets the number of arguments a function called by `call_shellcode` can be given, following the calling
+    /// convention of the architecture.
+    fn max_call_args(&self) -> usize {
+        MAX_CALL_ARGS
+    }
+
+    /// Gets the length of the largest map the first stage can allocate.
+    fn max_alloc_len(&self) -> usize;
+
+    /// Gets the address of the code of a function from the address of its symbol.
+    fn code_addr(&self, addr: usize) -> usize {
+        addr
+    }
+
+    /// Builds the first stage, allocating a map of `alloc_len` bytes. With `exec_len`, the map is allocated
+    /// writable and its first `exec_len` bytes are made executable (and no longer writable) before jumping to it.
+    ///
+    /// The thread claiming the first stage writes the protocol version in the mailbox block at `block_addr`, and
+    /// the system call which failed along with its errno. If the map can't be allocated, the threads jump to
+    /// `ret.addr`, which the injector restores. The first stage is built to run at `ret.stage_addr`, its length
+    /// does not depend on it.
+    fn first_shellcode(&self, var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError>;
+
+    /// Builds the second stage loading the libraries at `dlopen_paths` in order, then calling `entry`.
+    fn raw_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError>;
+
+    /// Builds the second stage unloading `library`, once its `fini_addr` hook is called.
+    fn raw_dlclose_shellcode(&self, dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError>;
+
+    /// Builds the second stage calling the function at `func_addr` with at most `max_call_args` arguments.
+    fn call_shellcode(&self, func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError>;
+
+    /// Builds the second stage loading one library from each memfd named in `memfd_names`, which the injector fills.
+    fn memfd_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError>;
+
+    /// Builds the second stage calling a raw shellcode.
+    fn shellcode_stage(&self, shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError>;
+
+    /// Builds a self jmp, holding the threads entering the first stage at `ret.stage_addr` while the hijacked
+    /// function is restored.
+    fn self_jmp(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError>;
+
+    /// Builds the tail unmapping the map of the second stage, written outside of it at `ret.tail_addr`. It is given
+    /// the map and its length in the registers of the `munmap` call, then restores the registers saved by the first
+    /// stage and jumps to `ret.addr`. Its length does not depend on `ret`.
+    fn unmap_tail(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError>;
+}
+
+/// States of the mailbox, each one set by a single writer, in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MailboxState {
+    /// Written by the injector along with the first stage.
+    Idle = 0,
+    /// A thread has claimed the first stage and allocates the map, the others wait for it.
+    Claimed = 1,
+    /// The map address is published, the threads wait at its start for the second stage.
+    Allocated = 2,
+    /// A thread has claimed the second stage written by the injector, and waits for the others to leave the first stage.
+    Stage2Ready = 3,
+    /// Every thread has left the first stage: the injector restores it, along with the mailbox. The end of the
+    /// second stage is reported in its result block.
+    Done = 4,
+    /// A system call of the first stage failed, instead of `Allocated`: the threads go back to the function. The
+    /// system call and its errno are in the mailbox block.
+    Failed = 5,
+}
+
+/// A mailbox, as read by the injector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mailbox {
+    pub state: MailboxState,
+    /// Number of threads in the first stage.
+    pub threads: usize,
+    /// The page aligned address of the map.
+    pub value: u64,
 }
 
-pub(crate) fn raw_dlopen_shellcode(class: &ProcClass, dlopen_addr: usize, dlopen_path: String, jmp_addr: usize) -> Result<Vec<u8>, InjectionError> {
-    match class {
-        #[cfg(any(target_arch = "aarch64"))]
-        ProcClass::ThirtyTwo => aarch64::raw_dlopen_shellcode(dlopen_addr, dlopen_path, jmp_addr),
-        #[cfg(any(target_arch = "aarch64"))]
-        ProcClass::SixtyFour => aarch64::raw_dlopen_shellcode(dlopen_addr, dlopen_path, jmp_addr),
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        ProcClass::ThirtyTwo => x86::raw_dlopen_shellcode(dlopen_addr, dlopen_path, jmp_addr),
-        #[cfg(target_arch = "x86")]
-        ProcClass::SixtyFour => unimplemented!(),
-        #[cfg(target_arch = "x86_64")]
-        ProcClass::SixtyFour => x86_64::raw_dlopen_shellcode(dlopen_addr, dlopen_path, jmp_addr),
+impl Mailbox {
+    /// Parses the mailbox, if its state is a known one.
+    pub fn parse(word: u64) -> Option<Self> {
+        let state = match word & MAILBOX_STATE {
+            0 => MailboxState::Idle,
+            1 => MailboxState::Claimed,
+            2 => MailboxState::Allocated,
+            3 => MailboxState::Stage2Ready,
+            4 => MailboxState::Done,
+            5 => MailboxState::Failed,
+            _ => return None,
+        };
+
+        Some(Self {
+            state,
+            threads: ((word & MAILBOX_THREADS) / MAILBOX_THREAD) as usize,
+            value: word >> MAILBOX_VALUE_SHIFT,
+        })
+    }
+
+    /// Gets the address of the map, once the first stage has allocated it.
+    pub fn map(&self) -> Option<usize> {
+        match self.state {
+            MailboxState::Allocated | MailboxState::Stage2Ready | MailboxState::Done => {
+                Some((self.value << MAILBOX_VALUE_SHIFT) as usize)
+            }
+            _ => None,
+        }
     }
 }
 
-pub(crate) fn self_jmp() -> Result<Vec<u8>, InjectionError> {
-    #[cfg(any(target_arch = "aarch64"))]
-    return aarch64::self_jmp();
+/// The mailbox block, as read by the injector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxBlock {
+    /// Version of the mailbox protocol of the first stage, 0 until a thread has claimed it.
+    pub version: u32,
+    /// The system call of the first stage which failed (see the `MAILBOX_SYSCALL_*` values).
+    pub syscall: u32,
+    /// The errno of the system call which failed.
+    pub errno: u32,
+}
 
-    unimplemented!()
-}
\ No newline at end of file
+impl MailboxBlock {
+    /// Parses the `MAILBOX_BLOCK_LEN` bytes of the mailbox block.
+    pub fn parse(bytes: &[u8]) -> Self {
+        let field = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        Self {
+            version: field(MAILBOX_BLOCK_VERSION_OFFSET),
+            syscall: field(MAILBOX_BLOCK_SYSCALL_OFFSET),
+            errno: field(MAILBOX_BLOCK_ERRNO_OFFSET),
+        }
+    }
+
+    /// Gets the system call of the first stage which failed, along with its errno.
+    pub fn failure(&self) -> Option<(&'static str, Errno)> {
+        let syscall = match self.syscall {
+            MAILBOX_SYSCALL_NONE => return None,
+            MAILBOX_SYSCALL_MMAP => "mmap",
+            MAILBOX_SYSCALL_MPROTECT => "mprotect",
+            _ => "unknown system call",
+        };
+        Some((syscall, Errno::from_raw(self.errno as i32)))
+    }
+}
+
+/// Gets the protection of the map allocated by the first stage: writable and executable,
+/// or only writable if its code is made executable afterwards.
+fn map_prot(exec_len: Option<usize>) -> usize {
+    match exec_len {
+        Some(_) => PROT_READ | PROT_WRITE,
+        None => PROT_READ | PROT_WRITE | PROT_EXEC,
+    }
+}
+
+/// Gets the offset of a global label of an assembled payload.
+fn label_offset(labels: &LabelRegistry, name: &'static str) -> Result<usize, InjectionError> {
+    labels
+        .resolve_static(&StaticLabel::global(name))
+        .map(|offset| offset.0)
+        .map_err(|_| InjectionError::ShellcodeError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a mailbox word from its fields.
+    fn word(state: MailboxState, threads: u64, value: u64) -> u64 {
+        (value << MAILBOX_VALUE_SHIFT) | (threads * MAILBOX_THREAD) | state as u64
+    }
+
+    #[test]
+    fn parse_fields() {
+        let mailbox = Mailbox::parse(word(MailboxState::Stage2Ready, 3, 0x7_f123_4567)).unwrap();
+        assert_eq!(mailbox.state, MailboxState::Stage2Ready);
+        assert_eq!(mailbox.threads, 3);
+        assert_eq!(mailbox.value, 0x7_f123_4567);
+    }
+
+    #[test]
+    fn parse_thread_count_bounds() {
+        let mailbox = Mailbox::parse(word(MailboxState::Claimed, 511, 0)).unwrap();
+        assert_eq!(mailbox.threads, 511);
+        assert_eq!(mailbox.value, 0);
+    }
+
+    #[test]
+    fn parse_unknown_states() {
+        assert_eq!(Mailbox::parse(0x6), None);
+        assert_eq!(Mailbox::parse(0x7 | MAILBOX_THREAD), None);
+    }
+
+    #[test]
+    fn map_once_allocated() {
+        for state in [MailboxState::Allocated, MailboxState::Stage2Ready, MailboxState::Done] {
+            let mailbox = Mailbox::parse(word(state, 0, 0x7_f123_4567)).unwrap();
+            assert_eq!(mailbox.map(), Some(0x7f12_3456_7000));
+        }
+
+        for state in [MailboxState::Idle, MailboxState::Claimed] {
+            assert_eq!(Mailbox::parse(word(state, 1, 0)).unwrap().map(), None);
+        }
+    }
+
+    #[test]
+    fn map_of_32_bit_mailbox() {
+        let mailbox = Mailbox::parse(word(MailboxState::Allocated, 2, 0xf_7ffe)).unwrap();
+        assert_eq!(mailbox.map(), Some(0xf7ff_e000));
+        assert_eq!(mailbox.threads, 2);
+    }
+
+    /// Builds the bytes of a mailbox block from its fields.
+    fn block(version: u32, syscall: u32, errno: u32) -> Vec<u8> {
+        let mut bytes = vec![0x0; MAILBOX_BLOCK_LEN];
+        bytes[MAILBOX_BLOCK_VERSION_OFFSET..][..4].copy_from_slice(&version.to_le_bytes());
+        bytes[MAILBOX_BLOCK_SYSCALL_OFFSET..][..4].copy_from_slice(&syscall.to_le_bytes());
+        bytes[MAILBOX_BLOCK_ERRNO_OFFSET..][..4].copy_from_slice(&errno.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn block_fields() {
+        let mailbox_block = MailboxBlock::parse(&block(MAILBOX_VERSION, MAILBOX_SYSCALL_NONE, 0));
+        assert_eq!(mailbox_block.version, MAILBOX_VERSION);
+        assert_eq!(mailbox_block.failure(), None);
+    }
+
+    #[test]
+    fn failure_of_mmap() {
+        let mailbox_block = MailboxBlock::parse(&block(MAILBOX_VERSION, MAILBOX_SYSCALL_MMAP, Errno::ENOMEM as u32));
+        assert_eq!(mailbox_block.failure(), Some(("mmap", Errno::ENOMEM)));
+    }
+
+    #[test]
+    fn failure_of_mprotect() {
+        let mailbox_block = MailboxBlock::parse(&block(MAILBOX_VERSION, MAILBOX_SYSCALL_MPROTECT, Errno::EACCES as u32));
+        assert_eq!(mailbox_block.failure(), Some(("mprotect", Errno::EACCES)));
+    }
+
+    #[test]
+    fn failure_keeps_the_whole_errno() {
+        let mailbox_block = MailboxBlock::parse(&block(MAILBOX_VERSION, MAILBOX_SYSCALL_MMAP, 0x1fff));
+        assert_eq!(mailbox_block.failure(), Some(("mmap", Errno::from_raw(0x1fff))));
+    }
+
+    #[test]
+    fn failed_mailbox_has_no_map() {
+        let mailbox = Mailbox::parse(word(MailboxState::Failed, 1, 0)).unwrap();
+        assert_eq!(mailbox.map(), None);
+    }
+
+    const ARCHS: [TargetArch; 5] = [TargetArch::X86, TargetArch::X86_64, TargetArch::Arm, TargetArch::Aarch64, TargetArch::Riscv64];
+
+    /// Returns to a function in the first page of a 32 bit address space, from a tail in a code cave or in the map.
+    fn stage_return(tail_addr: Option<usize>) -> StageReturn {
+        StageReturn {
+            addr: 0x4000_1000,
+            tail_addr,
+            tail_len: 0x40,
+            stage_addr: 0x4000_1000,
+            stage_len: 0x100,
+        }
+    }
+
+    fn stage_config() -> StageConfig {
+        StageConfig {
+            var_addr: 0x4000_8000,
+            data_align: 0x8,
+            full_context: false,
+            page_size: 0x1000,
+        }
+    }
+
+    #[test]
+    fn tail_in_a_code_cave() {
+        for arch in ARCHS {
+            let backend = arch.backend();
+            let stage = backend.shellcode_stage(&[0x0; 0x10], stage_return(Some(0x4000_f000)), stage_config()).unwrap();
+            assert_eq!(stage.tail_offset, None, "{:?}", arch);
+            assert!(stage.code.len() < 0x1000, "{:?}", arch);
+        }
+    }
+
+    #[test]
+    fn tail_in_the_map() {
+        for arch in ARCHS {
+            let backend = arch.backend();
+            let ret = stage_return(None);
+            let stage = backend.shellcode_stage(&[0x0; 0x10], ret, stage_config()).unwrap();
+
+            // on a page of its own, unmapping the code before it, and made executable along with it
+            let tail_offset = stage.tail_offset.unwrap();
+            assert_eq!(tail_offset, 0x1000, "{:?}", arch);
+            assert!(tail_offset < stage.data_offset, "{:?}", arch);
+
+            // the same one as in a code cave, but for x86 which can't jump through its return address
+            let tail = backend.unmap_tail(ret).unwrap();
+            if arch != TargetArch::X86 {
+                assert_eq!(stage.code[tail_offset..tail_offset + tail.len()], tail, "{:?}", arch);
+            }
+        }
+    }
+
+    /// Returns to a function at the top of the address space of the target, from a tail in a code cave.
+    fn high_stage_return(backend: &dyn ArchBackend) -> StageReturn {
+        let addr = match backend.pointer_len() {
+            4 => 0xf7ff_1000,
+            _ => 0x7fff_f7ff_1000,
+        };
+
+        StageReturn {
+            addr,
+            tail_addr: Some(addr + 0xe000),
+            tail_len: 0x40,
+            stage_addr: addr,
+            stage_len: 0x100,
+        }
+    }
+
+    /// Builds every second stage of a backend, along with the number of libraries it loads.
+    fn second_stages(backend: &dyn ArchBackend, ret: StageReturn, config: StageConfig) -> Vec<(&'static str, SecondStage, Option<usize>)> {
+        let dl = DlFns {
+            dlopen: 0x4000_2000,
+            dlsym: 0x4000_2100,
+            dlclose: 0x4000_2200,
+            dlerror: 0x4000_2300,
+        };
+        let entry = Entry {
+            name: "goauld_main".to_string(),
+            args: b"port=4444".to_vec(),
+        };
+        let paths = ["/tmp/libone.so".to_string(), "/tmp/libtwo.so".to_string()];
+        let memfd_names = ["libone.so".to_string(), "libtwo.so".to_string(), MEMFD_NAME.to_string()];
+        let args: Vec<CallArg> = (0..backend.max_call_args() as u64)
+            .map(|i| match i % 2 {
+                0 => CallArg::Int(0x1000 + i),
+                _ => CallArg::Bytes(vec![i as u8; i as usize]),
+            })
+            .collect();
+
+        vec![
+            ("raw_dlopen", backend.raw_dlopen_shellcode(&dl, Some(&entry), &paths, ret, config).unwrap(), Some(paths.len())),
+            ("raw_dlopen_no_entry", backend.raw_dlopen_shellcode(&dl, None, &paths[..1], ret, config).unwrap(), Some(1)),
+            ("memfd_dlopen", backend.memfd_dlopen_shellcode(&dl, Some(&entry), &memfd_names, ret, config).unwrap(), Some(3)),
+            ("raw_dlclose", backend.raw_dlclose_shellcode(&dl, Some(0x4000_3000), &LoadedLibrary::Path(paths[0].clone()), ret, config).unwrap(), None),
+            ("raw_dlclose_no_fini", backend.raw_dlclose_shellcode(&dl, None, &LoadedLibrary::Path(paths[0].clone()), ret, config).unwrap(), None),
+            ("raw_dlclose_handle", backend.raw_dlclose_shellcode(&dl, Some(0x4000_3000), &LoadedLibrary::Handle(0x4000_5000), ret, config).unwrap(), None),
+            ("call", backend.call_shellcode(0x4000_4000, 0x4000_4100, &args, ret, config).unwrap(), None),
+            ("shellcode", backend.shellcode_stage(&[0x0; 0x10], ret, config).unwrap(), None),
+        ]
+    }
+
+    #[test]
+    fn second_stage_layout() {
+        for arch in ARCHS {
+            let backend = arch.backend();
+            for data_align in [0x8, 0x1000] {
+                for full_context in [false, true] {
+                    let config = StageConfig {
+                        data_align,
+                        full_context,
+                        ..stage_config()
+                    };
+
+                    for (name, stage, libraries) in second_stages(backend, stage_return(Some(0x4000_f000)), config) {
+                        let what = format!("{:?} {} {:#x} {}", arch, name, data_align, full_context);
+
+                        // the code, then the data, ending with the result block
+                        assert!(stage.code.len() > MAP_SELF_JMP_LEN, "{}", what);
+                        assert!(stage.data_offset > MAP_SELF_JMP_LEN, "{}", what);
+                        assert_eq!(stage.data_offset % data_align, 0, "{}", what);
+                        assert!(stage.data_offset <= stage.result_offset, "{}", what);
+                        assert_eq!(stage.result_offset % 0x8, 0, "{}", what);
+                        assert_eq!(stage.code.len(), stage.result_offset + RESULT_BUFFER_OFFSET + RESULT_BUFFER_SIZE, "{}", what);
+                        assert_eq!(stage.tail_offset, None, "{}", what);
+
+                        // one handle per library, before the result block
+                        match libraries {
+                            Some(count) => {
+                                let handles_offset = stage.handles_offset.unwrap();
+                                assert!(stage.data_offset <= handles_offset, "{}", what);
+                                assert_eq!(handles_offset % 0x8, 0, "{}", what);
+                                assert!(handles_offset + count * 0x8 <= stage.result_offset, "{}", what);
+                            }
+                            None => assert_eq!(stage.handles_offset, None, "{}", what),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stage_lengths_do_not_depend_on_addresses() {
+        for arch in ARCHS {
+            let backend = arch.backend();
+            let low = stage_return(Some(0x4000_f000));
+            let high = high_stage_return(backend);
+
+            // the injector builds them once to know their lengths, then with their addresses
+            for exec_len in [None, Some(0x1000)] {
+                let first = backend.first_shellcode(0x4000_8000, 0x4000_9ff0, low, 0x2000, exec_len).unwrap();
+                let moved = backend.first_shellcode(high.addr + 0x8000, high.addr + 0x9ff0, high, 0x2000, exec_len).unwrap();
+                assert_eq!(first.len(), moved.len(), "{:?} {:?}", arch, exec_len);
+                assert_ne!(first, moved, "{:?} {:?}", arch, exec_len);
+            }
+            assert_eq!(backend.unmap_tail(low).unwrap().len(), backend.unmap_tail(high).unwrap().len(), "{:?}", arch);
+            assert_eq!(backend.unmap_tail(StageReturn::default()).unwrap().len(), backend.unmap_tail(high).unwrap().len(), "{:?}", arch);
+            assert_eq!(backend.self_jmp(low).unwrap().len(), backend.self_jmp(high).unwrap().len(), "{:?}", arch);
+        }
+    }
+
+    #[test]
+    fn stage_length_limits() {
+        for arch in ARCHS {
+            let backend = arch.backend();
+            let ret = stage_return(Some(0x4000_f000));
+            let first = backend.first_shellcode(0x4000_8000, 0x4000_9ff0, ret, 0x2000, None).unwrap();
+
+            // the largest map, its code made executable apart
+            let max_alloc_len = backend.max_alloc_len() & !0xfff;
+            let largest = backend.first_shellcode(0x4000_8000, 0x4000_9ff0, ret, max_alloc_len, Some(max_alloc_len - 0x1000)).unwrap();
+            assert!(!largest.is_empty(), "{:?}", arch);
+
+            // the self jmp is written over the start of the first stage
+            let self_jmp = backend.self_jmp(ret).unwrap();
+            assert!(!self_jmp.is_empty(), "{:?}", arch);
+            assert!(self_jmp.len() < first.len(), "{:?}", arch);
+
+            // a stage with every argument the backend can pass
+            let args = vec![CallArg::Int(0x0); backend.max_call_args()];
+            assert!(backend.call_shellcode(0x4000_4000, 0x4000_4100, &args, ret, stage_config()).is_ok(), "{:?}", arch);
+        }
+    }
+
+    /// Formats the stages of a backend, 32 bytes per line.
+    fn snapshot(backend: &dyn ArchBackend) -> String {
+        let ret = stage_return(Some(0x4000_f000));
+        let mut stages = vec![
+            ("first", backend.first_shellcode(0x4000_8000, 0x4000_9ff0, ret, 0x2000, None).unwrap()),
+            ("first_w_xor_x", backend.first_shellcode(0x4000_8000, 0x4000_9ff0, ret, 0x2000, Some(0x1000)).unwrap()),
+            ("self_jmp", backend.self_jmp(ret).unwrap()),
+            ("unmap_tail", backend.unmap_tail(ret).unwrap()),
+        ];
+        for (name, stage, _) in second_stages(backend, ret, stage_config()) {
+            stages.push((name, stage.code));
+        }
+
+        let mut text = String::new();
+        for (name, code) in stages {
+            text.push_str(&format!("[{}]\n", name));
+            for line in code.chunks(32) {
+                let hex: String = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+                text.push_str(&hex);
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// The bytes of every stage, in `src/payloads/snapshots`. Run the tests with `UPDATE_SNAPSHOTS=1` to write them
+    /// again once a change of a stage has been checked.
+    #[test]
+    fn stage_snapshots() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/payloads/snapshots");
+        let update = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+
+        for arch in ARCHS {
+            let backend = arch.backend();
+            let path = dir.join(format!("{}.txt", backend.name()));
+            let text = snapshot(backend);
+
+            if update {
+                std::fs::create_dir_all(&dir).unwrap();
+                std::fs::write(&path, &text).unwrap();
+                continue;
+            }
+
+            let expected = std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("{} is missing", path.display()));
+            assert!(expected == text, "the stages of {:?} changed, run the tests with UPDATE_SNAPSHOTS=1 to write {} again", arch, path.display());
+        }
+    }
+}
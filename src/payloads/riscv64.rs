@@ -0,0 +1,1417 @@
+use std::collections::HashMap;
+
+use crate::error::InjectionError;
+use crate::payloads::{
+    map_prot, ArchBackend, CallArg, DlFns, Entry, LoadedLibrary, MailboxState, SecondStage, StageConfig, StageReturn, HANDLE_SKIPPED,
+    MAILBOX_BLOCK_ERRNO_OFFSET, MAILBOX_BLOCK_SYSCALL_OFFSET, MAILBOX_BLOCK_VERSION_OFFSET, MAILBOX_STATE, MAILBOX_SYSCALL_MMAP, MAILBOX_SYSCALL_MPROTECT,
+    MAILBOX_THREAD, MAILBOX_THREADS, MAILBOX_VALUE_SHIFT, MAILBOX_VERSION, MFD_CLOEXEC, PROT_EXEC, PROT_READ, RESULT_BUFFER_SIZE,
+    RESULT_STATUS_DONE, RESULT_STATUS_REQUEST, RESULT_STATUS_RESUME, RTLD_NOLOAD, RTLD_NOW,
+};
+
+const SYS_CLOSE: i32 = 57;
+const SYS_MUNMAP: i32 = 215;
+const SYS_MMAP: i32 = 222;
+const SYS_MPROTECT: i32 = 226;
+const SYS_SCHED_YIELD: i32 = 124;
+const SYS_MEMFD_CREATE: i32 = 279;
+/// RISC-V system call making the code of the process visible to the instruction fetches of every hart, including the
+/// ones its threads later migrate to, which `fence.i` alone does not.
+const SYS_RISCV_FLUSH_ICACHE: i32 = 259;
+
+/// Control and status register of the floating point unit.
+const CSR_FCSR: u32 = 0x003;
+
+/// Self loop, `j .`
+const SELF_JMP: u32 = 0x0000006f;
+
+/// Largest map the first stage can allocate, its length being loaded as a 64 bit immediate.
+pub const MAX_ALLOC_LEN: usize = usize::MAX;
+
+const ZERO: u32 = 0;
+const RA: u32 = 1;
+const SP: u32 = 2;
+const T0: u32 = 5;
+const T1: u32 = 6;
+const S1: u32 = 9;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+const A3: u32 = 13;
+const A4: u32 = 14;
+const A5: u32 = 15;
+const A7: u32 = 17;
+const S2: u32 = 18;
+const S3: u32 = 19;
+/// Holds the address the stages jump back to, as the PLT stubs leave it for the lazy binding resolver.
+const T3: u32 = 28;
+
+/// Conditions of the branches, as their funct3.
+const BEQ: u32 = 0x0;
+const BNE: u32 = 0x1;
+const BLT: u32 = 0x4;
+const BLTU: u32 = 0x6;
+
+/// Opcodes.
+const OP_LOAD: u32 = 0x03;
+const OP_LOAD_FP: u32 = 0x07;
+const OP_MISC_MEM: u32 = 0x0f;
+const OP_IMM: u32 = 0x13;
+const OP_AUIPC: u32 = 0x17;
+const OP_IMM_32: u32 = 0x1b;
+const OP_STORE: u32 = 0x23;
+const OP_STORE_FP: u32 = 0x27;
+const OP_AMO: u32 = 0x2f;
+const OP: u32 = 0x33;
+const OP_LUI: u32 = 0x37;
+const OP_BRANCH: u32 = 0x63;
+const OP_JALR: u32 = 0x67;
+const OP_JAL: u32 = 0x6f;
+const OP_SYSTEM: u32 = 0x73;
+
+/// A label of the assembled code, bound to an offset once.
+#[derive(Debug, Clone, Copy)]
+struct Label(usize);
+
+/// A reference to a label, patched once the code is assembled.
+enum Fixup {
+    /// The 13 bit offset of a conditional branch.
+    Branch,
+    /// The 21 bit offset of a jal.
+    Jal,
+    /// The high 20 bits of the offset of an auipc.
+    PcrelHigh,
+    /// The low 12 bits of the offset of the auipc before, in an addi or a load.
+    PcrelLow,
+}
+
+/// Assembles RV64 code (I, A, D, Zicsr and Zifencei), dynasm having no RISC-V target. Its global labels are created
+/// on first use, like the `->name` labels of dynasm, and their offsets are relative to the start of the code.
+struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    globals: HashMap<&'static str, Label>,
+    fixups: Vec<(usize, Label, Fixup)>,
+    /// Set when an immediate can't be encoded: the code is then rejected by `finalize`.
+    invalid: bool,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            labels: Vec::new(),
+            globals: HashMap::new(),
+            fixups: Vec::new(),
+            invalid: false,
+        }
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Gets the global label `name`.
+    fn global(&mut self, name: &'static str) -> Label {
+        if let Some(label) = self.globals.get(name) {
+            return *label;
+        }
+
+        let label = self.new_label();
+        self.globals.insert(name, label);
+        label
+    }
+
+    /// Binds `label` to the current offset.
+    fn bind(&mut self, label: Label) {
+        if self.labels[label.0].replace(self.code.len()).is_some() {
+            self.invalid = true;
+        }
+    }
+
+    /// Binds the global label `name` to the current offset.
+    fn bind_global(&mut self, name: &'static str) {
+        let label = self.global(name);
+        self.bind(label);
+    }
+
+    /// Gets the offset of the global label `name`, once bound.
+    fn offset_of(&self, name: &'static str) -> Result<usize, InjectionError> {
+        self.globals
+            .get(name)
+            .and_then(|label| self.labels[label.0])
+            .ok_or(InjectionError::ShellcodeError)
+    }
+
+    fn emit(&mut self, word: u32) {
+        self.code.extend_from_slice(&word.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn qword(&mut self, value: u64) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Pads the code with zeroes up to a multiple of `align`.
+    fn align(&mut self, align: usize) {
+        self.code.resize(self.code.len().next_multiple_of(align), 0x0);
+    }
+
+    /// Checks that `imm` fits in a signed immediate of `bits` bits.
+    fn check(&mut self, imm: i64, bits: u32) {
+        if imm < -(1 << (bits - 1)) || imm >= 1 << (bits - 1) {
+            self.invalid = true;
+        }
+    }
+
+    fn r_type(&mut self, op: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) {
+        self.emit((funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | op);
+    }
+
+    fn i_type(&mut self, op: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) {
+        self.check(imm as i64, 12);
+        self.emit(((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | op);
+    }
+
+    fn s_type(&mut self, op: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) {
+        self.check(imm as i64, 12);
+        let imm = imm as u32 & 0xfff;
+        self.emit(((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | op);
+    }
+
+    fn addi(&mut self, rd: u32, rs1: u32, imm: i32) {
+        self.i_type(OP_IMM, 0x0, rd, rs1, imm);
+    }
+
+    fn addiw(&mut self, rd: u32, rs1: u32, imm: i32) {
+        self.i_type(OP_IMM_32, 0x0, rd, rs1, imm);
+    }
+
+    fn andi(&mut self, rd: u32, rs1: u32, imm: i32) {
+        self.i_type(OP_IMM, 0x7, rd, rs1, imm);
+    }
+
+    fn ori(&mut self, rd: u32, rs1: u32, imm: i32) {
+        self.i_type(OP_IMM, 0x6, rd, rs1, imm);
+    }
+
+    fn slli(&mut self, rd: u32, rs1: u32, shamt: u32) {
+        self.emit(((shamt & 0x3f) << 20) | (rs1 << 15) | (0x1 << 12) | (rd << 7) | OP_IMM);
+    }
+
+    fn srli(&mut self, rd: u32, rs1: u32, shamt: u32) {
+        self.emit(((shamt & 0x3f) << 20) | (rs1 << 15) | (0x5 << 12) | (rd << 7) | OP_IMM);
+    }
+
+    fn add(&mut self, rd: u32, rs1: u32, rs2: u32) {
+        self.r_type(OP, 0x0, 0x00, rd, rs1, rs2);
+    }
+
+    fn sub(&mut self, rd: u32, rs1: u32, rs2: u32) {
+        self.r_type(OP, 0x0, 0x20, rd, rs1, rs2);
+    }
+
+    fn and(&mut self, rd: u32, rs1: u32, rs2: u32) {
+        self.r_type(OP, 0x7, 0x00, rd, rs1, rs2);
+    }
+
+    fn mv(&mut self, rd: u32, rs: u32) {
+        self.addi(rd, rs, 0x0);
+    }
+
+    fn lui(&mut self, rd: u32, imm20: u32) {
+        self.emit(((imm20 & 0xfffff) << 12) | (rd << 7) | OP_LUI);
+    }
+
+    /// Loads an immediate fitting in 12 bits.
+    fn li(&mut self, rd: u32, imm: i32) {
+        self.addi(rd, ZERO, imm);
+    }
+
+    /// Loads a 64 bit value with 8 instructions, whatever it is: its high half with lui and addiw, then its low half
+    /// 11, 11 and 10 bits at a time.
+    fn li64(&mut self, rd: u32, value: u64) {
+        let high = (value >> 32) as u32;
+        self.lui(rd, high.wrapping_add(0x800) >> 12);
+        self.addiw(rd, rd, ((high & 0xfff) as i32) << 20 >> 20);
+        self.slli(rd, rd, 11);
+        self.ori(rd, rd, ((value >> 21) & 0x7ff) as i32);
+        self.slli(rd, rd, 11);
+        self.ori(rd, rd, ((value >> 10) & 0x7ff) as i32);
+        self.slli(rd, rd, 10);
+        self.ori(rd, rd, (value & 0x3ff) as i32);
+    }
+
+    fn ld(&mut self, rd: u32, rs1: u32, offset: i32) {
+        self.i_type(OP_LOAD, 0x3, rd, rs1, offset);
+    }
+
+    fn lw(&mut self, rd: u32, rs1: u32, offset: i32) {
+        self.i_type(OP_LOAD, 0x2, rd, rs1, offset);
+    }
+
+    fn sd(&mut self, rs2: u32, rs1: u32, offset: i32) {
+        self.s_type(OP_STORE, 0x3, rs1, rs2, offset);
+    }
+
+    fn sw(&mut self, rs2: u32, rs1: u32, offset: i32) {
+        self.s_type(OP_STORE, 0x2, rs1, rs2, offset);
+    }
+
+    fn fld(&mut self, rd: u32, rs1: u32, offset: i32) {
+        self.i_type(OP_LOAD_FP, 0x3, rd, rs1, offset);
+    }
+
+    fn fsd(&mut self, rs2: u32, rs1: u32, offset: i32) {
+        self.s_type(OP_STORE_FP, 0x3, rs1, rs2, offset);
+    }
+
+    /// Loads a reserved doubleword, with acquire semantics.
+    fn lr_d(&mut self, rd: u32, rs1: u32) {
+        self.r_type(OP_AMO, 0x3, (0x02 << 2) | 0x2, rd, rs1, ZERO);
+    }
+
+    /// Stores `rs2` at `rs1` if the reservation is still held, with release semantics, setting `rd` to 0, or to
+    /// a non zero value if it is not.
+    fn sc_d(&mut self, rd: u32, rs2: u32, rs1: u32) {
+        self.r_type(OP_AMO, 0x3, (0x03 << 2) | 0x1, rd, rs1, rs2);
+    }
+
+    fn csrr(&mut self, rd: u32, csr: u32) {
+        self.emit((csr << 20) | (0x2 << 12) | (rd << 7) | OP_SYSTEM);
+    }
+
+    fn csrw(&mut self, csr: u32, rs1: u32) {
+        self.emit((csr << 20) | (rs1 << 15) | (0x1 << 12) | OP_SYSTEM);
+    }
+
+    fn ecall(&mut self) {
+        self.emit(OP_SYSTEM);
+    }
+
+    /// Synchronises the instruction fetches of the hart with its own stores, and the ones the kernel made visible.
+    fn fence_i(&mut self) {
+        self.emit((0x1 << 12) | OP_MISC_MEM);
+    }
+
+    /// Spin loop hint (Zihintpause), a fence ordering nothing elsewhere.
+    fn pause(&mut self) {
+        self.emit(0x0100000f);
+    }
+
+    fn b(&mut self, funct3: u32, rs1: u32, rs2: u32, label: Label) {
+        self.fixups.push((self.code.len(), label, Fixup::Branch));
+        self.emit((rs2 << 20) | (rs1 << 15) | (funct3 << 12) | OP_BRANCH);
+    }
+
+    fn b_global(&mut self, funct3: u32, rs1: u32, rs2: u32, name: &'static str) {
+        let label = self.global(name);
+        self.b(funct3, rs1, rs2, label);
+    }
+
+    fn jal(&mut self, rd: u32, label: Label) {
+        self.fixups.push((self.code.len(), label, Fixup::Jal));
+        self.emit((rd << 7) | OP_JAL);
+    }
+
+    fn j(&mut self, label: Label) {
+        self.jal(ZERO, label);
+    }
+
+    fn j_global(&mut self, name: &'static str) {
+        let label = self.global(name);
+        self.j(label);
+    }
+
+    fn jalr(&mut self, rd: u32, rs1: u32) {
+        self.i_type(OP_JALR, 0x0, rd, rs1, 0x0);
+    }
+
+    fn jr(&mut self, rs1: u32) {
+        self.jalr(ZERO, rs1);
+    }
+
+    /// Loads the address of a label, with auipc and addi.
+    fn la(&mut self, rd: u32, label: Label) {
+        self.fixups.push((self.code.len(), label, Fixup::PcrelHigh));
+        self.emit((rd << 7) | OP_AUIPC);
+        self.fixups.push((self.code.len(), label, Fixup::PcrelLow));
+        self.addi(rd, rd, 0x0);
+    }
+
+    fn la_global(&mut self, rd: u32, name: &'static str) {
+        let label = self.global(name);
+        self.la(rd, label);
+    }
+
+    /// Loads the doubleword at the global label `name`, with auipc and ld.
+    fn ld_global(&mut self, rd: u32, name: &'static str) {
+        let label = self.global(name);
+        self.fixups.push((self.code.len(), label, Fixup::PcrelHigh));
+        self.emit((rd << 7) | OP_AUIPC);
+        self.fixups.push((self.code.len(), label, Fixup::PcrelLow));
+        self.ld(rd, rd, 0x0);
+    }
+
+    /// Patches the references to the labels, and gets the code.
+    fn finalize(mut self) -> Result<Vec<u8>, InjectionError> {
+        for (at, label, fixup) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].ok_or(InjectionError::ShellcodeError)?;
+            let word = u32::from_le_bytes(self.code[at..at + 4].try_into().unwrap());
+            let word = match fixup {
+                Fixup::Branch => {
+                    let offset = target as i64 - at as i64;
+                    self.check(offset, 13);
+                    let offset = offset as u32;
+                    word | (((offset >> 12) & 0x1) << 31)
+                        | (((offset >> 5) & 0x3f) << 25)
+                        | (((offset >> 1) & 0xf) << 8)
+                        | (((offset >> 11) & 0x1) << 7)
+                }
+                Fixup::Jal => {
+                    let offset = target as i64 - at as i64;
+                    self.check(offset, 21);
+                    let offset = offset as u32;
+                    word | (((offset >> 20) & 0x1) << 31)
+                        | (((offset >> 1) & 0x3ff) << 21)
+                        | (((offset >> 11) & 0x1) << 20)
+                        | (((offset >> 12) & 0xff) << 12)
+                }
+                // the low 12 bits are sign extended, the high ones are rounded to make up for it
+                Fixup::PcrelHigh => {
+                    let offset = target as i64 - at as i64;
+                    self.check(offset, 32);
+                    word | ((offset as u32).wrapping_add(0x800) & 0xfffff000)
+                }
+                Fixup::PcrelLow => {
+                    let offset = target as i64 - (at - 4) as i64;
+                    word | ((offset as u32 & 0xfff) << 20)
+                }
+            };
+            self.code[at..at + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        match self.invalid {
+            true => Err(InjectionError::ShellcodeError),
+            false => Ok(self.code),
+        }
+    }
+}
+
+/// Builds the first stage. It saves the context of the thread in a frame of 0x100 bytes below the stack pointer of
+/// the hijacked call, which the psABI keeps 16 bytes aligned, with no red zone above: xN at `8 * n`, sp (x2) being
+/// left out, and the slot of x0 unused.
+///
+/// The function may start on a halfword, so the constants are loaded as immediates rather than from literals. The
+/// second stage runs below this frame, and the tail unmapping it restores it (see `restore_registers`).
+pub fn first_shellcode(var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+    debug!("first_shellcode riscv64");
+
+    let mut ops = Assembler::new();
+
+    // parking spot, replaced by a self loop holding the new threads while the function is restored
+    ops.addi(SP, SP, -0x100);
+
+    // save the registers
+    for reg in (1..32).filter(|&reg| reg != SP) {
+        ops.sd(reg, SP, (reg * 8) as i32);
+    }
+
+    // count the thread in the first stage
+    ops.li64(T1, var_addr as u64);
+    ops.bind_global("count");
+    ops.lr_d(A1, T1);
+    ops.addi(A1, A1, MAILBOX_THREAD as i32);
+    ops.sc_d(A2, A1, T1);
+    ops.b_global(BNE, A2, ZERO, "count");
+
+    // claim the stage if nobody has: idle -> claimed
+    ops.bind_global("claim");
+    ops.lr_d(A1, T1);
+    ops.andi(A0, A1, MAILBOX_STATE as i32);
+    ops.b_global(BNE, A0, ZERO, "wait_map");
+    ops.addi(A1, A1, MailboxState::Claimed as i32);
+    ops.sc_d(A2, A1, T1);
+    ops.b_global(BNE, A2, ZERO, "claim");
+    ops.j_global("claimed");
+
+    // wait for the map, and join the thread which claimed the stage there
+    ops.bind_global("wait_map");
+    ops.ld(A1, T1, 0x0);
+    ops.andi(A0, A1, MAILBOX_STATE as i32);
+    ops.li(A2, MailboxState::Failed as i32);
+    ops.b_global(BEQ, A0, A2, "leave");
+    ops.li(A2, MailboxState::Allocated as i32);
+    ops.b_global(BLTU, A0, A2, "wait_map");
+    ops.srli(A0, A1, MAILBOX_VALUE_SHIFT);
+    ops.slli(A0, A0, MAILBOX_VALUE_SHIFT);
+    ops.jr(A0);
+
+    // write the protocol version in the mailbox block
+    ops.bind_global("claimed");
+    ops.li64(S3, block_addr as u64);
+    ops.li(A1, MAILBOX_VERSION as i32);
+    ops.sw(A1, S3, MAILBOX_BLOCK_VERSION_OFFSET as i32);
+
+    // mmap call
+    ops.li(A0, 0x0); // addr       (NULL)
+    ops.li64(A1, alloc_len as u64); // len (alloc_len)
+    ops.li(A2, map_prot(exec_len) as i32); // prot (RWX, or RW)
+    ops.li(A3, 0x22); // flags      (MAP_PRIVATE | MAP_ANONYMOUS)
+    ops.li(A4, -0x1); // fd         (-1)
+    ops.li(A5, 0x0); // offset     (ignored)
+    ops.li(A7, SYS_MMAP);
+    ops.ecall();
+    ops.lui(A2, 0xfffff); // -4095..-1 is an errno
+    ops.b_global(BLTU, A2, A0, "mmap_failed");
+
+    // write self loop instruction to the new map
+    ops.li(A1, SELF_JMP as i32);
+    ops.sw(A1, A0, 0x0);
+    ops.mv(S1, A0); // keep the map, t1 holds var_addr
+
+    if let Some(exec_len) = exec_len {
+        // make the code executable, and no longer writable
+        ops.li64(A1, exec_len as u64); // len (exec_len)
+        ops.li(A2, (PROT_READ | PROT_EXEC) as i32); // prot (RX)
+        ops.li(A7, SYS_MPROTECT);
+        ops.ecall();
+        ops.b_global(BNE, A0, ZERO, "mprotect_failed");
+        ops.mv(A0, S1);
+    }
+
+    // make the self loop visible to the instruction fetches of every hart, before any thread jumps to it
+    ops.addi(A1, A0, 0x4);
+    flush_icache(&mut ops);
+    ops.mv(A0, S1);
+
+    // publish the map address next to the count: claimed -> allocated
+    ops.bind_global("publish");
+    ops.lr_d(A1, T1);
+    ops.add(A1, A1, A0);
+    ops.addi(A1, A1, MailboxState::Allocated as i32 - MailboxState::Claimed as i32);
+    ops.sc_d(A2, A1, T1);
+    ops.b_global(BNE, A2, ZERO, "publish");
+
+    // jump to the new map
+    ops.jr(A0);
+
+    // write the system call and its errno in the mailbox block, then publish them: claimed -> failed
+    ops.bind_global("mmap_failed");
+    ops.sub(A0, ZERO, A0);
+    ops.li(S2, MAILBOX_SYSCALL_MMAP as i32);
+    ops.bind_global("failed");
+    ops.sw(S2, S3, MAILBOX_BLOCK_SYSCALL_OFFSET as i32);
+    ops.sw(A0, S3, MAILBOX_BLOCK_ERRNO_OFFSET as i32);
+    ops.bind_global("publish_failure");
+    ops.lr_d(A1, T1);
+    ops.addi(A1, A1, MailboxState::Failed as i32 - MailboxState::Claimed as i32);
+    ops.sc_d(A2, A1, T1);
+    ops.b_global(BNE, A2, ZERO, "publish_failure");
+
+    // leave the first stage, and go back to the function once the injector has restored it
+    ops.bind_global("leave");
+    ops.lr_d(A1, T1);
+    ops.addi(A1, A1, -(MAILBOX_THREAD as i32));
+    ops.sc_d(A2, A1, T1);
+    ops.b_global(BNE, A2, ZERO, "leave");
+
+    restore_registers(&mut ops);
+    ops.li64(T3, ret.addr as u64);
+    ops.jr(T3);
+
+    if exec_len.is_some() {
+        // unmap the map, and publish the errno
+        ops.bind_global("mprotect_failed");
+        ops.sub(S2, ZERO, A0);
+        ops.mv(A0, S1); // addr       (map)
+        ops.li64(A1, alloc_len as u64); // len (alloc_len)
+        ops.li(A7, SYS_MUNMAP);
+        ops.ecall();
+        ops.mv(A0, S2);
+        ops.li(S2, MAILBOX_SYSCALL_MPROTECT as i32);
+        ops.j_global("failed");
+    }
+
+    ops.finalize()
+}
+
+pub fn raw_dlopen_shellcode(
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    dlopen_paths: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("raw_dlopen_shellcode riscv64");
+
+    let mut ops = Assembler::new();
+    let paths: Vec<_> = dlopen_paths.iter().map(|_| ops.new_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, path) in paths.iter().enumerate() {
+        // load args
+        ops.la(A0, *path);
+        ops.li(A1, RTLD_NOW as i32);
+
+        // call dlopen
+        call(&mut ops, "dlopen");
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, entry);
+    ops.j_global("done");
+
+    dlopen_failed(&mut ops);
+
+    // tell the injector we are done
+    ops.bind_global("done");
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (path, dlopen_path) in paths.iter().zip(dlopen_paths) {
+        ops.align(0x4);
+        ops.bind(*path);
+        ops.bytes(dlopen_path.as_bytes());
+        ops.bytes(&[0x0]);
+    }
+
+    literal(&mut ops, "dlopen", dl.dlopen as u64);
+    literal(&mut ops, "dlsym", dl.dlsym as u64);
+    literal(&mut ops, "dlerror", dl.dlerror as u64);
+
+    data_section(&mut ops, config.data_align);
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, dlopen_paths.len());
+    finalize_second_stage(ops)
+}
+
+pub fn memfd_dlopen_shellcode(
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    memfd_names: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("memfd_dlopen_shellcode riscv64");
+
+    let count = memfd_names.len();
+    let mut ops = Assembler::new();
+    let names: Vec<_> = memfd_names.iter().map(|_| ops.new_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, name) in names.iter().enumerate() {
+        let wait = ops.new_label();
+
+        // mark the library as attempted
+        ops.la_global(T0, "handles");
+        ops.sd(ZERO, T0, (i * 8) as i32);
+
+        // create the memfd
+        ops.la(A0, *name);
+        ops.li(A1, MFD_CLOEXEC as i32);
+        ops.li(A7, SYS_MEMFD_CREATE);
+        ops.ecall();
+        ops.b_global(BLT, A0, ZERO, "memfd_failed");
+        ops.la_global(T0, "memfds");
+        ops.sd(A0, T0, (i * 8) as i32);
+
+        // ask the injector to fill the memfd and its path, and wait for it
+        ops.la_global(T0, "result_value");
+        ops.sd(A0, T0, 0x0);
+        set_status(&mut ops, RESULT_STATUS_REQUEST);
+        ops.bind(wait);
+        ops.li(A7, SYS_SCHED_YIELD);
+        ops.ecall();
+        ops.la_global(T0, "result_status");
+        ops.ld(A0, T0, 0x0);
+        ops.li(T1, RESULT_STATUS_RESUME as i32);
+        ops.b(BNE, A0, T1, wait);
+
+        // call dlopen on /proc/self/fd/<memfd>
+        ops.la_global(A0, "result_buffer");
+        ops.li(A1, RTLD_NOW as i32);
+        call(&mut ops, "dlopen");
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, entry);
+    ops.j_global("done");
+
+    dlopen_failed(&mut ops);
+    ops.j_global("done");
+
+    // save the errno of memfd_create
+    ops.bind_global("memfd_failed");
+    ops.sub(A0, ZERO, A0);
+    ops.la_global(T0, "result_errno");
+    ops.sw(A0, T0, 0x0);
+
+    ops.bind_global("done");
+
+    // close the memfds, the library mappings keep them alive. They are kept open until now,
+    // so each library gets its own /proc/self/fd/<memfd> path.
+    for i in 0..count {
+        let closed = ops.new_label();
+
+        ops.la_global(T0, "memfds");
+        ops.ld(A0, T0, (i * 8) as i32);
+        ops.b(BLT, A0, ZERO, closed);
+        ops.li(A7, SYS_CLOSE);
+        ops.ecall();
+        ops.bind(closed);
+    }
+
+    // tell the injector we are done
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (name, memfd_name) in names.iter().zip(memfd_names) {
+        ops.align(0x4);
+        ops.bind(*name);
+        ops.bytes(memfd_name.as_bytes());
+        ops.bytes(&[0x0]);
+    }
+
+    literal(&mut ops, "dlopen", dl.dlopen as u64);
+    literal(&mut ops, "dlsym", dl.dlsym as u64);
+    literal(&mut ops, "dlerror", dl.dlerror as u64);
+
+    data_section(&mut ops, config.data_align);
+
+    // file descriptors of the memfds, one per library
+    ops.bind_global("memfds");
+    for _ in 0..count {
+        ops.qword(u64::MAX);
+    }
+
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, count);
+    finalize_second_stage(ops)
+}
+
+pub fn raw_dlclose_shellcode(
+    dl: &DlFns,
+    fini_addr: Option<usize>,
+    library: &LoadedLibrary,
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("raw_dlclose_shellcode riscv64");
+
+    let fini_addr = fini_addr.unwrap_or(0);
+
+    let mut ops = Assembler::new();
+    handshake(&mut ops, config);
+
+    match library {
+        LoadedLibrary::Path(_) => {
+            // get a handle on the loaded library, without loading it again
+            ops.la_global(A0, "dlopen_path");
+            ops.li(A1, (RTLD_NOW | RTLD_NOLOAD) as i32);
+            call(&mut ops, "dlopen");
+            ops.b_global(BEQ, A0, ZERO, "not_loaded");
+            ops.mv(S2, A0);
+        }
+        LoadedLibrary::Handle(_) => {
+            // the handle returned when the library was injected
+            ops.ld_global(S2, "handle");
+        }
+    }
+
+    // call the fini hook if the library exports one
+    ops.ld_global(T1, "fini");
+    ops.b_global(BEQ, T1, ZERO, "close");
+    ops.jalr(RA, T1);
+
+    ops.bind_global("close");
+    if let LoadedLibrary::Path(_) = library {
+        // drop the reference we just took
+        ops.mv(A0, S2);
+        call(&mut ops, "dlclose");
+    }
+
+    // drop the reference of the injection
+    ops.mv(A0, S2);
+    call(&mut ops, "dlclose");
+    ops.la_global(T0, "result_value");
+    ops.sd(A0, T0, 0x0);
+    ops.b_global(BEQ, A0, ZERO, "closed");
+    ops.j_global("failed");
+
+    ops.bind_global("not_loaded");
+    ops.li(A0, -0x1);
+    ops.la_global(T0, "result_value");
+    ops.sd(A0, T0, 0x0);
+
+    // save the dlerror() text
+    ops.bind_global("failed");
+    call(&mut ops, "dlerror");
+    ops.la_global(T0, "result_error");
+    ops.sd(A0, T0, 0x0);
+
+    // tell the injector we are done
+    ops.bind_global("closed");
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    match library {
+        LoadedLibrary::Path(dlopen_path) => {
+            ops.align(0x4);
+            ops.bind_global("dlopen_path");
+            ops.bytes(dlopen_path.as_bytes());
+            ops.bytes(&[0x0]);
+        }
+        LoadedLibrary::Handle(handle) => literal(&mut ops, "handle", *handle as u64),
+    }
+
+    literal(&mut ops, "dlopen", dl.dlopen as u64);
+    literal(&mut ops, "dlclose", dl.dlclose as u64);
+    literal(&mut ops, "dlerror", dl.dlerror as u64);
+    literal(&mut ops, "fini", fini_addr as u64);
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+pub fn call_shellcode(
+    func_addr: usize,
+    errno_fn_addr: usize,
+    args: &[CallArg],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!("call_shellcode riscv64");
+
+    let mut ops = Assembler::new();
+    let values: Vec<_> = args.iter().map(|_| ops.new_label()).collect();
+    handshake(&mut ops, config);
+
+    // clear errno
+    call(&mut ops, "errno_fn");
+    ops.sw(ZERO, A0, 0x0);
+
+    // load the arguments in a0-a5, following the psABI
+    for (i, arg) in args.iter().enumerate() {
+        let reg = A0 + i as u32;
+        match arg {
+            CallArg::Int(_) => {
+                ops.la(reg, values[i]);
+                ops.ld(reg, reg, 0x0);
+            }
+            CallArg::Bytes(_) => ops.la(reg, values[i]),
+        }
+    }
+
+    // call the function
+    call(&mut ops, "func");
+    ops.la_global(T0, "result_value");
+    ops.sd(A0, T0, 0x0);
+
+    // save errno
+    call(&mut ops, "errno_fn");
+    ops.lw(A0, A0, 0x0);
+    ops.la_global(T0, "result_errno");
+    ops.sw(A0, T0, 0x0);
+
+    // tell the injector we are done
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    literal(&mut ops, "func", func_addr as u64);
+    literal(&mut ops, "errno_fn", errno_fn_addr as u64);
+
+    // the called function may write in the buffers
+    data_section(&mut ops, config.data_align);
+
+    for (i, arg) in args.iter().enumerate() {
+        ops.align(0x8);
+        ops.bind(values[i]);
+        match arg {
+            CallArg::Int(value) => ops.qword(*value),
+            CallArg::Bytes(bytes) => ops.bytes(bytes),
+        }
+    }
+
+    finalize_second_stage(ops)
+}
+
+pub fn shellcode_stage(shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("shellcode_stage riscv64 {} bytes", shellcode.len());
+
+    let mut ops = Assembler::new();
+    handshake(&mut ops, config);
+
+    // call the shellcode, and save what it returned
+    let start = ops.global("shellcode");
+    ops.jal(RA, start);
+    ops.la_global(T0, "result_value");
+    ops.sd(A0, T0, 0x0);
+
+    // tell the injector we are done
+    set_status(&mut ops, RESULT_STATUS_DONE);
+
+    restore_and_return(&mut ops, ret, config);
+
+    ops.align(0x10);
+    ops.bind_global("shellcode");
+    ops.bytes(shellcode);
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+/// Lets a single thread run the second stage, once it has counted the threads left in the first stage.
+/// The others call the function, which the injector holds until it is restored.
+///
+/// Every thread synchronises its instruction fetches with the code of the stage first. The thread running the stage
+/// keeps its stack pointer in s1, and aligns it.
+fn handshake(ops: &mut Assembler, config: StageConfig) {
+    let leave_count = ops.new_label();
+    let claim = ops.new_label();
+    let ready = ops.new_label();
+    let wait = ops.new_label();
+    let done = ops.new_label();
+
+    // the first instruction replaces the self loop the threads spin on, the kernel made the stage visible to the
+    // other harts when it was written, but the next instructions may have been fetched before
+    ops.bind_global("map");
+    ops.fence_i();
+
+    // the thread has left the first stage
+    ops.ld_global(S3, "mailbox");
+    ops.bind(leave_count);
+    ops.lr_d(A1, S3);
+    ops.addi(A1, A1, -(MAILBOX_THREAD as i32));
+    ops.sc_d(A2, A1, S3);
+    ops.b(BNE, A2, ZERO, leave_count);
+
+    // only the first thread runs the stage: allocated -> stage2-ready
+    ops.la_global(A3, "gate");
+    ops.bind(claim);
+    ops.lr_d(A1, A3);
+    ops.b_global(BNE, A1, ZERO, "leave");
+    ops.li(A1, 0x1);
+    ops.sc_d(A2, A1, A3);
+    ops.b(BNE, A2, ZERO, claim);
+    ops.bind(ready);
+    ops.lr_d(A1, S3);
+    ops.addi(A1, A1, MailboxState::Stage2Ready as i32 - MailboxState::Allocated as i32);
+    ops.sc_d(A2, A1, S3);
+    ops.b(BNE, A2, ZERO, ready);
+
+    // wait for the other threads to leave the first stage, and tell the injector: stage2-ready -> done
+    ops.li64(A4, MAILBOX_THREADS);
+    ops.bind(wait);
+    ops.pause();
+    ops.ld(A1, S3, 0x0);
+    ops.and(A1, A1, A4);
+    ops.b(BNE, A1, ZERO, wait);
+    ops.bind(done);
+    ops.lr_d(A1, S3);
+    ops.addi(A1, A1, MailboxState::Done as i32 - MailboxState::Stage2Ready as i32);
+    ops.sc_d(A2, A1, S3);
+    ops.b(BNE, A2, ZERO, done);
+    ops.j_global("handshaken");
+
+    literal(ops, "mailbox", config.var_addr as u64);
+
+    ops.bind_global("handshaken");
+    ops.mv(S1, SP);
+    ops.andi(SP, SP, -0x10);
+
+    if config.full_context {
+        save_context(ops);
+    }
+}
+
+/// Saves the floating point registers and fcsr below the stack. The targets have the D extension (RV64GC).
+fn save_context(ops: &mut Assembler) {
+    ops.addi(SP, SP, -0x110);
+    ops.csrr(T0, CSR_FCSR);
+    ops.sd(T0, SP, 0x0);
+    for reg in 0..32 {
+        ops.fsd(reg, SP, (0x8 + reg * 8) as i32);
+    }
+}
+
+/// Restores the registers saved by `save_context`, and the stack pointer.
+fn restore_context(ops: &mut Assembler) {
+    ops.ld(T0, SP, 0x0);
+    ops.csrw(CSR_FCSR, T0);
+    for reg in 0..32 {
+        ops.fld(reg, SP, (0x8 + reg * 8) as i32);
+    }
+    ops.addi(SP, SP, 0x110);
+}
+
+/// Calls the function whose address is at the global label `name`.
+fn call(ops: &mut Assembler, name: &'static str) {
+    ops.ld_global(T1, name);
+    ops.jalr(RA, T1);
+}
+
+/// Appends a literal at the global label `name`.
+fn literal(ops: &mut Assembler, name: &'static str, value: u64) {
+    ops.align(0x8);
+    ops.bind_global(name);
+    ops.qword(value);
+}
+
+/// Saves the handle returned by dlopen for the library `index`, and stops loading if it failed.
+fn save_handle(ops: &mut Assembler, index: usize) {
+    ops.la_global(T0, "handles");
+    ops.sd(A0, T0, (index * 8) as i32);
+    ops.b_global(BEQ, A0, ZERO, "dlopen_failed");
+}
+
+/// Calls the entry point of the last loaded library with its arguments, if one is set.
+fn call_entry(ops: &mut Assembler, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+    let called = ops.new_label();
+
+    // look for the entry point, the handle is still in a0
+    ops.la_global(A1, "entry_name");
+    call(ops, "dlsym");
+    ops.b_global(BEQ, A0, ZERO, "dlopen_failed");
+
+    // call it with a pointer to its arguments and their length
+    ops.mv(T1, A0);
+    ops.la_global(A0, "entry_args");
+    ops.ld_global(A1, "entry_args_len");
+    ops.jalr(RA, T1);
+    ops.la_global(T0, "result_entry");
+    ops.sd(A0, T0, 0x0);
+    ops.j(called);
+
+    literal(ops, "entry_args_len", entry.args.len() as u64);
+
+    ops.bind(called);
+}
+
+/// Appends the name of the entry point and its arguments to the data of the second stage, which it may write to.
+fn entry_data(ops: &mut Assembler, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    ops.bind_global("entry_name");
+    ops.bytes(entry.name.as_bytes());
+    ops.bytes(&[0x0]);
+    ops.align(0x4);
+    ops.bind_global("entry_args");
+    ops.bytes(entry.args.as_slice());
+    ops.bytes(&[0x0]);
+    ops.align(0x4);
+}
+
+/// Saves the dlerror() text when a library or the entry point could not be loaded.
+fn dlopen_failed(ops: &mut Assembler) {
+    ops.bind_global("dlopen_failed");
+    call(ops, "dlerror");
+    ops.la_global(T0, "result_error");
+    ops.sd(A0, T0, 0x0);
+}
+
+/// Writes the status word of the result block.
+fn set_status(ops: &mut Assembler, status: u64) {
+    ops.li(A0, status as i32);
+    ops.la_global(T0, "result_status");
+    ops.sd(A0, T0, 0x0);
+}
+
+/// Starts the data written by the second stage, aligned to `data_align` to be protected apart from its code.
+fn data_section(ops: &mut Assembler, data_align: usize) {
+    ops.align(data_align);
+    ops.bind_global("data");
+}
+
+/// Appends the table of the handles returned by dlopen, one per library.
+fn handles_table(ops: &mut Assembler, count: usize) {
+    ops.align(0x8);
+    ops.bind_global("handles");
+
+    for _ in 0..count {
+        ops.qword(HANDLE_SKIPPED);
+    }
+}
+
+/// Jumps back to the hijacked function. The thread which ran the stage waits for the injector to release the map,
+/// and jumps to the tail unmapping it with the stack pointer back on the frame of the first stage, once its
+/// instruction fetches are synchronised with the tail and the restored bytes of the function. The others restore
+/// the registers saved by the first stage themselves.
+///
+/// Without a code cave for the tail, it follows on a page of its own, the pages before it being unmapped.
+fn restore_and_return(ops: &mut Assembler, ret: StageReturn, config: StageConfig) {
+    let release = ops.new_label();
+
+    if config.full_context {
+        restore_context(ops);
+    }
+
+    // wait for the injector to read the result block, and the other threads to leave the map
+    ops.la_global(T0, "result_release");
+    ops.bind(release);
+    ops.pause();
+    ops.ld(A1, T0, 0x0);
+    ops.b(BEQ, A1, ZERO, release);
+
+    // the injector restored the function before the release, and wrote the tail before the stage: the kernel
+    // flushes the whole instruction cache, whatever the range
+    ops.li64(A0, ret.stage_addr as u64);
+    ops.li64(A1, (ret.stage_addr + ret.stage_len) as u64);
+    flush_icache(ops);
+    ops.ld(A1, T0, 0x0);
+
+    // jump to the tail with the map and its length as the arguments of munmap
+    ops.mv(SP, S1);
+    ops.la_global(A0, "map");
+    match ret.tail_addr {
+        Some(_) => {
+            ops.ld_global(T3, "tail_addr");
+            ops.jr(T3);
+        }
+        None => ops.j_global("tail"),
+    }
+
+    ops.bind_global("leave");
+    restore_registers(ops);
+    ops.ld_global(T3, "oldfun");
+    ops.jr(T3);
+
+    literal(ops, "oldfun", ret.addr as u64);
+    match ret.tail_addr {
+        Some(tail_addr) => literal(ops, "tail_addr", tail_addr as u64),
+        None => {
+            ops.align(config.page_size);
+            ops.bind_global("tail");
+            tail(ops, ret);
+        }
+    }
+}
+
+/// Makes the code between a0 and a1 visible to the instruction fetches of every hart of the process, with the
+/// riscv_flush_icache system call. Clobbers a0, a2 and a7.
+fn flush_icache(ops: &mut Assembler) {
+    ops.li(A2, 0x0); // flags (every hart)
+    ops.li(A7, SYS_RISCV_FLUSH_ICACHE);
+    ops.ecall();
+}
+
+/// Restores the registers saved by the first stage, along with the stack pointer.
+fn restore_registers(ops: &mut Assembler) {
+    for reg in (1..32).filter(|&reg| reg != SP) {
+        ops.ld(reg, SP, (reg * 8) as i32);
+    }
+    ops.addi(SP, SP, 0x100);
+}
+
+/// Appends the result block read back by the injector, and assembles the second stage.
+fn finalize_second_stage(mut ops: Assembler) -> Result<SecondStage, InjectionError> {
+    // claimed by the thread running the stage
+    ops.align(0x8);
+    ops.bind_global("gate");
+    ops.qword(0x0);
+
+    // result block, read back by the injector
+    ops.bind_global("result");
+    for name in ["result_status", "result_value", "result_error", "result_errno", "result_entry", "result_release"] {
+        ops.bind_global(name);
+        ops.qword(0x0);
+    }
+    ops.bind_global("result_buffer");
+    ops.bytes(&[0x0; RESULT_BUFFER_SIZE]);
+
+    let result_offset = ops.offset_of("result")?;
+    let handles_offset = ops.offset_of("handles").ok();
+    let data_offset = ops.offset_of("data")?;
+    let tail_offset = ops.offset_of("tail").ok();
+
+    Ok(SecondStage {
+        code: ops.finalize()?,
+        result_offset,
+        handles_offset,
+        data_offset,
+        tail_offset,
+    })
+}
+
+pub fn self_jmp(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("self_jmp riscv64 0x{:x}", ret.stage_addr);
+
+    Ok(SELF_JMP.to_le_bytes().to_vec())
+}
+
+/// Builds the tail. It is written in a code cave on 16 bytes, so its literal is aligned.
+pub fn unmap_tail(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("unmap_tail riscv64");
+
+    let mut ops = Assembler::new();
+    tail(&mut ops, ret);
+    ops.finalize()
+}
+
+/// Unmaps the map given in a0 and a1, restores the registers saved by the first stage and jumps to `ret.addr`,
+/// from a code cave or from the page of the map left mapped.
+fn tail(ops: &mut Assembler, ret: StageReturn) {
+    // unmap the map, given in a0 and a1
+    ops.li(A7, SYS_MUNMAP);
+    ops.ecall();
+
+    restore_registers(ops);
+
+    // return to the hijacked function
+    ops.ld_global(T3, "return_addr");
+    ops.jr(T3);
+
+    literal(ops, "return_addr", ret.addr as u64);
+}
+
+/// Builds the payloads for the riscv64 processes.
+pub struct Backend;
+
+impl ArchBackend for Backend {
+    fn name(&self) -> &'static str {
+        "riscv64"
+    }
+
+    fn pointer_len(&self) -> usize {
+        8
+    }
+
+    fn syncs_icache(&self) -> bool {
+        true
+    }
+
+    fn max_alloc_len(&self) -> usize {
+        MAX_ALLOC_LEN
+    }
+
+    fn first_shellcode(&self, var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+        first_shellcode(var_addr, block_addr, ret, alloc_len, exec_len)
+    }
+
+    fn raw_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlopen_shellcode(dl, entry, dlopen_paths, ret, config)
+    }
+
+    fn raw_dlclose_shellcode(&self, dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlclose_shellcode(dl, fini_addr, library, ret, config)
+    }
+
+    fn call_shellcode(&self, func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        call_shellcode(func_addr, errno_fn_addr, args, ret, config)
+    }
+
+    fn memfd_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        memfd_dlopen_shellcode(dl, entry, memfd_names, ret, config)
+    }
+
+    fn shellcode_stage(&self, shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        shellcode_stage(shellcode, ret, config)
+    }
+
+    fn self_jmp(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        self_jmp(ret)
+    }
+
+    fn unmap_tail(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        unmap_tail(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles the instructions emitted by `emit`, as words.
+    fn assemble(emit: impl FnOnce(&mut Assembler)) -> Vec<u32> {
+        let mut ops = Assembler::new();
+        emit(&mut ops);
+        words(&ops.finalize().unwrap())
+    }
+
+    fn words(code: &[u8]) -> Vec<u32> {
+        code.chunks_exact(0x4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+    }
+
+    /// Runs the lui, addiw, slli and ori of `li64`, and gets the value they load.
+    fn run_li64(code: &[u32]) -> u64 {
+        let mut value = 0u64;
+        for &word in code {
+            let imm12 = ((word as i32) >> 20) as i64 as u64;
+            value = match (word & 0x7f, (word >> 12) & 0x7) {
+                (OP_LUI, _) => (word & 0xfffff000) as i32 as i64 as u64,
+                (OP_IMM_32, 0x0) => value.wrapping_add(imm12) as i32 as i64 as u64,
+                (OP_IMM, 0x1) => value << ((word >> 20) & 0x3f),
+                (OP_IMM, 0x6) => value | imm12,
+                _ => panic!("unexpected instruction 0x{:08x}", word),
+            };
+        }
+        value
+    }
+
+    #[test]
+    fn immediates() {
+        let code = assemble(|ops| {
+            ops.addi(SP, SP, -0x100);
+            ops.addiw(A1, A1, -0x1);
+            ops.andi(A0, A1, 0x7);
+            ops.ori(T1, T1, 0x7ff);
+            ops.slli(A0, A0, 12);
+            ops.srli(A0, A1, 12);
+            ops.mv(S1, A0);
+            ops.lui(A2, 0xfffff);
+            ops.li(A4, -0x1);
+        });
+        assert_eq!(code, [0xf0010113, 0xfff5859b, 0x0075f513, 0x7ff36313, 0x00c51513, 0x00c5d513, 0x00050493, 0xfffff637, 0xfff00713]);
+    }
+
+    #[test]
+    fn immediate_out_of_range() {
+        let mut ops = Assembler::new();
+        ops.addi(A0, A0, 0x800);
+        assert!(ops.finalize().is_err());
+
+        let mut ops = Assembler::new();
+        ops.sd(A0, SP, -0x801);
+        assert!(ops.finalize().is_err());
+    }
+
+    #[test]
+    fn registers() {
+        let code = assemble(|ops| {
+            ops.add(A1, A1, A0);
+            ops.sub(A0, ZERO, A0);
+            ops.and(A0, A1, A2);
+        });
+        assert_eq!(code, [0x00a585b3, 0x40a00533, 0x00c5f533]);
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let code = assemble(|ops| {
+            ops.ld(RA, SP, 0x8);
+            ops.lw(A1, T0, 0x0);
+            ops.sd(T3, SP, 0xf8);
+            ops.sw(A1, A0, 0x0);
+            ops.fld(8, SP, -0x800);
+            ops.fsd(31, SP, 0x7f8);
+        });
+        assert_eq!(code, [0x00813083, 0x0002a583, 0x0fc13c23, 0x00b52023, 0x80013407, 0x7ff13c27]);
+    }
+
+    #[test]
+    fn atomics() {
+        let code = assemble(|ops| {
+            ops.lr_d(A1, T1);
+            ops.sc_d(A2, A1, T1);
+        });
+        assert_eq!(code, [0x140335af, 0x1ab3362f]);
+    }
+
+    #[test]
+    fn system() {
+        let code = assemble(|ops| {
+            ops.csrr(A0, CSR_FCSR);
+            ops.csrw(CSR_FCSR, A0);
+            ops.ecall();
+            ops.fence_i();
+            ops.pause();
+            ops.jalr(RA, T3);
+            ops.jr(T3);
+        });
+        assert_eq!(code, [0x00302573, 0x00351073, 0x00000073, 0x0000100f, 0x0100000f, 0x000e00e7, 0x000e0067]);
+    }
+
+    #[test]
+    fn branches() {
+        let code = assemble(|ops| {
+            let back = ops.new_label();
+            ops.bind(back);
+            ops.b(BEQ, A1, ZERO, back);
+            ops.b_global(BNE, A2, ZERO, "forward");
+            ops.b_global(BLTU, A2, A0, "forward");
+            ops.b(BLT, A0, A1, back);
+            ops.j(back);
+            ops.j_global("forward");
+            ops.bytes(&[0x0; 0x7e8]);
+            ops.bind_global("forward");
+            ops.ecall();
+        });
+        assert_eq!(code[..6], [0x00058063, 0x7e061e63, 0x7ea66c63, 0xfeb54ae3, 0xff1ff06f, 0x7ec0006f]);
+    }
+
+    #[test]
+    fn branch_out_of_range() {
+        let mut ops = Assembler::new();
+        ops.b_global(BEQ, A0, ZERO, "far");
+        ops.bytes(&[0x0; 0x1000]);
+        ops.bind_global("far");
+        assert!(ops.finalize().is_err());
+    }
+
+    #[test]
+    fn self_jmp_encoding() {
+        let code = assemble(|ops| {
+            let itself = ops.new_label();
+            ops.bind(itself);
+            ops.j(itself);
+        });
+        assert_eq!(code, [SELF_JMP]);
+    }
+
+    #[test]
+    fn pc_relative_loads() {
+        // the low 12 bits are negative, the auipc rounds up to the next page
+        let code = assemble(|ops| {
+            ops.la_global(A0, "data");
+            ops.ld_global(T3, "data");
+            ops.bytes(&[0x0; 0x17f8]);
+            ops.bind_global("data");
+        });
+        assert_eq!(code[..4], [0x00002517, 0x80850513, 0x00002e17, 0x800e3e03]);
+    }
+
+    #[test]
+    fn li64() {
+        for value in [0x0, 0x1, 0x7ff, 0x800, 0x7fff_f800_0000_0800, 0x8000_0000_0000_0000, 0x0000_7fff_1234_5678, 0x3f_ffff_f000, u64::MAX] {
+            let code = assemble(|ops| ops.li64(A1, value));
+            assert_eq!(code.len(), 8);
+            assert_eq!(run_li64(&code), value, "0x{:x}", value);
+        }
+    }
+
+    #[test]
+    fn first_stage_flushes_the_icache_of_every_hart() {
+        let ret = StageReturn { addr: 0x3f_8000_1000, tail_addr: Some(0x3f_8000_f000), tail_len: 0x40, stage_addr: 0x3f_8000_1000, stage_len: 0x100 };
+        for exec_len in [None, Some(0x1000)] {
+            let code = words(&first_shellcode(0x3f_8000_8000, 0x3f_8000_9ff0, ret, 0x2000, exec_len).unwrap());
+
+            // li a7, 259; ecall, and no fence.i relying on the hart the thread runs on
+            let flush = code.windows(2).position(|pair| pair == [0x10300893, 0x00000073]);
+            assert!(flush.is_some());
+            assert!(!code.contains(&0x0000100f));
+        }
+    }
+
+    #[test]
+    fn second_stage_flushes_the_icache_before_the_tail() {
+        let ret = StageReturn { addr: 0x3f_8000_1000, tail_addr: Some(0x3f_8000_f000), tail_len: 0x40, stage_addr: 0x3f_8000_1000, stage_len: 0x100 };
+        let config = StageConfig { var_addr: 0x3f_8000_8000, data_align: 0x8, full_context: false, page_size: 0x1000 };
+        let stage = shellcode_stage(&[0x0; 0x10], ret, config).unwrap();
+        let code = words(&stage.code[..stage.data_offset]);
+        assert!(code.windows(2).any(|pair| pair == [0x10300893, 0x00000073]));
+    }
+}
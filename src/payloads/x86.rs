@@ -1,60 +1,194 @@
 use crate::error::InjectionError;
+use crate::payloads::{
+    label_offset, map_prot, ArchBackend, CallArg, DlFns, Entry, LoadedLibrary, MailboxState, SecondStage, StageConfig, StageReturn, HANDLE_SKIPPED,
+    MAILBOX_BLOCK_ERRNO_OFFSET, MAILBOX_BLOCK_SYSCALL_OFFSET, MAILBOX_BLOCK_VERSION_OFFSET, MAILBOX_FIELDS, MAILBOX_STATE, MAILBOX_SYSCALL_MMAP,
+    MAILBOX_SYSCALL_MPROTECT, MAILBOX_THREAD, MAILBOX_THREADS, MAILBOX_VERSION, MAX_CALL_ARGS, MFD_CLOEXEC, PROT_EXEC, PROT_READ, RESULT_BUFFER_SIZE,
+    RESULT_STATUS_DONE, RESULT_STATUS_REQUEST, RESULT_STATUS_RESUME, RTLD_NOLOAD, RTLD_NOW,
+};
 
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
 
 use dynasmrt::x86::X86Relocation;
 use dynasmrt::VecAssembler;
 
-pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, InjectionError> {
+const SYS_CLOSE: i32 = 6;
+const SYS_MPROTECT: i32 = 125;
+const SYS_MUNMAP: i32 = 91;
+const SYS_SCHED_YIELD: i32 = 158;
+const SYS_MEMFD_CREATE: i32 = 356;
+
+/// Bit of ecx set by cpuid leaf 1 when the kernel enabled xsave.
+const OSXSAVE_BIT: u8 = 27;
+/// Components saved by xsave: x87, SSE, AVX and the AVX-512 ones. AMX is left alone, the kernel traps its first use.
+const XSAVE_COMPONENTS: u32 = 0xe7;
+/// Offset of the header of the xsave area, zeroed before xsave which does not write all of it.
+const XSAVE_HEADER_OFFSET: i32 = 0x200;
+/// Length of the header of the xsave area.
+const XSAVE_HEADER_LEN: i32 = 0x40;
+/// Length of the fxsave area.
+const FXSAVE_LEN: i32 = 0x200;
+
+/// Alignment of the stack pointer at a call, required by the i386 psABI.
+const STACK_ALIGN: i32 = 0x10;
+/// Length of the area holding the arguments of the calls made by the second stage, at its stack pointer.
+const CALL_ARGS_LEN: i32 = (MAX_CALL_ARGS * 0x4).next_multiple_of(STACK_ALIGN as usize) as i32;
+
+/// Largest map the first stage can allocate, its length being a 32 bit immediate.
+pub const MAX_ALLOC_LEN: usize = u32::MAX as usize;
+
+/// Gets the base address to assemble a payload at `addr` with: the absolute addresses of its labels are encoded as
+/// signed 32 bit values, so the ones above 2 GiB are sign extended.
+fn base_addr(addr: usize) -> usize {
+    addr as u32 as i32 as isize as usize
+}
+
+/// Builds the first stage, at `ret.stage_addr`. It saves the context of the thread in a frame below the stack
+/// pointer of the hijacked call (i386 has no red zone), from the top:
+///
+/// - eflags
+/// - eax, ecx, edx, ebx, esp, ebp, esi, edi (pushad), edi being at the stack pointer
+///
+/// The second stage runs below this frame, and the tail unmapping it restores it.
+pub fn first_shellcode(var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
     debug!("Creating first_shellcode x86...");
 
-    let mut ops = dynasmrt::x86::Assembler::new().unwrap();
+    let mut ops = VecAssembler::<X86Relocation>::new(base_addr(ret.stage_addr));
     dynasm!(ops
         ; .arch x86
 
-        // Sync other threads to avoid the code to be executed multiple time
+        // Parking spot, replaced by a self jmp holding the new threads while the function is restored
         ; ->start:
+        ; .bytes [0x66, 0x90]
+
+        // Save the flags, before anything changes them
+        ; pushf
+
+        // Count the thread in the first stage
         ; push ebx
         ; mov ebx, var_addr as _
-        ; lock bts WORD [ebx], 0x0i8
-        ; jc ->start
+        ; lock add DWORD [ebx], MAILBOX_THREAD as _
         ; pop ebx
 
         // Save registers
         ; pushad
 
+        // Sync other threads to avoid the code to be executed multiple time: idle -> claimed
+        ; mov esi, var_addr as _
+        ; mov eax, [esi]
+        ; ->claim:
+        ; test al, MAILBOX_STATE as _
+        ; jnz ->wait_map
+        ; lea ecx, [eax + MailboxState::Claimed as _]
+        ; lock cmpxchg [esi], ecx
+        ; jnz ->claim
+        ; jmp ->claimed
+
+        // Wait for the map, and join the thread which claimed the stage there
+        ; ->wait_map:
+        ; pause
+        ; mov eax, [esi]
+        ; mov ecx, eax
+        ; and ecx, MAILBOX_STATE as _
+        ; cmp ecx, MailboxState::Failed as _
+        ; je ->leave
+        ; cmp ecx, MailboxState::Allocated as _
+        ; jb ->wait_map
+        ; and eax, !MAILBOX_FIELDS as _
+        ; jmp eax
+
+        // Write the protocol version in the mailbox block
+        ; ->claimed:
+        ; mov edx, block_addr as _
+        ; mov DWORD [edx + MAILBOX_BLOCK_VERSION_OFFSET as _], MAILBOX_VERSION as _
+
         // mmap2 call
         ; mov ebx, 0x0                     // addr     = 0
         ; mov ecx, alloc_len as _          // length   = alloc_len
-        ; mov edx, 0x7                     // prot     = PROT_READ | PROT_WRITE | PROT_EXEC
+        ; mov edx, map_prot(exec_len) as _
         ; mov esi, 0x22                    // flags    = MAP_PRIVATE | MAP_ANON
         ; mov edi, -1                      // fd       = NULL
         ; mov ebp, 0x0                     // offset   = 0
         ; mov eax, 0xc0                    // __NR_mmap2
         ; int 0x80u8 as _
+        ; cmp eax, -4095
+        ; jae ->mmap_failed
 
-        // Turn on the control variable bit
-        ; or al, 0x1
+        // Write a self jmp to the new allocated code
+        ; mov DWORD [eax], 0xfeeb
+    );
 
-        // move mmap addr value to our variable
-        ; mov ebx, var_addr as _
-        ; mov [ebx], eax
+    if let Some(exec_len) = exec_len {
+        dynasm!(ops
+            ; .arch x86
 
-        // turn off the control variable bit
-        ; xor al, al
+            // Make the code executable, and no longer writable
+            ; mov edi, eax
+            ; mov ebx, eax                     // addr     = map
+            ; mov ecx, exec_len as _           // length   = exec_len
+            ; mov edx, (PROT_READ | PROT_EXEC) as _
+            ; mov eax, SYS_MPROTECT
+            ; int 0x80u8 as _
+            ; test eax, eax
+            ; jnz ->mprotect_failed
+            ; mov eax, edi
+        );
+    }
 
-        // Write a self jmp to the new allocated code
-        ; mov DWORD [eax], 0xfeeb
-        // Jump to it
+    let claimed_to_allocated = MailboxState::Allocated as i32 - MailboxState::Claimed as i32;
+    dynasm!(ops
+        ; .arch x86
+
+        // Publish the map address next to the count: claimed -> allocated
+        ; mov esi, var_addr as _
+        ; lea ecx, [eax + claimed_to_allocated]
+        ; lock add [esi], ecx
+
+        // Jump to the self jmp
         ; jmp eax
 
-        ; .align 4
-        ; ->var_addr:
-        ; .qword var_addr as _
+        // Write the system call and its errno in the mailbox block, then publish them: claimed -> failed
+        ; ->mmap_failed:
+        ; neg eax
+        ; mov ecx, MAILBOX_SYSCALL_MMAP as _
+        ; ->failed:
+        ; mov edx, block_addr as _
+        ; mov [edx + MAILBOX_BLOCK_SYSCALL_OFFSET as _], ecx
+        ; mov [edx + MAILBOX_BLOCK_ERRNO_OFFSET as _], eax
+        ; mov esi, var_addr as _
+        ; lock add DWORD [esi], MailboxState::Failed as i32 - MailboxState::Claimed as i32
+
+        // Leave the first stage, and go back to the function once the injector has restored it
+        ; ->leave:
+        ; lock sub DWORD [esi], MAILBOX_THREAD as _
+        ; popad
+        ; popf
+        ; jmp DWORD [->return_addr]
+    );
+
+    if exec_len.is_some() {
+        dynasm!(ops
+            ; .arch x86
+
+            // Unmap the map, and publish the errno
+            ; ->mprotect_failed:
+            ; neg eax
+            ; mov esi, eax
+            ; mov ebx, edi                     // addr     = map
+            ; mov ecx, alloc_len as _          // length   = alloc_len
+            ; mov eax, SYS_MUNMAP
+            ; int 0x80u8 as _
+            ; mov eax, esi
+            ; mov ecx, MAILBOX_SYSCALL_MPROTECT as _
+            ; jmp ->failed
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x86
 
         ; .align 4
-        ; ->alloc_len:
-        ; .qword alloc_len as _ 
+        ; ->return_addr:
+        ; .dword ret.addr as _
     );
 
     match ops.finalize() {
@@ -63,63 +197,787 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
     }
 }
 
-pub fn raw_dlopen_shellcode(dlopen_addr: usize, dlopen_path: String, origin_hijack_addr: usize) -> Result<Vec<u8>, InjectionError> {
-    debug!("Creating raw_dlopen_shellcode x86 0x{:x} ...", origin_hijack_addr);
+pub fn raw_dlopen_shellcode(dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("Creating raw_dlopen_shellcode x86 0x{:x} ...", ret.addr);
 
-    // dlopen flags RTLD_NOW
-    let dlopen_flags: usize = 0x2;
     let mut ops = VecAssembler::<X86Relocation>::new(0);
+    let paths: Vec<_> = dlopen_paths.iter().map(|_| ops.new_dynamic_label()).collect();
+    enter(&mut ops);
+    handshake(&mut ops, config);
+
+    for (i, path) in paths.iter().enumerate() {
+        dynasm!(ops
+            ; .arch x86
+
+            // Store the path addr + the dlopen flags
+            ; lea ecx, [=>*path]
+            ; add ecx, ebx
+            ; mov [esp], ecx
+            ; mov DWORD [esp + 0x4], RTLD_NOW as _
+
+            // Call dlopen
+            ; mov eax, dl.dlopen as _
+            ; call eax
+        );
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, dl, entry);
 
     dynasm!(ops
         ; .arch x86
+        ; jmp ->done
+    );
 
-        // Get EIP value
-        ; call 0x0u8 as _
-        ; pop ebx
+    dlopen_failed(&mut ops, dl);
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Tell the injector we are done
+        ; ->done:
+        ; lea ecx, [->result_status]
+        ; mov DWORD [ebx + ecx], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (path, dlopen_path) in paths.iter().zip(dlopen_paths) {
+        dynasm!(ops
+            ; .arch x86
+            ; =>*path
+            ; .bytes dlopen_path.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    data_section(&mut ops, config.data_align);
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, dlopen_paths.len());
+    finalize_second_stage(ops)
+}
+
+pub fn memfd_dlopen_shellcode(dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("Creating memfd_dlopen_shellcode x86 0x{:x} ...", ret.addr);
+
+    let count = memfd_names.len();
+    let mut ops = VecAssembler::<X86Relocation>::new(0);
+    let names: Vec<_> = memfd_names.iter().map(|_| ops.new_dynamic_label()).collect();
+    enter(&mut ops);
+    handshake(&mut ops, config);
+
+    for (i, name) in names.iter().enumerate() {
+        let wait = ops.new_dynamic_label();
+
+        dynasm!(ops
+            ; .arch x86
+
+            // Mark the library as attempted
+            ; lea ecx, [->handles]
+            ; add ecx, ebx
+            ; mov DWORD [ecx + (i * 8) as i32], 0x0
+            ; mov DWORD [ecx + (i * 8 + 4) as i32], 0x0
+
+            // Create the memfd (ebx is needed by the syscall)
+            ; lea ecx, [=>*name]
+            ; add ecx, ebx
+            ; push ebx
+            ; mov ebx, ecx
+            ; mov ecx, MFD_CLOEXEC as _
+            ; mov eax, SYS_MEMFD_CREATE
+            ; int 0x80u8 as _
+            ; pop ebx
+            ; test eax, eax
+            ; js ->memfd_failed
+            ; lea ecx, [->memfds]
+            ; mov [ebx + ecx + (i * 4) as i32], eax
+
+            // Ask the injector to fill the memfd and its path, and wait for it
+            ; lea ecx, [->result_value]
+            ; mov [ebx + ecx], eax
+            ; lea ecx, [->result_status]
+            ; mov DWORD [ebx + ecx], RESULT_STATUS_REQUEST as _
+            ; =>wait
+            ; mov eax, SYS_SCHED_YIELD
+            ; int 0x80u8 as _
+            ; lea ecx, [->result_status]
+            ; cmp DWORD [ebx + ecx], RESULT_STATUS_RESUME as _
+            ; jne =>wait
+
+            // Call dlopen on /proc/self/fd/<memfd>
+            ; lea ecx, [->result_buffer]
+            ; add ecx, ebx
+            ; mov [esp], ecx
+            ; mov DWORD [esp + 0x4], RTLD_NOW as _
+            ; mov eax, dl.dlopen as _
+            ; call eax
+        );
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, dl, entry);
+
+    dynasm!(ops
+        ; .arch x86
+        ; jmp ->done
+    );
+
+    dlopen_failed(&mut ops, dl);
+
+    dynasm!(ops
+        ; .arch x86
+        ; jmp ->done
+
+        // Save the errno of memfd_create
+        ; ->memfd_failed:
+        ; neg eax
+        ; lea ecx, [->result_errno]
+        ; mov [ebx + ecx], eax
+
+        ; ->done:
+    );
+
+    // Close the memfds, the library mappings keep them alive. They are kept open until now,
+    // so each library gets its own /proc/self/fd/<memfd> path.
+    for i in 0..count {
+        let closed = ops.new_dynamic_label();
+
+        dynasm!(ops
+            ; .arch x86
+            ; lea ecx, [->memfds]
+            ; mov ecx, [ebx + ecx + (i * 4) as i32]
+            ; test ecx, ecx
+            ; js =>closed
+            ; push ebx
+            ; mov ebx, ecx
+            ; mov eax, SYS_CLOSE
+            ; int 0x80u8 as _
+            ; pop ebx
+            ; =>closed
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Tell the injector we are done
+        ; lea ecx, [->result_status]
+        ; mov DWORD [ebx + ecx], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (name, memfd_name) in names.iter().zip(memfd_names) {
+        dynasm!(ops
+            ; .arch x86
+            ; =>*name
+            ; .bytes memfd_name.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    data_section(&mut ops, config.data_align);
+
+    dynasm!(ops
+        ; .arch x86
+
+        // File descriptors of the memfds, one per library
+        ; ->memfds:
+    );
 
-        // Get the address of the lib to use for dlopen
-        ; lea eax, [->dlopen_path_addr]
-        ; add ebx, eax
-        ; add ebx, -0x5
-        ; mov eax, dlopen_addr as _
+    for _ in 0..count {
+        dynasm!(ops
+            ; .arch x86
+            ; .dword -1
+        );
+    }
+
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, count);
+    finalize_second_stage(ops)
+}
+
+pub fn raw_dlclose_shellcode(dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("Creating raw_dlclose_shellcode x86 0x{:x} ...", ret.addr);
+
+    let fini_addr = fini_addr.unwrap_or(0);
+
+    let mut ops = VecAssembler::<X86Relocation>::new(0);
+    enter(&mut ops);
+    handshake(&mut ops, config);
+
+    match library {
+        LoadedLibrary::Path(_) => dynasm!(ops
+            ; .arch x86
+
+            // Get a handle on the loaded library, without loading it again
+            ; lea ecx, [->dlopen_path_addr]
+            ; add ecx, ebx
+            ; mov [esp], ecx
+            ; mov DWORD [esp + 0x4], (RTLD_NOW | RTLD_NOLOAD) as _
+            ; mov eax, dl.dlopen as _
+            ; call eax
+            ; test eax, eax
+            ; jz ->not_loaded
+            ; mov esi, eax
+        ),
+        LoadedLibrary::Handle(handle) => dynasm!(ops
+            ; .arch x86
+
+            // The handle returned when the library was injected
+            ; mov esi, *handle as _
+        ),
+    }
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Call the fini hook if the library exports one
+        ; mov eax, fini_addr as _
+        ; test eax, eax
+        ; jz ->close
+        ; call eax
+        ; ->close:
+    );
+
+    if let LoadedLibrary::Path(_) = library {
+        dynasm!(ops
+            ; .arch x86
+
+            // Drop the reference we just took
+            ; mov [esp], esi
+            ; mov eax, dl.dlclose as _
+            ; call eax
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Drop the reference of the injection
+        ; mov [esp], esi
+        ; mov eax, dl.dlclose as _
+        ; call eax
+        ; lea ecx, [->result_value]
+        ; mov [ebx + ecx], eax
+        ; test eax, eax
+        ; jz ->closed
+        ; jmp ->failed
+
+        ; ->not_loaded:
+        ; lea ecx, [->result_value]
+        ; mov DWORD [ebx + ecx], -1
+        ; mov DWORD [ebx + ecx + 0x4], -1
+
+        // Save the dlerror() text
+        ; ->failed:
+        ; mov eax, dl.dlerror as _
+        ; call eax
+        ; lea ecx, [->result_error]
+        ; mov [ebx + ecx], eax
+
+        // Tell the injector we are done
+        ; ->closed:
+        ; lea ecx, [->result_status]
+        ; mov DWORD [ebx + ecx], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    if let LoadedLibrary::Path(dlopen_path) = library {
+        dynasm!(ops
+            ; .arch x86
+            ; ->dlopen_path_addr:
+            ; .bytes dlopen_path.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+pub fn call_shellcode(func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("Creating call_shellcode x86 0x{:x} ...", func_addr);
+
+    let mut ops = VecAssembler::<X86Relocation>::new(0);
+    let buffers: Vec<_> = args.iter().map(|_| ops.new_dynamic_label()).collect();
+    enter(&mut ops);
+    handshake(&mut ops, config);
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Clear errno
+        ; mov eax, errno_fn_addr as _
+        ; call eax
+        ; mov DWORD [eax], 0x0
+    );
+
+    // Store the arguments at the stack pointer, the first one at the lowest address (cdecl calling convention)
+    for (i, arg) in args.iter().enumerate() {
+        let offset = (i * 0x4) as i32;
+        match arg {
+            CallArg::Int(value) => dynasm!(ops
+                ; .arch x86
+                ; mov DWORD [esp + offset], *value as i32
+            ),
+            CallArg::Bytes(_) => dynasm!(ops
+                ; .arch x86
+                ; lea eax, [=>buffers[i]]
+                ; add eax, ebx
+                ; mov [esp + offset], eax
+            ),
+        }
+    }
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Call the function
+        ; mov eax, func_addr as _
+        ; call eax
+        ; lea ecx, [->result_value]
+        ; mov [ebx + ecx], eax
+
+        // Save errno
+        ; mov eax, errno_fn_addr as _
+        ; call eax
+        ; mov eax, DWORD [eax]
+        ; lea ecx, [->result_errno]
+        ; mov [ebx + ecx], eax
+
+        // Tell the injector we are done
+        ; lea ecx, [->result_status]
+        ; mov DWORD [ebx + ecx], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    // the called function may write in the buffers
+    data_section(&mut ops, config.data_align);
+
+    for (i, arg) in args.iter().enumerate() {
+        if let CallArg::Bytes(bytes) = arg {
+            dynasm!(ops
+                ; .arch x86
+                ; .align 4
+                ; =>buffers[i]
+                ; .bytes bytes
+            );
+        }
+    }
+
+    finalize_second_stage(ops)
+}
+
+pub fn shellcode_stage(shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+    debug!("Creating shellcode_stage x86 {} bytes, 0x{:x} ...", shellcode.len(), ret.addr);
+
+    let mut ops = VecAssembler::<X86Relocation>::new(0);
+    enter(&mut ops);
+    handshake(&mut ops, config);
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Call the shellcode, and save what it returned
+        ; call ->shellcode
+        ; lea ecx, [->result_value]
+        ; mov [ebx + ecx], eax
+
+        // Tell the injector we are done
+        ; lea ecx, [->result_status]
+        ; mov DWORD [ebx + ecx], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    dynasm!(ops
+        ; .arch x86
+        ; .align 16
+        ; ->shellcode:
+        ; .bytes shellcode
+    );
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+/// Keeps the base of the map in ebx, and makes a new call frame on the frame of the first stage.
+fn enter(ops: &mut VecAssembler<X86Relocation>) {
+    let get_pc = ops.new_dynamic_label();
+    let entered = ops.new_dynamic_label();
+    let pc = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x86
+        ; jmp =>entered
+
+        // Get EIP value with a call returning, to keep the return stack balanced
+        ; =>get_pc
+        ; mov ebx, [esp]
+        ; ret
+
+        // Keep the base of the map in ebx
+        ; =>entered
+        ; call =>get_pc
+        ; =>pc
+        ; lea ecx, [=>pc]
+        ; sub ebx, ecx
 
         // Make a new call frame
         ; push ebp
         ; mov ebp, esp
+    );
+}
+
+/// Lets a single thread run the second stage, once it has counted the threads left in the first stage.
+/// The others call the function, which the injector holds until it is restored.
+///
+/// The thread running the stage aligns the stack pointer below its call frame, and keeps `CALL_ARGS_LEN` bytes
+/// there for the arguments of its calls.
+fn handshake(ops: &mut VecAssembler<X86Relocation>, config: StageConfig) {
+    let wait = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x86
+
+        // The thread has left the first stage
+        ; mov eax, config.var_addr as _
+        ; lock sub DWORD [eax], MAILBOX_THREAD as _
+
+        // Only the first thread runs the stage: allocated -> stage2-ready
+        ; lea ecx, [->gate]
+        ; lock bts DWORD [ebx + ecx], 0x0i8
+        ; jc ->leave
+        ; lock add DWORD [eax], MailboxState::Stage2Ready as i32 - MailboxState::Allocated as i32
 
-        // Push the dlopen flags + path addr
-        ; push dlopen_flags as _
+        // Wait for the other threads to leave the first stage, and tell the injector: stage2-ready -> done
+        ; =>wait
+        ; pause
+        ; test DWORD [eax], MAILBOX_THREADS as _
+        ; jnz =>wait
+        ; lock add DWORD [eax], MailboxState::Done as i32 - MailboxState::Stage2Ready as i32
+        ; and esp, -STACK_ALIGN
+    );
+
+    if config.full_context {
+        save_context(ops);
+    }
+
+    dynasm!(ops
+        ; .arch x86
+        ; sub esp, CALL_ARGS_LEN
+    );
+}
+
+/// Saves the x87, SSE and AVX (up to AVX-512) registers below the stack, with xsave if the kernel enabled it,
+/// fxsave otherwise. The stack pointer stays aligned, and cpuid is called with the base of the map saved.
+fn save_context(ops: &mut VecAssembler<X86Relocation>) {
+    let fxsave = ops.new_dynamic_label();
+    let saved = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x86
+        ; push ebx
+        ; mov eax, 0x1
+        ; cpuid
+        ; bt ecx, OSXSAVE_BIT as _
+        ; jnc =>fxsave
+
+        // The size of the area depends on the components enabled in XCR0, and its header must be zeroed
+        ; mov eax, 0xd
+        ; xor ecx, ecx
+        ; cpuid
+        ; mov ecx, ebx
+        ; pop ebx
+        ; sub esp, ecx
+        ; and esp, -0x40
+    );
+
+    for offset in (XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + XSAVE_HEADER_LEN).step_by(4) {
+        dynasm!(ops
+            ; .arch x86
+            ; mov DWORD [esp + offset], 0x0
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x86
+        ; mov eax, XSAVE_COMPONENTS as _
+        ; xor edx, edx
+        ; xsave [esp]
+        ; jmp =>saved
+
+        ; =>fxsave
+        ; pop ebx
+        ; sub esp, FXSAVE_LEN as _
+        ; fxsave [esp]
+        ; =>saved
+    );
+}
+
+/// Restores the registers saved by `save_context`. The stack pointer is restored along with the call frame.
+fn restore_context(ops: &mut VecAssembler<X86Relocation>) {
+    let fxrstor = ops.new_dynamic_label();
+    let restored = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x86
         ; push ebx
+        ; mov eax, 0x1
+        ; cpuid
+        ; pop ebx
+        ; bt ecx, OSXSAVE_BIT as _
+        ; jnc =>fxrstor
+        ; mov eax, XSAVE_COMPONENTS as _
+        ; xor edx, edx
+        ; xrstor [esp]
+        ; jmp =>restored
+
+        ; =>fxrstor
+        ; fxrstor [esp]
+        ; =>restored
+    );
+}
+
+/// Saves the handle returned by dlopen for the library `index`, and stops loading if it failed.
+fn save_handle(ops: &mut VecAssembler<X86Relocation>, index: usize) {
+    dynasm!(ops
+        ; .arch x86
+        ; lea ecx, [->handles]
+        ; add ecx, ebx
+        ; mov [ecx + (index * 8) as i32], eax
+        ; mov DWORD [ecx + (index * 8 + 4) as i32], 0x0
+        ; test eax, eax
+        ; jz ->dlopen_failed
+    );
+}
+
+/// Calls the entry point of the last loaded library with its arguments, if one is set.
+fn call_entry(ops: &mut VecAssembler<X86Relocation>, dl: &DlFns, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+    dynasm!(ops
+        ; .arch x86
+
+        // Look for the entry point
+        ; lea ecx, [->entry_name]
+        ; add ecx, ebx
+        ; mov [esp], eax
+        ; mov [esp + 0x4], ecx
+        ; mov eax, dl.dlsym as _
+        ; call eax
+        ; test eax, eax
+        ; jz ->dlopen_failed
+
+        // Call it with a pointer to its arguments and their length
+        ; lea ecx, [->entry_args]
+        ; add ecx, ebx
+        ; mov [esp], ecx
+        ; mov DWORD [esp + 0x4], entry.args.len() as _
+        ; call eax
+        ; lea ecx, [->result_entry]
+        ; mov [ebx + ecx], eax
+    );
+}
+
+/// Appends the name of the entry point and its arguments to the data of the second stage, which it may write to.
+fn entry_data(ops: &mut VecAssembler<X86Relocation>, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    dynasm!(ops
+        ; .arch x86
+        ; ->entry_name:
+        ; .bytes entry.name.as_bytes()
+        ; .bytes [0x0]
+        ; .align 4
+        ; ->entry_args:
+        ; .bytes entry.args.as_slice()
+        ; .bytes [0x0]
+    );
+}
 
-        // Call dlopen
+/// Saves the dlerror() text when a library or the entry point could not be loaded.
+fn dlopen_failed(ops: &mut VecAssembler<X86Relocation>, dl: &DlFns) {
+    dynasm!(ops
+        ; .arch x86
+        ; ->dlopen_failed:
+        ; mov eax, dl.dlerror as _
         ; call eax
+        ; lea ecx, [->result_error]
+        ; mov [ebx + ecx], eax
+    );
+}
+
+/// Starts the data written by the second stage, aligned to `data_align` to be protected apart from its code.
+fn data_section(ops: &mut VecAssembler<X86Relocation>, data_align: usize) {
+    dynasm!(ops
+        ; .arch x86
+        ; .align data_align
+        ; ->data:
+    );
+}
+
+/// Appends the table of the handles returned by dlopen, one per library.
+fn handles_table(ops: &mut VecAssembler<X86Relocation>, count: usize) {
+    dynasm!(ops
+        ; .arch x86
+        ; .align 8
+        ; ->handles:
+    );
+
+    for _ in 0..count {
+        dynasm!(ops
+            ; .arch x86
+            ; .qword HANDLE_SKIPPED as _
+        );
+    }
+}
+
+/// Restores the call frame and jumps back to the hijacked function. The thread which ran the stage waits for the
+/// injector to release the map, and jumps to the tail unmapping it with the stack pointer back on the frame of the
+/// first stage. The others restore the registers saved by the first stage themselves, and jump through the return
+/// address of the tail.
+///
+/// Without a code cave for the tail, it follows on a page of its own, the pages before it being unmapped. With no
+/// return address at a known address, the threads then push it and return to it.
+fn restore_and_return(ops: &mut VecAssembler<X86Relocation>, ret: StageReturn, config: StageConfig) {
+    let release = ops.new_dynamic_label();
+
+    if config.full_context {
+        dynasm!(ops
+            ; .arch x86
+            ; add esp, CALL_ARGS_LEN
+        );
+
+        restore_context(ops);
+    }
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Wait for the injector to read the result block, and the other threads to leave the map
+        ; =>release
+        ; pause
+        ; lea ecx, [->result_release]
+        ; cmp DWORD [ebx + ecx], 0x0
+        ; je =>release
 
         // Restore the call frame
         ; mov esp, ebp
         ; pop ebp
 
-        // Restore the registers
+        // Jump to the tail with the map and its length as the arguments of munmap
+        ; lea ecx, [->result_release]
+        ; mov ecx, [ebx + ecx]
+    );
+
+    match ret.tail_addr {
+        Some(tail_addr) => dynasm!(ops
+            ; .arch x86
+            ; mov eax, tail_addr as _
+            ; jmp eax
+        ),
+        None => dynasm!(ops
+            ; .arch x86
+            ; jmp ->tail
+        ),
+    }
+
+    dynasm!(ops
+        ; .arch x86
+        ; ->leave:
+
+        // Restore the call frame
+        ; mov esp, ebp
+        ; pop ebp
+
+        // Restore the registers and the flags
         ; popad
-        
+        ; popf
+    );
+
+    match ret.tail_addr {
         // Jump back to the original hijack addr
-        ; push origin_hijack_addr as _
-        ; ret
+        Some(_) => dynasm!(ops
+            ; .arch x86
+            ; jmp DWORD [tail_return_slot(&ret) as i32]
+        ),
+        None => {
+            dynasm!(ops
+                ; .arch x86
+                ; push ret.addr as _
+                ; ret
 
-        ; .align 4
-        ; ->dlopen_flags:
-        ; .qword dlopen_flags as _
+                ; .align config.page_size
+                ; ->tail:
+            );
 
-        ; ->dlopen_path_addr:
-        ; .bytes dlopen_path.as_bytes()
-        ; .bytes [0x0]
+            in_map_tail(ops, ret);
+        }
+    }
+}
 
-        ; .align 4
-        ; ->dlopen:
-        ; .qword dlopen_addr as _
+/// Appends the result block read back by the injector, and assembles the second stage.
+fn finalize_second_stage(mut ops: VecAssembler<X86Relocation>) -> Result<SecondStage, InjectionError> {
+    dynasm!(ops
+        ; .arch x86
+        ; .align 8
 
-        ; .align 4
-        ; ->origin_hijack_addr:
-        ; .qword origin_hijack_addr as _
+        // claimed by the thread running the stage
+        ; ->gate:
+        ; .qword 0x0
+
+        ; ->result:
+        ; ->result_status:
+        ; .qword 0x0
+        ; ->result_value:
+        ; .qword 0x0
+        ; ->result_error:
+        ; .qword 0x0
+        ; ->result_errno:
+        ; .qword 0x0
+        ; ->result_entry:
+        ; .qword 0x0
+        ; ->result_release:
+        ; .qword 0x0
+        ; ->result_buffer:
+        ; .bytes [0x0; RESULT_BUFFER_SIZE]
+    );
+
+    let result_offset = label_offset(ops.labels(), "result")?;
+    let handles_offset = label_offset(ops.labels(), "handles").ok();
+    let data_offset = label_offset(ops.labels(), "data")?;
+    let tail_offset = label_offset(ops.labels(), "tail").ok();
+
+    match ops.finalize() {
+        Ok(shellcode) => Ok(SecondStage {
+            code: shellcode.to_vec(),
+            result_offset,
+            handles_offset,
+            data_offset,
+            tail_offset,
+        }),
+        Err(_) => Err(InjectionError::ShellcodeError),
+    }
+}
+
+pub fn self_jmp(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("Creating self_jmp x86 0x{:x} ...", ret.stage_addr);
+
+    let mut ops = dynasmrt::x86::Assembler::new().unwrap();
+
+    dynasm!(ops
+        ; .arch x86
+        ; ->self_jmp:
+        ; jmp ->self_jmp
     );
 
     match ops.finalize() {
@@ -127,3 +985,152 @@ pub fn raw_dlopen_shellcode(dlopen_addr: usize, dlopen_path: String, origin_hija
         Err(_) => Err(InjectionError::ShellcodeError),
     }
 }
+
+/// Gets the address of the return address ending the tail, which the second stage jumps through. The tail is
+/// written before the second stage, and restored once no thread is left in its map.
+fn tail_return_slot(ret: &StageReturn) -> usize {
+    ret.tail_addr.unwrap_or_default() + ret.tail_len - 0x4
+}
+
+pub fn unmap_tail(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("Creating unmap_tail x86 ...");
+
+    let mut ops = VecAssembler::<X86Relocation>::new(base_addr(ret.tail_addr.unwrap_or_default()));
+
+    dynasm!(ops
+        ; .arch x86
+
+        // Unmap the map, given in ebx and ecx
+        ; mov eax, SYS_MUNMAP
+        ; int 0x80u8 as _
+
+        // Restore the registers and the flags, and return to the hijacked function
+        ; popad
+        ; popf
+        ; jmp DWORD [->return_addr]
+
+        // last, see tail_return_slot
+        ; ->return_addr:
+        ; .dword ret.addr as _
+    );
+
+    match ops.finalize() {
+        Ok(shellcode) => Ok(shellcode.to_vec()),
+        Err(_) => Err(InjectionError::ShellcodeError),
+    }
+}
+
+/// Unmaps the map given in ebx and ecx from its page left mapped, restores the registers saved by the first stage
+/// and returns to the hijacked function.
+fn in_map_tail(ops: &mut VecAssembler<X86Relocation>, ret: StageReturn) {
+    dynasm!(ops
+        ; .arch x86
+
+        // Unmap the map, given in ebx and ecx
+        ; mov eax, SYS_MUNMAP
+        ; int 0x80u8 as _
+
+        // Restore the registers and the flags, and return to the hijacked function
+        ; popad
+        ; popf
+        ; push ret.addr as _
+        ; ret
+    );
+}
+
+/// Builds the payloads for the x86 processes.
+pub struct Backend;
+
+impl ArchBackend for Backend {
+    fn name(&self) -> &'static str {
+        "x86"
+    }
+
+    fn pointer_len(&self) -> usize {
+        4
+    }
+
+    fn syncs_icache(&self) -> bool {
+        false
+    }
+
+    fn max_alloc_len(&self) -> usize {
+        MAX_ALLOC_LEN
+    }
+
+    fn first_shellcode(&self, var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+        first_shellcode(var_addr, block_addr, ret, alloc_len, exec_len)
+    }
+
+    fn raw_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlopen_shellcode(dl, entry, dlopen_paths, ret, config)
+    }
+
+    fn raw_dlclose_shellcode(&self, dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlclose_shellcode(dl, fini_addr, library, ret, config)
+    }
+
+    fn call_shellcode(&self, func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        call_shellcode(func_addr, errno_fn_addr, args, ret, config)
+    }
+
+    fn memfd_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        memfd_dlopen_shellcode(dl, entry, memfd_names, ret, config)
+    }
+
+    fn shellcode_stage(&self, shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        shellcode_stage(shellcode, ret, config)
+    }
+
+    fn self_jmp(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        self_jmp(ret)
+    }
+
+    fn unmap_tail(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        unmap_tail(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns to a function above 2 GiB, as the libraries of a 32 bit process on a 64 bit kernel are.
+    fn high_stage_return() -> StageReturn {
+        StageReturn {
+            addr: 0xf7d8_1230,
+            tail_addr: Some(0xf7ff_0fc0),
+            tail_len: 0x0,
+            stage_addr: 0xf7d8_1230,
+            stage_len: 0x100,
+        }
+    }
+
+    /// Finds the `jmp DWORD [slot]` of a payload, and returns the address of its slot.
+    fn jmp_slot(code: &[u8]) -> u32 {
+        let at = code.windows(2).rposition(|bytes| bytes == [0xff, 0x25]).unwrap();
+        u32::from_le_bytes(code[at + 2..at + 6].try_into().unwrap())
+    }
+
+    #[test]
+    fn first_stage_above_2_gib() {
+        let ret = high_stage_return();
+        let code = first_shellcode(0xf7fa_0010, 0xf7fa_1ff0, ret, 0x2000, Some(0x1000)).unwrap();
+
+        // the function is left through the return address ending the stage
+        let slot = jmp_slot(&code) as usize;
+        assert_eq!(slot, ret.stage_addr + code.len() - 0x4);
+        assert_eq!(code[code.len() - 0x4..], (ret.addr as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn unmap_tail_above_2_gib() {
+        // the injector sizes the code cave with a first build
+        let mut ret = high_stage_return();
+        ret.tail_len = unmap_tail(ret).unwrap().len();
+        let code = unmap_tail(ret).unwrap();
+
+        assert_eq!(jmp_slot(&code) as usize, tail_return_slot(&ret));
+        assert_eq!(code[code.len() - 0x4..], (ret.addr as u32).to_le_bytes());
+    }
+}
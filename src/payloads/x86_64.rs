@@ -1,19 +1,69 @@
 use crate::error::InjectionError;
+use crate::payloads::{
+    label_offset, map_prot, ArchBackend, CallArg, DlFns, Entry, LoadedLibrary, MailboxState, SecondStage, StageConfig, StageReturn, HANDLE_SKIPPED,
+    MAILBOX_BLOCK_ERRNO_OFFSET, MAILBOX_BLOCK_SYSCALL_OFFSET, MAILBOX_BLOCK_VERSION_OFFSET, MAILBOX_FIELDS, MAILBOX_STATE, MAILBOX_SYSCALL_MMAP,
+    MAILBOX_SYSCALL_MPROTECT, MAILBOX_THREAD, MAILBOX_THREADS, MAILBOX_VERSION, MAX_CALL_ARGS, MFD_CLOEXEC, PROT_EXEC, PROT_READ, RESULT_BUFFER_SIZE,
+    RESULT_STATUS_DONE, RESULT_STATUS_REQUEST, RESULT_STATUS_RESUME, RTLD_NOLOAD, RTLD_NOW,
+};
 
+use dynasmrt::x64::Assembler;
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi};
 
-pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, InjectionError> {
+const SYS_CLOSE: i32 = 3;
+const SYS_MPROTECT: i32 = 10;
+const SYS_MUNMAP: i32 = 11;
+const SYS_SCHED_YIELD: i32 = 24;
+const SYS_MEMFD_CREATE: i32 = 319;
+
+/// Bit of ecx set by cpuid leaf 1 when the kernel enabled xsave.
+const OSXSAVE_BIT: u8 = 27;
+/// Components saved by xsave: x87, SSE, AVX and the AVX-512 ones. AMX is left alone, the kernel traps its first use.
+const XSAVE_COMPONENTS: u32 = 0xe7;
+/// Offset of the header of the xsave area, zeroed before xsave which does not write all of it.
+const XSAVE_HEADER_OFFSET: i32 = 0x200;
+/// Length of the header of the xsave area.
+const XSAVE_HEADER_LEN: i32 = 0x40;
+/// Length of the fxsave area.
+const FXSAVE_LEN: i32 = 0x200;
+
+/// Length of the red zone below the stack pointer, which the hijacked code may use without moving it.
+const RED_ZONE_LEN: i32 = 0x80;
+/// Alignment of the stack pointer at a call, required by the System V psABI.
+const STACK_ALIGN: i32 = 0x10;
+
+/// Largest map the first stage can allocate, its length being a sign-extended 32 bit immediate.
+pub const MAX_ALLOC_LEN: usize = i32::MAX as usize;
+
+/// Registers of the integer arguments in the System V calling convention: rdi, rsi, rdx, rcx, r8, r9.
+const CALL_ARG_REGS: [u8; MAX_CALL_ARGS] = [7, 6, 2, 1, 8, 9];
+
+/// Builds the first stage. It saves the context of the thread in a frame below the stack pointer of the hijacked
+/// call, from the top:
+///
+/// - the red zone of the hijacked code, skipped (`RED_ZONE_LEN` bytes)
+/// - rflags
+/// - rax, rbx, rcx, rdx, rbp, rsi, rdi, r8 to r15, r15 being at the stack pointer
+///
+/// The second stage runs below this frame, and the tail unmapping it restores it (see `restore_registers`).
+pub fn first_shellcode(var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
     debug!("creating first_shellcode x64");
 
     let mut ops = dynasmrt::x64::Assembler::new().unwrap();
     dynasm!(ops
         ; .arch x64
 
+        // Parking spot, replaced by a self jmp holding the new threads while the function is restored
         ; ->start:
+        ; .bytes [0x66, 0x90]
+
+        // Skip the red zone of the hijacked code, and save the flags before anything changes them
+        ; lea rsp, [rsp - RED_ZONE_LEN]
+        ; pushfq
+
+        // Count the thread in the first stage
         ; push rax
         ; mov rax, [->var_addr]
-        ; lock bts DWORD [rax], 0x0
-        ; jc ->start
+        ; lock add QWORD [rax], MAILBOX_THREAD as _
         ; pop rax
 
         // Save registers
@@ -33,35 +83,133 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
         ; push r14
         ; push r15
 
+        // Sync other threads to avoid the code to be executed multiple time: idle -> claimed
+        ; mov rbx, [->var_addr]
+        ; mov rax, [rbx]
+        ; ->claim:
+        ; test al, MAILBOX_STATE as _
+        ; jnz ->wait_map
+        ; lea rcx, [rax + MailboxState::Claimed as _]
+        ; lock cmpxchg [rbx], rcx
+        ; jnz ->claim
+        ; jmp ->claimed
+
+        // Wait for the map, and join the thread which claimed the stage there
+        ; ->wait_map:
+        ; pause
+        ; mov rax, [rbx]
+        ; mov ecx, eax
+        ; and ecx, MAILBOX_STATE as _
+        ; cmp ecx, MailboxState::Failed as _
+        ; je ->leave
+        ; cmp ecx, MailboxState::Allocated as _
+        ; jb ->wait_map
+        ; and rax, !MAILBOX_FIELDS as _
+        ; jmp rax
+
+        // Write the protocol version in the mailbox block
+        ; ->claimed:
+        ; mov r14, [->block_addr]
+        ; mov DWORD [r14 + MAILBOX_BLOCK_VERSION_OFFSET as _], MAILBOX_VERSION as _
+
         // mmap call
         ; mov rax, 0x9                  // __NR_mmap
         ; mov rdi, 0                    // addr     = 0
         ; mov rsi, alloc_len as _       // length   = alloc_len
-        ; mov rdx, 0x7                  // prot     = PROT_READ | PROT_WRITE | PROT_EXEC
+        ; mov rdx, map_prot(exec_len) as _
         ; mov r10, 0x22                 // flags    = MAP_PRIVATE | MAP_ANON
         ; mov r8, 0                     // fd       = NULL
         ; mov r9, 0                     // offset   = 0
         ; syscall
+        ; cmp rax, -4095
+        ; jae ->mmap_failed
 
-        // Turn on the control variable bit
-        ; or al, 0x1
+        // Write a self jmp to the new allocated code
+        ; mov DWORD [rax], 0xfeeb
+    );
 
-        // move mmap addr value to our variable
-        ; mov rbx, [->var_addr]
-        ; mov [rbx], rax
+    if let Some(exec_len) = exec_len {
+        dynasm!(ops
+            ; .arch x64
 
-        // turn off the control variable bit
-        ; xor al, al
+            // Make the code executable, and no longer writable
+            ; mov r12, rax
+            ; mov rdi, rax                  // addr     = map
+            ; mov rsi, exec_len as _        // length   = exec_len
+            ; mov rdx, (PROT_READ | PROT_EXEC) as _
+            ; mov rax, SYS_MPROTECT
+            ; syscall
+            ; test rax, rax
+            ; jnz ->mprotect_failed
+            ; mov rax, r12
+        );
+    }
 
-        // Write a self jmp to the new allocated code
-        ; mov DWORD [rax], 0xfeeb
-        // Jump to it
+    let claimed_to_allocated = MailboxState::Allocated as i32 - MailboxState::Claimed as i32;
+    dynasm!(ops
+        ; .arch x64
+
+        // Publish the map address next to the count: claimed -> allocated
+        ; lea rcx, [rax + claimed_to_allocated]
+        ; lock add [rbx], rcx
+
+        // Jump to the self jmp
         ; jmp rax
 
+        // Write the system call and its errno in the mailbox block, then publish them: claimed -> failed
+        ; ->mmap_failed:
+        ; neg rax
+        ; mov ecx, MAILBOX_SYSCALL_MMAP as _
+        ; ->failed:
+        ; mov [r14 + MAILBOX_BLOCK_SYSCALL_OFFSET as _], ecx
+        ; mov [r14 + MAILBOX_BLOCK_ERRNO_OFFSET as _], eax
+        ; lock add QWORD [rbx], MailboxState::Failed as i32 - MailboxState::Claimed as i32
+
+        // Leave the first stage, and go back to the function once the injector has restored it
+        ; ->leave:
+        ; lock sub QWORD [rbx], MAILBOX_THREAD as _
+    );
+
+    restore_registers(&mut ops);
+
+    dynasm!(ops
+        ; .arch x64
+        ; jmp QWORD [->return_addr]
+    );
+
+    if exec_len.is_some() {
+        dynasm!(ops
+            ; .arch x64
+
+            // Unmap the map, and publish the errno
+            ; ->mprotect_failed:
+            ; neg rax
+            ; mov r13, rax
+            ; mov rdi, r12                  // addr     = map
+            ; mov rsi, alloc_len as _       // length   = alloc_len
+            ; mov rax, SYS_MUNMAP
+            ; syscall
+            ; mov rax, r13
+            ; mov ecx, MAILBOX_SYSCALL_MPROTECT as _
+            ; jmp ->failed
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x64
+
         ; .align 4
         ; ->var_addr:
         ; .qword var_addr as _
 
+        ; .align 4
+        ; ->block_addr:
+        ; .qword block_addr as _
+
+        ; .align 4
+        ; ->return_addr:
+        ; .qword ret.addr as _
+
         ; .align 4
         ; ->alloc_len:
         ; .qword alloc_len as _
@@ -74,30 +222,687 @@ pub fn first_shellcode(var_addr: usize, alloc_len: usize) -> Result<Vec<u8>, Inj
 }
 
 pub fn raw_dlopen_shellcode(
-    dlopen_addr: usize,
-    dlopen_path: String,
-    origin_hijack_addr: usize,
-) -> Result<Vec<u8>, InjectionError> {
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    dlopen_paths: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
     debug!(
         "raw_dlopen_shellcode x64 0x{:x}, 0x{:x}",
-        dlopen_addr, origin_hijack_addr
+        dl.dlopen, ret.addr
     );
 
-    // dlopen flags RTLD_NOW
-    let dlopen_flags: usize = 0x2;
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    let paths: Vec<_> = dlopen_paths.iter().map(|_| ops.new_dynamic_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, path) in paths.iter().enumerate() {
+        dynasm!(ops
+            ; .arch x64
+
+            // Call dlopen with 2 arguments
+            ; mov rsi, RTLD_NOW as _
+            ; lea rdi, [=>*path]
+
+            ; mov rax, QWORD dl.dlopen as _
+            ; call rax
+        );
 
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, dl, entry);
+
+    dynasm!(ops
+        ; .arch x64
+        ; jmp ->done
+    );
+
+    dlopen_failed(&mut ops, dl);
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Tell the injector we are done
+        ; ->done:
+        ; mov QWORD [->result_status], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (path, dlopen_path) in paths.iter().zip(dlopen_paths) {
+        dynasm!(ops
+            ; .arch x64
+            ; =>*path
+            ; .bytes dlopen_path.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    data_section(&mut ops, config.data_align);
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, dlopen_paths.len());
+    finalize_second_stage(ops)
+}
+
+pub fn memfd_dlopen_shellcode(
+    dl: &DlFns,
+    entry: Option<&Entry>,
+    memfd_names: &[String],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!(
+        "memfd_dlopen_shellcode x64 0x{:x}, 0x{:x}",
+        dl.dlopen, ret.addr
+    );
+
+    let count = memfd_names.len();
     let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    let names: Vec<_> = memfd_names.iter().map(|_| ops.new_dynamic_label()).collect();
+    handshake(&mut ops, config);
+
+    for (i, name) in names.iter().enumerate() {
+        let wait = ops.new_dynamic_label();
+
+        dynasm!(ops
+            ; .arch x64
+
+            // Mark the library as attempted
+            ; lea rcx, [->handles]
+            ; mov QWORD [rcx + (i * 8) as i32], 0x0
+
+            // Create the memfd
+            ; mov rax, SYS_MEMFD_CREATE
+            ; lea rdi, [=>*name]
+            ; mov rsi, MFD_CLOEXEC as _
+            ; syscall
+            ; test rax, rax
+            ; js ->memfd_failed
+            ; lea rcx, [->memfds]
+            ; mov [rcx + (i * 8) as i32], rax
+
+            // Ask the injector to fill the memfd and its path, and wait for it
+            ; mov [->result_value], rax
+            ; mov QWORD [->result_status], RESULT_STATUS_REQUEST as _
+            ; =>wait
+            ; mov rax, SYS_SCHED_YIELD
+            ; syscall
+            ; cmp QWORD [->result_status], RESULT_STATUS_RESUME as _
+            ; jne =>wait
+
+            // Call dlopen on /proc/self/fd/<memfd>
+            ; mov rsi, RTLD_NOW as _
+            ; lea rdi, [->result_buffer]
+            ; mov rax, QWORD dl.dlopen as _
+            ; call rax
+        );
+
+        save_handle(&mut ops, i);
+    }
+
+    call_entry(&mut ops, dl, entry);
+
+    dynasm!(ops
+        ; .arch x64
+        ; jmp ->done
+    );
+
+    dlopen_failed(&mut ops, dl);
+
+    dynasm!(ops
+        ; .arch x64
+        ; jmp ->done
+
+        // Save the errno of memfd_create
+        ; ->memfd_failed:
+        ; neg eax
+        ; mov [->result_errno], eax
+
+        ; ->done:
+    );
+
+    // Close the memfds, the library mappings keep them alive. They are kept open until now,
+    // so each library gets its own /proc/self/fd/<memfd> path.
+    for i in 0..count {
+        let closed = ops.new_dynamic_label();
+
+        dynasm!(ops
+            ; .arch x64
+            ; lea rcx, [->memfds]
+            ; mov rdi, [rcx + (i * 8) as i32]
+            ; test rdi, rdi
+            ; js =>closed
+            ; mov rax, SYS_CLOSE
+            ; syscall
+            ; =>closed
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Tell the injector we are done
+        ; mov QWORD [->result_status], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    for (name, memfd_name) in names.iter().zip(memfd_names) {
+        dynasm!(ops
+            ; .arch x64
+            ; =>*name
+            ; .bytes memfd_name.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    data_section(&mut ops, config.data_align);
+
+    dynasm!(ops
+        ; .arch x64
+
+        // File descriptors of the memfds, one per library
+        ; ->memfds:
+    );
+
+    for _ in 0..count {
+        dynasm!(ops
+            ; .arch x64
+            ; .qword -1
+        );
+    }
+
+    entry_data(&mut ops, entry);
+    handles_table(&mut ops, count);
+    finalize_second_stage(ops)
+}
+
+pub fn raw_dlclose_shellcode(
+    dl: &DlFns,
+    fini_addr: Option<usize>,
+    library: &LoadedLibrary,
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!(
+        "raw_dlclose_shellcode x64 0x{:x}, 0x{:x}",
+        dl.dlclose, ret.addr
+    );
+
+    let fini_addr = fini_addr.unwrap_or(0);
+
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    handshake(&mut ops, config);
+
+    match library {
+        LoadedLibrary::Path(_) => dynasm!(ops
+            ; .arch x64
+
+            // Get a handle on the loaded library, without loading it again
+            ; mov rsi, (RTLD_NOW | RTLD_NOLOAD) as _
+            ; lea rdi, [->dlopen_path_addr]
+            ; mov rax, QWORD dl.dlopen as _
+            ; call rax
+            ; test rax, rax
+            ; jz ->not_loaded
+            ; mov rbx, rax
+        ),
+        LoadedLibrary::Handle(handle) => dynasm!(ops
+            ; .arch x64
+
+            // The handle returned when the library was injected
+            ; mov rbx, QWORD *handle as _
+        ),
+    }
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Call the fini hook if the library exports one
+        ; mov rax, QWORD fini_addr as _
+        ; test rax, rax
+        ; jz ->close
+        ; call rax
+        ; ->close:
+    );
+
+    if let LoadedLibrary::Path(_) = library {
+        dynasm!(ops
+            ; .arch x64
+
+            // Drop the reference we just took
+            ; mov rdi, rbx
+            ; mov rax, QWORD dl.dlclose as _
+            ; call rax
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Drop the reference of the injection
+        ; mov rdi, rbx
+        ; mov rax, QWORD dl.dlclose as _
+        ; call rax
+        ; mov [->result_value], rax
+        ; test rax, rax
+        ; jz ->closed
+        ; jmp ->failed
+
+        ; ->not_loaded:
+        ; mov QWORD [->result_value], -1
+
+        // Save the dlerror() text
+        ; ->failed:
+        ; mov rax, QWORD dl.dlerror as _
+        ; call rax
+        ; mov [->result_error], rax
+
+        // Tell the injector we are done
+        ; ->closed:
+        ; mov QWORD [->result_status], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    if let LoadedLibrary::Path(dlopen_path) = library {
+        dynasm!(ops
+            ; .arch x64
+            ; ->dlopen_path_addr:
+            ; .bytes dlopen_path.as_bytes()
+            ; .bytes [0x0]
+        );
+    }
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+pub fn call_shellcode(
+    func_addr: usize,
+    errno_fn_addr: usize,
+    args: &[CallArg],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!(
+        "call_shellcode x64 0x{:x}, 0x{:x}",
+        func_addr, ret.addr
+    );
+
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    let buffers: Vec<_> = args.iter().map(|_| ops.new_dynamic_label()).collect();
+    handshake(&mut ops, config);
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Clear errno
+        ; mov rax, QWORD errno_fn_addr as _
+        ; call rax
+        ; mov DWORD [rax], 0x0
+    );
+
+    // Load the arguments, following the System V calling convention
+    for (i, arg) in args.iter().enumerate() {
+        let reg = CALL_ARG_REGS[i];
+        match arg {
+            CallArg::Int(value) => dynasm!(ops
+                ; .arch x64
+                ; mov Rq(reg), QWORD *value as _
+            ),
+            CallArg::Bytes(_) => dynasm!(ops
+                ; .arch x64
+                ; lea Rq(reg), [=>buffers[i]]
+            ),
+        }
+    }
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Call the function, with no vector registers used in case it is variadic
+        ; mov r11, QWORD func_addr as _
+        ; xor eax, eax
+        ; call r11
+        ; mov [->result_value], rax
+
+        // Save errno
+        ; mov rax, QWORD errno_fn_addr as _
+        ; call rax
+        ; mov eax, DWORD [rax]
+        ; mov [->result_errno], eax
+
+        // Tell the injector we are done
+        ; mov QWORD [->result_status], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    // the called function may write in the buffers
+    data_section(&mut ops, config.data_align);
+
+    for (i, arg) in args.iter().enumerate() {
+        if let CallArg::Bytes(bytes) = arg {
+            dynasm!(ops
+                ; .arch x64
+                ; .align 8
+                ; =>buffers[i]
+                ; .bytes bytes
+            );
+        }
+    }
+
+    finalize_second_stage(ops)
+}
+
+pub fn shellcode_stage(
+    shellcode: &[u8],
+    ret: StageReturn,
+    config: StageConfig,
+) -> Result<SecondStage, InjectionError> {
+    debug!(
+        "shellcode_stage x64 {} bytes, 0x{:x}",
+        shellcode.len(),
+        ret.addr
+    );
+
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    handshake(&mut ops, config);
+    dynasm!(ops
+        ; .arch x64
+
+        // Call the shellcode, and save what it returned
+        ; call ->shellcode
+        ; mov [->result_value], rax
+
+        // Tell the injector we are done
+        ; mov QWORD [->result_status], RESULT_STATUS_DONE as _
+    );
+
+    restore_and_return(&mut ops, ret, config);
+
+    dynasm!(ops
+        ; .arch x64
+        ; .align 16
+        ; ->shellcode:
+        ; .bytes shellcode
+    );
+
+    data_section(&mut ops, config.data_align);
+    finalize_second_stage(ops)
+}
+
+/// Saves the handle returned by dlopen for the library `index`, and stops loading if it failed.
+fn save_handle(ops: &mut Assembler, index: usize) {
     dynasm!(ops
         ; .arch x64
+        ; lea rcx, [->handles]
+        ; mov [rcx + (index * 8) as i32], rax
+        ; test rax, rax
+        ; jz ->dlopen_failed
+    );
+}
+
+/// Lets a single thread run the second stage, once it has counted the threads left in the first stage.
+/// The others call the function, which the injector holds until it is restored.
+///
+/// The thread running the stage keeps the frame of the first stage in rbp, and aligns the stack pointer below it
+/// for the calls of the stage.
+fn handshake(ops: &mut Assembler, config: StageConfig) {
+    let wait = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x64
+        ; ->map:
+
+        // The thread has left the first stage
+        ; mov rax, QWORD config.var_addr as _
+        ; lock sub QWORD [rax], MAILBOX_THREAD as _
+
+        // Only the first thread runs the stage: allocated -> stage2-ready
+        ; lock bts QWORD [->gate], 0x0
+        ; jc ->leave
+        ; lock add QWORD [rax], MailboxState::Stage2Ready as i32 - MailboxState::Allocated as i32
+
+        // Wait for the other threads to leave the first stage, and tell the injector: stage2-ready -> done
+        ; =>wait
+        ; pause
+        ; test QWORD [rax], MAILBOX_THREADS as _
+        ; jnz =>wait
+        ; lock add QWORD [rax], MailboxState::Done as i32 - MailboxState::Stage2Ready as i32
+
+        ; mov rbp, rsp
+        ; and rsp, -STACK_ALIGN
+    );
+
+    if config.full_context {
+        save_context(ops);
+    }
+}
 
-        // Call dlopen with 2 arguments
-        ; mov rsi, dlopen_flags as _
-        ; lea rdi, [->dlopen_path_addr]
+/// Saves the x87, SSE and AVX (up to AVX-512) registers below the stack, with xsave if the kernel enabled it,
+/// fxsave otherwise. The stack pointer stays aligned.
+fn save_context(ops: &mut Assembler) {
+    let fxsave = ops.new_dynamic_label();
+    let saved = ops.new_dynamic_label();
 
-        ; mov rax, QWORD dlopen_addr as _
+    dynasm!(ops
+        ; .arch x64
+        ; mov eax, 0x1
+        ; cpuid
+        ; bt ecx, OSXSAVE_BIT as _
+        ; jnc =>fxsave
+
+        // The size of the area depends on the components enabled in XCR0, and its header must be zeroed
+        ; mov eax, 0xd
+        ; xor ecx, ecx
+        ; cpuid
+        ; sub rsp, rbx
+        ; and rsp, -0x40
+    );
+
+    for offset in (XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + XSAVE_HEADER_LEN).step_by(8) {
+        dynasm!(ops
+            ; .arch x64
+            ; mov QWORD [rsp + offset], 0x0
+        );
+    }
+
+    dynasm!(ops
+        ; .arch x64
+        ; mov eax, XSAVE_COMPONENTS as _
+        ; xor edx, edx
+        ; xsave [rsp]
+        ; jmp =>saved
+
+        ; =>fxsave
+        ; sub rsp, FXSAVE_LEN as _
+        ; fxsave [rsp]
+        ; =>saved
+    );
+}
+
+/// Restores the registers saved by `save_context`.
+fn restore_context(ops: &mut Assembler) {
+    let fxrstor = ops.new_dynamic_label();
+    let restored = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x64
+        ; mov eax, 0x1
+        ; cpuid
+        ; bt ecx, OSXSAVE_BIT as _
+        ; jnc =>fxrstor
+        ; mov eax, XSAVE_COMPONENTS as _
+        ; xor edx, edx
+        ; xrstor [rsp]
+        ; jmp =>restored
+
+        ; =>fxrstor
+        ; fxrstor [rsp]
+        ; =>restored
+    );
+}
+
+/// Calls the entry point of the last loaded library with its arguments, if one is set.
+fn call_entry(ops: &mut Assembler, dl: &DlFns, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+    dynasm!(ops
+        ; .arch x64
+
+        // Look for the entry point
+        ; mov rdi, rax
+        ; lea rsi, [->entry_name]
+        ; mov rax, QWORD dl.dlsym as _
+        ; call rax
+        ; test rax, rax
+        ; jz ->dlopen_failed
+
+        // Call it with a pointer to its arguments and their length
+        ; lea rdi, [->entry_args]
+        ; mov rsi, QWORD entry.args.len() as _
+        ; call rax
+        ; mov [->result_entry], rax
+    );
+}
+
+/// Appends the name of the entry point and its arguments to the data of the second stage, which it may write to.
+fn entry_data(ops: &mut Assembler, entry: Option<&Entry>) {
+    let Some(entry) = entry else {
+        return;
+    };
+
+    dynasm!(ops
+        ; .arch x64
+        ; ->entry_name:
+        ; .bytes entry.name.as_bytes()
+        ; .bytes [0x0]
+        ; .align 8
+        ; ->entry_args:
+        ; .bytes entry.args.as_slice()
+        ; .bytes [0x0]
+    );
+}
+
+/// Saves the dlerror() text when a library or the entry point could not be loaded.
+fn dlopen_failed(ops: &mut Assembler, dl: &DlFns) {
+    dynasm!(ops
+        ; .arch x64
+        ; ->dlopen_failed:
+        ; mov rax, QWORD dl.dlerror as _
         ; call rax
+        ; mov [->result_error], rax
+    );
+}
+
+/// Starts the data written by the second stage, aligned to `data_align` to be protected apart from its code.
+fn data_section(ops: &mut Assembler, data_align: usize) {
+    dynasm!(ops
+        ; .arch x64
+        ; .align data_align
+        ; ->data:
+    );
+}
+
+/// Appends the table of the handles returned by dlopen, one per library.
+fn handles_table(ops: &mut Assembler, count: usize) {
+    dynasm!(ops
+        ; .arch x64
+        ; .align 8
+        ; ->handles:
+    );
+
+    for _ in 0..count {
+        dynasm!(ops
+            ; .arch x64
+            ; .qword HANDLE_SKIPPED as _
+        );
+    }
+}
+
+/// Jumps back to the hijacked function. The thread which ran the stage waits for the injector to release the map,
+/// and jumps to the tail unmapping it with the stack pointer back on the frame of the first stage. The others
+/// restore the registers saved by the first stage themselves.
+///
+/// Without a code cave for the tail, it follows on a page of its own, the pages before it being unmapped.
+fn restore_and_return(ops: &mut Assembler, ret: StageReturn, config: StageConfig) {
+    let release = ops.new_dynamic_label();
+
+    if config.full_context {
+        restore_context(ops);
+    }
+
+    dynasm!(ops
+        ; .arch x64
+        ; mov rsp, rbp
+
+        // Wait for the injector to read the result block, and the other threads to leave the map
+        ; =>release
+        ; pause
+        ; cmp QWORD [->result_release], 0x0
+        ; je =>release
 
-        // Restore the registers
+        // Jump to the tail with the map and its length as the arguments of munmap
+        ; lea rdi, [->map]
+        ; mov rsi, [->result_release]
+    );
+
+    match ret.tail_addr {
+        Some(_) => dynasm!(ops
+            ; .arch x64
+            ; jmp QWORD [->tail_addr]
+        ),
+        None => dynasm!(ops
+            ; .arch x64
+            ; jmp ->tail
+        ),
+    }
+
+    dynasm!(ops
+        ; .arch x64
+        ; ->leave:
+    );
+
+    restore_registers(ops);
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Jump back to the original hijack addr
+        ; jmp QWORD [->origin_hijack_addr]
+
+        ; .align 4
+        ; ->origin_hijack_addr:
+        ; .qword ret.addr as _
+    );
+
+    match ret.tail_addr {
+        Some(tail_addr) => dynasm!(ops
+            ; .arch x64
+            ; .align 4
+            ; ->tail_addr:
+            ; .qword tail_addr as _
+        ),
+        None => {
+            dynasm!(ops
+                ; .arch x64
+                ; .align config.page_size
+                ; ->tail:
+            );
+            tail(ops, ret);
+        }
+    }
+}
+
+/// Restores the registers and the flags saved by the first stage, and the stack pointer of the hijacked call.
+fn restore_registers(ops: &mut Assembler) {
+    dynasm!(ops
+        ; .arch x64
         ; pop r15
         ; pop r14
         ; pop r13
@@ -113,26 +918,64 @@ pub fn raw_dlopen_shellcode(
         ; pop rcx
         ; pop rbx
         ; pop rax
+        ; popfq
+        ; lea rsp, [rsp + RED_ZONE_LEN]
+    );
+}
 
-        // Jump back to the original hijack addr
-        ; push QWORD [->origin_hijack_addr]
-        ; ret
+/// Appends the result block read back by the injector, and assembles the second stage.
+fn finalize_second_stage(mut ops: Assembler) -> Result<SecondStage, InjectionError> {
+    dynasm!(ops
+        ; .arch x64
+        ; .align 8
 
-        ; .align 4
-        ; ->dlopen_flags:
-        ; .qword dlopen_flags as _
+        // claimed by the thread running the stage
+        ; ->gate:
+        ; .qword 0x0
 
-        ; ->dlopen_path_addr:
-        ; .bytes dlopen_path.as_bytes()
-        ; .bytes [0x0]
+        ; ->result:
+        ; ->result_status:
+        ; .qword 0x0
+        ; ->result_value:
+        ; .qword 0x0
+        ; ->result_error:
+        ; .qword 0x0
+        ; ->result_errno:
+        ; .qword 0x0
+        ; ->result_entry:
+        ; .qword 0x0
+        ; ->result_release:
+        ; .qword 0x0
+        ; ->result_buffer:
+        ; .bytes [0x0; RESULT_BUFFER_SIZE]
+    );
 
-        ; .align 4
-        ; ->dlopen:
-        ; .qword dlopen_addr as _
+    let result_offset = label_offset(ops.labels(), "result")?;
+    let handles_offset = label_offset(ops.labels(), "handles").ok();
+    let data_offset = label_offset(ops.labels(), "data")?;
+    let tail_offset = label_offset(ops.labels(), "tail").ok();
 
-        ; .align 4
-        ; ->origin_hijack_addr:
-        ; .qword origin_hijack_addr as _
+    match ops.finalize() {
+        Ok(shellcode) => Ok(SecondStage {
+            code: shellcode.to_vec(),
+            result_offset,
+            handles_offset,
+            data_offset,
+            tail_offset,
+        }),
+        Err(_) => Err(InjectionError::ShellcodeError),
+    }
+}
+
+pub fn self_jmp(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("self_jmp x64 0x{:x}", ret.stage_addr);
+
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+
+    dynasm!(ops
+        ; .arch x64
+        ; ->self_jmp:
+        ; jmp ->self_jmp
     );
 
     match ops.finalize() {
@@ -140,3 +983,94 @@ pub fn raw_dlopen_shellcode(
         Err(_) => Err(InjectionError::ShellcodeError),
     }
 }
+
+pub fn unmap_tail(ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+    debug!("unmap_tail x64");
+
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    tail(&mut ops, ret);
+
+    match ops.finalize() {
+        Ok(shellcode) => Ok(shellcode.to_vec()),
+        Err(_) => Err(InjectionError::ShellcodeError),
+    }
+}
+
+/// Unmaps the map given in rdi and rsi, restores the registers saved by the first stage and jumps to `ret.addr`,
+/// from a code cave or from the page of the map left mapped.
+fn tail(ops: &mut Assembler, ret: StageReturn) {
+    let return_addr = ops.new_dynamic_label();
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Unmap the map, given in rdi and rsi
+        ; mov eax, SYS_MUNMAP
+        ; syscall
+    );
+
+    restore_registers(ops);
+
+    dynasm!(ops
+        ; .arch x64
+
+        // Return to the hijacked function
+        ; jmp QWORD [=>return_addr]
+
+        ; =>return_addr
+        ; .qword ret.addr as _
+    );
+}
+
+/// Builds the payloads for the x86_64 processes.
+pub struct Backend;
+
+impl ArchBackend for Backend {
+    fn name(&self) -> &'static str {
+        "x86_64"
+    }
+
+    fn pointer_len(&self) -> usize {
+        8
+    }
+
+    fn syncs_icache(&self) -> bool {
+        false
+    }
+
+    fn max_alloc_len(&self) -> usize {
+        MAX_ALLOC_LEN
+    }
+
+    fn first_shellcode(&self, var_addr: usize, block_addr: usize, ret: StageReturn, alloc_len: usize, exec_len: Option<usize>) -> Result<Vec<u8>, InjectionError> {
+        first_shellcode(var_addr, block_addr, ret, alloc_len, exec_len)
+    }
+
+    fn raw_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, dlopen_paths: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlopen_shellcode(dl, entry, dlopen_paths, ret, config)
+    }
+
+    fn raw_dlclose_shellcode(&self, dl: &DlFns, fini_addr: Option<usize>, library: &LoadedLibrary, ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        raw_dlclose_shellcode(dl, fini_addr, library, ret, config)
+    }
+
+    fn call_shellcode(&self, func_addr: usize, errno_fn_addr: usize, args: &[CallArg], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        call_shellcode(func_addr, errno_fn_addr, args, ret, config)
+    }
+
+    fn memfd_dlopen_shellcode(&self, dl: &DlFns, entry: Option<&Entry>, memfd_names: &[String], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        memfd_dlopen_shellcode(dl, entry, memfd_names, ret, config)
+    }
+
+    fn shellcode_stage(&self, shellcode: &[u8], ret: StageReturn, config: StageConfig) -> Result<SecondStage, InjectionError> {
+        shellcode_stage(shellcode, ret, config)
+    }
+
+    fn self_jmp(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        self_jmp(ret)
+    }
+
+    fn unmap_tail(&self, ret: StageReturn) -> Result<Vec<u8>, InjectionError> {
+        unmap_tail(ret)
+    }
+}
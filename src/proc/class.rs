@@ -1,5 +0,0 @@
-/// A enum that represents the class of a process (32 bit or 64 bit).
-pub enum ProcClass {
-    ThirtyTwo,
-    SixtyFour,
-}
\ No newline at end of file
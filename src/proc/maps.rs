@@ -1,20 +1,17 @@
 use proc_maps::{get_process_maps, MapRange, Pid};
 
 
-use crate::utils::resolv::RemoteModule;
+use crate::utils::resolv::{DynamicLinker, RemoteModule};
 
 use crate::error::InjectionError;
 
-use super::mem::Mem;
-
 pub struct Maps {
     pid: i32,
-    mem: Mem,
 }
 
 impl Maps {
-    pub fn new(pid: i32, mem: Mem) -> Result<Self, InjectionError> {
-        Ok(Self { pid, mem })
+    pub fn new(pid: i32) -> Result<Self, InjectionError> {
+        Ok(Self { pid })
     }
 
     fn maps(&self) -> Result<Vec<MapRange>, InjectionError> {
@@ -49,17 +46,44 @@ impl Maps {
         Ok(maps_by_name)
     }
 
-    fn module_bytes(&mut self, module_name: &str) -> Result<Vec<u8>, InjectionError> {
-        let maps = self.maps_by_name(module_name)?;
-        let mut module_bytes: Vec<u8> = Vec::new();
-
-        for map in maps {
-            module_bytes.resize(map.offset, 0);
-            let mut buf = self.mem.read(map.start(), map.size())?;
-            module_bytes.append(&mut buf);
+    /// Is a module whose file name starts with `module_name` mapped ?
+    pub fn contains(&self, module_name: &str) -> Result<bool, InjectionError> {
+        match self.maps_by_name(module_name) {
+            Ok(_) => Ok(true),
+            Err(InjectionError::ModuleNotFound) => Ok(false),
+            Err(err) => Err(err),
         }
+    }
+
+    /// Is `addr` in one of the maps ?
+    pub fn is_mapped(&self, addr: usize) -> Result<bool, InjectionError> {
+        Ok(self.maps()?.iter().any(|map| map.start() <= addr && addr < map.start() + map.size()))
+    }
+
+    /// Gets the main executable of the process, from the file `/proc/<id>/exe` links to.
+    pub fn executable(&mut self) -> Result<RemoteModule, InjectionError> {
+        let path = std::fs::read_link(format!("/proc/{}/exe", self.pid))?;
+        let maps = self.maps()?;
+        let map = maps
+            .iter()
+            .find(|map| map.filename() == Some(path.as_path()))
+            .ok_or(InjectionError::ModuleNotFound)?;
+        Ok(RemoteModule::new(
+            path.to_str().unwrap(),
+            map.start(),
+            self.pid,
+            self.dynamic_linker()?,
+        ))
+    }
 
-        Ok(module_bytes)
+    /// Gets the dynamic linker mapped in the process, or the one of the libc of the injector without one.
+    pub fn dynamic_linker(&self) -> Result<DynamicLinker, InjectionError> {
+        Ok(self
+            .maps()?
+            .iter()
+            .filter_map(|map| map.filename()?.file_name()?.to_str())
+            .find_map(DynamicLinker::from_file_name)
+            .unwrap_or_default())
     }
 
     pub fn module(&mut self, module_name: &str) -> Result<RemoteModule, InjectionError> {
@@ -67,7 +91,8 @@ impl Maps {
         Ok(RemoteModule::new(
             maps[0].filename().unwrap().to_str().unwrap(),
             maps[0].start(),
-            self.module_bytes(module_name)?,
+            self.pid,
+            self.dynamic_linker()?,
         ))
     }
 }
\ No newline at end of file
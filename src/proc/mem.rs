@@ -22,6 +22,16 @@ impl Mem {
         Ok(Self { fd })
     }
 
+    /// Opens `/proc/<pid>/mem` for reading only, the returned [`Mem`] can not write.
+    pub fn read_only(pid: i32) -> Result<Self, InjectionError> {
+        let mem_path: String = format!("/proc/{}/mem", pid);
+        debug!("Opening {} for reading", mem_path);
+
+        let fd = File::open(&mem_path).map_err(|_| InjectionError::OpenMemoryError)?;
+
+        Ok(Self { fd })
+    }
+
     pub fn read(&mut self, addr: usize, len: usize) -> Result<Vec<u8>, InjectionError> {
         debug!(
             "reading from remote memory: addr: 0x{:x}, len: {}",
@@ -35,7 +45,7 @@ impl Mem {
         Ok(ret)
     }
 
-    pub fn write(&mut self, addr: usize, buf: &Vec<u8>) -> Result<(), InjectionError> {
+    pub fn write(&mut self, addr: usize, buf: &[u8]) -> Result<(), InjectionError> {
         debug!(
             "writing into remote memory: addr: 0x{:x}, len: {}",
             addr,
@@ -44,4 +54,24 @@ impl Mem {
 
         self.fd.write_all_at(buf, addr as u64).map_err(|_x| InjectionError::WriteMemoryError)
     }
-}
\ No newline at end of file
+
+    /// Reads a NUL-terminated string from remote memory, up to `max_len` bytes.
+    pub fn read_c_string(&mut self, addr: usize, max_len: usize) -> Result<String, InjectionError> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        // read by small chunks, the string may end close to the end of its mapping
+        while bytes.len() < max_len {
+            let chunk = self.read(addr + bytes.len(), 0x10)?;
+            match chunk.iter().position(|b| *b == 0x0) {
+                Some(end) => {
+                    bytes.extend_from_slice(&chunk[..end]);
+                    break;
+                }
+                None => bytes.extend_from_slice(&chunk),
+            }
+        }
+
+        bytes.truncate(max_len);
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+}
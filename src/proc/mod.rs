@@ -1,21 +1,26 @@
-pub mod class;
 pub mod maps;
 pub mod mem;
+pub mod task;
 
 
 use std::{
-    fs::{File, ReadDir},
+    fs::{File, OpenOptions, ReadDir},
     io::Error as IoError,
     io::Read,
     os::unix::prelude::MetadataExt,
     path::PathBuf,
 };
 use goblin::elf::Elf;
-use goblin::elf::header::{EM_386, EM_ARM, EM_AARCH64, EM_X86_64};
 
 use crate::error::InjectionError;
-use class::ProcClass;
+use crate::payloads::TargetArch;
 use maps::Maps;
+use task::Task;
+
+/// End of the auxiliary vector.
+const AT_NULL: usize = 0;
+/// Entry of the auxiliary vector giving the page size.
+const AT_PAGESZ: usize = 6;
 
 pub type Gid = u32;
 pub type Uid = u32;
@@ -68,7 +73,7 @@ impl Proc {
 
     /// Reads `/proc/<id>/maps` of the current [`Proc`].
     pub fn maps(&self) -> Result<Maps, InjectionError> {
-        maps::Maps::new(self.pid, mem::Mem::new(self.pid).unwrap())
+        maps::Maps::new(self.pid)
     }
 
     /// Reads `/proc/<id>/mem` of the current [`Proc`].
@@ -76,33 +81,84 @@ impl Proc {
         mem::Mem::new(self.pid)
     }
 
+    /// Reads `/proc/<id>/mem` of the current [`Proc`], without opening it for writing.
+    pub fn mem_read_only(&self) -> Result<mem::Mem, InjectionError> {
+        mem::Mem::read_only(self.pid)
+    }
+
     /// Reads `/proc/<id>/syscall` of the current [`Proc`].
     pub fn syscall(&self) -> Result<File, IoError> {
         File::open(self.path.join("syscall"))
     }
 
+    /// Opens `/proc/<id>/fd/<fd>` of the current [`Proc`] for writing.
+    pub fn fd(&self, fd: usize) -> Result<File, IoError> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path.join("fd").join(fd.to_string()))
+    }
+
     /// Reads `/proc/<id>/task` of the current [`Proc`].
     pub fn task(&self) -> Result<ReadDir, IoError> {
         std::fs::read_dir(self.path.join("task"))
     }
 
-    /// Return the class type of the executable to inject
-    pub fn class(&self) -> Option<ProcClass> {
+    /// Reads the threads of the current [`Proc`], from `/proc/<id>/task`.
+    ///
+    /// The threads which exit while they are read are left out.
+    pub fn tasks(&self) -> Result<Vec<Task>, InjectionError> {
+        let mut tasks = Vec::new();
+        for entry in self.task()? {
+            match Task::new(&entry?.path()) {
+                Ok(task) => tasks.push(task),
+                Err(InjectionError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(tasks)
+    }
+
+    /// Reads the page size of the current [`Proc`], from the `AT_PAGESZ` entry of `/proc/<id>/auxv`.
+    pub fn page_size(&self) -> Result<usize, InjectionError> {
+        let word_len = self.arch().ok_or(InjectionError::UnsupportedArch)?.backend().pointer_len();
+
+        // the auxiliary vector is made of (type, value) pairs of words, ended by AT_NULL
+        let auxv = std::fs::read(self.path.join("auxv"))?;
+        auxv.chunks_exact(word_len * 2)
+            .map(|entry| {
+                let mut words = entry.chunks_exact(word_len).map(|word| {
+                    let mut value = [0x0; 0x8];
+                    value[..word_len].copy_from_slice(word);
+                    u64::from_le_bytes(value) as usize
+                });
+                (words.next().unwrap(), words.next().unwrap())
+            })
+            .take_while(|&(key, _)| key != AT_NULL)
+            .find(|&(key, _)| key == AT_PAGESZ)
+            .map(|(_, value)| value)
+            .ok_or(InjectionError::RemoteProcessError)
+    }
+
+    /// Return the architecture of the executable to inject, if this build of the injector supports it
+    pub fn arch(&self) -> Option<TargetArch> {
         let mut header = [0_u8; 0x40];
         self.exe().ok()?.read_exact(&mut header).ok()?;
 
         let header = Elf::parse_header(&header).ok()?;
 
-        match header.e_machine {
-            #[cfg(target_arch = "aarch64")]
-            EM_ARM => Some(ProcClass::ThirtyTwo),
-            #[cfg(target_arch = "aarch64")]
-            EM_AARCH64 => Some(ProcClass::SixtyFour),
-            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-            EM_386 => Some(ProcClass::ThirtyTwo),
-            #[cfg(target_arch = "x86_64")]
-            EM_X86_64 => Some(ProcClass::SixtyFour),
-            _ => None,
+        TargetArch::from_elf_header(&header).filter(TargetArch::is_native)
+    }
+
+    /// Is the process still running ? A zombie process is not.
+    pub fn is_running(&self) -> bool {
+        let Ok(stat) = std::fs::read_to_string(self.path.join("stat")) else {
+            return false;
+        };
+
+        // the state follows the command name, which may contain spaces and parentheses
+        match stat.rsplit_once(')') {
+            Some((_, rest)) => !matches!(rest.trim_start().chars().next(), Some('Z' | 'X')),
+            None => false,
         }
     }
 
@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use crate::error::InjectionError;
+
+/// What a thread is doing, as read from [`/proc/<id>/task/<tid>/syscall`](https://man7.org/linux/man-pages/man5/proc.5.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskSyscall {
+    /// The thread is running, its registers can't be read.
+    Running,
+    /// The thread is blocked, but not in a system call (e.g. on a page fault).
+    Blocked { sp: usize, pc: usize },
+    /// The thread is blocked in the system call `nr`.
+    InSyscall { nr: usize, sp: usize, pc: usize },
+}
+
+impl TaskSyscall {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |field: &str| match field.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => field.parse::<usize>().ok(),
+        };
+
+        match fields.as_slice() {
+            ["running"] => Some(TaskSyscall::Running),
+            ["-1", sp, pc] => Some(TaskSyscall::Blocked {
+                sp: number(sp)?,
+                pc: number(pc)?,
+            }),
+            [nr, .., sp, pc] if fields.len() == 9 => Some(TaskSyscall::InSyscall {
+                nr: number(nr)?,
+                sp: number(sp)?,
+                pc: number(pc)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A thread of a process, referencing the `/proc/<id>/task/<tid>` directory.
+#[derive(Debug, Clone)]
+pub struct Task {
+    pub tid: i32,
+    /// Name of the thread, as found in `comm`.
+    pub name: String,
+    /// State of the thread (`R` running, `S` sleeping, `D` waiting on I/O, ...).
+    pub state: char,
+    pub syscall: TaskSyscall,
+    /// Time the thread has spent on a CPU: in nanoseconds from `schedstat`, or in clock ticks from `stat`
+    /// on kernels without it. It only makes sense compared to itself.
+    pub cpu_time: u64,
+}
+
+impl Task {
+    /// Reads the `stat`, `syscall` and `schedstat` files of the task directory at `path`.
+    pub(crate) fn new(path: &Path) -> Result<Self, InjectionError> {
+        let tid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<i32>().ok())
+            .ok_or(InjectionError::RemoteProcessError)?;
+
+        // the name is between parentheses and may contain spaces and parentheses itself
+        let stat = std::fs::read_to_string(path.join("stat"))?;
+        let (name, rest) = stat
+            .split_once('(')
+            .and_then(|(_, rest)| rest.rsplit_once(')'))
+            .ok_or(InjectionError::RemoteProcessError)?;
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let state = fields
+            .first()
+            .and_then(|state| state.chars().next())
+            .ok_or(InjectionError::RemoteProcessError)?;
+
+        // utime and stime, the 14th and 15th fields counting from the pid
+        let ticks = fields
+            .get(11..13)
+            .and_then(|times| times.iter().map(|time| time.parse::<u64>().ok()).sum::<Option<u64>>())
+            .ok_or(InjectionError::RemoteProcessError)?;
+        let cpu_time = std::fs::read_to_string(path.join("schedstat"))
+            .ok()
+            .and_then(|schedstat| schedstat.split_whitespace().next()?.parse::<u64>().ok())
+            .unwrap_or(ticks);
+
+        let syscall = std::fs::read_to_string(path.join("syscall"))?;
+        let syscall = TaskSyscall::parse(&syscall).ok_or(InjectionError::InstructionPointerNotFound)?;
+
+        Ok(Self {
+            tid,
+            name: name.to_string(),
+            state,
+            syscall,
+            cpu_time,
+        })
+    }
+}
@@ -0,0 +1,439 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+use crate::proc::task::TaskSyscall;
+use crate::trigger::WaitKind;
+use crate::Staging;
+
+/// The outcome of an injection, as reported back by the second stage.
+#[derive(Debug, Clone)]
+pub struct InjectionReport {
+    /// Address of the hijacked function, where the first stage was written.
+    pub first_stage_addr: usize,
+    /// Address of the map allocated by the first stage, where the second stage was written.
+    pub second_stage_addr: usize,
+    /// Is the map gone from `/proc/<id>/maps`, once the second stage has unmapped it ?
+    pub second_stage_unmapped: bool,
+    /// The injected libraries, in the order they were loaded (empty if a shellcode was injected).
+    pub libraries: Vec<LibraryReport>,
+    /// Value returned by the entry point of the library if one was set and found, or by the shellcode.
+    pub entry_value: Option<usize>,
+    /// Text returned by `dlerror()` in the target when the entry point was not found.
+    pub error: Option<String>,
+    /// Length of a pointer in the target, the width of `entry_value`.
+    pub pointer_len: usize,
+}
+
+impl InjectionReport {
+    /// Are all the libraries loaded (or the shellcode run) in the target ?
+    pub fn is_loaded(&self) -> bool {
+        match self.libraries.is_empty() {
+            true => self.entry_value.is_some(),
+            false => self.libraries.iter().all(LibraryReport::is_loaded),
+        }
+    }
+}
+
+impl fmt::Display for InjectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "first stage:  0x{:x}", self.first_stage_addr)?;
+        write!(f, "second stage: {}", second_stage(self.second_stage_addr, self.second_stage_unmapped))?;
+        for library in &self.libraries {
+            write!(f, "\n{}", library)?;
+        }
+        if let Some(value) = self.entry_value {
+            write!(f, "\nentry:        0x{:x} ({})", value, signed(value, self.pointer_len))?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, "\nerror:        {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of the loading of one library.
+#[derive(Debug, Clone)]
+pub struct LibraryReport {
+    /// Path of the library, as given to the injector.
+    pub path: String,
+    /// Handle returned by `dlopen` in the target (0 if it failed), or `None` if a previous library failed.
+    pub handle: Option<usize>,
+    /// Text returned by `dlerror()` in the target when `dlopen` failed.
+    pub error: Option<String>,
+}
+
+impl LibraryReport {
+    /// Is the library loaded in the target ?
+    pub fn is_loaded(&self) -> bool {
+        self.handle.is_some_and(|handle| handle != 0)
+    }
+}
+
+impl fmt::Display for LibraryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "library:      {}", self.path)?;
+        match (self.handle, &self.error) {
+            (None, _) => write!(f, " (skipped)"),
+            (Some(_), Some(error)) => write!(f, " (failed: {})", error),
+            (Some(handle), None) => write!(f, " (handle 0x{:x})", handle),
+        }
+    }
+}
+
+/// The outcome of an ejection, as reported back by the second stage.
+#[derive(Debug, Clone)]
+pub struct EjectionReport {
+    /// Address of the map allocated by the first stage, where the second stage was written.
+    pub second_stage_addr: usize,
+    /// Is the map gone from `/proc/<id>/maps`, once the second stage has unmapped it ?
+    pub second_stage_unmapped: bool,
+    /// Was the `goauld_fini` export of the library called ?
+    pub fini_called: bool,
+    /// Is the library gone from `/proc/<id>/maps` ?
+    pub unloaded: bool,
+    /// Text returned by `dlerror()` in the target when `dlclose` failed.
+    pub error: Option<String>,
+}
+
+impl fmt::Display for EjectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "second stage: {}", second_stage(self.second_stage_addr, self.second_stage_unmapped))?;
+        writeln!(f, "fini called:  {}", self.fini_called)?;
+        write!(f, "unloaded:     {}", self.unloaded)?;
+        if let Some(error) = &self.error {
+            write!(f, "\nerror:        {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Type of the value returned by a function called in the target. The register holding it is as wide as a
+/// pointer, but only the bits of the type are defined (e.g. the upper half of rax or x0 for an `int`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReturnType {
+    /// A C `int`, 32 bit wide on every supported architecture.
+    Int,
+    /// A `long`, a `ssize_t` or a pointer, as wide as a pointer of the target.
+    #[default]
+    Long,
+}
+
+/// The outcome of a function called in the target.
+#[derive(Debug, Clone)]
+pub struct CallReport {
+    /// Address of the map allocated by the first stage, where the second stage was written.
+    pub second_stage_addr: usize,
+    /// Is the map gone from `/proc/<id>/maps`, once the second stage has unmapped it ?
+    pub second_stage_unmapped: bool,
+    /// Value returned by the function.
+    pub value: usize,
+    /// Value of `errno` after the function returned (cleared before the call).
+    pub errno: i32,
+    /// Length of a pointer in the target, the width of `value`.
+    pub pointer_len: usize,
+    /// Type `value` is read as, a `long` unless set otherwise.
+    pub return_type: ReturnType,
+}
+
+impl CallReport {
+    /// Gets the value returned by the function, sign extended from the width of its return type.
+    pub fn signed_value(&self) -> i64 {
+        match self.return_type {
+            ReturnType::Int => signed(self.value, 4),
+            ReturnType::Long => signed(self.value, self.pointer_len),
+        }
+    }
+}
+
+impl fmt::Display for CallReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.signed_value();
+        let hex = match self.return_type {
+            ReturnType::Int => value as u32 as u64,
+            ReturnType::Long => self.value as u64,
+        };
+
+        writeln!(f, "second stage: {}", second_stage(self.second_stage_addr, self.second_stage_unmapped))?;
+        writeln!(f, "value:        {} (0x{:x})", value, hex)?;
+        write!(f, "errno:        {}", self.errno)
+    }
+}
+
+/// The choice of the function to hijack, made from the system calls the threads of the target are blocked in.
+#[derive(Debug, Clone)]
+pub struct TriggerReport {
+    /// The threads of the target, as they were when the choice was made.
+    pub threads: Vec<ThreadReport>,
+    /// The libc functions considered, the best ranked first.
+    pub candidates: Vec<TriggerCandidate>,
+    /// Name of the function to hijack: the best candidate not rejected, or the one set before.
+    pub function: String,
+    /// Is `function` one of the candidates ?
+    pub chosen: bool,
+}
+
+impl fmt::Display for TriggerReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for thread in &self.threads {
+            writeln!(f, "{}", thread)?;
+        }
+        for candidate in &self.candidates {
+            writeln!(f, "{}", candidate)?;
+        }
+        match self.chosen {
+            true => write!(f, "trigger:      {}", self.function),
+            false => write!(f, "trigger:      {} (no candidate left, kept)", self.function),
+        }
+    }
+}
+
+/// A thread of the target, along with the system call it is blocked in.
+#[derive(Debug, Clone)]
+pub struct ThreadReport {
+    pub tid: i32,
+    pub name: String,
+    /// State of the thread (`R` running, `S` sleeping, `D` waiting on I/O, ...).
+    pub state: char,
+    pub syscall: TaskSyscall,
+    /// Name of the system call, if it is one a wrapper may be hijacked for.
+    pub syscall_name: Option<&'static str>,
+}
+
+impl fmt::Display for ThreadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "thread:       {} {} ({}) ", self.tid, self.name, self.state)?;
+        match (self.syscall, self.syscall_name) {
+            (TaskSyscall::Running, _) => write!(f, "running"),
+            (TaskSyscall::Blocked { .. }, _) => write!(f, "blocked outside of a system call"),
+            (TaskSyscall::InSyscall { .. }, Some(name)) => write!(f, "in {}", name),
+            (TaskSyscall::InSyscall { nr, .. }, None) => write!(f, "in system call {}", nr),
+        }
+    }
+}
+
+/// A libc function which may be hijacked, because threads are blocked in a system call it issues.
+#[derive(Debug, Clone)]
+pub struct TriggerCandidate {
+    pub symbol: &'static str,
+    /// Address of the function in the target (0 if it is not exported).
+    pub addr: usize,
+    /// The system call issued by the function, as the first thread considered is blocked in it.
+    pub syscall: &'static str,
+    pub kind: WaitKind,
+    /// Number of threads blocked in a system call issued by the function.
+    pub threads: usize,
+    /// Sum of the weights of these threads, according to how soon they should call the function again.
+    pub score: usize,
+    /// Why the function can't be hijacked safely, or would not be called.
+    pub rejected: Option<String>,
+}
+
+impl fmt::Display for TriggerCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "candidate:    {}, score {} ({} thread(s) in {}, which {})",
+            self.symbol, self.score, self.threads, self.syscall, self.kind
+        )?;
+        if let Some(reason) = &self.rejected {
+            write!(f, ": rejected, {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// What an injection would do, built without writing anything in the target.
+#[derive(Debug, Clone, Serialize)]
+pub struct InjectionPlan {
+    /// Pid of the target.
+    pub pid: i32,
+    /// Backend assembling the payloads.
+    pub backend: &'static str,
+    /// How the libraries are made available to the target (`None` if a shellcode is injected).
+    pub staging: Option<Staging>,
+    /// The libraries to load, in order.
+    pub libraries: Vec<PlannedLibrary>,
+    /// Export of the last library called once it is loaded.
+    pub entry: Option<String>,
+    /// The functions called by the second stage.
+    pub symbols: Vec<PlannedSymbol>,
+    /// The hijacked function overwritten by the first stage, or the code cave it is written in.
+    pub function: PlannedPatch,
+    /// The GOT slot pointed to the first stage, when the function is hijacked through it.
+    pub got: Option<PlannedPatch>,
+    /// The code cave the tail unmapping the second stage is written in, if it is not in its map.
+    pub tail: Option<PlannedPatch>,
+    /// The variable used as a mailbox by the first stage.
+    pub mailbox: PlannedPatch,
+    /// The data cave the first stage writes the mailbox block in.
+    pub mailbox_block: PlannedPatch,
+    /// Version of the mailbox protocol, checked in the mailbox block.
+    pub mailbox_version: u32,
+    /// Size of the first stage.
+    pub first_stage_size: usize,
+    /// Size of the second stage.
+    pub second_stage_size: usize,
+    /// Length of the map allocated by the first stage, a multiple of the page size.
+    pub alloc_len: usize,
+    /// Page size of the target.
+    pub page_size: usize,
+    /// Length of the code of the second stage made executable by the first stage, in W^X mode.
+    pub exec_len: Option<usize>,
+    /// Are the FP/SIMD registers saved by the second stage, along with the general purpose ones and the flags ?
+    pub full_context: bool,
+}
+
+/// A library to load.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedLibrary {
+    /// Path of the library, as given to the injector.
+    pub path: String,
+    /// Path the library is loaded from in the target.
+    pub staged_path: String,
+}
+
+/// A function of the target called by the second stage.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedSymbol {
+    /// Name of the symbol.
+    pub name: &'static str,
+    /// Address of the symbol in the target.
+    #[serde(serialize_with = "serialize_hex")]
+    pub addr: usize,
+}
+
+/// A location of the target overwritten during the injection.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedPatch {
+    /// Name of the symbol.
+    pub symbol: String,
+    /// Address of the symbol in the target.
+    #[serde(serialize_with = "serialize_hex")]
+    pub addr: usize,
+    /// Bytes which would be overwritten, then restored.
+    #[serde(serialize_with = "serialize_bytes")]
+    pub original_bytes: Vec<u8>,
+}
+
+impl fmt::Display for InjectionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pid:          {}", self.pid)?;
+        writeln!(f, "backend:      {}", self.backend)?;
+        match self.staging {
+            Some(Staging::TmpFile) => writeln!(f, "staging:      tmp file")?,
+            Some(Staging::Memfd) => writeln!(f, "staging:      memfd")?,
+            None => writeln!(f, "staging:      shellcode")?,
+        }
+        for library in &self.libraries {
+            writeln!(f, "library:      {} -> {}", library.path, library.staged_path)?;
+        }
+        if let Some(entry) = &self.entry {
+            writeln!(f, "entry:        {}", entry)?;
+        }
+        for symbol in &self.symbols {
+            writeln!(f, "symbol:       {} at 0x{:x}", symbol.name, symbol.addr)?;
+        }
+        writeln!(f, "function:     {}", self.function)?;
+        if let Some(got) = &self.got {
+            writeln!(f, "got slot:     {}", got)?;
+        }
+        match &self.tail {
+            Some(tail) => writeln!(f, "tail:         {}", tail)?,
+            None => writeln!(f, "tail:         in the second stage map, left mapped")?,
+        }
+        writeln!(f, "mailbox:      {} (protocol v{})", self.mailbox, self.mailbox_version)?;
+        writeln!(f, "block:        {}", self.mailbox_block)?;
+        writeln!(f, "first stage:  {} bytes", self.first_stage_size)?;
+        write!(
+            f,
+            "second stage: {} bytes (map of {} bytes, pages of {} bytes)",
+            self.second_stage_size, self.alloc_len, self.page_size
+        )?;
+        if let Some(exec_len) = self.exec_len {
+            write!(f, "\nw^x:          {} bytes of code made executable, the rest writable", exec_len)?;
+        }
+        match self.full_context {
+            true => write!(f, "\ncontext:      registers, flags and FP/SIMD registers saved"),
+            false => write!(f, "\ncontext:      registers and flags saved"),
+        }
+    }
+}
+
+impl fmt::Display for PlannedPatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at 0x{:x}, {} bytes overwritten: {}",
+            self.symbol,
+            self.addr,
+            self.original_bytes.len(),
+            hex(&self.original_bytes)
+        )
+    }
+}
+
+/// Reads a value returned by the target as a signed integer of its pointer width.
+fn signed(value: usize, pointer_len: usize) -> i64 {
+    match pointer_len {
+        4 => value as u32 as i32 as i64,
+        _ => value as i64,
+    }
+}
+
+/// Formats the address of the map of a second stage, and whether it has been unmapped.
+fn second_stage(addr: usize, unmapped: bool) -> String {
+    match unmapped {
+        true => format!("0x{:x} (unmapped)", addr),
+        false => format!("0x{:x} (still mapped)", addr),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn serialize_hex<S: Serializer>(value: &usize, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{:x}", value))
+}
+
+fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call_report(value: usize, pointer_len: usize, return_type: ReturnType) -> CallReport {
+        CallReport {
+            second_stage_addr: 0x7f00_0000_0000,
+            second_stage_unmapped: true,
+            value,
+            errno: 0,
+            pointer_len,
+            return_type,
+        }
+    }
+
+    #[test]
+    fn int_value_ignores_the_upper_bits() {
+        // -1 in eax, whatever is left in the upper half of rax
+        for value in [0xffff_ffff, 0xdead_beef_ffff_ffff] {
+            let report = call_report(value, 8, ReturnType::Int);
+            assert_eq!(report.signed_value(), -1);
+            assert!(report.to_string().contains("value:        -1 (0xffffffff)"));
+        }
+    }
+
+    #[test]
+    fn long_value_is_signed() {
+        let report = call_report(usize::MAX, 8, ReturnType::Long);
+        assert_eq!(report.signed_value(), -1);
+        assert!(report.to_string().contains("value:        -1 (0xffffffffffffffff)"));
+
+        // a 32 bit target fills the value read back from its pointer
+        assert_eq!(call_report(0xffff_fffe, 4, ReturnType::Long).signed_value(), -2);
+        assert_eq!(call_report(0x1234, 8, ReturnType::Long).signed_value(), 0x1234);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+
+use crate::payloads::TargetArch;
+
+/// Number of bytes above the stack pointer of a blocked thread scanned for return addresses.
+pub const STACK_SCAN_LEN: usize = 0x400;
+
+/// How soon a thread blocked in a system call is expected to return, and call its wrapper again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitKind {
+    /// Sleeps for a bounded time, usually in a loop.
+    Sleep,
+    /// Waits for events, often with a timeout, and waits again once they are handled.
+    Event,
+    /// Waits for data or a connection, which may never come.
+    Io,
+    /// Waits for another thread.
+    Lock,
+}
+
+impl WaitKind {
+    /// Weight of a thread blocked this way when ranking the wrappers to hijack.
+    pub fn weight(self) -> usize {
+        match self {
+            WaitKind::Sleep => 3,
+            WaitKind::Event => 2,
+            WaitKind::Io => 1,
+            WaitKind::Lock => 0,
+        }
+    }
+}
+
+impl fmt::Display for WaitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitKind::Sleep => write!(f, "sleeps for a bounded time, usually in a loop"),
+            WaitKind::Event => write!(f, "waits for events, and is called again once they are handled"),
+            WaitKind::Io => write!(f, "waits for data or a connection, which may never come"),
+            WaitKind::Lock => write!(f, "waits for another thread"),
+        }
+    }
+}
+
+/// A system call a thread may be blocked in, along with the libc functions issuing it.
+#[derive(Debug)]
+pub struct SyscallWrappers {
+    pub nr: usize,
+    pub name: &'static str,
+    pub kind: WaitKind,
+    /// The exported libc functions issuing the system call, the innermost first
+    /// (e.g. `nanosleep` calls `clock_nanosleep`). `futex` has none worth hijacking.
+    pub wrappers: &'static [&'static str],
+}
+
+const fn wrappers(
+    nr: usize,
+    name: &'static str,
+    kind: WaitKind,
+    wrappers: &'static [&'static str],
+) -> SyscallWrappers {
+    SyscallWrappers {
+        nr,
+        name,
+        kind,
+        wrappers,
+    }
+}
+
+const CLOCK_NANOSLEEP: &[&str] = &["clock_nanosleep", "nanosleep", "usleep", "sleep"];
+const NANOSLEEP: &[&str] = &["nanosleep", "usleep", "sleep"];
+
+const X86_64: &[SyscallWrappers] = &[
+    wrappers(0, "read", WaitKind::Io, &["read"]),
+    wrappers(7, "poll", WaitKind::Event, &["poll"]),
+    wrappers(23, "select", WaitKind::Event, &["select"]),
+    wrappers(35, "nanosleep", WaitKind::Sleep, NANOSLEEP),
+    wrappers(43, "accept", WaitKind::Io, &["accept"]),
+    wrappers(45, "recvfrom", WaitKind::Io, &["recvfrom", "recv"]),
+    wrappers(47, "recvmsg", WaitKind::Io, &["recvmsg"]),
+    wrappers(202, "futex", WaitKind::Lock, &[]),
+    wrappers(230, "clock_nanosleep", WaitKind::Sleep, CLOCK_NANOSLEEP),
+    wrappers(232, "epoll_wait", WaitKind::Event, &["epoll_wait"]),
+    wrappers(270, "pselect6", WaitKind::Event, &["pselect", "select"]),
+    wrappers(271, "ppoll", WaitKind::Event, &["ppoll", "poll"]),
+    wrappers(281, "epoll_pwait", WaitKind::Event, &["epoll_pwait", "epoll_wait"]),
+    wrappers(288, "accept4", WaitKind::Io, &["accept4", "accept"]),
+    wrappers(441, "epoll_pwait2", WaitKind::Event, &["epoll_pwait2"]),
+];
+
+const I386: &[SyscallWrappers] = &[
+    wrappers(3, "read", WaitKind::Io, &["read"]),
+    wrappers(142, "_newselect", WaitKind::Event, &["select"]),
+    wrappers(162, "nanosleep", WaitKind::Sleep, NANOSLEEP),
+    wrappers(168, "poll", WaitKind::Event, &["poll"]),
+    wrappers(240, "futex", WaitKind::Lock, &[]),
+    wrappers(256, "epoll_wait", WaitKind::Event, &["epoll_wait"]),
+    wrappers(267, "clock_nanosleep", WaitKind::Sleep, CLOCK_NANOSLEEP),
+    wrappers(308, "pselect6", WaitKind::Event, &["pselect", "select"]),
+    wrappers(309, "ppoll", WaitKind::Event, &["ppoll", "poll"]),
+    wrappers(319, "epoll_pwait", WaitKind::Event, &["epoll_pwait", "epoll_wait"]),
+    wrappers(364, "accept4", WaitKind::Io, &["accept4", "accept"]),
+    wrappers(371, "recvfrom", WaitKind::Io, &["recvfrom", "recv"]),
+    wrappers(372, "recvmsg", WaitKind::Io, &["recvmsg"]),
+    wrappers(407, "clock_nanosleep_time64", WaitKind::Sleep, CLOCK_NANOSLEEP),
+    wrappers(413, "pselect6_time64", WaitKind::Event, &["pselect", "select"]),
+    wrappers(414, "ppoll_time64", WaitKind::Event, &["ppoll", "poll"]),
+    wrappers(422, "futex_time64", WaitKind::Lock, &[]),
+    wrappers(441, "epoll_pwait2", WaitKind::Event, &["epoll_pwait2"]),
+];
+
+/// Generic system call table of the kernel, shared by aarch64 and riscv64.
+const AARCH64: &[SyscallWrappers] = &[
+    wrappers(22, "epoll_pwait", WaitKind::Event, &["epoll_pwait", "epoll_wait"]),
+    wrappers(63, "read", WaitKind::Io, &["read"]),
+    wrappers(72, "pselect6", WaitKind::Event, &["pselect", "select"]),
+    wrappers(73, "ppoll", WaitKind::Event, &["ppoll", "poll"]),
+    wrappers(98, "futex", WaitKind::Lock, &[]),
+    wrappers(101, "nanosleep", WaitKind::Sleep, NANOSLEEP),
+    wrappers(115, "clock_nanosleep", WaitKind::Sleep, CLOCK_NANOSLEEP),
+    wrappers(202, "accept", WaitKind::Io, &["accept"]),
+    wrappers(207, "recvfrom", WaitKind::Io, &["recvfrom", "recv"]),
+    wrappers(212, "recvmsg", WaitKind::Io, &["recvmsg"]),
+    wrappers(242, "accept4", WaitKind::Io, &["accept4", "accept"]),
+    wrappers(441, "epoll_pwait2", WaitKind::Event, &["epoll_pwait2"]),
+];
+
+const ARM: &[SyscallWrappers] = &[
+    wrappers(3, "read", WaitKind::Io, &["read"]),
+    wrappers(142, "_newselect", WaitKind::Event, &["select"]),
+    wrappers(162, "nanosleep", WaitKind::Sleep, NANOSLEEP),
+    wrappers(168, "poll", WaitKind::Event, &["poll"]),
+    wrappers(240, "futex", WaitKind::Lock, &[]),
+    wrappers(252, "epoll_wait", WaitKind::Event, &["epoll_wait"]),
+    wrappers(265, "clock_nanosleep", WaitKind::Sleep, CLOCK_NANOSLEEP),
+    wrappers(285, "accept", WaitKind::Io, &["accept"]),
+    wrappers(292, "recvfrom", WaitKind::Io, &["recvfrom", "recv"]),
+    wrappers(297, "recvmsg", WaitKind::Io, &["recvmsg"]),
+    wrappers(335, "pselect6", WaitKind::Event, &["pselect", "select"]),
+    wrappers(336, "ppoll", WaitKind::Event, &["ppoll", "poll"]),
+    wrappers(346, "epoll_pwait", WaitKind::Event, &["epoll_pwait", "epoll_wait"]),
+    wrappers(366, "accept4", WaitKind::Io, &["accept4", "accept"]),
+    wrappers(407, "clock_nanosleep_time64", WaitKind::Sleep, CLOCK_NANOSLEEP),
+    wrappers(413, "pselect6_time64", WaitKind::Event, &["pselect", "select"]),
+    wrappers(414, "ppoll_time64", WaitKind::Event, &["ppoll", "poll"]),
+    wrappers(422, "futex_time64", WaitKind::Lock, &[]),
+    wrappers(441, "epoll_pwait2", WaitKind::Event, &["epoll_pwait2"]),
+];
+
+/// Gets the system call `nr` of a process of this architecture, if a thread blocked in it is worth considering.
+pub(crate) fn syscall_wrappers(arch: &TargetArch, nr: usize) -> Option<&'static SyscallWrappers> {
+    let table = match arch {
+        TargetArch::X86 => I386,
+        TargetArch::X86_64 => X86_64,
+        TargetArch::Arm => ARM,
+        TargetArch::Aarch64 | TargetArch::Riscv64 => AARCH64,
+    };
+
+    table.iter().find(|syscall| syscall.nr == nr)
+}
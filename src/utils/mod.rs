@@ -27,6 +27,17 @@ pub fn get_dlopen_lib_name() -> String {
     "libdl.so".to_string()
 }
 
+/// Gets the name of the libc function returning the address of the thread's `errno`.
+#[cfg(target_os = "linux")]
+pub fn get_errno_fn_name() -> String {
+    "__errno_location".to_string()
+}
+
+#[cfg(target_os = "android")]
+pub fn get_errno_fn_name() -> String {
+    "__errno".to_string()
+}
+
 pub fn hexdumpm(buffer: &[u8], max_lines_opt: Option<usize>, writer: &mut Vec<u8>) -> Result<(), InjectionError>
 {
     let sixteen_iter = buffer.chunks(16).enumerate();
@@ -128,7 +139,11 @@ pub fn verify_elf_file(file_path: &str) -> Result<(), InjectionError> {
         }
     }
 
-    if magic != [0x7f, 0x45, 0x4c, 0x46] {
+    verify_elf_bytes(&magic)
+}
+
+pub fn verify_elf_bytes(bytes: &[u8]) -> Result<(), InjectionError> {
+    if !bytes.starts_with(&[0x7f, 0x45, 0x4c, 0x46]) {
         error!("File is not an ELF file");
         return Err(InjectionError::FileError);
     }
@@ -138,7 +153,8 @@ pub fn verify_elf_file(file_path: &str) -> Result<(), InjectionError> {
 
 
 
-pub fn copy_file_to_tmp(file_path: &str) -> Result<String, InjectionError> {
+/// Gets the path a library is copied to so that the target can access it, without copying it.
+pub fn tmp_file_path(file_path: &str) -> Result<String, InjectionError> {
     // get absolute path
     let file_path_absolute = match std::path::Path::new(file_path).canonicalize() {
         Ok(path) => path,
@@ -150,9 +166,8 @@ pub fn copy_file_to_tmp(file_path: &str) -> Result<String, InjectionError> {
 
     info!("File path: {}", file_path_absolute.to_str().unwrap());
 
-    // skip if the file is already in /dev/local/tmp
+    // keep it if the file is already in /dev/local/tmp
     if file_path_absolute.starts_with(TMP_DIR_PATH) {
-        info!("File is already in {}", TMP_DIR_PATH);
         return Ok(String::from(file_path_absolute.to_str().unwrap()));
     }
 
@@ -164,14 +179,27 @@ pub fn copy_file_to_tmp(file_path: &str) -> Result<String, InjectionError> {
         }
     };
 
-    // copy file to /data/local/tmp so that the target app can access it
-    let tmp_file_path = std::path::Path::new(TMP_DIR_PATH)
+    Ok(std::path::Path::new(TMP_DIR_PATH)
         .join(file_name)
         .as_os_str()
         .to_str()
         .unwrap()
-        .to_string();
+        .to_string())
+}
+
+pub fn copy_file_to_tmp(file_path: &str) -> Result<String, InjectionError> {
+    let tmp_file_path = tmp_file_path(file_path)?;
+
+    // skip if the file is already in /dev/local/tmp
+    if std::path::Path::new(file_path)
+        .canonicalize()
+        .is_ok_and(|path| path.starts_with(TMP_DIR_PATH))
+    {
+        info!("File is already in {}", TMP_DIR_PATH);
+        return Ok(tmp_file_path);
+    }
 
+    // copy file to /data/local/tmp so that the target app can access it
     info!("Copying file {} to {}", file_path, tmp_file_path);
     match std::fs::copy(file_path, &tmp_file_path) {
         Ok(_) => {
@@ -1,36 +1,178 @@
+use goblin::container::{Ctx, Endian};
+use goblin::elf::dynamic::{
+    DT_DEBUG, DT_GNU_HASH, DT_HASH, DT_JMPREL, DT_NULL, DT_PLTREL, DT_PLTRELSZ, DT_REL, DT_RELA, DT_RELASZ, DT_RELSZ, DT_STRTAB,
+    DT_SYMTAB, DT_VERSYM,
+};
+use goblin::elf::header::{EM_MIPS, EM_RISCV};
+use goblin::elf::program_header::{ProgramHeader, PT_DYNAMIC, PT_LOAD};
+use goblin::elf::reloc::RelocSection;
+use goblin::elf::sym::Sym;
+use goblin::elf::Elf;
+
 use crate::error::InjectionError;
+use crate::proc::maps::Maps;
+use crate::proc::mem::Mem;
+use crate::proc::Proc;
+
+/// Bit of a version index set when the symbol is not the default version of its name.
+const VERSYM_HIDDEN: u16 = 0x8000;
+
+/// Dynamic linker of a target, which tells how the dynamic sections of its modules are left in its memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicLinker {
+    Glibc,
+    Musl,
+    /// The linker of Android.
+    Bionic,
+}
+
+impl DynamicLinker {
+    /// Gets the dynamic linker from the file name of a module, if it is one.
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        match file_name {
+            _ if file_name.starts_with("ld-musl-") => Some(Self::Musl),
+            "linker" | "linker64" => Some(Self::Bionic),
+            // ld-linux-x86-64.so.2, ld-linux-armhf.so.3, ld-2.31.so, ...
+            _ if file_name.starts_with("ld-") || file_name.starts_with("ld64.so") => Some(Self::Glibc),
+            _ => None,
+        }
+    }
+
+    /// Does the linker relocate the addresses (`d_ptr`) of the dynamic section of a module in place ? glibc does,
+    /// but on riscv64 and mips, where the dynamic sections are read-only, and for the ones not mapped writable.
+    /// musl and bionic never do.
+    fn relocates_dynamic(&self, machine: u16, dynamic: &ProgramHeader) -> bool {
+        match self {
+            Self::Glibc => !matches!(machine, EM_RISCV | EM_MIPS) && dynamic.is_write(),
+            Self::Musl | Self::Bionic => false,
+        }
+    }
+}
+
+impl Default for DynamicLinker {
+    /// The linker of the libc this injector is built for, for the targets without one (static executables).
+    fn default() -> Self {
+        match cfg!(target_os = "android") {
+            true => Self::Bionic,
+            false => Self::Glibc,
+        }
+    }
+}
 
 pub struct RemoteModule {
     pub name: String,
     pub vm_addr: usize,
-    #[allow(dead_code)]
-    pub bytes: Vec<u8>,
+    /// Pid of the target the module is mapped in.
+    pub pid: i32,
+    /// Dynamic linker of the target.
+    pub linker: DynamicLinker,
 }
 
 impl RemoteModule {
-    pub fn new(name: &str, vm_addr: usize, bytes: Vec<u8>) -> Self {
+    pub fn new(name: &str, vm_addr: usize, pid: i32, linker: DynamicLinker) -> Self {
         Self {
             name: name.to_string(),
             vm_addr,
-            bytes,
+            pid,
+            linker,
+        }
+    }
+
+    /// Gets the address of a symbol in the target, see [`RemoteModule::sym`].
+    pub fn dlsym(&self, symbol_name: &str) -> Result<usize, InjectionError> {
+        self.sym(symbol_name).map(|(addr, _)| addr)
+    }
+
+    /// Gets the address of a symbol in the target along with its size, from the module mapped in its memory,
+    /// then from the file of the module if the symbol is not exported (e.g. only in `.symtab`) or the memory
+    /// can not be parsed.
+    pub fn sym(&self, symbol_name: &str) -> Result<(usize, usize), InjectionError> {
+        self.resolve(symbol_name, || self.sym_from_mem(symbol_name), || self.sym_from_fs(symbol_name))
+    }
+
+    /// Gets the address in the target of the GOT slot through which the module calls `symbol_name`, from the
+    /// module mapped in its memory, then from its file, see [`RemoteModule::got_slot_from_mem`].
+    pub fn got_slot(&self, symbol_name: &str) -> Result<usize, InjectionError> {
+        let what = format!("{}@got", symbol_name);
+        self.resolve(&what, || self.got_slot_from_mem(symbol_name), || self.got_slot_from_fs(symbol_name))
+    }
+
+    /// Finds `len` unused bytes after the end of an executable segment of the module, from its program headers
+    /// mapped in the memory of the target, then from its file.
+    pub fn code_cave(&self, len: usize) -> Result<usize, InjectionError> {
+        let what = format!("a code cave of {} bytes", len);
+        self.resolve(&what, || self.code_cave_from_mem(len), || self.code_cave_from_fs(len))
+    }
+
+    /// Finds `len` unused bytes after the end of a writable segment of the module, from its program headers
+    /// mapped in the memory of the target, then from its file.
+    ///
+    /// musl gives the rest of the last pages of the writable segments of the modules it loads at startup to
+    /// `malloc`, they are not unused there.
+    pub fn data_cave(&self, len: usize) -> Result<usize, InjectionError> {
+        if self.linker == DynamicLinker::Musl {
+            return Err(InjectionError::DataCaveNotFound(self.name.clone()));
         }
+
+        let what = format!("a data cave of {} bytes", len);
+        self.resolve(&what, || self.data_cave_from_mem(len), || self.data_cave_from_fs(len))
+    }
+
+    /// Gets the page size of the target.
+    fn page_size(&self) -> Result<usize, InjectionError> {
+        Proc::new(self.pid).ok_or(InjectionError::ProcessNotRunning)?.page_size()
+    }
+
+    /// Gets `what` from the module mapped in the memory of the target, then from its file if the memory can not
+    /// be parsed or does not have it.
+    fn resolve<T>(
+        &self,
+        what: &str,
+        from_mem: impl FnOnce() -> Result<T, InjectionError>,
+        from_fs: impl FnOnce() -> Result<T, InjectionError>,
+    ) -> Result<T, InjectionError> {
+        let mem_err = match from_mem() {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        debug!(
+            "{} not resolved from the memory of {} ({:?}), trying its file",
+            what, self.name, mem_err
+        );
+
+        // the file may be gone (deleted, memfd, in another mount namespace), the memory tells more
+        from_fs().map_err(|err| match err {
+            InjectionError::FileError => mem_err,
+            err => err,
+        })
     }
 
     pub fn dlsym_from_fs(&self, symbol_name: &str) -> Result<usize, InjectionError> {
+        self.sym_from_fs(symbol_name).map(|(addr, _)| addr)
+    }
+
+    /// Gets the address of a symbol in the target along with its size (e.g. the length of a function's code).
+    ///
+    /// The symbols the module imports are skipped, and the addresses of its segments may not start at 0 (e.g. the
+    /// prelinked 32 bit libraries of Android). The address of a Thumb function keeps its bit 0 set.
+    pub fn sym_from_fs(&self, symbol_name: &str) -> Result<(usize, usize), InjectionError> {
         let bytes = std::fs::read(&self.name).map_err(|_| InjectionError::FileError)?;
-        let elf = goblin::elf::Elf::parse(&bytes).map_err(|_| InjectionError::RemoteModuleError)?;
+        let elf = Elf::parse(&bytes).map_err(|_| InjectionError::RemoteModuleError)?;
+        let bias = load_bias(elf.header.e_type, &elf.program_headers, self.vm_addr)?;
 
         let result = elf
             .syms
             .iter()
+            .filter(|sym| !sym.is_import())
             .find(|sym| symbol_name == elf.strtab.get_at(sym.st_name).unwrap());
 
-        if result.is_some() {
-            let offset = result.unwrap().st_value as usize;
-            return Ok(offset + self.vm_addr);
+        if let Some(sym) = result {
+            let offset = sym.st_value as usize;
+            return Ok((offset + bias, sym.st_size as usize));
         }
 
-        warn!(
+        debug!(
             "symbol not found in .symtab, trying .dynsym: {}",
             symbol_name
         );
@@ -38,18 +180,625 @@ impl RemoteModule {
         let result = elf
             .dynsyms
             .iter()
+            .filter(|sym| !sym.is_import())
             .find(|sym| symbol_name == elf.dynstrtab.get_at(sym.st_name).unwrap());
 
+        // an optional symbol may be missing, the callers needing it report the error
         if result.is_none() {
-            error!("symbol not found: {}", symbol_name);
+            debug!("symbol not found: {}", symbol_name);
             return Err(InjectionError::SymbolNotFound(symbol_name.to_string()));
         }
 
-        let offset = result.unwrap().st_value as usize;
-        Ok(offset + self.vm_addr)
+        let sym = result.unwrap();
+        Ok((sym.st_value as usize + bias, sym.st_size as usize))
+    }
+
+    /// Gets the address in the target of the GOT slot through which the module calls `symbol_name`,
+    /// from its `.rela.plt` (or `.rel.plt`) relocations, then its `.rela.dyn` (or `.rel.dyn`) ones.
+    pub fn got_slot_from_fs(&self, symbol_name: &str) -> Result<usize, InjectionError> {
+        let bytes = std::fs::read(&self.name).map_err(|_| InjectionError::FileError)?;
+        let elf = Elf::parse(&bytes).map_err(|_| InjectionError::RemoteModuleError)?;
+
+        let result = elf
+            .pltrelocs
+            .iter()
+            .chain(elf.dynrelas.iter())
+            .chain(elf.dynrels.iter())
+            .filter(|reloc| is_got_reloc(elf.header.e_machine, reloc.r_type))
+            .find(|reloc| {
+                elf.dynsyms
+                    .get(reloc.r_sym)
+                    .and_then(|sym| elf.dynstrtab.get_at(sym.st_name))
+                    == Some(symbol_name)
+            });
+
+        match result {
+            Some(reloc) => Ok(load_bias(elf.header.e_type, &elf.program_headers, self.vm_addr)? + reloc.r_offset as usize),
+            None => {
+                debug!("no GOT slot for {} in {}", symbol_name, self.name);
+                Err(InjectionError::SymbolNotFound(format!("{}@got", symbol_name)))
+            }
+        }
+    }
+
+    /// Gets the address in the target of the GOT slot through which the module calls `symbol_name`, from the
+    /// `DT_JMPREL`, `DT_RELA` and `DT_REL` relocations of the module mapped in its memory, in this order.
+    pub fn got_slot_from_mem(&self, symbol_name: &str) -> Result<usize, InjectionError> {
+        let mut mem = Mem::read_only(self.pid)?;
+        let elf = MappedElf::read(&mut mem, self.vm_addr)?;
+        let dynamic = DynamicSection::read(&mut mem, &elf, self)?;
+
+        for (addr, len, is_rela) in dynamic.reloc_tables() {
+            let bytes = mem.read(addr, len)?;
+            let relocs =
+                RelocSection::parse(&bytes, 0, len, is_rela, elf.ctx).map_err(|_| InjectionError::RemoteModuleError)?;
+
+            for reloc in relocs.iter().filter(|reloc| is_got_reloc(elf.machine, reloc.r_type)) {
+                let sym = dynamic.sym(&mut mem, reloc.r_sym)?;
+                if dynamic.is_named(&mut mem, &sym, symbol_name)? {
+                    return Ok(elf.bias + reloc.r_offset as usize);
+                }
+            }
+        }
+
+        debug!("no GOT slot for {} in the memory of {}", symbol_name, self.name);
+        Err(InjectionError::SymbolNotFound(format!("{}@got", symbol_name)))
+    }
+
+    /// Finds `len` unused bytes after the end of an executable segment of the module, in its last page.
+    pub fn code_cave_from_fs(&self, len: usize) -> Result<usize, InjectionError> {
+        let bytes = std::fs::read(&self.name).map_err(|_| InjectionError::FileError)?;
+        let elf = Elf::parse(&bytes).map_err(|_| InjectionError::RemoteModuleError)?;
+        let bias = load_bias(elf.header.e_type, &elf.program_headers, self.vm_addr)?;
+
+        code_cave(&elf.program_headers, bias, len).ok_or(InjectionError::CodeCaveNotFound(self.name.clone()))
+    }
+
+    /// Finds `len` unused bytes after the end of an executable segment of the module, from its program headers
+    /// mapped in the memory of the target.
+    pub fn code_cave_from_mem(&self, len: usize) -> Result<usize, InjectionError> {
+        let mut mem = Mem::read_only(self.pid)?;
+        let elf = MappedElf::read(&mut mem, self.vm_addr)?;
+
+        code_cave(&elf.program_headers, elf.bias, len).ok_or(InjectionError::CodeCaveNotFound(self.name.clone()))
+    }
+
+    /// Finds `len` unused bytes after the end of a writable segment of the module, in its last page.
+    pub fn data_cave_from_fs(&self, len: usize) -> Result<usize, InjectionError> {
+        let bytes = std::fs::read(&self.name).map_err(|_| InjectionError::FileError)?;
+        let elf = Elf::parse(&bytes).map_err(|_| InjectionError::RemoteModuleError)?;
+        let bias = load_bias(elf.header.e_type, &elf.program_headers, self.vm_addr)?;
+
+        data_cave(&elf.program_headers, bias, len).ok_or(InjectionError::DataCaveNotFound(self.name.clone()))
+    }
+
+    /// Finds `len` unused bytes after the end of a writable segment of the module, from its program headers
+    /// mapped in the memory of the target.
+    pub fn data_cave_from_mem(&self, len: usize) -> Result<usize, InjectionError> {
+        let mut mem = Mem::read_only(self.pid)?;
+        let elf = MappedElf::read(&mut mem, self.vm_addr)?;
+
+        data_cave(&elf.program_headers, elf.bias, len).ok_or(InjectionError::DataCaveNotFound(self.name.clone()))
+    }
+
+    /// Gets the `link_map` of the module, which glibc and musl return as its `dlopen` handle, from the list of the
+    /// loaded modules: `r_debug`, which the linker points the `DT_DEBUG` entry of `executable` to, heads it. The
+    /// module is the one whose `l_ld` is its `PT_DYNAMIC` segment in memory.
+    ///
+    /// The handles of bionic are not pointers, they are not found this way.
+    pub fn link_map(&self, executable: &RemoteModule) -> Result<usize, InjectionError> {
+        if self.linker == DynamicLinker::Bionic {
+            return Err(InjectionError::RemoteModuleError);
+        }
+
+        let mut mem = Mem::read_only(self.pid)?;
+        let elf = MappedElf::read(&mut mem, self.vm_addr)?;
+        let ld = elf.bias + elf.dynamic()?.p_vaddr as usize;
+
+        let exe = MappedElf::read(&mut mem, executable.vm_addr)?;
+        let r_debug = exe
+            .dynamic_entries(&mut mem)?
+            .into_iter()
+            .find(|&(tag, value)| tag == DT_DEBUG && value != 0)
+            .map(|(_, value)| value as usize)
+            .ok_or(InjectionError::RemoteModuleError)?;
+
+        // r_debug starts with an int padded to a word then r_map, and a link_map (the start of a struct dso for
+        // musl) with l_addr, l_name, l_ld then l_next
+        let word = exe.ctx.size();
+        let mut link_map = exe.word(&mut mem, r_debug + word)?;
+        while link_map != 0 {
+            if exe.word(&mut mem, link_map + 2 * word)? == ld {
+                return Ok(link_map);
+            }
+            link_map = exe.word(&mut mem, link_map + 3 * word)?;
+        }
+
+        debug!("no link_map for {} at 0x{:x}", self.name, ld);
+        Err(InjectionError::ModuleNotFound)
+    }
+
+    pub fn dlsym_from_mem(&self, symbol_name: &str) -> Result<usize, InjectionError> {
+        self.sym_from_mem(symbol_name).map(|(addr, _)| addr)
+    }
+
+    /// Gets the address of a symbol in the target along with its size, from the dynamic symbols of the module
+    /// mapped in its memory: its ELF header at its base, its `PT_DYNAMIC` segment, then its `DT_SYMTAB` and
+    /// `DT_STRTAB` tables, looked up through `DT_GNU_HASH` (or `DT_HASH`).
+    ///
+    /// The file of the module is not read: it may be deleted, a memfd, replaced on disk by an update, or only
+    /// present in the mount namespace of the target. The default version of a versioned symbol is returned.
+    pub fn sym_from_mem(&self, symbol_name: &str) -> Result<(usize, usize), InjectionError> {
+        let mut mem = Mem::read_only(self.pid)?;
+        let elf = MappedElf::read(&mut mem, self.vm_addr)?;
+        let dynamic = DynamicSection::read(&mut mem, &elf, self)?;
+
+        let result = match (dynamic.gnu_hash, dynamic.hash) {
+            (Some(gnu_hash), _) => dynamic.gnu_lookup(&mut mem, gnu_hash, symbol_name)?,
+            (None, Some(hash)) => dynamic.sysv_lookup(&mut mem, hash, symbol_name)?,
+            (None, None) => return Err(InjectionError::RemoteModuleError),
+        };
+
+        match result {
+            Some(sym) => Ok((sym.st_value as usize + elf.bias, sym.st_size as usize)),
+            None => Err(InjectionError::SymbolNotFound(symbol_name.to_string())),
+        }
+    }
+}
+
+/// The ELF header and the program headers of a module, in the memory of the target.
+struct MappedElf {
+    ctx: Ctx,
+    machine: u16,
+    /// Difference between the addresses of the module in the target and the ones of its ELF file.
+    bias: usize,
+    program_headers: Vec<ProgramHeader>,
+}
+
+impl MappedElf {
+    /// Reads the headers of the module mapped at `vm_addr`.
+    fn read(mem: &mut Mem, vm_addr: usize) -> Result<Self, InjectionError> {
+        // the header of a 32 bit module is shorter, its program headers follow it in the same page
+        let header = Elf::parse_header(&mem.read(vm_addr, 0x40)?).map_err(|_| InjectionError::RemoteModuleError)?;
+        let container = header.container().map_err(|_| InjectionError::RemoteModuleError)?;
+        let endian = header.endianness().map_err(|_| InjectionError::RemoteModuleError)?;
+        let ctx = Ctx::new(container, endian);
+
+        let phnum = header.e_phnum as usize;
+        let bytes = mem.read(vm_addr + header.e_phoff as usize, phnum * header.e_phentsize as usize)?;
+        let program_headers =
+            ProgramHeader::parse(&bytes, 0, phnum, ctx).map_err(|_| InjectionError::RemoteModuleError)?;
+        let bias = load_bias(header.e_type, &program_headers, vm_addr)?;
+
+        Ok(Self {
+            ctx,
+            machine: header.e_machine,
+            bias,
+            program_headers,
+        })
+    }
+
+    /// Gets the `PT_DYNAMIC` segment of the module.
+    fn dynamic(&self) -> Result<&ProgramHeader, InjectionError> {
+        self.program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_DYNAMIC)
+            .ok_or(InjectionError::RemoteModuleError)
+    }
+
+    /// Reads the (tag, value) entries of the dynamic section of the module, as the linker left them.
+    fn dynamic_entries(&self, mem: &mut Mem) -> Result<Vec<(u64, u64)>, InjectionError> {
+        let dynamic = self.dynamic()?;
+        let bytes = mem.read(self.bias + dynamic.p_vaddr as usize, dynamic.p_memsz as usize)?;
+
+        // (tag, value) pairs of words, ended by DT_NULL
+        Ok(bytes
+            .chunks_exact(self.ctx.size() * 2)
+            .map(|entry| entry.split_at(self.ctx.size()))
+            .map(|(tag, value)| (uint(tag, self.ctx.le), uint(value, self.ctx.le)))
+            .take_while(|&(tag, _)| tag != DT_NULL)
+            .collect())
+    }
+
+    /// Reads a word of the module.
+    fn word(&self, mem: &mut Mem, addr: usize) -> Result<usize, InjectionError> {
+        Ok(uint(&mem.read(addr, self.ctx.size())?, self.ctx.le) as usize)
+    }
+}
+
+/// The tables of the dynamic section of a module, in the memory of the target.
+struct DynamicSection {
+    ctx: Ctx,
+    /// Pid of the target, and its page size.
+    pid: i32,
+    page_size: usize,
+    symtab: usize,
+    strtab: usize,
+    gnu_hash: Option<usize>,
+    hash: Option<usize>,
+    versym: Option<usize>,
+    /// Relocations of the PLT, and their length.
+    jmprel: usize,
+    pltrelsz: usize,
+    /// Type of the relocations of the PLT, `DT_RELA` or `DT_REL`.
+    pltrel: u64,
+    rela: usize,
+    relasz: usize,
+    rel: usize,
+    relsz: usize,
+}
+
+impl DynamicSection {
+    /// Finds the tables from the `PT_DYNAMIC` segment of a module.
+    fn read(mem: &mut Mem, elf: &MappedElf, module: &RemoteModule) -> Result<Self, InjectionError> {
+        let ctx = elf.ctx;
+        let dynamic = elf.dynamic()?;
+        let relocated = module.linker.relocates_dynamic(elf.machine, dynamic);
+        let addr = |value: u64| match relocated {
+            true => value as usize,
+            false => value as usize + elf.bias,
+        };
+
+        let mut section = Self {
+            ctx,
+            pid: module.pid,
+            page_size: module.page_size()?,
+            symtab: 0,
+            strtab: 0,
+            gnu_hash: None,
+            hash: None,
+            versym: None,
+            jmprel: 0,
+            pltrelsz: 0,
+            pltrel: DT_RELA,
+            rela: 0,
+            relasz: 0,
+            rel: 0,
+            relsz: 0,
+        };
+
+        for (tag, value) in elf.dynamic_entries(mem)? {
+            match tag {
+                DT_SYMTAB => section.symtab = addr(value),
+                DT_STRTAB => section.strtab = addr(value),
+                DT_GNU_HASH => section.gnu_hash = Some(addr(value)),
+                DT_HASH => section.hash = Some(addr(value)),
+                DT_VERSYM => section.versym = Some(addr(value)),
+                DT_JMPREL => section.jmprel = addr(value),
+                DT_PLTRELSZ => section.pltrelsz = value as usize,
+                DT_PLTREL => section.pltrel = value,
+                DT_RELA => section.rela = addr(value),
+                DT_RELASZ => section.relasz = value as usize,
+                DT_REL => section.rel = addr(value),
+                DT_RELSZ => section.relsz = value as usize,
+                _ => {}
+            }
+        }
+
+        if section.symtab == 0 || section.strtab == 0 {
+            return Err(InjectionError::RemoteModuleError);
+        }
+
+        Ok(section)
+    }
+
+    /// Gets the address, the length and the type (RELA or not) of the relocation tables of the module.
+    fn reloc_tables(&self) -> Vec<(usize, usize, bool)> {
+        [
+            (self.jmprel, self.pltrelsz, self.pltrel == DT_RELA),
+            (self.rela, self.relasz, true),
+            (self.rel, self.relsz, false),
+        ]
+        .into_iter()
+        .filter(|&(addr, len, _)| addr != 0 && len != 0)
+        .collect()
+    }
+
+    /// Looks `symbol_name` up through the GNU hash table at `gnu_hash`.
+    fn gnu_lookup(&self, mem: &mut Mem, gnu_hash: usize, symbol_name: &str) -> Result<Option<Sym>, InjectionError> {
+        // nbuckets, symoffset, bloom_size, bloom_shift, then the bloom filter words, the buckets and the chains
+        let nbuckets = self.u32(mem, gnu_hash)?;
+        let symoffset = self.u32(mem, gnu_hash + 4)?;
+        let bloom_size = self.u32(mem, gnu_hash + 8)? as usize;
+        if nbuckets == 0 {
+            return Ok(None);
+        }
+
+        let buckets = gnu_hash + 16 + bloom_size * self.ctx.size();
+        let chains = buckets + nbuckets as usize * 4;
+
+        let hash = goblin::elf64::gnu_hash::hash(symbol_name);
+        let mut index = self.u32(mem, buckets + (hash % nbuckets) as usize * 4)?;
+        if index < symoffset {
+            return Ok(None);
+        }
+
+        // the hashes of the symbols of a bucket follow each other, the last one has its bit 0 set
+        loop {
+            let chain = self.u32(mem, chains + (index - symoffset) as usize * 4)?;
+            if chain | 1 == hash | 1 {
+                if let Some(sym) = self.matching_sym(mem, index as usize, symbol_name)? {
+                    return Ok(Some(sym));
+                }
+            }
+
+            if chain & 1 != 0 {
+                return Ok(None);
+            }
+            index += 1;
+        }
+    }
+
+    /// Looks `symbol_name` up through the SysV hash table at `hash`, for the modules without a GNU one.
+    fn sysv_lookup(&self, mem: &mut Mem, hash: usize, symbol_name: &str) -> Result<Option<Sym>, InjectionError> {
+        // nbucket, nchain, then the buckets and the chains, both indexed by the symbols
+        let nbucket = self.u32(mem, hash)?;
+        let nchain = self.u32(mem, hash + 4)?;
+        if nbucket == 0 {
+            return Ok(None);
+        }
+
+        let buckets = hash + 8;
+        let chains = buckets + nbucket as usize * 4;
+
+        let mut index = self.u32(mem, buckets + (elf_hash(symbol_name) % nbucket) as usize * 4)?;
+        for _ in 0..nchain {
+            if index == 0 {
+                break;
+            }
+
+            if let Some(sym) = self.matching_sym(mem, index as usize, symbol_name)? {
+                return Ok(Some(sym));
+            }
+            index = self.u32(mem, chains + index as usize * 4)?;
+        }
+
+        Ok(None)
+    }
+
+    /// Gets the symbol at `index` if it is named `symbol_name`, defined by the module and its default version.
+    fn matching_sym(&self, mem: &mut Mem, index: usize, symbol_name: &str) -> Result<Option<Sym>, InjectionError> {
+        let sym = self.sym(mem, index)?;
+        if !self.is_named(mem, &sym, symbol_name)? {
+            return Ok(None);
+        }
+
+        if sym.is_import() {
+            return Ok(None);
+        }
+
+        if let Some(versym) = self.versym {
+            let version = uint(&mem.read(versym + index * 2, 2)?, self.ctx.le) as u16;
+            if version & VERSYM_HIDDEN != 0 {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(sym))
+    }
+
+    /// Reads the symbol at `index`.
+    fn sym(&self, mem: &mut Mem, index: usize) -> Result<Sym, InjectionError> {
+        let len = Sym::size(self.ctx.container);
+        let bytes = mem.read(self.symtab + index * len, len)?;
+        Ok(Sym::parse(&bytes, 0, 1, self.ctx)
+            .map_err(|_| InjectionError::RemoteModuleError)?
+            .remove(0))
+    }
+
+    /// Is the name of `sym` `symbol_name` ?
+    fn is_named(&self, mem: &mut Mem, sym: &Sym, symbol_name: &str) -> Result<bool, InjectionError> {
+        let addr = self.strtab + sym.st_name;
+        let len = symbol_name.len() + 1;
+
+        // the name may cross a page, the rest of it is read from the next one: a shorter name may end the last
+        // page of the table, followed by an unmapped one
+        let first_len = len.min(self.page_size - addr % self.page_size);
+        let mut name = mem.read(addr, first_len)?;
+        if name.len() < len && !name.contains(&0x0) {
+            let next_page = addr + first_len;
+            match mem.read(next_page, len - first_len) {
+                Ok(rest) => name.extend(rest),
+                Err(_) if !Maps::new(self.pid)?.is_mapped(next_page)? => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(name[..name.len() - 1] == *symbol_name.as_bytes() && name[name.len() - 1] == 0x0)
+    }
+
+    fn u32(&self, mem: &mut Mem, addr: usize) -> Result<u32, InjectionError> {
+        Ok(uint(&mem.read(addr, 4)?, self.ctx.le) as u32)
+    }
+}
+
+/// Reads an unsigned integer of up to 8 bytes in the byte order of the module.
+fn uint(bytes: &[u8], endian: Endian) -> u64 {
+    let push = |value: u64, byte: &u8| value << 8 | *byte as u64;
+    match endian.is_little() {
+        true => bytes.iter().rev().fold(0, push),
+        false => bytes.iter().fold(0, push),
+    }
+}
+
+/// Hash function of the SysV hash tables (`DT_HASH`).
+fn elf_hash(symbol_name: &str) -> u32 {
+    symbol_name.bytes().fold(0, |hash, byte| {
+        let hash = (hash << 4).wrapping_add(byte as u32);
+        let high = hash & 0xf000_0000;
+        (hash ^ (high >> 24)) & !high
+    })
+}
+
+/// Gets the difference between the addresses of a module in the target and the ones of its ELF file.
+fn load_bias(e_type: u16, program_headers: &[ProgramHeader], vm_addr: usize) -> Result<usize, InjectionError> {
+    // the addresses of an executable which is not position-independent are absolute
+    if e_type == goblin::elf::header::ET_EXEC {
+        return Ok(0);
+    }
+
+    let first_vaddr = program_headers
+        .iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| ph.p_vaddr as usize & !0xfff)
+        .min()
+        .unwrap_or(0);
+
+    // the first map of the module is not its first segment, e.g. the file does not match the memory
+    vm_addr.checked_sub(first_vaddr).ok_or_else(|| {
+        debug!("module at 0x{:x} below its first segment 0x{:x}", vm_addr, first_vaddr);
+        InjectionError::RemoteModuleError
+    })
+}
+
+/// Finds `len` unused bytes after the end of an executable segment, in its last page.
+fn code_cave(program_headers: &[ProgramHeader], bias: usize, len: usize) -> Option<usize> {
+    cave(program_headers.iter().filter(|ph| ph.is_executable()), bias, len)
+}
+
+/// Finds `len` unused bytes after the end of a writable segment, in its last page. The `.bss` of the segment
+/// is zeroed up to the end of this page, which nothing else is mapped in.
+fn data_cave(program_headers: &[ProgramHeader], bias: usize, len: usize) -> Option<usize> {
+    cave(program_headers.iter().filter(|ph| ph.is_write()), bias, len)
+}
+
+/// Finds `len` unused bytes after the end of one of the `segments`, in its last page.
+fn cave<'a>(segments: impl Iterator<Item = &'a ProgramHeader>, bias: usize, len: usize) -> Option<usize> {
+    // the pages are at least 4 KiB large, the rest of the last one is mapped but not used
+    segments
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| bias + (ph.p_vaddr + ph.p_memsz) as usize)
+        .map(|end| ((end + 0xf) & !0xf, (end + 0xfff) & !0xfff))
+        .find(|(start, page_end)| start + len <= *page_end)
+        .map(|(start, _)| start)
+}
+
+/// Does a relocation of this type fill a GOT slot with the address of a function ?
+fn is_got_reloc(machine: u16, r_type: u32) -> bool {
+    use goblin::elf::header::{EM_386, EM_AARCH64, EM_ARM, EM_RISCV, EM_X86_64};
+    use goblin::elf::reloc::*;
+
+    match machine {
+        EM_X86_64 => matches!(r_type, R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT),
+        EM_386 => matches!(r_type, R_386_GLOB_DAT | R_386_JMP_SLOT),
+        EM_AARCH64 => matches!(r_type, R_AARCH64_GLOB_DAT | R_AARCH64_JUMP_SLOT),
+        EM_ARM => matches!(r_type, R_ARM_GLOB_DAT | R_ARM_JUMP_SLOT),
+        // the GOT slots are filled by plain 64 bit relocations, there is no GLOB_DAT
+        EM_RISCV => matches!(r_type, R_RISCV_64 | R_RISCV_JUMP_SLOT),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use goblin::elf::header::{EM_AARCH64, EM_X86_64};
+    use goblin::elf::program_header::{PF_R, PF_W};
+
+    use super::*;
+
+    fn segment(p_type: u32, p_flags: u32, p_vaddr: u64) -> ProgramHeader {
+        ProgramHeader {
+            p_type,
+            p_flags,
+            p_vaddr,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn linker_from_file_name() {
+        assert_eq!(DynamicLinker::from_file_name("ld-linux-x86-64.so.2"), Some(DynamicLinker::Glibc));
+        assert_eq!(DynamicLinker::from_file_name("ld-2.31.so"), Some(DynamicLinker::Glibc));
+        assert_eq!(DynamicLinker::from_file_name("ld-musl-aarch64.so.1"), Some(DynamicLinker::Musl));
+        assert_eq!(DynamicLinker::from_file_name("linker64"), Some(DynamicLinker::Bionic));
+        assert_eq!(DynamicLinker::from_file_name("libc.so.6"), None);
+        assert_eq!(DynamicLinker::from_file_name("ldap.so"), None);
+    }
+
+    #[test]
+    fn dynamic_relocated_by_glibc_only() {
+        let writable = segment(PT_DYNAMIC, PF_R | PF_W, 0x1000);
+        let read_only = segment(PT_DYNAMIC, PF_R, 0x1000);
+
+        assert!(DynamicLinker::Glibc.relocates_dynamic(EM_X86_64, &writable));
+        assert!(DynamicLinker::Glibc.relocates_dynamic(EM_AARCH64, &writable));
+        assert!(!DynamicLinker::Glibc.relocates_dynamic(EM_RISCV, &writable));
+        assert!(!DynamicLinker::Glibc.relocates_dynamic(EM_X86_64, &read_only));
+        assert!(!DynamicLinker::Musl.relocates_dynamic(EM_X86_64, &writable));
+        assert!(!DynamicLinker::Bionic.relocates_dynamic(EM_AARCH64, &writable));
+    }
+
+    #[test]
+    fn load_bias_of_the_first_segment() {
+        let program_headers = [segment(PT_LOAD, PF_R, 0x1000_0000), segment(PT_LOAD, PF_R, 0x1001_0000)];
+
+        assert_eq!(load_bias(goblin::elf::header::ET_DYN, &program_headers, 0x7f00_1000_0000).unwrap(), 0x7f00_0000_0000);
+        assert_eq!(load_bias(goblin::elf::header::ET_EXEC, &program_headers, 0x1000_0000).unwrap(), 0x0);
+        assert!(matches!(
+            load_bias(goblin::elf::header::ET_DYN, &program_headers, 0x0fff_f000),
+            Err(InjectionError::RemoteModuleError)
+        ));
+    }
+
+    /// Gets a module of the test process.
+    fn own_module(module_name: &str) -> RemoteModule {
+        Maps::new(std::process::id() as i32).unwrap().module(module_name).unwrap()
+    }
+
+    #[test]
+    fn memory_matches_the_file() {
+        let libc = own_module("libc.so");
+        assert_eq!(libc.sym_from_mem("malloc").unwrap(), libc.sym_from_fs("malloc").unwrap());
+        assert_eq!(libc.code_cave_from_mem(0x40).unwrap(), libc.code_cave_from_fs(0x40).unwrap());
+        assert_eq!(libc.data_cave_from_mem(0x10).unwrap(), libc.data_cave_from_fs(0x10).unwrap());
+
+        // the test executable calls malloc through its GOT
+        let exe = std::env::current_exe().unwrap();
+        let exe = own_module(exe.file_name().unwrap().to_str().unwrap());
+        assert_eq!(exe.got_slot_from_mem("malloc").unwrap(), exe.got_slot_from_fs("malloc").unwrap());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn link_map_is_the_dlopen_handle() {
+        extern "C" {
+            fn dlopen(filename: *const std::ffi::c_char, flag: std::ffi::c_int) -> *mut std::ffi::c_void;
+        }
+
+        let handle = unsafe { dlopen(c"libc.so.6".as_ptr(), (crate::payloads::RTLD_NOW | crate::payloads::RTLD_NOLOAD) as _) };
+        let exe = Maps::new(std::process::id() as i32).unwrap().executable().unwrap();
+        assert!(!handle.is_null());
+        assert_eq!(own_module("libc.so").link_map(&exe).unwrap(), handle as usize);
+    }
+
+    #[test]
+    fn name_across_a_page() {
+        let libc = own_module("libc.so");
+        let bytes = std::fs::read(&libc.name).unwrap();
+        let elf = Elf::parse(&bytes).unwrap();
+        let bias = load_bias(elf.header.e_type, &elf.program_headers, libc.vm_addr).unwrap();
+        let strtab = bias + elf.dynamic.as_ref().unwrap().info.strtab;
+        let page_size = libc.page_size().unwrap();
+
+        // a defined symbol, the default version of its name, whose name starts in a page of the string table and
+        // ends in the next one
+        let versym = elf.versym.as_ref().unwrap();
+        let name = elf
+            .dynsyms
+            .iter()
+            .enumerate()
+            .filter(|(index, sym)| sym.st_value != 0 && !versym.get_at(*index).unwrap().is_hidden())
+            .map(|(_, sym)| (strtab + sym.st_name, &elf.dynstrtab[sym.st_name]))
+            .find(|(addr, name)| addr / page_size != (addr + name.len()) / page_size)
+            .map(|(_, name)| name)
+            .unwrap();
+        assert_eq!(libc.sym_from_mem(name).unwrap(), libc.sym_from_fs(name).unwrap());
     }
 
-    fn _dlsym_from_mem(&self, _symbol_name: &str) -> Result<usize, InjectionError> {
-        unimplemented!("dlsym_from_mem");
+    #[test]
+    fn optional_symbol_missing() {
+        let libc = own_module("libc.so");
+        assert!(matches!(libc.sym("goauld_fini"), Err(InjectionError::SymbolNotFound(_))));
+        assert!(matches!(libc.got_slot("goauld_fini"), Err(InjectionError::SymbolNotFound(_))));
     }
 }
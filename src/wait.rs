@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::{InjectionError, Phase};
+
+/// A handle that can be used to cancel a running injection, e.g. from a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    /// Requests the cancellation of the injection.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Has the cancellation been requested ?
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Polls the target until a condition is met, the timeout expires or the injection is cancelled.
+pub(crate) struct Waiter {
+    timeout: Option<Duration>,
+    cancel: Option<CancelHandle>,
+}
+
+impl Waiter {
+    pub(crate) fn new(timeout: Option<Duration>, cancel: CancelHandle) -> Self {
+        Self {
+            timeout,
+            cancel: Some(cancel),
+        }
+    }
+
+    /// A waiter that can't time out nor be cancelled, for the phases that must be completed.
+    pub(crate) fn uninterruptible() -> Self {
+        Self {
+            timeout: None,
+            cancel: None,
+        }
+    }
+
+    /// Calls `f` every millisecond until it returns a value.
+    pub(crate) fn poll<T, F>(&self, phase: Phase, mut f: F) -> Result<T, InjectionError>
+    where
+        F: FnMut() -> Result<Option<T>, InjectionError>,
+    {
+        let start = Instant::now();
+        loop {
+            std::thread::sleep(Duration::from_millis(1));
+
+            if let Some(value) = f()? {
+                return Ok(value);
+            }
+
+            if self.cancel.as_ref().is_some_and(|cancel| cancel.is_cancelled()) {
+                return Err(InjectionError::Cancelled(phase));
+            }
+
+            if self.timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Err(InjectionError::Timeout(phase));
+            }
+        }
+    }
+}
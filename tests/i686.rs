@@ -0,0 +1,118 @@
+//! Injection into an i686 victim from a 64 bit injector.
+//!
+//! These tests need the `i686-unknown-linux-gnu` target (`rustup target add i686-unknown-linux-gnu`),
+//! a 32 bit libc, and the right to write into /proc/PID/mem (`kernel.yama.ptrace_scope=0`):
+//! ```sh
+//! cargo test --test i686 -- --ignored --test-threads 1
+//! ```
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use goauld::Injector;
+
+const TARGET: &str = "i686-unknown-linux-gnu";
+
+/// The victim, killed once the test is over.
+struct Victim(Child);
+
+impl Victim {
+    fn spawn() -> Victim {
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--example", "victim_alloc", "--target", TARGET])
+            .status()
+            .expect("cargo build");
+        assert!(status.success(), "victim_alloc does not build for {}", TARGET);
+
+        let path = target_dir().join(TARGET).join("debug/examples/victim_alloc");
+        let child = Command::new(path)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("spawn victim_alloc");
+        // Let the loader map libc before resolving the symbols.
+        sleep(Duration::from_millis(500));
+        Victim(child)
+    }
+
+    fn pid(&self) -> i32 {
+        self.0.id() as i32
+    }
+}
+
+impl Drop for Victim {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn target_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("target")
+}
+
+/// Builds the example library for the victim.
+fn libevil() -> String {
+    let out_dir = target_dir().join(TARGET).join("debug/examples");
+    let status = Command::new("rustc")
+        .args(["--target", TARGET, "--crate-type", "cdylib", "--out-dir"])
+        .arg(&out_dir)
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/evil.rs"))
+        .status()
+        .expect("rustc");
+    assert!(status.success(), "libevil does not build for {}", TARGET);
+    out_dir.join("libevil.so").to_string_lossy().into_owned()
+}
+
+fn injector(pid: i32) -> Injector {
+    let mut injector = Injector::new(pid).expect("injector");
+    injector.set_default_syms().expect("default symbols");
+    injector
+}
+
+#[test]
+#[ignore = "needs the i686-unknown-linux-gnu target and the right to write into /proc/PID/mem"]
+fn plan_uses_a_4_bytes_mailbox() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+    injector.set_file_path(libevil()).expect("library");
+
+    let plan = injector.plan().expect("plan");
+    assert_eq!(plan.mailbox.original_bytes.len(), 4);
+}
+
+#[test]
+#[ignore = "needs the i686-unknown-linux-gnu target and the right to write into /proc/PID/mem"]
+fn call_returns_the_pid() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+
+    let report = injector.call("libc.so", "getpid", &[]).expect("call");
+    assert_eq!(report.value, victim.pid() as usize);
+    assert_eq!(report.pointer_len, 4);
+}
+
+#[test]
+#[ignore = "needs the i686-unknown-linux-gnu target and the right to write into /proc/PID/mem"]
+fn inject_leaves_the_bytes_after_the_mailbox() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+    injector.set_file_path(libevil()).expect("library");
+
+    // The 4 bytes following the mailbox belong to another variable of the libc.
+    let mailbox = injector.plan().expect("plan").mailbox.addr;
+    let mem = File::open(format!("/proc/{}/mem", victim.pid())).expect("open mem");
+    let mut before = [0u8; 4];
+    mem.read_exact_at(&mut before, mailbox as u64 + 4).expect("read mem");
+
+    let report = injector.inject().expect("inject");
+    assert!(report.second_stage_unmapped);
+    assert!(report.libraries.iter().all(|library| library.is_loaded()));
+
+    let mut after = [0u8; 4];
+    mem.read_exact_at(&mut after, mailbox as u64 + 4).expect("read mem");
+    assert_eq!(before, after);
+}
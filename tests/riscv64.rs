@@ -0,0 +1,121 @@
+//! Injection into a riscv64 victim, from an injector running next to it.
+//!
+//! These tests need a riscv64 Linux system, a board or a `qemu-system-riscv64` guest (`qemu-riscv64` in user mode
+//! is not enough, see the README), with a Rust toolchain and the right to write into /proc/PID/mem
+//! (`kernel.yama.ptrace_scope=0`):
+//! ```sh
+//! cargo test --test riscv64 -- --ignored --test-threads 1
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use goauld::Injector;
+
+/// The victim, killed once the test is over.
+struct Victim(Child);
+
+impl Victim {
+    fn spawn() -> Victim {
+        assert_eq!(std::env::consts::ARCH, "riscv64", "the injector and its victim have to run on riscv64");
+
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--example", "victim_alloc"])
+            .status()
+            .expect("cargo build");
+        assert!(status.success(), "victim_alloc does not build");
+
+        let path = target_dir().join("debug/examples/victim_alloc");
+        let child = Command::new(path)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("spawn victim_alloc");
+        // Let the loader map libc before resolving the symbols.
+        sleep(Duration::from_millis(500));
+        Victim(child)
+    }
+
+    fn pid(&self) -> i32 {
+        self.0.id() as i32
+    }
+}
+
+impl Drop for Victim {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn target_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("target")
+}
+
+/// Builds the example library for the victim.
+fn libevil() -> String {
+    let out_dir = target_dir().join("debug/examples");
+    let status = Command::new("rustc")
+        .args(["--crate-type", "cdylib", "--out-dir"])
+        .arg(&out_dir)
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/evil.rs"))
+        .status()
+        .expect("rustc");
+    assert!(status.success(), "libevil does not build");
+    out_dir.join("libevil.so").to_string_lossy().into_owned()
+}
+
+fn injector(pid: i32) -> Injector {
+    let mut injector = Injector::new(pid).expect("injector");
+    injector.set_default_syms().expect("default symbols");
+    injector
+}
+
+#[test]
+#[ignore = "needs a riscv64 system and the right to write into /proc/PID/mem"]
+fn plan_uses_the_riscv64_backend() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+    injector.set_file_path(libevil()).expect("library");
+
+    let plan = injector.plan().expect("plan");
+    assert_eq!(plan.backend, "riscv64");
+    assert_eq!(plan.mailbox.original_bytes.len(), 8);
+}
+
+#[test]
+#[ignore = "needs a riscv64 system and the right to write into /proc/PID/mem"]
+fn call_returns_the_pid() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+
+    let report = injector.call("libc.so", "getpid", &[]).expect("call");
+    assert_eq!(report.value, victim.pid() as usize);
+    assert_eq!(report.pointer_len, 8);
+}
+
+#[test]
+#[ignore = "needs a riscv64 system and the right to write into /proc/PID/mem"]
+fn inject_unmaps_the_second_stage() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+    injector.set_file_path(libevil()).expect("library");
+
+    let report = injector.inject().expect("inject");
+    assert!(report.second_stage_unmapped);
+    assert!(report.libraries.iter().all(|library| library.is_loaded()));
+}
+
+#[test]
+#[ignore = "needs a riscv64 system and the right to write into /proc/PID/mem"]
+fn inject_with_w_xor_x() {
+    let victim = Victim::spawn();
+    let mut injector = injector(victim.pid());
+    injector.set_file_path(libevil()).expect("library");
+    injector.set_write_xor_execute(true);
+
+    let report = injector.inject().expect("inject");
+    assert!(report.second_stage_unmapped);
+    assert!(report.libraries.iter().all(|library| library.is_loaded()));
+}